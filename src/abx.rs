@@ -0,0 +1,221 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use krusz::Sound;
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    error::ErrorKind,
+    repl::{play_to_end, render},
+    Opts,
+};
+
+/// Probability of guessing at least this well below which the difference counts as audible
+const SIGNIFICANCE: f64 = 0.05;
+
+const HELP: &str = "\
+A is the original sound, B is the KRUSZED one and X is randomly either of them.
+    a, b, x     Play A, B or X
+    x=a, x=b    Answer, and move on to the next trial
+    quit        Stop early and show the results";
+
+/// The outcome of a series of trials
+#[derive(Debug, PartialEq)]
+pub struct Score {
+    pub correct: u32,
+    pub trials: u32,
+}
+
+impl Score {
+    /// The probability of scoring at least as well by guessing at random
+    pub fn p_value(&self) -> f64 {
+        (self.correct..=self.trials)
+            .map(|k| binomial(self.trials, k))
+            .sum::<f64>()
+            / 2f64.powi(self.trials as i32)
+    }
+}
+
+fn binomial(n: u32, k: u32) -> f64 {
+    (0..k).fold(1.0, |c, i| c * (n - i) as f64 / (i + 1) as f64)
+}
+
+/// Runs the trials, reading answers from `lines` and playing sounds with `play`
+pub fn trials<L, P>(
+    original: &Sound,
+    crushed: &Sound,
+    count: u32,
+    rng: &mut ChaCha8Rng,
+    mut lines: L,
+    mut play: P,
+) -> Result<Score>
+where
+    L: Iterator<Item = io::Result<String>>,
+    P: FnMut(&Sound) -> Result<()>,
+{
+    let mut score = Score {
+        correct: 0,
+        trials: 0,
+    };
+
+    'trials: for trial in 1..=count {
+        let x_is_b = rng.gen::<bool>();
+
+        loop {
+            print!("Trial {}/{}> ", trial, count);
+            io::stdout().flush()?;
+
+            let line = match lines.next() {
+                Some(line) => line?,
+                None => break 'trials,
+            };
+
+            let answer = match line.trim().to_lowercase().as_str() {
+                "a" => play(original).map(|_| None),
+                "b" => play(crushed).map(|_| None),
+                "x" => play(if x_is_b { crushed } else { original }).map(|_| None),
+                "x=a" => Ok(Some(false)),
+                "x=b" => Ok(Some(true)),
+                "quit" | "exit" => break 'trials,
+                "" => Ok(None),
+                _ => {
+                    println!("{}", HELP);
+                    Ok(None)
+                }
+            };
+
+            match answer {
+                Ok(Some(answer)) => {
+                    score.trials += 1;
+                    if answer == x_is_b {
+                        score.correct += 1;
+                    }
+                    break;
+                }
+                Ok(None) => {}
+                Err(report) => eprintln!("Error: {:#}", report),
+            }
+        }
+    }
+
+    Ok(score)
+}
+
+/// Loads the input, then runs ABX trials between it and its KRUSZED version on stdin
+pub fn run(opts: &Opts, input: &Path, count: u32, mut rng: ChaCha8Rng) -> Result<()> {
+    let original = opts.decode(input).wrap_err(ErrorKind::Input)?;
+    let crushed = render(&original, &opts.settings(), opts.auto_gain, rng.gen())?;
+    let device = opts.device_config();
+
+    println!("{}", HELP);
+
+    let stdin = io::stdin();
+    let score = trials(
+        &original,
+        &crushed,
+        count,
+        &mut rng,
+        stdin.lock().lines(),
+        |sound| play_to_end(sound, &device),
+    )?;
+
+    if score.trials == 0 {
+        println!("No trials were answered");
+        return Ok(());
+    }
+
+    let p_value = score.p_value();
+    println!(
+        "{}/{} correct. The probability of doing at least as well by guessing is {:.1}%",
+        score.correct,
+        score.trials,
+        p_value * 100.0
+    );
+
+    if p_value < SIGNIFICANCE {
+        println!("The KRUSZED sound can reliably be told apart from the original");
+    } else {
+        println!("The KRUSZED sound could not reliably be told apart from the original");
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use krusz::Channel;
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_p_value() {
+        let p = |correct, trials| Score { correct, trials }.p_value();
+
+        assert_eq!(p(0, 10), 1.0);
+        assert_eq!(p(10, 10), 1.0 / 1024.0);
+        assert!((p(9, 10) - 11.0 / 1024.0).abs() < 1e-12);
+        assert!(p(7, 10) > SIGNIFICANCE);
+        assert!(p(12, 16) < SIGNIFICANCE);
+    }
+
+    #[test]
+    fn test_trials() {
+        let sound = |sample| Sound {
+            channels: vec![Channel {
+                samples: vec![sample; 10],
+            }],
+            sample_rate: 44100,
+        };
+        let (original, crushed) = (sound(0), sound(1));
+
+        // Listens to everything, then answers every trial correctly but the first
+        let mut answers = ChaCha8Rng::seed_from_u64(3);
+        let mut lines = Vec::new();
+        for trial in 0..5 {
+            let x_is_b = answers.gen::<bool>();
+            lines.extend(["a", "b", "x", "?"].map(String::from));
+            lines.push(if x_is_b == (trial > 0) { "x=b" } else { "x=a" }.to_owned());
+        }
+
+        let mut played = 0;
+        let score = trials(
+            &original,
+            &crushed,
+            5,
+            &mut ChaCha8Rng::seed_from_u64(3),
+            lines.into_iter().map(Ok),
+            |_| {
+                played += 1;
+                Ok(())
+            },
+        )
+        .unwrap();
+
+        assert_eq!(
+            score,
+            Score {
+                correct: 4,
+                trials: 5
+            }
+        );
+        assert_eq!(played, 15);
+
+        let score = trials(
+            &original,
+            &crushed,
+            5,
+            &mut ChaCha8Rng::seed_from_u64(3),
+            ["x=a", "quit", "x=a"]
+                .map(|line| Ok(line.to_owned()))
+                .into_iter(),
+            |_| Ok(()),
+        )
+        .unwrap();
+        assert_eq!(score.trials, 1);
+    }
+}
@@ -0,0 +1,219 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display, Formatter},
+};
+
+use num::Complex;
+
+use crate::{sample::FULL_SCALE, stft::fft, Sound};
+
+/// Frames of each analysis window, about 10 Hz per bin at 44.1 kHz
+const WINDOW: usize = 4096;
+/// Frames between the starts of consecutive windows, half a window apart
+const HOP: usize = WINDOW / 2;
+
+/// Measures, block by block, how the spectrum of a sound restored from a lower sample rate splits
+/// between the band below the KRUSZED Nyquist frequency and the images of it which the resampler
+/// leaves above, each as wide as the band itself. Averages the power spectra of overlapping Hann
+/// windows over all channels.
+pub struct AliasMeter {
+    crushed_rate: u32,
+    sample_rate: u32,
+    /// Samples of each channel not yet shifted out of the window
+    buffers: Vec<Vec<f64>>,
+    /// Power summed over every window, from DC up to the Nyquist frequency
+    power: Vec<f64>,
+}
+
+impl AliasMeter {
+    /// For a sound KRUSZED at `crushed_rate` and restored to `sample_rate`
+    pub fn new(crushed_rate: u32, sample_rate: u32) -> Self {
+        Self {
+            crushed_rate,
+            sample_rate,
+            buffers: Vec::new(),
+            power: vec![0.0; WINDOW / 2 + 1],
+        }
+    }
+
+    pub fn update(&mut self, block: &Sound) {
+        self.buffers
+            .resize_with(block.channels.len(), || Vec::with_capacity(WINDOW));
+
+        for (buffer, channel) in self.buffers.iter_mut().zip(&block.channels) {
+            buffer.extend(
+                channel
+                    .samples
+                    .iter()
+                    .map(|&sample| sample as f64 / FULL_SCALE),
+            );
+
+            while buffer.len() >= WINDOW {
+                analyze(&buffer[..WINDOW], &mut self.power);
+                buffer.drain(..HOP);
+            }
+        }
+    }
+
+    /// The levels of the band and its images over everything seen so far. Sounds shorter than a
+    /// window are analyzed as a single window padded with silence.
+    pub fn report(mut self) -> AliasReport {
+        if self.power.iter().all(|&power| power == 0.0) {
+            for buffer in &mut self.buffers {
+                buffer.resize(WINDOW, 0.0);
+                analyze(buffer, &mut self.power);
+            }
+        }
+
+        let nyquist = self.crushed_rate as f64 / 2.0;
+        let top = self.sample_rate as f64 / 2.0;
+        let count = (top / nyquist).ceil().max(1.0) as usize;
+
+        let mut bands: Vec<Band> = (0..count)
+            .map(|i| Band {
+                low: nyquist * i as f64,
+                high: (nyquist * (i + 1) as f64).min(top),
+                power: 0.0,
+            })
+            .collect();
+        for (bin, power) in self.power.iter().enumerate() {
+            let frequency = bin as f64 * self.sample_rate as f64 / WINDOW as f64;
+            let band = ((frequency / nyquist) as usize).min(count - 1);
+            bands[band].power += power;
+        }
+
+        AliasReport {
+            crushed_rate: self.crushed_rate,
+            bands,
+        }
+    }
+}
+
+/// Adds the power spectrum of a Hann windowed frame to `power`
+fn analyze(frame: &[f64], power: &mut [f64]) {
+    let mut spectrum: Vec<Complex<f64>> = frame
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let window = 0.5 - 0.5 * (2.0 * PI * i as f64 / WINDOW as f64).cos();
+            Complex::new(x * window, 0.0)
+        })
+        .collect();
+    fft(&mut spectrum, false);
+
+    for (power, bin) in power.iter_mut().zip(&spectrum) {
+        *power += bin.norm_sqr();
+    }
+}
+
+/// A band of the spectrum, in Hz, and the power measured in it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub low: f64,
+    pub high: f64,
+    power: f64,
+}
+
+/// How much of a restored sound lies in the images above the KRUSZED Nyquist frequency, printed with
+/// --alias-report as the level of each band relative to the band below it
+#[derive(Clone, Debug, PartialEq)]
+pub struct AliasReport {
+    crushed_rate: u32,
+    /// The band below the KRUSZED Nyquist frequency first, then its images
+    pub bands: Vec<Band>,
+}
+
+impl AliasReport {
+    /// Level of a band relative to the band below the KRUSZED Nyquist frequency, in dB
+    pub fn level(&self, band: &Band) -> f64 {
+        10.0 * (band.power / self.bands[0].power).log10()
+    }
+
+    /// Level of all the images together relative to the band below the KRUSZED Nyquist frequency,
+    /// in dB
+    pub fn imaging(&self) -> f64 {
+        let images = self.bands[1..].iter().map(|band| band.power).sum::<f64>();
+        10.0 * (images / self.bands[0].power).log10()
+    }
+}
+
+impl Display for AliasReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{:<24} {:>10}", "band", "level")?;
+
+        for band in &self.bands {
+            writeln!(
+                f,
+                "{:<24} {:>10}",
+                format!("{:.0}-{:.0} Hz", band.low, band.high),
+                format!("{:.1} dB", self.level(band))
+            )?;
+        }
+
+        writeln!(
+            f,
+            "{:<24} {:>10}",
+            format!("images above {:.0} Hz", self.crushed_rate as f64 / 2.0),
+            format!("{:.1} dB", self.imaging())
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        resample::{Interpolation, Resampler},
+        stream::Stage,
+        Channel,
+    };
+
+    /// A 1 kHz tone at 8 kHz, restored to 44.1 kHz
+    fn restored(interpolation: Interpolation) -> Sound {
+        let tone = Sound {
+            channels: vec![Channel {
+                samples: (0..8000)
+                    .map(|i| {
+                        let t = i as f64 / 8000.0;
+                        ((t * 1000.0 * 2.0 * PI).sin() * 0.5 * FULL_SCALE) as i32
+                    })
+                    .collect(),
+            }],
+            sample_rate: 8000,
+        };
+
+        Resampler::new(8000, 44100, interpolation).run(tone)
+    }
+
+    fn report(sound: &Sound, block: usize) -> AliasReport {
+        let mut meter = AliasMeter::new(8000, 44100);
+        for start in (0..sound.frames()).step_by(block) {
+            let end = (start + block).min(sound.frames());
+            meter.update(&Sound {
+                channels: vec![Channel {
+                    samples: sound.channels[0].samples[start..end].to_vec(),
+                }],
+                sample_rate: sound.sample_rate,
+            });
+        }
+        meter.report()
+    }
+
+    #[test]
+    fn test_alias_report() {
+        let nearest = report(&restored(Interpolation::Nearest), 44100);
+        let linear = report(&restored(Interpolation::Linear), 44100);
+
+        assert_eq!(nearest.bands.len(), 6);
+        assert_eq!(nearest.bands[5].high, 22050.0);
+        assert_eq!(nearest.level(&nearest.bands[0]), 0.0);
+
+        // Linear interpolation holds the images down better than holding each sample
+        assert!(linear.imaging() < nearest.imaging() - 6.0);
+        assert!(nearest.imaging() > -40.0);
+
+        // The same however the sound is split into blocks
+        let blocks = report(&restored(Interpolation::Nearest), 1000);
+        assert!((blocks.imaging() - nearest.imaging()).abs() < 1e-9);
+    }
+}
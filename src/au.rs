@@ -0,0 +1,173 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::Result;
+
+use crate::{
+    output::{Encoder, OutputOptions},
+    sample::{to_i16, Sample},
+    simd, Sound,
+};
+
+/// Size of the header, which is also where the data starts
+const HEADER_SIZE: u32 = 24;
+
+/// Data size of files whose length isn't known
+const UNKNOWN_SIZE: u32 = u32::MAX;
+
+/// AU encoding of 8-bit G.711 μ-law samples
+const MU_LAW: u32 = 1;
+
+/// Offset added to magnitudes before μ-law encoding, so that the segments line up
+const MU_LAW_BIAS: i32 = 0x84;
+
+/// Largest magnitude μ-law can encode
+const MU_LAW_CLIP: i32 = 32635;
+
+/// Encodes a sample as G.711 μ-law, which keeps about 14 bits of dynamic range in 8 bits by
+/// spacing levels logarithmically
+pub fn to_mu_law(sample: Sample) -> u8 {
+    let sample = i32::from(to_i16(sample));
+    let sign = if sample < 0 { 0x80 } else { 0 };
+    let magnitude = sample.abs().min(MU_LAW_CLIP) + MU_LAW_BIAS;
+
+    let exponent = 24 - magnitude.leading_zeros();
+    let mantissa = (magnitude >> (exponent + 3)) & 0x0f;
+
+    !(sign | (exponent << 4) as u8 | mantissa as u8)
+}
+
+/// Writes Sun/NeXT AU files: a short big endian header followed by interleaved big endian
+/// samples, either linear PCM or μ-law
+pub struct AuEncoder {
+    file: BufWriter<File>,
+    bits_per_sample: u16,
+    mu_law: bool,
+    data_size: u64,
+}
+
+impl AuEncoder {
+    pub(crate) fn create(
+        path: &Path,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let encoding = if options.mu_law {
+            MU_LAW
+        } else {
+            // 8, 16, 24 and 32-bit linear PCM are encodings 2 to 5
+            u32::from(options.bits_per_sample / 8 + 1)
+        };
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b".snd")?;
+        file.write_all(&HEADER_SIZE.to_be_bytes())?;
+        // Filled in by finalize
+        file.write_all(&UNKNOWN_SIZE.to_be_bytes())?;
+        file.write_all(&encoding.to_be_bytes())?;
+        file.write_all(&sample_rate.to_be_bytes())?;
+        file.write_all(&u32::try_from(channels)?.to_be_bytes())?;
+
+        Ok(Self {
+            file,
+            bits_per_sample: options.bits_per_sample,
+            mu_law: options.mu_law,
+            data_size: 0,
+        })
+    }
+}
+
+impl Encoder for AuEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let samples = simd::interleave(&block.channels);
+
+        let bytes: Vec<u8> = if self.mu_law {
+            samples.into_iter().map(to_mu_law).collect()
+        } else {
+            let width = usize::from(self.bits_per_sample / 8);
+            samples
+                .into_iter()
+                .flat_map(|sample| sample.to_be_bytes().into_iter().take(width))
+                .collect()
+        };
+
+        self.file.write_all(&bytes)?;
+        self.data_size += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<()> {
+        let mut file = self.file;
+
+        // Longer files keep the unknown size, readers then read to the end
+        if let Ok(data_size) = u32::try_from(self.data_size) {
+            file.seek(SeekFrom::Start(8))?;
+            file.write_all(&data_size.to_be_bytes())?;
+        }
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    #[test]
+    fn test_mu_law() {
+        assert_eq!(to_mu_law(0), 0xff);
+        assert_eq!(to_mu_law(i32::MAX), 0x80);
+        assert_eq!(to_mu_law(i32::MIN), 0x00);
+        assert_eq!(to_mu_law(1000 << 16), 0xce);
+        assert_eq!(to_mu_law(-1000 << 16), 0x4e);
+    }
+
+    #[test]
+    fn test_save_au() {
+        let path = std::env::temp_dir().join("krusz_test_save_au.au");
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0x1234_5678, -1],
+                },
+                Channel {
+                    samples: vec![i32::MIN, 0],
+                },
+            ],
+            sample_rate: 8000,
+        };
+
+        save(&sound, &path, OutputFormat::Au, OutputOptions::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let header: Vec<u32> = bytes[4..24]
+            .chunks(4)
+            .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(&bytes[..4], b".snd");
+        assert_eq!(header, [24, 8, 3, 8000, 2]);
+        assert_eq!(
+            bytes[24..],
+            [0x12, 0x34, 0x80, 0x00, 0xff, 0xff, 0x00, 0x00]
+        );
+
+        let options = OutputOptions {
+            mu_law: true,
+            ..OutputOptions::default()
+        };
+        save(&sound, &path, OutputFormat::Au, options).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes[8..16], [0, 0, 0, 4, 0, 0, 0, 1]);
+        assert_eq!(bytes[24..], [0xad, 0x00, 0x7f, 0xff]);
+    }
+}
@@ -0,0 +1,97 @@
+use std::path::Path;
+
+use clap::ArgEnum;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use krusz::Sound;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    error::ErrorKind,
+    excerpt,
+    preset::Preset,
+    repl::{play_to_end, render},
+    Opts,
+};
+
+fn name(preset: Preset) -> &'static str {
+    preset.to_possible_value().unwrap().get_name()
+}
+
+/// KRUSZES the first `duration` seconds of the input with each of the presets, or all of them if
+/// none are given, then plays them back to back, announcing each
+pub fn run(opts: &Opts, input: &Path, presets: &[Preset], duration: f64) -> Result<()> {
+    if opts.preset.is_some() {
+        return Err(eyre!(
+            "--preset can't be used with audition, list the presets to compare with --presets instead"
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    }
+    if duration <= 0.0 {
+        return Err(eyre!("Audition duration must be positive")).wrap_err(ErrorKind::Parameter);
+    }
+
+    let presets = if presets.is_empty() {
+        Preset::value_variants()
+    } else {
+        presets
+    };
+
+    let mut rng = match opts.seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+
+    let original = excerpt(opts, input, duration)?;
+
+    // All rendered up front, so that they play without gaps in between
+    let renders = presets
+        .iter()
+        .map(|&preset| {
+            let mut opts = opts.clone();
+            preset.apply(&mut opts);
+
+            let settings = opts.settings();
+            settings.validate().wrap_err(ErrorKind::Parameter)?;
+            render(&original, &settings, opts.auto_gain, rng.gen())
+        })
+        .collect::<Result<Vec<Sound>>>()?;
+
+    let device = opts.device_config();
+    for (i, (&preset, sound)) in presets.iter().zip(&renders).enumerate() {
+        println!("{}/{}: {}", i + 1, presets.len(), name(preset));
+        play_to_end(sound, &device)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+    use crate::Command;
+
+    #[test]
+    fn test_presets() {
+        let opts = Opts::parse_from([
+            "krusz",
+            "audition",
+            "-i",
+            "file.wav",
+            "--presets",
+            "cassette,digi",
+        ]);
+        match opts.command {
+            Some(Command::Audition { presets, .. }) => {
+                assert_eq!(presets, [Preset::Cassette, Preset::Digi]);
+            }
+            _ => panic!("Expected the audition subcommand"),
+        }
+
+        let opts = Opts::parse_from(["krusz", "--preset", "digi", "audition", "-i", "file.wav"]);
+        let error = run(&opts, Path::new("file.wav"), &[], 5.0).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), Some(ErrorKind::Parameter));
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+
+use crate::{
+    crusher::Settings,
+    filter::{parse_frequency, FilterKind, FilterSpec, FilterStage},
+    sample::{to_sample, ClipMode},
+    stream::{Pipeline, Stage},
+    Channel, Sound,
+};
+
+/// Q of the Butterworth filters, two of which make each Linkwitz-Riley crossover
+const BUTTERWORTH_Q: f64 = std::f64::consts::FRAC_1_SQRT_2;
+
+/// A frequency band KRUSZED at a bit depth of its own, e.g. `200-2000:8bit`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    /// Crossover frequencies below and above the band, if any
+    pub low: Option<f64>,
+    pub high: Option<f64>,
+    pub bit_depth: f64,
+}
+
+/// Bands as given on the command line, e.g. `0-200:12bit,200-2000:8bit,2000-:4bit`, from the lowest
+/// up. Each band starts where the one below it ends, the lowest starts at 0 and the highest is
+/// open ended.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BandsSpec(pub Vec<Band>);
+
+impl FromStr for BandsSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let form = || {
+            eyre!("Bands must be of the form <low>-<high>:<bits>bit, separated by commas, e.g. \"0-200:12bit,200-:4bit\"")
+        };
+        let edge = |edge: &str| -> Result<Option<f64>> {
+            match edge.trim() {
+                "" | "0" => Ok(None),
+                edge => parse_frequency(edge).map(Some),
+            }
+        };
+
+        let bands = s
+            .split(',')
+            .map(|band| {
+                let (range, depth) = band.split_once(':').ok_or_else(form)?;
+                let (low, high) = range.split_once('-').ok_or_else(form)?;
+                let depth = depth.trim().to_lowercase();
+                let bit_depth: f64 = depth.strip_suffix("bit").unwrap_or(&depth).trim().parse()?;
+
+                ensure!(
+                    (1.0..=32.0).contains(&bit_depth),
+                    "Band bit depths must be between 1 and 32 bits inclusive"
+                );
+
+                Ok(Band {
+                    low: edge(low)?,
+                    high: edge(high)?,
+                    bit_depth,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(bands.len() >= 2, "There must be at least two bands");
+        ensure!(
+            bands[0].low.is_none() && bands[bands.len() - 1].high.is_none(),
+            "The lowest band must start at 0 and the highest must be open ended, as in \"2000-\""
+        );
+        for pair in bands.windows(2) {
+            ensure!(
+                pair[0].high.is_some() && pair[0].high == pair[1].low,
+                "Each band must start where the one below it ends"
+            );
+        }
+
+        Ok(Self(bands))
+    }
+}
+
+impl Display for BandsSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, band) in self.0.iter().enumerate() {
+            write!(
+                f,
+                "{}{}-{}:{}bit",
+                if i == 0 { "" } else { "," },
+                band.low.unwrap_or(0.0),
+                band.high.map_or_else(String::new, |high| high.to_string()),
+                band.bit_depth
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+serde_via_str!(BandsSpec);
+
+/// The Linkwitz-Riley filters isolating a band: two Butterworth filters at each of its edges
+pub fn crossover(band: &Band, sample_rate: u32, clip_mode: ClipMode) -> Result<FilterStage> {
+    let butterworth = |kind, frequency| FilterSpec {
+        kind,
+        frequency,
+        q: BUTTERWORTH_Q,
+        gain: 0.0,
+    };
+
+    let specs: Vec<_> = band
+        .low
+        .map(|low| butterworth(FilterKind::Highpass, low))
+        .into_iter()
+        .chain(band.high.map(|high| butterworth(FilterKind::Lowpass, high)))
+        .flat_map(|spec| [spec, spec])
+        .collect();
+
+    FilterStage::new(&specs, sample_rate, clip_mode)
+}
+
+/// Splits the sound into bands, runs each through stages of its own and sums them back up. The
+/// bands' stages may hold back different amounts, so what each has output is kept until the others
+/// have caught up.
+pub struct MultibandStage {
+    bands: Vec<(Band, Pipeline)>,
+    /// Output of each band not yet summed
+    pending: Vec<Vec<Channel>>,
+    sample_rate: u32,
+    clip_mode: ClipMode,
+}
+
+impl MultibandStage {
+    /// Takes each band along with the stages which isolate and KRUSZE it
+    pub fn new(bands: Vec<(Band, Pipeline)>, sample_rate: u32, clip_mode: ClipMode) -> Self {
+        Self {
+            pending: vec![Vec::new(); bands.len()],
+            bands,
+            sample_rate,
+            clip_mode,
+        }
+    }
+
+    fn queue(&mut self, band: usize, output: Sound) {
+        let pending = &mut self.pending[band];
+        if pending.len() < output.channels.len() {
+            pending.resize(output.channels.len(), Channel::default());
+        }
+
+        for (pending, channel) in pending.iter_mut().zip(output.channels) {
+            pending.samples.extend(channel.samples);
+        }
+    }
+
+    /// Sums as many frames as every band has output, or all of them at the end, where bands which
+    /// came out shorter are padded with silence
+    fn sum(&mut self, end: bool) -> Sound {
+        let length = |pending: &Vec<Channel>| pending.first().map_or(0, |c| c.samples.len());
+        let lengths = self.pending.iter().map(length);
+        let frames = if end {
+            lengths.max().unwrap_or(0)
+        } else {
+            lengths.min().unwrap_or(0)
+        };
+        let channels = self.pending.iter().map(Vec::len).max().unwrap_or(0);
+
+        let mut sum = vec![vec![0i64; frames]; channels];
+        for pending in &mut self.pending {
+            for (sum, channel) in sum.iter_mut().zip(pending) {
+                let take = frames.min(channel.samples.len());
+                for (sum, sample) in sum.iter_mut().zip(channel.samples.drain(..take)) {
+                    *sum += i64::from(sample);
+                }
+            }
+        }
+
+        let clip_mode = self.clip_mode;
+        Sound {
+            channels: sum
+                .into_iter()
+                .map(|sum| Channel {
+                    samples: sum
+                        .into_iter()
+                        .map(|s| to_sample(s as f64, clip_mode))
+                        .collect(),
+                })
+                .collect(),
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+impl Stage for MultibandStage {
+    fn name(&self) -> &'static str {
+        "multiband"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        for band in 0..self.bands.len() {
+            let output = self.bands[band].1.process(chunk.clone());
+            self.queue(band, output);
+        }
+
+        self.sum(false)
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        for band in 0..self.bands.len() {
+            if let Some(tail) = self.bands[band].1.finish() {
+                self.queue(band, tail);
+            }
+        }
+
+        Some(self.sum(true))
+    }
+
+    /// Each band keeps its own bit depth, and follows the rest
+    fn retune(&mut self, settings: &Settings) {
+        for (band, stages) in &mut self.bands {
+            stages.retune(&Settings {
+                bit_depth: band.bit_depth,
+                ..settings.clone()
+            });
+        }
+    }
+
+    fn latency(&self) -> f64 {
+        self.bands
+            .iter()
+            .map(|(_, stages)| stages.latency())
+            .fold(0.0, f64::max)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{
+        gain::rms,
+        stream::{gather, split},
+    };
+
+    #[test]
+    fn test_parse_bands() {
+        let bands: BandsSpec = "0-200:12bit,200-2k:8bit,2000-:4bit".parse().unwrap();
+        assert_eq!(
+            bands.0[1],
+            Band {
+                low: Some(200.0),
+                high: Some(2000.0),
+                bit_depth: 8.0
+            }
+        );
+        assert_eq!(bands.0[2].high, None);
+        assert_eq!(bands.to_string().parse::<BandsSpec>().unwrap(), bands);
+
+        assert!("0-200:12bit".parse::<BandsSpec>().is_err());
+        assert!("0-200:12bit,300-:4bit".parse::<BandsSpec>().is_err());
+        assert!("0-200:12bit,200-1k:4bit".parse::<BandsSpec>().is_err());
+        assert!("0-200:40bit,200-:4bit".parse::<BandsSpec>().is_err());
+    }
+
+    /// A low tone and a high one
+    fn tones() -> Sound {
+        Sound {
+            channels: vec![Channel {
+                samples: (0..8000)
+                    .map(|i| {
+                        let t = i as f64 / 44100.0;
+                        let low = (t * 100.0 * std::f64::consts::TAU).sin();
+                        let high = (t * 5000.0 * std::f64::consts::TAU).sin();
+                        ((low + high) * 0.4 * crate::sample::FULL_SCALE) as i32
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        }
+    }
+
+    #[test]
+    fn test_multiband() {
+        let settings = Settings {
+            bands: Some("0-1k:16bit,1k-:16bit".parse().unwrap()),
+            ..Settings::default()
+        };
+        let pipeline = || {
+            settings
+                .pipeline(1, 44100, &mut ChaCha8Rng::seed_from_u64(0))
+                .unwrap()
+        };
+
+        // At full resolution, the crossovers sum back up to the same level
+        let split_up = pipeline().run(tones());
+        assert_eq!(split_up.frames(), 8000);
+        let level = rms(&split_up) / rms(&tones());
+        assert!((level - 1.0).abs() < 0.05, "{}", level);
+
+        let chunked = gather(pipeline().pull(split(tones(), 333))).unwrap();
+        assert_eq!(chunked.channels[0].samples, split_up.channels[0].samples);
+    }
+}
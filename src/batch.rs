@@ -0,0 +1,594 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use clap::{CommandFactory, Parser};
+use color_eyre::eyre::{bail, ensure, eyre, Result, WrapErr};
+use serde::Deserialize;
+use toml::{value::Table, Value};
+use tracing::warn;
+
+use krusz::{checksum::hash_file, input::is_url};
+
+use crate::Opts;
+
+/// Name of the manifest written in the output directory of batch runs
+pub const MANIFEST_NAME: &str = "krusz-manifest.tsv";
+
+/// Identifies the exact state of an input file, so changed inputs get KRUSZED again on --resume
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct InputStamp {
+    modified: u128,
+    size: u64,
+}
+
+impl InputStamp {
+    fn of(path: &Path) -> Result<Self> {
+        // There's no cheap way to tell whether a remote file changed, so assume it didn't
+        if is_url(path) {
+            return Ok(Self {
+                modified: 0,
+                size: 0,
+            });
+        }
+
+        let metadata = fs::metadata(path)?;
+
+        Ok(Self {
+            modified: metadata.modified()?.duration_since(UNIX_EPOCH)?.as_nanos(),
+            size: metadata.len(),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Entry {
+    settings: String,
+    input: PathBuf,
+    stamp: InputStamp,
+    output_hash: String,
+}
+
+/// Record of the outputs completed by a batch run, one tab-separated line per output:
+/// settings fingerprint, input path, input modification time, input size, output path and output hash.
+/// Lines are appended as soon as each output is written, so the record survives interruptions.
+pub struct Manifest {
+    file: File,
+    settings: String,
+    entries: HashMap<PathBuf, Entry>,
+}
+
+impl Manifest {
+    /// Opens the manifest in the given directory. When `resume` is false any previous record is discarded.
+    /// `settings` fingerprints the processing parameters, so outputs made with other settings aren't skipped.
+    pub fn open(dir: &Path, settings: String, resume: bool) -> Result<Self> {
+        let path = dir.join(MANIFEST_NAME);
+        let mut entries = HashMap::new();
+
+        if resume && path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                let line = line?;
+
+                match parse_line(&line) {
+                    Some((output, entry)) => {
+                        entries.insert(output, entry);
+                    }
+                    None => warn!("Ignoring malformed manifest line {:?}", line),
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(resume)
+            .write(true)
+            .truncate(!resume)
+            .open(&path)?;
+
+        Ok(Self {
+            file,
+            settings,
+            entries,
+        })
+    }
+
+    /// Whether the output was already made from the current version of the input with the current settings,
+    /// and hasn't been modified since
+    pub fn is_complete(&self, input: &Path, output: &Path) -> Result<bool> {
+        let entry = match self.entries.get(output) {
+            Some(entry) => entry,
+            None => return Ok(false),
+        };
+
+        Ok(entry.settings == self.settings
+            && entry.input == input
+            && entry.stamp == InputStamp::of(input)?
+            && output.exists()
+            && entry.output_hash == hash_file(output)?)
+    }
+
+    /// Fingerprints the settings of the outputs checked and recorded from now on, for batches whose
+    /// files have settings of their own
+    pub fn set_settings(&mut self, settings: String) {
+        self.settings = settings;
+    }
+
+    pub fn record(&mut self, input: &Path, output: &Path) -> Result<()> {
+        let entry = Entry {
+            settings: self.settings.clone(),
+            input: input.to_owned(),
+            stamp: InputStamp::of(input)?,
+            output_hash: hash_file(output)?,
+        };
+
+        writeln!(
+            self.file,
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            entry.settings,
+            entry.input.display(),
+            entry.stamp.modified,
+            entry.stamp.size,
+            output.display(),
+            entry.output_hash
+        )?;
+        self.file.flush()?;
+
+        self.entries.insert(output.to_owned(), entry);
+
+        Ok(())
+    }
+}
+
+fn parse_line(line: &str) -> Option<(PathBuf, Entry)> {
+    let mut fields = line.split('\t');
+
+    let settings = fields.next()?.to_owned();
+    let input = PathBuf::from(fields.next()?);
+    let modified = fields.next()?.parse().ok()?;
+    let size = fields.next()?.parse().ok()?;
+    let output = PathBuf::from(fields.next()?);
+    let output_hash = fields.next()?.to_owned();
+
+    if fields.next().is_some() {
+        return None;
+    }
+
+    Some((
+        output,
+        Entry {
+            settings,
+            input,
+            stamp: InputStamp { modified, size },
+            output_hash,
+        },
+    ))
+}
+
+/// A file listed in a --batch list, with the options it overrides as command line arguments
+#[derive(Clone, Debug, PartialEq)]
+pub struct BatchEntry {
+    pub input: PathBuf,
+    args: Vec<String>,
+    /// Names of the options overridden, as they're stored in sessions
+    names: Vec<String>,
+}
+
+impl BatchEntry {
+    /// The options of the file: those given on the command line, with its own on top. Parsed on
+    /// their own and merged in as they're stored in sessions, the same way sessions merge in the
+    /// command line. `defaults` are the options of the run, and `given` the same options before
+    /// the preset of the command line filled any in, so that the file's own preset can fill them
+    /// in instead.
+    pub fn opts(&self, defaults: &Opts, given: &Opts) -> Result<Opts> {
+        let input = self.input.to_string_lossy();
+        let args = ["krusz", "--input", &input]
+            .into_iter()
+            .chain(self.args.iter().map(String::as_str));
+        let own = Opts::try_parse_from(args)
+            .map_err(|e| eyre!("{}", e.to_string().trim()))
+            .wrap_err_with(|| format!("Invalid options for {}", self.input.display()))?;
+
+        let mut options = table(defaults)?;
+        let own = table(&own)?;
+
+        // Whatever the preset of the command line filled in goes back to how it was given
+        if let Some(preset) = given.preset {
+            let mut filled = given.clone();
+            preset.apply(&mut filled);
+
+            let given = table(given)?;
+            for (name, value) in table(&filled)? {
+                match given.get(&name) {
+                    Some(original) if *original == value => {}
+                    Some(original) => {
+                        options.insert(name, original.clone());
+                    }
+                    None => {
+                        options.remove(&name);
+                    }
+                }
+            }
+        }
+
+        for name in &self.names {
+            match own.get(name) {
+                Some(value) => options.insert(name.clone(), value.clone()),
+                None => options.remove(name),
+            };
+        }
+
+        let mut opts: Opts = Value::Table(options).try_into()?;
+
+        // Not stored in sessions, and not overridable per file
+        opts.input = vec![self.input.clone()];
+        opts.play_null = defaults.play_null;
+        opts.host = defaults.host.clone();
+        opts.buffer_size = defaults.buffer_size;
+        opts.seed = defaults.seed;
+        opts.randomize = defaults.randomize;
+        opts.list_formats = defaults.list_formats;
+        opts.stats = defaults.stats;
+
+        if let Some(preset) = opts.preset {
+            preset.apply(&mut opts);
+        }
+
+        Ok(opts)
+    }
+}
+
+/// Options as they're stored in sessions
+fn table(opts: &Opts) -> Result<Table> {
+    match Value::try_from(opts)? {
+        Value::Table(table) => Ok(table),
+        _ => unreachable!("Options are always serialized as a table"),
+    }
+}
+
+/// Options which apply to the whole run, such as where and how the outputs are written, and so
+/// can't be given to a single file
+const RUN_OPTIONS: &[&str] = &[
+    "batch",
+    "buffer-size",
+    "chain",
+    "format",
+    "host",
+    "input-raw-bytes",
+    "interpret",
+    "list-formats",
+    "load-session",
+    "log-level",
+    "output",
+    "output-dir",
+    "play-null",
+    "preview",
+    "randomize",
+    "resume",
+    "save-session",
+    "seed",
+    "stats",
+];
+
+#[derive(Deserialize)]
+struct BatchFile {
+    file: Vec<Table>,
+}
+
+/// Reads a --batch list of inputs and their options, as TOML with a `[[file]]` table per input, or as
+/// CSV with an `input` column and a column per option. Options are named as on the command line,
+/// with or without the leading dashes, or as in saved sessions, and empty CSV cells leave an option as it is. Relative paths are
+/// relative to the list's directory.
+pub fn read_batch(path: &Path) -> Result<Vec<BatchEntry>> {
+    let contents = fs::read_to_string(path)?;
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+    let is_csv = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("csv"));
+
+    let files: Vec<Vec<(String, Value)>> = if is_csv {
+        let mut lines = contents
+            .lines()
+            .map(|line| line.trim_start_matches('\u{feff}'))
+            .filter(|line| !line.trim().is_empty());
+        let header = split_csv(
+            lines
+                .next()
+                .ok_or_else(|| eyre!("The batch list is empty"))?,
+        );
+
+        lines
+            .map(|line| {
+                let cells = split_csv(line);
+                ensure!(
+                    cells.len() <= header.len(),
+                    "Batch line {:?} has more cells than the header",
+                    line
+                );
+
+                Ok(header
+                    .iter()
+                    .zip(cells)
+                    .filter(|(_, cell)| !cell.is_empty())
+                    .map(|(name, cell)| (name.clone(), Value::String(cell)))
+                    .collect())
+            })
+            .collect::<Result<_>>()?
+    } else {
+        let batch: BatchFile = toml::from_str(&contents)?;
+        batch
+            .file
+            .into_iter()
+            .map(|table| table.into_iter().collect())
+            .collect()
+    };
+
+    let command = Opts::command();
+    files
+        .into_iter()
+        .map(|options| {
+            let mut input = None;
+            let mut args = Vec::new();
+            let mut names = Vec::new();
+
+            for (name, value) in options {
+                let name = name.trim().trim_start_matches('-').replace('_', "-");
+                if name == "input" {
+                    let path = PathBuf::from(value_string(&value)?);
+                    input = Some(if is_url(&path) || path.is_absolute() {
+                        path
+                    } else {
+                        dir.join(path)
+                    });
+                    continue;
+                }
+                ensure!(
+                    !RUN_OPTIONS.contains(&name.as_str()),
+                    "{} applies to the whole run, and can't be set per file in the batch list",
+                    name
+                );
+
+                let arg = command
+                    .get_arguments()
+                    .find(|arg| arg.get_long() == Some(&name))
+                    .ok_or_else(|| eyre!("Unknown option {} in the batch list", name))?;
+                names.push(arg.get_id().replace('-', "_"));
+                let values = match value {
+                    Value::Array(values) => values,
+                    value => vec![value],
+                };
+
+                for value in values {
+                    if arg.is_takes_value_set() {
+                        args.push(format!("--{}={}", name, value_string(&value)?));
+                    } else if flag_value(&value)? {
+                        args.push(format!("--{}", name));
+                    }
+                }
+            }
+
+            Ok(BatchEntry {
+                input: input.ok_or_else(|| eyre!("Every file in the batch list needs an input"))?,
+                args,
+                names,
+            })
+        })
+        .collect()
+}
+
+/// Splits a CSV line into its cells, with double quotes around cells holding commas and doubled
+/// double quotes within them
+fn split_csv(line: &str) -> Vec<String> {
+    let mut cells = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                cells.last_mut().unwrap().push('"');
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(String::new()),
+            c => cells.last_mut().unwrap().push(c),
+        }
+    }
+
+    cells.iter().map(|cell| cell.trim().to_owned()).collect()
+}
+
+/// The value of an option as it would be given on the command line
+fn value_string(value: &Value) -> Result<String> {
+    Ok(match value {
+        Value::String(s) => s.clone(),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        other => bail!("Unsupported option value {}", other),
+    })
+}
+
+/// Whether a flag is set, from a TOML boolean or a CSV cell such as `true`, `yes` or `1`
+fn flag_value(value: &Value) -> Result<bool> {
+    Ok(match value {
+        Value::Boolean(b) => *b,
+        other => match value_string(other)?.to_lowercase().as_str() {
+            "true" | "yes" | "1" => true,
+            "false" | "no" | "0" => false,
+            flag => bail!("Flags must be true or false, not {}", flag),
+        },
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use krusz::sample::ClipMode;
+
+    #[test]
+    fn test_manifest() {
+        let dir = std::env::temp_dir().join("krusz_test_manifest");
+        fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.wav");
+        let output = dir.join("output.wav");
+        fs::write(&input, b"input").unwrap();
+        fs::write(&output, b"output").unwrap();
+
+        let mut manifest = Manifest::open(&dir, "settings".to_owned(), false).unwrap();
+        assert!(!manifest.is_complete(&input, &output).unwrap());
+        manifest.record(&input, &output).unwrap();
+        drop(manifest);
+
+        let manifest = Manifest::open(&dir, "settings".to_owned(), true).unwrap();
+        assert!(manifest.is_complete(&input, &output).unwrap());
+
+        let manifest = Manifest::open(&dir, "other settings".to_owned(), true).unwrap();
+        assert!(!manifest.is_complete(&input, &output).unwrap());
+
+        fs::write(&output, b"truncated").unwrap();
+        let manifest = Manifest::open(&dir, "settings".to_owned(), true).unwrap();
+        assert!(!manifest.is_complete(&input, &output).unwrap());
+
+        let manifest = Manifest::open(&dir, "settings".to_owned(), false).unwrap();
+        assert!(manifest.entries.is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_read_batch() {
+        let dir = std::env::temp_dir().join("krusz_test_read_batch");
+        fs::create_dir_all(&dir).unwrap();
+        let defaults = Opts::parse_from([
+            "krusz",
+            "--batch",
+            "list",
+            "--output-dir",
+            "out",
+            "-b",
+            "8",
+            "--auto-gain",
+        ]);
+
+        let toml = dir.join("batch.toml");
+        fs::write(
+            &toml,
+            r#"
+[[file]]
+input = "kick.wav"
+sample-rate = 11025
+filter = ["lowpass:3k,q=0.7", "highpass:40"]
+
+[[file]]
+input = "/samples/cymbal.wav"
+bit_depth = 12
+"--packed" = true
+"#,
+        )
+        .unwrap();
+
+        let entries = read_batch(&toml).unwrap();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].input, dir.join("kick.wav"));
+        assert_eq!(entries[1].input, Path::new("/samples/cymbal.wav"));
+
+        let kick = entries[0].opts(&defaults, &defaults).unwrap();
+        assert_eq!(kick.sample_rate, Some(11025));
+        assert_eq!(kick.bit_depth, Some(8.0));
+        assert_eq!(kick.filter.len(), 2);
+        assert!(kick.auto_gain);
+        assert_eq!(kick.input, [dir.join("kick.wav")]);
+
+        let cymbal = entries[1].opts(&defaults, &defaults).unwrap();
+        assert_eq!(cymbal.bit_depth, Some(12.0));
+        assert!(cymbal.packed);
+
+        let csv = dir.join("batch.csv");
+        fs::write(
+            &csv,
+            "input,--bit-depth,post-filter,packed\nsnare.wav,,\"lowpass:5k,q=0.5\",yes\nhat.wav,4,,\n",
+        )
+        .unwrap();
+
+        let entries = read_batch(&csv).unwrap();
+        let snare = entries[0].opts(&defaults, &defaults).unwrap();
+        assert_eq!(snare.bit_depth, Some(8.0));
+        assert_eq!(snare.post_filter[0].frequency, 5000.0);
+        assert!(snare.packed);
+        let hat = entries[1].opts(&defaults, &defaults).unwrap();
+        assert_eq!(hat.bit_depth, Some(4.0));
+        assert!(!hat.packed);
+
+        fs::write(&csv, "input,clip-mode\nsnare.wav,wrap\nhat.wav,\n").unwrap();
+        let entries = read_batch(&csv).unwrap();
+        let snare = entries[0].opts(&defaults, &defaults).unwrap();
+        assert_eq!(snare.settings().clip_mode, ClipMode::Wrap);
+        let hat = entries[1].opts(&defaults, &defaults).unwrap();
+        assert_eq!(hat.settings().clip_mode, ClipMode::Clamp);
+
+        fs::write(&csv, "input,crunchiness\nsnare.wav,11\n").unwrap();
+        assert!(read_batch(&csv).is_err());
+        fs::write(&csv, "input,--format\nsnare.wav,flac\n").unwrap();
+        assert!(read_batch(&csv).is_err());
+        fs::write(&csv, "input,bit-depth\nsnare.wav,lots\n").unwrap();
+        assert!(read_batch(&csv).unwrap()[0]
+            .opts(&defaults, &defaults)
+            .is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_batch_preset() {
+        let dir = std::env::temp_dir().join("krusz_test_batch_preset");
+        fs::create_dir_all(&dir).unwrap();
+        let given = Opts::parse_from([
+            "krusz",
+            "--batch",
+            "list",
+            "--output-dir",
+            "out",
+            "--preset",
+            "cassette",
+            "-b",
+            "8",
+        ]);
+        let mut defaults = given.clone();
+        crate::preset::Preset::Cassette.apply(&mut defaults);
+
+        let toml = dir.join("batch.toml");
+        fs::write(
+            &toml,
+            r#"
+[[file]]
+input = "break.wav"
+preset = "sp1200"
+
+[[file]]
+input = "pad.wav"
+"#,
+        )
+        .unwrap();
+
+        let entries = read_batch(&toml).unwrap();
+        fs::remove_dir_all(dir).unwrap();
+
+        // Nothing of the cassette deck is left, but what was given on the command line still holds
+        let sp1200 = entries[0].opts(&defaults, &given).unwrap();
+        assert_eq!(sp1200.sample_rate, Some(26040));
+        assert_eq!(sp1200.speed, Some(1.35));
+        assert_eq!(sp1200.bit_depth, Some(8.0));
+        assert!(sp1200.waveshape.is_none());
+        assert!(sp1200.post_filter.is_empty());
+
+        let cassette = entries[1].opts(&defaults, &given).unwrap();
+        assert_eq!(cassette.sample_rate, Some(32000));
+        assert_eq!(cassette.post_filter.len(), 1);
+        assert_eq!(cassette.bit_depth, Some(8.0));
+    }
+}
@@ -0,0 +1,167 @@
+use std::{
+    convert::TryInto,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{ensure, Result};
+use tracing::{debug, warn};
+
+use crate::{
+    checksum::hash_file,
+    input::{decode, is_url},
+    output::write_atomically,
+    Channel, Sound,
+};
+
+/// Starts every cache file. The number goes up whenever decoding changes, so that older entries
+/// are decoded again rather than trusted.
+const MAGIC: &[u8; 8] = b"KRUSZPC1";
+
+/// Size of the magic, sample rate, channel count and frame count
+const HEADER_SIZE: usize = 8 + 4 + 4 + 8;
+
+/// The default cache directory, in the system's temporary directory
+pub fn cache_dir() -> PathBuf {
+    env::temp_dir().join("krusz-cache")
+}
+
+/// Keeps the PCM data of decoded inputs, keyed by a hash of their contents, so that decoding the
+/// same file again only costs reading it once to hash it. URLs are always downloaded and decoded.
+pub struct DecodeCache {
+    dir: PathBuf,
+}
+
+impl DecodeCache {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// Decodes a local file or an HTTP(S) URL, from the cache if the file was decoded before
+    pub fn decode(&self, path: &Path) -> Result<Sound> {
+        if is_url(path) {
+            return decode(path);
+        }
+
+        let entry = self.dir.join(hash_file(path)?).with_extension("pcm");
+
+        if entry.exists() {
+            match read(&entry) {
+                Ok(sound) => {
+                    debug!("Read {} from the cache", path.display());
+                    return Ok(sound);
+                }
+                Err(e) => warn!("Ignoring unreadable cache entry {}: {}", entry.display(), e),
+            }
+        }
+
+        let sound = decode(path)?;
+
+        // The cache only saves time, so failing to fill it isn't worth failing the run
+        if let Err(e) = fs::create_dir_all(&self.dir)
+            .map_err(Into::into)
+            .and_then(|()| write(&sound, &entry))
+        {
+            warn!("Could not cache {}: {}", path.display(), e);
+        }
+
+        Ok(sound)
+    }
+}
+
+fn write(sound: &Sound, path: &Path) -> Result<()> {
+    let mut bytes = Vec::with_capacity(HEADER_SIZE + 4 * sound.channels.len() * sound.frames());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&sound.sample_rate.to_le_bytes());
+    bytes.extend_from_slice(&(sound.channels.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(&(sound.frames() as u64).to_le_bytes());
+
+    for channel in &sound.channels {
+        for sample in &channel.samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+    }
+
+    write_atomically(path, |temp_path| Ok(fs::write(temp_path, &bytes)?))
+}
+
+fn read(path: &Path) -> Result<Sound> {
+    let bytes = fs::read(path)?;
+    ensure!(
+        bytes.len() >= HEADER_SIZE && bytes[..8] == *MAGIC,
+        "Not a cache entry of this version"
+    );
+
+    let sample_rate = u32::from_le_bytes(bytes[8..12].try_into()?);
+    let channels = u32::from_le_bytes(bytes[12..16].try_into()?) as usize;
+    let frames = u64::from_le_bytes(bytes[16..24].try_into()?) as usize;
+    ensure!(
+        bytes.len() == HEADER_SIZE + 4 * channels * frames,
+        "Truncated cache entry"
+    );
+
+    let channels = (0..channels)
+        .map(|i| Channel {
+            samples: bytes[HEADER_SIZE + 4 * frames * i..][..4 * frames]
+                .chunks(4)
+                .map(|sample| i32::from_le_bytes(sample.try_into().unwrap()))
+                .collect(),
+        })
+        .collect();
+
+    Ok(Sound {
+        channels,
+        sample_rate,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::output::{save_wav, OutputOptions};
+
+    fn assert_same(a: &Sound, b: &Sound) {
+        assert_eq!(a.sample_rate, b.sample_rate);
+        assert_eq!(a.channels.len(), b.channels.len());
+        for (a, b) in a.channels.iter().zip(&b.channels) {
+            assert_eq!(a.samples, b.samples);
+        }
+    }
+
+    #[test]
+    fn test_decode_cache() {
+        let dir = env::temp_dir().join("krusz_test_decode_cache");
+        let _ = fs::remove_dir_all(&dir);
+        let input = env::temp_dir().join("krusz_test_decode_cache.wav");
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![1 << 16, 2 << 16, 3 << 16],
+                },
+                Channel {
+                    samples: vec![-1 << 16, -2 << 16, -3 << 16],
+                },
+            ],
+            sample_rate: 22050,
+        };
+        save_wav(&sound, &input, OutputOptions::default()).unwrap();
+
+        let cache = DecodeCache::new(dir.clone());
+        assert_same(&cache.decode(&input).unwrap(), &sound);
+        let entry = dir.join(hash_file(&input).unwrap()).with_extension("pcm");
+        assert_same(&read(&entry).unwrap(), &sound);
+
+        // Cached entries are used as they are, and broken ones are replaced
+        let mut cached = sound.clone();
+        cached.sample_rate = 8000;
+        write(&cached, &entry).unwrap();
+        assert_same(&cache.decode(&input).unwrap(), &cached);
+
+        fs::write(&entry, b"KRUSZPC1").unwrap();
+        assert_same(&cache.decode(&input).unwrap(), &sound);
+        assert_same(&read(&entry).unwrap(), &sound);
+
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_file(&input).unwrap();
+    }
+}
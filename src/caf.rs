@@ -0,0 +1,144 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::Result;
+
+use crate::{
+    output::{Encoder, OutputOptions},
+    simd, Sound,
+};
+
+/// Format flags of little endian integer samples
+const LITTLE_ENDIAN: u32 = 2;
+
+/// Offset of the data chunk's size: the file header, the desc chunk and the data chunk's type
+const DATA_SIZE_OFFSET: u64 = 8 + 12 + 32 + 4;
+
+/// Writes Core Audio Format files, with interleaved little endian linear PCM. Chunk sizes are 64
+/// bits, so unlike WAV files there is no 4 GB limit.
+pub struct CafEncoder {
+    file: BufWriter<File>,
+    bits_per_sample: u16,
+    data_size: u64,
+}
+
+impl CafEncoder {
+    pub(crate) fn create(
+        path: &Path,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let bytes_per_frame = channels as u32 * u32::from(options.bits_per_sample / 8);
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"caff")?;
+        file.write_all(&1u16.to_be_bytes())?; // mFileVersion
+        file.write_all(&0u16.to_be_bytes())?; // mFileFlags
+
+        file.write_all(b"desc")?;
+        file.write_all(&32i64.to_be_bytes())?;
+        file.write_all(&f64::from(sample_rate).to_be_bytes())?;
+        file.write_all(b"lpcm")?;
+        file.write_all(&LITTLE_ENDIAN.to_be_bytes())?;
+        file.write_all(&bytes_per_frame.to_be_bytes())?; // mBytesPerPacket
+        file.write_all(&1u32.to_be_bytes())?; // mFramesPerPacket
+        file.write_all(&(channels as u32).to_be_bytes())?;
+        file.write_all(&u32::from(options.bits_per_sample).to_be_bytes())?;
+
+        // The size is filled in by finalize. If it never is, -1 still tells readers that the
+        // data goes on to the end of the file.
+        file.write_all(b"data")?;
+        file.write_all(&(-1i64).to_be_bytes())?;
+        file.write_all(&0u32.to_be_bytes())?; // mEditCount
+
+        Ok(Self {
+            file,
+            bits_per_sample: options.bits_per_sample,
+            data_size: 0,
+        })
+    }
+}
+
+impl Encoder for CafEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let width = usize::from(self.bits_per_sample / 8);
+        let bytes: Vec<u8> = simd::interleave(&block.channels)
+            .into_iter()
+            .flat_map(|sample| sample.to_le_bytes().into_iter().skip(4 - width))
+            .collect();
+
+        self.file.write_all(&bytes)?;
+        self.data_size += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<()> {
+        let mut file = self.file;
+
+        // The edit count is part of the data chunk
+        file.seek(SeekFrom::Start(DATA_SIZE_OFFSET))?;
+        file.write_all(&(self.data_size + 4).to_be_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    #[test]
+    fn test_save_caf() {
+        let path = std::env::temp_dir().join("krusz_test_save_caf.caf");
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0x1234_5678, -1],
+                },
+                Channel {
+                    samples: vec![i32::MIN, 0],
+                },
+            ],
+            sample_rate: 48000,
+        };
+
+        let options = OutputOptions {
+            bits_per_sample: 24,
+            ..OutputOptions::default()
+        };
+        save(&sound, &path, OutputFormat::Caf, options).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..8], b"caff\0\x01\0\0");
+        assert_eq!(&bytes[8..12], b"desc");
+        assert_eq!(
+            f64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+            48000.0
+        );
+        assert_eq!(&bytes[28..32], b"lpcm");
+        let fields: Vec<u32> = bytes[32..52]
+            .chunks(4)
+            .map(|word| u32::from_be_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(fields, [LITTLE_ENDIAN, 6, 1, 2, 24]);
+
+        assert_eq!(&bytes[52..56], b"data");
+        assert_eq!(bytes[56..64], 16i64.to_be_bytes());
+        assert_eq!(
+            bytes[68..],
+            [0x56, 0x34, 0x12, 0x00, 0x00, 0x80, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00]
+        );
+    }
+}
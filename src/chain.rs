@@ -0,0 +1,145 @@
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use krusz::{
+    output::{save_chain, OutputFormat, OutputOptions},
+    Channel, Sound,
+};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+use tracing::{debug, debug_span};
+
+use crate::{error::ErrorKind, repl::render, Opts};
+
+/// Lays the one-shots out one after the other in `slots` slots as long as the longest of them, so
+/// that slicing the chain evenly finds each at the start of its slot. Slots past the last one-shot
+/// are left silent.
+fn lay_out(one_shots: &[Sound], slots: usize) -> (Sound, u64) {
+    let slot = one_shots.iter().map(Sound::frames).max().unwrap_or(0);
+    let channels = one_shots.first().map_or(1, |sound| sound.channels.len());
+
+    let chain = Sound {
+        channels: (0..channels)
+            .map(|c| Channel {
+                samples: one_shots
+                    .iter()
+                    .flat_map(|sound| {
+                        let samples = &sound.channels[c].samples;
+                        samples
+                            .iter()
+                            .copied()
+                            .chain(std::iter::repeat_n(0, slot - samples.len()))
+                    })
+                    .chain(std::iter::repeat_n(0, slot * (slots - one_shots.len())))
+                    .collect(),
+            })
+            .collect(),
+        sample_rate: one_shots.first().map_or(44100, |sound| sound.sample_rate),
+    };
+
+    (chain, slot as u64)
+}
+
+/// KRUSZES the inputs with the settings of `opts`, as one-shots, and saves them as a sample chain
+/// of `slots` even slots at the output, with a cue marker at the start of each
+pub fn run(opts: &Opts, slots: usize, mut rng: ChaCha8Rng) -> Result<()> {
+    let output = match opts.output.as_slice() {
+        [output] => output,
+        _ => {
+            return Err(eyre!("--chain writes a single chain, give one --output"))
+                .wrap_err(ErrorKind::Parameter)
+        }
+    };
+    if OutputFormat::from_path(output).wrap_err(ErrorKind::UnsupportedFormat)? != OutputFormat::Wav
+    {
+        return Err(eyre!("Sample chains can only be saved as WAV files"))
+            .wrap_err(ErrorKind::UnsupportedFormat);
+    }
+    if opts.input.len() > slots {
+        return Err(eyre!(
+            "Got {} inputs for a chain of {} slots",
+            opts.input.len(),
+            slots
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    }
+
+    let one_shots = opts
+        .input
+        .iter()
+        .map(|input| {
+            let original = opts.decode(input).wrap_err(ErrorKind::Input)?;
+            render(&original, &opts.settings(), opts.auto_gain, rng.gen())
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let first = &one_shots[0];
+    for (input, sound) in opts.input.iter().zip(&one_shots) {
+        if sound.sample_rate != first.sample_rate || sound.channels.len() != first.channels.len() {
+            return Err(eyre!(
+                "{} has {} channels at {} Hz, but {} has {} at {} Hz. The one-shots of a chain must match, fold them down with --downmix-matrix mono if needed",
+                input.display(),
+                sound.channels.len(),
+                sound.sample_rate,
+                opts.input[0].display(),
+                first.channels.len(),
+                first.sample_rate
+            ))
+            .wrap_err(ErrorKind::Input);
+        }
+    }
+
+    let (chain, slot) = lay_out(&one_shots, slots);
+    debug!(
+        "Chained {} one-shots into {} slots of {} frames",
+        one_shots.len(),
+        slots,
+        slot
+    );
+
+    let slices: Vec<(u64, String)> = (0..slots)
+        .map(|i| {
+            let label = match opts.input.get(i) {
+                Some(input) => stem(input),
+                None => format!("Slot {}", i + 1),
+            };
+            (i as u64 * slot, label)
+        })
+        .collect();
+
+    // The slots are one-shots, which don't loop
+    let options = OutputOptions {
+        looped: false,
+        loop_points: None,
+        ..opts.output_options()
+    };
+    debug_span!("encode", output = %output.display())
+        .in_scope(|| save_chain(&chain, output, &slices, options))
+        .wrap_err(ErrorKind::Output)
+}
+
+fn stem(path: &Path) -> String {
+    path.file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_lay_out() {
+        let one_shot = |samples: Vec<i32>| Sound {
+            channels: vec![Channel { samples }],
+            sample_rate: 8000,
+        };
+
+        let (chain, slot) = lay_out(&[one_shot(vec![1, 2, 3]), one_shot(vec![4])], 4);
+        assert_eq!(slot, 3);
+        assert_eq!(
+            chain.channels[0].samples,
+            [1, 2, 3, 4, 0, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(chain.sample_rate, 8000);
+    }
+}
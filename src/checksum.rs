@@ -0,0 +1,82 @@
+use std::{fs::File, io, path::Path};
+
+use color_eyre::eyre::Result;
+use sha2::{Digest, Sha256};
+
+use crate::Sound;
+
+/// Hashes the interleaved PCM samples of the sound, independently of any container format
+pub fn checksum(sound: &Sound) -> String {
+    let mut checksum = Checksum::default();
+    checksum.update(sound);
+    checksum.finish()
+}
+
+/// Hashes a stream of PCM samples block by block, with the same result as `checksum` on the whole sound
+#[derive(Default)]
+pub struct Checksum {
+    hasher: Sha256,
+}
+
+impl Checksum {
+    pub fn update(&mut self, block: &Sound) {
+        for i in 0..block.frames() {
+            for channel in &block.channels {
+                self.hasher.update(channel.samples[i].to_le_bytes());
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        hex(self.hasher)
+    }
+}
+
+/// Hashes the contents of a file
+pub fn hash_file<P: AsRef<Path>>(path: P) -> Result<String> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hex(hasher))
+}
+
+/// Hashes arbitrary bytes, e.g. to fingerprint a set of parameters
+pub fn hash_bytes<B: AsRef<[u8]>>(bytes: B) -> String {
+    hex(Sha256::new().chain_update(bytes))
+}
+
+fn hex(hasher: Sha256) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_checksum() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![1, 2, 3],
+                },
+                Channel {
+                    samples: vec![4, 5, 6],
+                },
+            ],
+            sample_rate: 44100,
+        };
+
+        let hash = checksum(&sound);
+        assert_eq!(hash.len(), 64);
+        assert_eq!(hash, checksum(&sound.clone()));
+
+        let mut other = sound;
+        other.channels[1].samples[2] = 7;
+        assert_ne!(hash, checksum(&other));
+    }
+}
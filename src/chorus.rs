@@ -0,0 +1,257 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+
+use crate::{
+    echo::parse_duration,
+    filter::parse_frequency,
+    resample::{lerp, Interpolation},
+    sample::{to_sample, ClipMode},
+    stream::{map_channels, Stage},
+    Sound,
+};
+
+/// Delay of the voices at the middle of their sweep, in seconds
+const BASE_DELAY: f64 = 0.015;
+
+/// A chorus as given on the command line, e.g. `voices=3,rate=0.8,depth=4ms,mix=0.5`. Every
+/// parameter is optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChorusSpec {
+    /// Number of detuned copies of the sound
+    pub voices: u8,
+    /// Frequency at which the delays sweep, in Hz
+    pub rate: f64,
+    /// How far the delays sweep, in seconds
+    pub depth: f64,
+    /// Level of the voices relative to the dry sound
+    pub mix: f64,
+}
+
+impl Default for ChorusSpec {
+    fn default() -> Self {
+        Self {
+            voices: 3,
+            rate: 0.8,
+            depth: 0.004,
+            mix: 0.5,
+        }
+    }
+}
+
+impl FromStr for ChorusSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut spec = Self::default();
+
+        for param in s.split(',').filter(|param| !param.trim().is_empty()) {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| eyre!("Chorus parameter {} must be of the form key=value", param))?;
+            let value = value.trim();
+
+            match key.trim().to_lowercase().as_str() {
+                "voices" => spec.voices = value.parse()?,
+                "rate" => spec.rate = parse_frequency(value)?,
+                "depth" => spec.depth = parse_duration(value)?,
+                "mix" => spec.mix = value.parse()?,
+                other => bail!("Unknown chorus parameter {}", other),
+            }
+        }
+
+        ensure!(
+            (1..=8).contains(&spec.voices),
+            "Chorus voices must be between 1 and 8 inclusive"
+        );
+        ensure!(
+            (0.0..=BASE_DELAY).contains(&spec.depth),
+            "Chorus depth must be between 0 and {} ms inclusive",
+            BASE_DELAY * 1000.0
+        );
+        ensure!(
+            (0.0..=1.0).contains(&spec.mix),
+            "Chorus mix must be between 0 and 1"
+        );
+
+        Ok(spec)
+    }
+}
+
+impl Display for ChorusSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "voices={},rate={},depth={}ms,mix={}",
+            self.voices,
+            self.rate,
+            self.depth * 1000.0,
+            self.mix
+        )
+    }
+}
+
+serde_via_str!(ChorusSpec);
+
+/// Chorus state of a single channel
+struct ChorusChannel {
+    /// The most recent input samples, oldest first, with at least the longest delay's worth
+    history: Vec<f64>,
+    /// Frames processed so far, which drive the sweep
+    time: u64,
+    /// Where the sweep starts, as a fraction of a cycle, so that channels sweep out of step
+    phase: f64,
+}
+
+/// Mixes in copies of the sound behind delays which sweep back and forth, each slightly out of
+/// step with the others. The sweeping detunes the copies, thickening the sound into an ensemble.
+pub struct ChorusStage {
+    spec: ChorusSpec,
+    sample_rate: u32,
+    channels: Vec<ChorusChannel>,
+    clip_mode: ClipMode,
+}
+
+impl ChorusStage {
+    pub fn new(spec: ChorusSpec, sample_rate: u32, clip_mode: ClipMode) -> Self {
+        Self {
+            spec,
+            sample_rate,
+            channels: Vec::new(),
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for ChorusStage {
+    fn name(&self) -> &'static str {
+        "chorus"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            spec,
+            sample_rate,
+            channels,
+            clip_mode,
+        } = self;
+        let (spec, rate, clip_mode) = (*spec, *sample_rate as f64, *clip_mode);
+
+        let base = BASE_DELAY * rate;
+        let depth = spec.depth * rate;
+        let longest = (base + depth).ceil() as usize + 2;
+        let mut index = channels.len();
+
+        map_channels(
+            chunk,
+            channels,
+            || {
+                index += 1;
+                ChorusChannel {
+                    history: vec![0.0; longest],
+                    time: 0,
+                    phase: (index - 1) as f64 * 0.25,
+                }
+            },
+            |channel, sample| {
+                let dry = sample as f64;
+                channel.history.push(dry);
+
+                // Dropping the old samples only now and then keeps the cost down
+                if channel.history.len() >= 2 * longest {
+                    channel.history.drain(..longest);
+                }
+
+                let now = (channel.history.len() - 1) as f64;
+                let cycle = channel.time as f64 * spec.rate / rate + channel.phase;
+                channel.time += 1;
+
+                let wet = (0..spec.voices)
+                    .map(|voice| {
+                        let phase = cycle + voice as f64 / spec.voices as f64;
+                        let delay = base + depth * (2.0 * PI * phase).sin();
+                        lerp(&channel.history, now - delay, Interpolation::Linear)
+                    })
+                    .sum::<f64>()
+                    / spec.voices as f64;
+
+                to_sample(dry * (1.0 - spec.mix) + wet * spec.mix, clip_mode)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_parse_chorus() {
+        assert_eq!("".parse::<ChorusSpec>().unwrap(), ChorusSpec::default());
+
+        let chorus: ChorusSpec = "voices=2,rate=1.5hz,depth=2ms".parse().unwrap();
+        assert_eq!(
+            chorus,
+            ChorusSpec {
+                voices: 2,
+                rate: 1.5,
+                depth: 0.002,
+                mix: 0.5,
+            }
+        );
+        assert_eq!(chorus.to_string().parse::<ChorusSpec>().unwrap(), chorus);
+
+        assert!("voices=0".parse::<ChorusSpec>().is_err());
+        assert!("depth=1s".parse::<ChorusSpec>().is_err());
+    }
+
+    #[test]
+    fn test_chorus() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..4000).map(|i| ((i % 100) - 50) << 20).collect(),
+            }],
+            sample_rate: 8000,
+        };
+
+        // Without sweeping, a single voice is just the sound delayed by the base delay
+        let spec = ChorusSpec {
+            voices: 1,
+            depth: 0.0,
+            mix: 1.0,
+            ..ChorusSpec::default()
+        };
+        let delayed = ChorusStage::new(spec, 8000, ClipMode::Clamp).run(sound.clone());
+        let delay = (BASE_DELAY * 8000.0) as usize;
+        assert!(delayed.channels[0].samples[..delay]
+            .iter()
+            .all(|&sample| sample == 0));
+        assert_eq!(
+            delayed.channels[0].samples[delay..],
+            sound.channels[0].samples[..4000 - delay]
+        );
+
+        let mut stage = ChorusStage::new(ChorusSpec::default(), 8000, ClipMode::Clamp);
+        let whole =
+            ChorusStage::new(ChorusSpec::default(), 8000, ClipMode::Clamp).run(sound.clone());
+        let mut chunked = Sound {
+            channels: vec![Channel::default()],
+            sample_rate: 8000,
+        };
+        for start in (0..4000).step_by(333) {
+            chunked.append(stage.process(Sound {
+                channels: vec![Channel {
+                    samples: sound.channels[0].samples[start..(start + 333).min(4000)].to_vec(),
+                }],
+                sample_rate: 8000,
+            }));
+        }
+        assert_eq!(chunked.channels[0].samples, whole.channels[0].samples);
+        assert_ne!(whole.channels[0].samples, delayed.channels[0].samples);
+    }
+}
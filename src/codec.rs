@@ -0,0 +1,107 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, Report, Result};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    lpc10::{self, Lpc10},
+    resample::{brickwall, Interpolation, Resampler},
+    sample::ClipMode,
+    stream::Pipeline,
+};
+
+/// A lossy codec the sound can take a round trip through, as given on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Codec {
+    /// The LPC-10 speech vocoder of 80s talking toys
+    Lpc10,
+}
+
+impl Codec {
+    /// The sample rate the codec runs at
+    fn sample_rate(self) -> u32 {
+        match self {
+            Self::Lpc10 => lpc10::SAMPLE_RATE,
+        }
+    }
+
+    /// Encodes and decodes a sound at `sample_rate`, resampling it to the codec's rate and back
+    pub fn round_trip(self, sample_rate: u32, rng: ChaCha8Rng, clip_mode: ClipMode) -> Pipeline {
+        let codec_rate = self.sample_rate();
+        let mut pipeline = Pipeline::new();
+
+        if codec_rate < sample_rate {
+            pipeline
+                .push(brickwall(codec_rate as f64 / 2.0, sample_rate).with_clip_mode(clip_mode));
+        }
+
+        pipeline.push(Resampler::new(
+            sample_rate,
+            codec_rate,
+            Interpolation::Linear,
+        ));
+
+        match self {
+            Self::Lpc10 => pipeline.push(Lpc10::new(rng, clip_mode)),
+        }
+
+        pipeline.push(Resampler::new(
+            codec_rate,
+            sample_rate,
+            Interpolation::Linear,
+        ));
+
+        pipeline
+    }
+}
+
+impl FromStr for Codec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "lpc10" | "lpc-10" => Ok(Self::Lpc10),
+            other => bail!("Unknown codec {}", other),
+        }
+    }
+}
+
+impl Display for Codec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Lpc10 => write!(f, "lpc10"),
+        }
+    }
+}
+
+serde_via_str!(Codec);
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+    use crate::{stream::Stage, Channel, Sound};
+
+    #[test]
+    fn test_codec_round_trip() {
+        assert_eq!("LPC10".parse::<Codec>().unwrap(), Codec::Lpc10);
+        assert!("mp3".parse::<Codec>().is_err());
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..44100).map(|i| ((i % 200) - 100) << 22).collect(),
+            }],
+            sample_rate: 44100,
+        };
+
+        let mut round_trip =
+            Codec::Lpc10.round_trip(44100, ChaCha8Rng::seed_from_u64(0), ClipMode::Clamp);
+        let output = round_trip.run(sound);
+        assert_eq!(output.sample_rate, 44100);
+        assert!((output.frames() as i64 - 44100).abs() <= 1);
+    }
+}
@@ -0,0 +1,427 @@
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, Report, Result, WrapErr};
+use num::Complex;
+use rayon::prelude::*;
+
+use crate::{
+    filter::{Biquad, FilterKind, FilterSpec},
+    input::decode,
+    resample::{lerp, Interpolation},
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stft::fft,
+    stream::Stage,
+    Channel, Sound,
+};
+
+/// Longest impulse response accepted, in seconds. Speakers and handsets ring for a few
+/// milliseconds, anything much longer is a room, which is what the reverb is for.
+const MAX_LENGTH: f64 = 1.0;
+
+/// Length the built-in responses are cut to, in seconds
+const BUILT_IN_LENGTH: f64 = 0.05;
+
+/// The built-in responses, as the filters they're made of
+fn built_in(name: &str) -> Option<Vec<FilterSpec>> {
+    let filter = |kind, frequency, q, gain| FilterSpec {
+        kind,
+        frequency,
+        q,
+        gain,
+    };
+
+    match name {
+        // A tiny cone in a plastic toy: no bass at all, a honky cone resonance and a harsh peak
+        // where the cone breaks up, then nothing much above
+        "speaker" => Some(vec![
+            filter(FilterKind::Highpass, 450.0, 0.9, 0.0),
+            filter(FilterKind::Highpass, 450.0, 0.9, 0.0),
+            filter(FilterKind::Peak, 1100.0, 1.8, 8.0),
+            filter(FilterKind::Peak, 3600.0, 2.5, 5.0),
+            filter(FilterKind::Lowpass, 6000.0, 0.7, 0.0),
+            filter(FilterKind::Lowpass, 6000.0, 0.7, 0.0),
+        ]),
+        // The earpiece of a handset on a landline, band limited to 300-3400 Hz
+        "telephone" => Some(vec![
+            filter(FilterKind::Highpass, 300.0, 0.7, 0.0),
+            filter(FilterKind::Highpass, 300.0, 0.7, 0.0),
+            filter(FilterKind::Peak, 1500.0, 1.0, 4.0),
+            filter(FilterKind::Lowpass, 3400.0, 0.7, 0.0),
+            filter(FilterKind::Lowpass, 3400.0, 0.7, 0.0),
+        ]),
+        _ => None,
+    }
+}
+
+#[derive(Clone, PartialEq)]
+enum Response {
+    /// A built-in response, made at whatever sample rate it's needed at
+    Filters(Vec<FilterSpec>),
+    /// A response read from a sound file, with a channel for each channel of the sound or a single
+    /// one for all of them
+    Recorded {
+        channels: Vec<Vec<f64>>,
+        sample_rate: u32,
+    },
+}
+
+/// The response of a speaker, handset or anything else the KRUSZED sound is played through. Given
+/// on the command line as either the name of a built-in response or the path to a sound file.
+#[derive(Clone, PartialEq)]
+pub struct ImpulseResponse {
+    /// What the response was made from, to show it and store it again
+    source: String,
+    response: Response,
+}
+
+impl ImpulseResponse {
+    /// The response at a sample rate, a list of samples for each of its channels
+    fn channels(&self, sample_rate: u32) -> Result<Vec<Vec<f64>>> {
+        match &self.response {
+            Response::Filters(specs) => {
+                // Filters above the Nyquist frequency would have nothing left to filter
+                let mut chain = specs
+                    .iter()
+                    .filter(|spec| spec.frequency < sample_rate as f64 / 2.0)
+                    .map(|&spec| Biquad::new(spec, sample_rate))
+                    .collect::<Result<Vec<_>>>()?;
+                let length = (BUILT_IN_LENGTH * sample_rate as f64).round().max(1.0) as usize;
+
+                Ok(vec![(0..length)
+                    .map(|i| {
+                        let impulse = if i == 0 { 1.0 } else { 0.0 };
+                        chain
+                            .iter_mut()
+                            .fold(impulse, |x, biquad| biquad.process(x))
+                    })
+                    .collect()])
+            }
+            Response::Recorded {
+                channels,
+                sample_rate: recorded_rate,
+            } => {
+                if *recorded_rate == sample_rate {
+                    return Ok(channels.clone());
+                }
+
+                let ratio = *recorded_rate as f64 / sample_rate as f64;
+
+                Ok(channels
+                    .iter()
+                    .map(|channel| {
+                        let length = ((channel.len() as f64 / ratio).floor() as usize).max(1);
+                        (0..length)
+                            .map(|i| lerp(channel, i as f64 * ratio, Interpolation::Linear))
+                            .collect()
+                    })
+                    .collect())
+            }
+        }
+    }
+}
+
+impl FromStr for ImpulseResponse {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some(specs) = built_in(s.trim().to_lowercase().as_str()) {
+            return Ok(Self {
+                source: s.to_owned(),
+                response: Response::Filters(specs),
+            });
+        }
+
+        let sound = decode(Path::new(s)).wrap_err_with(|| {
+            format!(
+                "{} is neither a built-in impulse response nor a readable sound",
+                s
+            )
+        })?;
+
+        ensure!(sound.frames() > 0, "Impulse response {} is empty", s);
+        ensure!(
+            sound.frames() as f64 <= MAX_LENGTH * sound.sample_rate as f64,
+            "Impulse responses must be at most {} second long",
+            MAX_LENGTH
+        );
+
+        Ok(Self {
+            source: s.to_owned(),
+            response: Response::Recorded {
+                channels: sound
+                    .channels
+                    .iter()
+                    .map(|channel| {
+                        channel
+                            .samples
+                            .iter()
+                            .map(|&sample| sample as f64 / FULL_SCALE)
+                            .collect()
+                    })
+                    .collect(),
+                sample_rate: sound.sample_rate,
+            },
+        })
+    }
+}
+
+/// The samples are left out, they're far too many to be of any help
+impl Debug for ImpulseResponse {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple("ImpulseResponse")
+            .field(&self.source)
+            .finish()
+    }
+}
+
+impl Display for ImpulseResponse {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Responses are stored as their name or path, so sound files are read again when loading
+serde_via_str!(ImpulseResponse);
+
+/// Convolution state of a single channel
+#[derive(Clone, Default)]
+struct ConvolveChannel {
+    /// Input samples not yet making up a whole block
+    input: Vec<f64>,
+    /// What the blocks so far add to the next one
+    overlap: Vec<f64>,
+}
+
+/// Plays the sound through an impulse response, by convolving it block by block in the frequency
+/// domain. Each channel of the sound goes through the channel of the response with the same index,
+/// wrapping around, so a mono response is used for all of them. The response is scaled so that its
+/// loudest frequency passes at unity gain. The sound keeps its length, so whatever would ring on
+/// past its end is cut off.
+pub struct ConvolveStage {
+    /// Spectrum of each channel of the response, padded to twice the block
+    spectra: Vec<Vec<Complex<f64>>>,
+    block: usize,
+    sample_rate: u32,
+    channels: Vec<ConvolveChannel>,
+    received: usize,
+    emitted: usize,
+    clip_mode: ClipMode,
+}
+
+impl ConvolveStage {
+    pub fn new(ir: &ImpulseResponse, sample_rate: u32, clip_mode: ClipMode) -> Result<Self> {
+        let responses = ir.channels(sample_rate)?;
+        let block = responses
+            .iter()
+            .map(Vec::len)
+            .max()
+            .unwrap_or(1)
+            .next_power_of_two();
+
+        let mut spectra: Vec<Vec<Complex<f64>>> = responses
+            .iter()
+            .map(|response| {
+                let mut spectrum = vec![Complex::new(0.0, 0.0); 2 * block];
+                for (bin, &sample) in spectrum.iter_mut().zip(response) {
+                    *bin = Complex::new(sample, 0.0);
+                }
+                fft(&mut spectrum, false);
+                spectrum
+            })
+            .collect();
+
+        let peak = spectra
+            .iter()
+            .flatten()
+            .map(|bin| bin.norm())
+            .fold(0.0, f64::max);
+        ensure!(peak > 0.0, "Impulse response {} is silent", ir);
+
+        for bin in spectra.iter_mut().flatten() {
+            *bin /= peak;
+        }
+
+        Ok(Self {
+            spectra,
+            block,
+            sample_rate,
+            channels: Vec::new(),
+            received: 0,
+            emitted: 0,
+            clip_mode,
+        })
+    }
+
+    /// Convolves every complete block of each channel
+    fn blocks(&mut self) -> Sound {
+        let Self {
+            spectra,
+            block,
+            channels,
+            clip_mode,
+            ..
+        } = self;
+        let (block, clip_mode) = (*block, *clip_mode);
+
+        let channels = channels
+            .par_iter_mut()
+            .enumerate()
+            .map(|(index, channel)| {
+                let spectrum = &spectra[index % spectra.len()];
+                let mut samples = Vec::new();
+                let mut buffer = vec![Complex::new(0.0, 0.0); 2 * block];
+                let mut start = 0;
+
+                while channel.input.len() - start >= block {
+                    let (head, tail) = buffer.split_at_mut(block);
+                    for (bin, &sample) in head.iter_mut().zip(&channel.input[start..]) {
+                        *bin = Complex::new(sample, 0.0);
+                    }
+                    tail.fill(Complex::new(0.0, 0.0));
+
+                    fft(&mut buffer, false);
+                    for (bin, response) in buffer.iter_mut().zip(spectrum) {
+                        *bin *= response;
+                    }
+                    fft(&mut buffer, true);
+
+                    samples.extend(
+                        buffer[..block]
+                            .iter()
+                            .zip(&channel.overlap)
+                            .map(|(bin, overlap)| to_sample(bin.re + overlap, clip_mode)),
+                    );
+                    channel.overlap = buffer[block..].iter().map(|bin| bin.re).collect();
+                    start += block;
+                }
+
+                channel.input.drain(..start);
+
+                Channel { samples }
+            })
+            .collect();
+
+        Sound {
+            channels,
+            sample_rate: self.sample_rate,
+        }
+    }
+}
+
+impl Stage for ConvolveStage {
+    fn name(&self) -> &'static str {
+        "convolve"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        if self.channels.is_empty() {
+            self.channels = vec![
+                ConvolveChannel {
+                    input: Vec::new(),
+                    overlap: vec![0.0; self.block],
+                };
+                chunk.channels.len()
+            ];
+        }
+
+        self.received += chunk.frames();
+        for (state, channel) in self.channels.iter_mut().zip(&chunk.channels) {
+            state
+                .input
+                .extend(channel.samples.iter().map(|&sample| sample as f64));
+        }
+
+        let output = self.blocks();
+        self.emitted += output.frames();
+        output
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        if self.channels.is_empty() {
+            return None;
+        }
+
+        let block = self.block;
+        for channel in &mut self.channels {
+            let len = channel.input.len();
+            channel.input.resize(len + block - 1, 0.0);
+        }
+
+        // Only as much as is left of the input, the padding rings on past its end
+        let mut output = self.blocks();
+        let left = self.received - self.emitted;
+        for channel in &mut output.channels {
+            channel.samples.truncate(left);
+        }
+        self.emitted += output.frames();
+
+        Some(output)
+    }
+
+    fn latency(&self) -> f64 {
+        self.block as f64 / self.sample_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::stream::{gather, split};
+
+    fn noise() -> Sound {
+        let mut state = 1u32;
+        Sound {
+            channels: vec![Channel {
+                samples: (0..10000)
+                    .map(|_| {
+                        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                        (state as i32) >> 2
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        }
+    }
+
+    #[test]
+    fn test_built_in() {
+        let telephone: ImpulseResponse = "Telephone".parse().unwrap();
+        assert_eq!(telephone.to_string(), "Telephone");
+        assert_eq!(telephone.channels(8000).unwrap()[0].len(), 400);
+
+        // Filters past the Nyquist frequency are left out at low rates
+        let speaker: ImpulseResponse = "speaker".parse().unwrap();
+        ConvolveStage::new(&speaker, 8000, ClipMode::Clamp).unwrap();
+
+        assert!("nonexistent-ir".parse::<ImpulseResponse>().is_err());
+    }
+
+    #[test]
+    fn test_convolve() {
+        // A response of a single delayed sample just delays the sound
+        let delay = ImpulseResponse {
+            source: "delay".to_owned(),
+            response: Response::Recorded {
+                channels: vec![vec![0.0, 0.0, 0.0, 0.5]],
+                sample_rate: 44100,
+            },
+        };
+        let stage = || ConvolveStage::new(&delay, 44100, ClipMode::Clamp).unwrap();
+
+        let delayed = stage().run(noise());
+        assert_eq!(delayed.frames(), 10000);
+        for (&output, &input) in delayed.channels[0].samples[3..]
+            .iter()
+            .zip(&noise().channels[0].samples)
+        {
+            assert!((output - input).abs() <= 1);
+        }
+
+        let chunked = gather(stage().pull(split(noise(), 777))).unwrap();
+        assert_eq!(chunked.channels[0].samples, delayed.channels[0].samples);
+    }
+}
@@ -0,0 +1,953 @@
+use std::collections::VecDeque;
+
+use color_eyre::eyre::{ensure, Result};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    bands::{crossover, BandsSpec, MultibandStage},
+    codec::Codec,
+    convolve::{ConvolveStage, ImpulseResponse},
+    dac::Dac,
+    echo::{EchoSpec, EchoStage},
+    effect::EffectSpec,
+    filter::{FilterSpec, FilterStage, LadderStage},
+    gain::GainStage,
+    gate::Gate,
+    jitter::Jitter,
+    noise::{Hum, NoiseSpec},
+    quantize::Quantizer,
+    resample::{brickwall, Decimation, Interpolation, Resampler, Retune},
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    simd,
+    spectral::SpectralCrush,
+    stereo::{Invert, PolarityStage, WidthStage},
+    stream::{Pipeline, Stage},
+    tape::{FlutterSpec, FlutterStage, HissStage, SpinSpec, SpinStage},
+    transient::TransientStage,
+    waveshape::{WaveshapeStage, Waveshaper},
+    Channel, Sound,
+};
+
+/// Everything which determines how a sound gets KRUSZED
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// Gain applied to the input before anything else, in dB
+    pub input_gain: Option<f64>,
+    /// Target sample rate
+    pub sample_rate: u32,
+    /// Speed the sound is sampled at, played back at the original speed again by the sampler
+    pub speed: Option<f64>,
+    /// Whether the bit depth and sample rate are raised around attacks
+    pub preserve_transients: bool,
+    /// Frequency bands KRUSZED at bit depths of their own, instead of the bit depth
+    pub bands: Option<BandsSpec>,
+    /// Target bit depth, possibly fractional
+    pub bit_depth: f64,
+    pub interpolation: Interpolation,
+    pub decimation: Decimation,
+    /// Sample clock jitter, in sample periods
+    pub jitter: f64,
+    /// Maximum error of each bit's weight in the DAC, in percent
+    pub dac_error: Option<f64>,
+    /// Bit crushing of the spectrum, applied before resampling
+    pub spectral_crush: Option<SpectralCrush>,
+    /// Effects from the effect registry applied before KRUSZING, in order
+    pub effects: Vec<EffectSpec>,
+    /// Transfer curve applied before KRUSZING
+    pub waveshape: Option<Waveshaper>,
+    /// Uneven tape speed, applied before KRUSZING
+    pub flutter: Option<FlutterSpec>,
+    /// Lossy codec the sound takes a round trip through before KRUSZING
+    pub codec: Option<Codec>,
+    /// Noise gate, applied either before or after KRUSZING
+    pub gate: Option<Gate>,
+    /// Filters applied before KRUSZING
+    pub filter: Vec<FilterSpec>,
+    /// Filters applied after KRUSZING
+    pub post_filter: Vec<FilterSpec>,
+    /// Feedback delay applied after KRUSZING
+    pub echo: Option<EchoSpec>,
+    /// Level of the noise added after KRUSZING, in dB relative to full scale
+    pub hiss: Option<f64>,
+    /// Colored noise and mains hum mixed in, after KRUSZING unless `noise_before` is set
+    pub noise: Option<NoiseSpec>,
+    pub hum: Option<Hum>,
+    pub noise_before: bool,
+    /// Cutoff and resonance of the vintage sampler filter
+    pub vintage_filter: Option<(f64, f64)>,
+    /// Speaker or handset the KRUSZED sound is played through
+    pub ir: Option<ImpulseResponse>,
+    /// Stereo width, from 0 (mono) to 2
+    pub width: Option<f64>,
+    /// Channels whose polarity is inverted at the end, after swapping the first two if set
+    pub invert: Option<Invert>,
+    pub swap_channels: bool,
+    /// Tape stop at the end of the KRUSZED sound, or its start
+    pub spin: Option<SpinSpec>,
+    /// Sample rate to resample the KRUSZED sound back to, if any
+    pub restore_rate: Option<u32>,
+    /// What happens to samples pushed past full scale along the way
+    pub clip_mode: ClipMode,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            input_gain: None,
+            sample_rate: 44100,
+            speed: None,
+            preserve_transients: false,
+            bands: None,
+            bit_depth: 16.0,
+            interpolation: Interpolation::Nearest,
+            decimation: Decimation::Alias,
+            jitter: 0.0,
+            dac_error: None,
+            spectral_crush: None,
+            effects: Vec::new(),
+            waveshape: None,
+            flutter: None,
+            codec: None,
+            gate: None,
+            filter: Vec::new(),
+            post_filter: Vec::new(),
+            echo: None,
+            hiss: None,
+            noise: None,
+            hum: None,
+            noise_before: false,
+            vintage_filter: None,
+            ir: None,
+            width: None,
+            invert: None,
+            swap_channels: false,
+            spin: None,
+            restore_rate: Some(44100),
+            clip_mode: ClipMode::Clamp,
+        }
+    }
+}
+
+impl Settings {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            (1..=44100).contains(&self.sample_rate),
+            "Sample rate must be between 1 and 44100 Hz inclusive"
+        );
+
+        ensure!(
+            (1.0..=32.0).contains(&self.bit_depth),
+            "Bit depth must be between 1 and 32 bits inclusive"
+        );
+
+        if let Some(speed) = self.speed {
+            ensure!(
+                (0.25..=4.0).contains(&speed),
+                "Speed must be between 0.25 and 4 inclusive"
+            );
+        }
+
+        ensure!(self.jitter >= 0.0, "Jitter must not be negative");
+
+        ensure!(
+            !(self.bands.is_some() && self.preserve_transients),
+            "Transients can't be preserved when crushing in bands"
+        );
+
+        if let Some(width) = self.width {
+            ensure!(
+                (0.0..=2.0).contains(&width),
+                "Width must be between 0% and 200% inclusive"
+            );
+        }
+
+        if let Some(restore_rate) = self.restore_rate {
+            ensure!(
+                (1..=192000).contains(&restore_rate),
+                "Restore rate must be between 1 and 192000 Hz inclusive"
+            );
+        }
+
+        if let Some(spectral_crush) = self.spectral_crush {
+            ensure!(
+                (1..=16).contains(&spectral_crush.bits),
+                "Spectral crush bits must be between 1 and 16 inclusive"
+            );
+        }
+
+        // The effects check their own parameters as they're made
+        for effect in &self.effects {
+            effect.stage(44100, self.clip_mode)?;
+        }
+
+        if let Some(gate) = self.gate {
+            ensure!(
+                gate.threshold.is_finite(),
+                "Gate threshold must be a finite number of dB"
+            );
+            ensure!(
+                gate.attack >= 0.0 && gate.hold >= 0.0 && gate.release >= 0.0,
+                "Gate attack, hold and release times must not be negative"
+            );
+        }
+
+        if let Some(hiss) = self.hiss {
+            ensure!(
+                hiss.is_finite() && hiss <= 0.0,
+                "Hiss level must be a finite number of dB, at most 0 dB"
+            );
+        }
+
+        if let Some(noise) = self.noise {
+            ensure!(
+                noise.level.is_finite() && noise.level <= 0.0,
+                "Noise level must be a finite number of dB, at most 0 dB"
+            );
+        }
+
+        if let Some(hum) = self.hum {
+            ensure!(
+                hum.frequency.is_finite() && hum.frequency > 0.0,
+                "Hum frequency must be positive"
+            );
+            ensure!(
+                hum.level.is_finite() && hum.level <= 0.0,
+                "Hum level must be a finite number of dB, at most 0 dB"
+            );
+        }
+
+        if let Some(dac_error) = self.dac_error {
+            ensure!(
+                (0.0..=100.0).contains(&dac_error),
+                "DAC error must be between 0 and 100 percent inclusive"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// The rate the sound is effectively sampled at. Sampling a sped up sound and slowing it down
+    /// again on playback is the same as sampling it at a lower rate.
+    pub fn capture_rate(&self) -> u32 {
+        match self.speed {
+            Some(speed) => (self.sample_rate as f64 / speed).round().max(1.0) as u32,
+            None => self.sample_rate,
+        }
+    }
+
+    /// The sample rate of the KRUSZED sound
+    pub fn output_rate(&self) -> u32 {
+        self.restore_rate.unwrap_or(self.sample_rate)
+    }
+
+    /// The stages which KRUSZE, at the given bit depth: resampling, quantization, and playback. They
+    /// follow the bit depth and sample rate when retuned.
+    fn crush_stages(
+        &self,
+        source_rate: u32,
+        bit_depth: f64,
+        dac: Option<Dac>,
+        rng: &mut ChaCha8Rng,
+    ) -> Result<Pipeline> {
+        let capture_rate = self.capture_rate();
+        let mut core = Pipeline::new();
+
+        let mut jitter = Jitter::new(ChaCha8Rng::from_rng(&mut *rng)?, self.jitter);
+        core.push(
+            Resampler::new(source_rate, capture_rate, self.interpolation)
+                .with_retune(Retune::Target)
+                .with_clip_mode(self.clip_mode)
+                .with_jitter(self.jitter, move || jitter.next_offset()),
+        );
+
+        core.push(Quantizer::new(
+            bit_depth,
+            dac,
+            ChaCha8Rng::from_rng(&mut *rng)?,
+        ));
+
+        // Samplers pitch down by stepping through memory more slowly, repeating samples
+        if self.speed.is_some() {
+            core.push(
+                Resampler::new(capture_rate, self.sample_rate, Interpolation::Nearest)
+                    .with_retune(Retune::Playback)
+                    .with_clip_mode(self.clip_mode),
+            );
+        }
+
+        if let Some(restore_rate) = self.restore_rate {
+            core.push(
+                Resampler::new(self.sample_rate, restore_rate, self.interpolation)
+                    .with_retune(Retune::Source)
+                    .with_clip_mode(self.clip_mode),
+            );
+        }
+
+        Ok(core)
+    }
+
+    /// Builds the KRUSZING stages for a sound with the given channels and sample rate
+    pub fn pipeline(
+        &self,
+        channels: usize,
+        source_rate: u32,
+        rng: &mut ChaCha8Rng,
+    ) -> Result<Pipeline> {
+        let dac = self.dac_error.map(|dac_error| {
+            Dac::new(
+                self.bit_depth.ceil() as u8,
+                dac_error / 100.0,
+                self.clip_mode,
+                rng,
+            )
+        });
+
+        let mut pipeline = Pipeline::new();
+
+        if let Some(input_gain) = self.input_gain {
+            pipeline.push(GainStage::new(input_gain, self.clip_mode));
+        }
+
+        if !self.filter.is_empty() {
+            pipeline.push(FilterStage::new(&self.filter, source_rate, self.clip_mode)?);
+        }
+
+        if let Some(gate) = self.gate.filter(|gate| !gate.after) {
+            pipeline.push(gate.stage(source_rate));
+        }
+
+        for effect in &self.effects {
+            pipeline.push_boxed(effect.stage(source_rate, self.clip_mode)?);
+        }
+
+        if let Some(waveshape) = &self.waveshape {
+            pipeline.push(WaveshapeStage::new(waveshape.clone(), self.clip_mode));
+        }
+
+        if let Some(flutter) = self.flutter {
+            pipeline.push(FlutterStage::new(flutter, source_rate));
+        }
+
+        if let Some(codec) = self.codec {
+            pipeline.push(codec.round_trip(
+                source_rate,
+                ChaCha8Rng::from_rng(&mut *rng)?,
+                self.clip_mode,
+            ));
+        }
+
+        if let Some(spectral_crush) = self.spectral_crush {
+            pipeline.push(spectral_crush.stage(source_rate, self.clip_mode));
+        }
+
+        let capture_rate = self.capture_rate();
+
+        let core = match &self.bands {
+            Some(bands) => {
+                let mut stages = Vec::new();
+
+                for band in &bands.0 {
+                    let mut band_stages = Pipeline::new();
+                    band_stages.push(crossover(band, source_rate, self.clip_mode)?);
+
+                    let dac = self.dac_error.map(|dac_error| {
+                        Dac::new(
+                            band.bit_depth.ceil() as u8,
+                            dac_error / 100.0,
+                            self.clip_mode,
+                            rng,
+                        )
+                    });
+                    band_stages.append(self.crush_stages(source_rate, band.bit_depth, dac, rng)?);
+                    stages.push((*band, band_stages));
+                }
+
+                let mut core = Pipeline::new();
+                core.push(MultibandStage::new(
+                    stages,
+                    self.output_rate(),
+                    self.clip_mode,
+                ));
+                core
+            }
+            None => self.crush_stages(source_rate, self.bit_depth, dac, rng)?,
+        };
+
+        // Drawn last, so that adding hiss or noise leaves the rest of the KRUSZING as it was
+        let hiss = match self.hiss {
+            Some(hiss) => Some(HissStage::new(
+                hiss,
+                ChaCha8Rng::from_rng(&mut *rng)?,
+                self.clip_mode,
+            )),
+            None => None,
+        };
+        let noise = match self.noise {
+            Some(noise) => Some(noise.stage(ChaCha8Rng::from_rng(&mut *rng)?, self.clip_mode)),
+            None => None,
+        };
+
+        let output_rate = self.output_rate();
+        let mut bed = Pipeline::new();
+        let bed_rate = if self.noise_before {
+            source_rate
+        } else {
+            output_rate
+        };
+
+        if let Some(noise) = noise {
+            bed.push(noise);
+        }
+
+        if let Some(hum) = self.hum {
+            bed.push(hum.stage(bed_rate, self.clip_mode));
+        }
+
+        if self.noise_before {
+            pipeline.append(std::mem::take(&mut bed));
+        }
+
+        if self.decimation == Decimation::Fft && capture_rate < source_rate {
+            pipeline.push(
+                brickwall(capture_rate as f64 / 2.0, source_rate).with_clip_mode(self.clip_mode),
+            );
+        }
+
+        if self.preserve_transients {
+            pipeline.push(TransientStage::new(core, self.clone(), source_rate));
+        } else {
+            pipeline.append(core);
+        }
+
+        if let Some(hiss) = hiss {
+            pipeline.push(hiss);
+        }
+
+        pipeline.append(bed);
+
+        if let Some((cutoff, resonance)) = self.vintage_filter {
+            pipeline.push(LadderStage::new(
+                cutoff,
+                resonance,
+                output_rate,
+                self.clip_mode,
+            )?);
+        }
+
+        if let Some(ir) = &self.ir {
+            pipeline.push(ConvolveStage::new(ir, output_rate, self.clip_mode)?);
+        }
+
+        if !self.post_filter.is_empty() {
+            pipeline.push(FilterStage::new(
+                &self.post_filter,
+                output_rate,
+                self.clip_mode,
+            )?);
+        }
+
+        if let Some(echo) = self.echo {
+            pipeline.push(EchoStage::new(
+                echo,
+                output_rate,
+                self.bit_depth,
+                self.clip_mode,
+            ));
+        }
+
+        if let Some(gate) = self.gate.filter(|gate| gate.after) {
+            pipeline.push(gate.stage(output_rate));
+        }
+
+        if let Some(width) = self.width {
+            if channels == 2 {
+                pipeline.push(WidthStage::new(width, self.clip_mode));
+            }
+        }
+
+        if self.invert.is_some() || self.swap_channels {
+            pipeline.push(PolarityStage::new(
+                self.invert,
+                self.swap_channels,
+                self.clip_mode,
+            ));
+        }
+
+        if let Some(spin) = self.spin {
+            pipeline.push(SpinStage::new(spin, self.bit_depth, output_rate));
+        }
+
+        Ok(pipeline)
+    }
+}
+
+/// Lowest sample rate a crusher can be retuned to while running, unless it started lower
+pub const MIN_LIVE_SAMPLE_RATE: u32 = 1000;
+
+/// Default time taken by live parameter changes, in seconds
+pub const DEFAULT_SMOOTHING: f64 = 0.02;
+
+/// Frames processed between updates of the parameters while they're changing
+const SMOOTHING_BLOCK: usize = 32;
+
+/// A parameter which moves linearly towards its target instead of jumping, to avoid zipper noise
+#[derive(Clone, Copy, Debug)]
+struct Smoothed {
+    current: f64,
+    target: f64,
+    /// How far it moves per frame
+    step: f64,
+}
+
+impl Smoothed {
+    fn new(value: f64) -> Self {
+        Self {
+            current: value,
+            target: value,
+            step: 0.0,
+        }
+    }
+
+    /// Heads for `target`, getting there in `frames` frames
+    fn set(&mut self, target: f64, frames: f64) {
+        self.target = target;
+        self.step = (target - self.current).abs() / frames.max(1.0);
+    }
+
+    fn snap(&mut self) {
+        self.current = self.target;
+    }
+
+    fn is_settled(&self) -> bool {
+        self.current == self.target
+    }
+
+    fn advance(&mut self, frames: usize) -> f64 {
+        let delta = self.step * frames as f64;
+
+        self.current = if self.current < self.target {
+            (self.current + delta).min(self.target)
+        } else {
+            (self.current - delta).max(self.target)
+        };
+
+        self.current
+    }
+}
+
+/// KRUSZES audio in realtime, for hosts which hand over interleaved blocks of a fixed number of
+/// frames and expect as many back. The KRUSZED sound is restored to the host's sample rate, and
+/// delayed by `latency` frames so that stages which look ahead never run short. The dry signal
+/// is delayed just as much before being mixed back in, so the two never comb-filter.
+///
+/// The bit depth, sample rate and mix can be changed while running, and ramp to their new values
+/// over the smoothing time. The bit depth passes through fractional depths on the way, and the
+/// sample rate through the rates in between.
+pub struct Crusher {
+    settings: Settings,
+    channels: usize,
+    sample_rate: u32,
+    seed: u64,
+    pipeline: Pipeline,
+    latency: usize,
+    /// KRUSZED samples which haven't been handed out yet, interleaved
+    pending: VecDeque<f32>,
+    /// Input samples waiting to be mixed with the KRUSZED ones they line up with, interleaved
+    dry: VecDeque<f32>,
+    bit_depth: Smoothed,
+    rate: Smoothed,
+    mix: Smoothed,
+    /// Time taken by parameter changes, in seconds
+    smoothing: f64,
+    min_rate: u32,
+    /// Whether any block was processed since the crusher was created or reset. Changes made
+    /// before that take effect straight away.
+    started: bool,
+}
+
+impl Crusher {
+    pub fn new(
+        mut settings: Settings,
+        channels: usize,
+        sample_rate: u32,
+        seed: u64,
+    ) -> Result<Self> {
+        ensure!(channels > 0, "There must be at least one channel");
+        ensure!(sample_rate > 0, "The sample rate must be positive");
+
+        settings.restore_rate = Some(sample_rate);
+        settings.validate()?;
+
+        let pipeline =
+            settings.pipeline(channels, sample_rate, &mut ChaCha8Rng::seed_from_u64(seed))?;
+
+        // Lower sample rates look further ahead, so leave room for the lowest one reachable live
+        let min_rate = settings.sample_rate.min(MIN_LIVE_SAMPLE_RATE);
+        let slowest = Settings {
+            sample_rate: min_rate,
+            ..settings.clone()
+        }
+        .pipeline(channels, sample_rate, &mut ChaCha8Rng::seed_from_u64(seed))?;
+        let latency =
+            (pipeline.latency().max(slowest.latency()) * sample_rate as f64).ceil() as usize;
+
+        let mut crusher = Self {
+            bit_depth: Smoothed::new(settings.bit_depth),
+            rate: Smoothed::new(settings.sample_rate as f64),
+            settings,
+            channels,
+            sample_rate,
+            seed,
+            pipeline,
+            latency,
+            pending: VecDeque::new(),
+            dry: VecDeque::new(),
+            mix: Smoothed::new(1.0),
+            smoothing: DEFAULT_SMOOTHING,
+            min_rate,
+            started: false,
+        };
+        crusher.prime();
+
+        Ok(crusher)
+    }
+
+    /// The delay between input and output, in frames
+    pub fn latency(&self) -> usize {
+        self.latency
+    }
+
+    /// Sets the time taken by parameter changes from now on, in seconds
+    pub fn set_smoothing(&mut self, smoothing: f64) {
+        assert!(smoothing >= 0.0, "Smoothing must not be negative");
+        self.smoothing = smoothing;
+    }
+
+    /// Starts moving a parameter to a new value, or sets it straight away if nothing was played yet
+    fn retarget(&mut self, parameter: fn(&mut Self) -> &mut Smoothed, target: f64) {
+        let frames = self.smoothing * self.sample_rate as f64;
+        let started = self.started;
+        let parameter = parameter(self);
+
+        parameter.set(target, frames);
+        if !started {
+            parameter.snap();
+        }
+    }
+
+    /// Sets the proportion of KRUSZED sound in the output, from 0 (all dry) to 1 (all KRUSZED)
+    pub fn set_mix(&mut self, mix: f32) {
+        assert!((0.0..=1.0).contains(&mix), "Mix must be between 0 and 1");
+        self.retarget(|crusher| &mut crusher.mix, mix.into());
+    }
+
+    /// Sets the target bit depth, between 1 and 32 bits
+    pub fn set_bit_depth(&mut self, bit_depth: f64) {
+        assert!(
+            (1.0..=32.0).contains(&bit_depth),
+            "Bit depth must be between 1 and 32"
+        );
+        self.retarget(|crusher| &mut crusher.bit_depth, bit_depth);
+        self.retune_now();
+    }
+
+    /// Sets the target sample rate, from `MIN_LIVE_SAMPLE_RATE` (or the initial rate if lower) to
+    /// 44100 Hz
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(
+            (self.min_rate..=44100).contains(&sample_rate),
+            "Sample rate must be between {} and 44100 Hz",
+            self.min_rate
+        );
+        self.retarget(|crusher| &mut crusher.rate, sample_rate as f64);
+        self.retune_now();
+    }
+
+    /// Makes the pipeline follow the parameters if they were set straight away
+    fn retune_now(&mut self) {
+        if !self.started {
+            self.settings.bit_depth = self.bit_depth.current;
+            self.settings.sample_rate = self.rate.current.round() as u32;
+            self.pipeline.retune(&self.settings);
+        }
+    }
+
+    /// Clears all state, as if the crusher had just been created. The mix and any parameters set
+    /// since are kept, at their target values.
+    pub fn reset(&mut self) {
+        for parameter in [&mut self.bit_depth, &mut self.rate, &mut self.mix] {
+            parameter.snap();
+        }
+        self.settings.bit_depth = self.bit_depth.current;
+        self.settings.sample_rate = self.rate.current.round() as u32;
+        self.started = false;
+
+        self.pipeline = self
+            .settings
+            .pipeline(
+                self.channels,
+                self.sample_rate,
+                &mut ChaCha8Rng::seed_from_u64(self.seed),
+            )
+            .expect("Settings were already checked when the crusher was created");
+        self.prime();
+    }
+
+    fn prime(&mut self) {
+        for queue in [&mut self.pending, &mut self.dry] {
+            queue.clear();
+            queue.resize(self.latency * self.channels, 0.0);
+        }
+    }
+
+    /// KRUSZES a block of interleaved samples in the -1.0..1.0 range. Both slices must hold the
+    /// same whole number of frames.
+    pub fn process_block(&mut self, input: &[f32], output: &mut [f32]) {
+        assert_eq!(input.len(), output.len());
+        assert_eq!(input.len() % self.channels, 0);
+
+        self.started = true;
+
+        let settled = self.bit_depth.is_settled() && self.rate.is_settled();
+        let block_len = if settled {
+            input.len().max(1)
+        } else {
+            SMOOTHING_BLOCK * self.channels
+        };
+
+        for (input, output) in input.chunks(block_len).zip(output.chunks_mut(block_len)) {
+            if !settled {
+                self.advance_parameters(input.len() / self.channels);
+            }
+
+            self.process_sub_block(input, output);
+        }
+    }
+
+    /// Moves the bit depth and sample rate on by a number of frames
+    fn advance_parameters(&mut self, frames: usize) {
+        let bit_depth = self.bit_depth.advance(frames);
+        let sample_rate = self.rate.advance(frames).round() as u32;
+
+        if bit_depth != self.settings.bit_depth || sample_rate != self.settings.sample_rate {
+            self.settings.bit_depth = bit_depth;
+            self.settings.sample_rate = sample_rate;
+            self.pipeline.retune(&self.settings);
+        }
+    }
+
+    fn process_sub_block(&mut self, input: &[f32], output: &mut [f32]) {
+        let scale = FULL_SCALE as f32;
+        let block = Sound {
+            channels: (0..self.channels)
+                .map(|c| Channel {
+                    samples: input
+                        .iter()
+                        .skip(c)
+                        .step_by(self.channels)
+                        .map(|&sample| to_sample((sample * scale).into(), self.settings.clip_mode))
+                        .collect(),
+                })
+                .collect(),
+            sample_rate: self.sample_rate,
+        };
+
+        let crushed = self.pipeline.process(block);
+        self.pending.extend(
+            simd::interleave(&crushed.channels)
+                .into_iter()
+                .map(|sample| sample as f32 / scale),
+        );
+
+        self.dry.extend(input);
+
+        debug_assert!(
+            self.pending.len() >= output.len(),
+            "Latency was underestimated"
+        );
+
+        for frame in output.chunks_mut(self.channels) {
+            let mix = if self.mix.is_settled() {
+                self.mix.current
+            } else {
+                self.mix.advance(1)
+            } as f32;
+
+            for sample in frame {
+                let wet = self.pending.pop_front().unwrap_or(0.0);
+                let dry = self.dry.pop_front().unwrap();
+                *sample = dry * (1.0 - mix) + wet * mix;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::quantize::requantize_sample;
+
+    #[test]
+    fn test_crusher() {
+        let settings = Settings {
+            sample_rate: 11025,
+            bit_depth: 6.5,
+            jitter: 0.3,
+            vintage_filter: Some((5000.0, 0.5)),
+            ..Settings::default()
+        };
+
+        let frames = 3000;
+        let input: Vec<f32> = (0..frames * 2)
+            .map(|i| ((i * 37) % 2000) as f32 / 1000.0 - 1.0)
+            .collect();
+
+        let sound = Sound {
+            channels: (0..2)
+                .map(|c| Channel {
+                    samples: input
+                        .iter()
+                        .skip(c)
+                        .step_by(2)
+                        .map(|&sample| to_sample(sample as f64 * FULL_SCALE, ClipMode::Clamp))
+                        .collect(),
+                })
+                .collect(),
+            sample_rate: 48000,
+        };
+
+        let mut whole_settings = settings.clone();
+        whole_settings.restore_rate = Some(48000);
+        let whole = whole_settings
+            .pipeline(2, 48000, &mut ChaCha8Rng::seed_from_u64(7))
+            .unwrap()
+            .run(sound);
+
+        let mut crusher = Crusher::new(settings, 2, 48000, 7).unwrap();
+        assert!(crusher.latency() > 0);
+
+        for _ in 0..2 {
+            let mut output = vec![0.0; input.len()];
+            for (input, output) in input.chunks(2 * 64).zip(output.chunks_mut(2 * 64)) {
+                crusher.process_block(input, output);
+            }
+
+            let latency = crusher.latency() * 2;
+            assert!(output[..latency].iter().all(|&sample| sample == 0.0));
+            assert_eq!(
+                output[latency..],
+                simd::interleave(&whole.channels)[..input.len() - latency]
+                    .iter()
+                    .map(|&sample| sample as f32 / FULL_SCALE as f32)
+                    .collect::<Vec<_>>()[..]
+            );
+
+            crusher.reset();
+        }
+    }
+
+    #[test]
+    fn test_speed() {
+        let settings = Settings {
+            sample_rate: 8000,
+            speed: Some(2.0),
+            restore_rate: None,
+            ..Settings::default()
+        };
+        assert_eq!(settings.capture_rate(), 4000);
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..16000).map(|i| i << 16).collect(),
+            }],
+            sample_rate: 16000,
+        };
+        let crushed = settings
+            .pipeline(1, 16000, &mut ChaCha8Rng::seed_from_u64(0))
+            .unwrap()
+            .run(sound);
+
+        // As long as before, at the sample rate asked for, but with every sample held for two
+        assert_eq!(crushed.sample_rate, 8000);
+        assert!((crushed.frames() as i64 - 8000).abs() <= 2);
+        let samples = &crushed.channels[0].samples;
+        assert!(samples[1..].chunks_exact(2).all(|pair| pair[0] == pair[1]));
+        assert_ne!(samples[1], samples[3]);
+    }
+
+    #[test]
+    fn test_crusher_dry() {
+        let settings = Settings {
+            sample_rate: 8000,
+            bit_depth: 4.0,
+            ..Settings::default()
+        };
+
+        let mut crusher = Crusher::new(settings, 1, 44100, 0).unwrap();
+        crusher.set_mix(0.0);
+
+        let input: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.1).sin()).collect();
+        let mut output = vec![0.0; input.len()];
+        for (input, output) in input.chunks(100).zip(output.chunks_mut(100)) {
+            crusher.process_block(input, output);
+        }
+
+        let latency = crusher.latency();
+        assert!(latency > 0);
+        assert!(output[..latency].iter().all(|&sample| sample == 0.0));
+        assert_eq!(output[latency..], input[..input.len() - latency]);
+    }
+
+    #[test]
+    fn test_crusher_smoothing() {
+        let mut crusher = Crusher::new(Settings::default(), 1, 44100, 0).unwrap();
+        crusher.set_smoothing(0.01);
+        crusher.set_mix(0.0);
+
+        let input = vec![0.5; 44100 / 10];
+        let mut output = vec![0.0; input.len()];
+        crusher.process_block(&input, &mut output);
+        let latency = crusher.latency();
+        assert!(output[latency..].iter().all(|&sample| sample == 0.5));
+
+        // The mix ramps to fully KRUSZED over 441 frames instead of jumping
+        crusher.set_bit_depth(2.0);
+        crusher.reset();
+        crusher.set_smoothing(0.01);
+        crusher.set_mix(0.0);
+        crusher.process_block(&input, &mut output);
+
+        crusher.set_mix(1.0);
+        crusher.process_block(&input, &mut output);
+
+        let crushed = requantize_sample(to_sample(0.5 * FULL_SCALE, ClipMode::Clamp), 2) as f32
+            / FULL_SCALE as f32;
+        let step = (crushed - 0.5).abs() / 441.0;
+        assert!(output
+            .windows(2)
+            .all(|pair| (pair[0] - pair[1]).abs() <= step * 1.01));
+        assert!(output[441..].iter().all(|&sample| sample == crushed));
+
+        // The bit depth goes through the depths in between
+        crusher.set_bit_depth(16.0);
+        crusher.process_block(&input, &mut output);
+        assert!(output[100..300]
+            .iter()
+            .any(|&sample| sample != crushed && (sample - 0.5).abs() > 1e-4));
+        let clean = requantize_sample(to_sample(0.5 * FULL_SCALE, ClipMode::Clamp), 16) as f32
+            / FULL_SCALE as f32;
+        assert!(output[441 + latency..]
+            .iter()
+            .all(|&sample| sample == clean));
+        assert_eq!(crusher.settings.bit_depth, 16.0);
+
+        crusher.set_sample_rate(8000);
+        crusher.process_block(&input, &mut output);
+        assert_eq!(crusher.settings.sample_rate, 8000);
+
+        crusher.reset();
+        assert_eq!(crusher.settings.bit_depth, 16.0);
+        assert_eq!(crusher.settings.sample_rate, 8000);
+    }
+}
@@ -0,0 +1,70 @@
+use rand::Rng;
+
+use crate::sample::{to_sample, ClipMode, Sample};
+
+/// A DAC whose bits don't carry exactly their binary weight, like a cheap R-2R ladder with
+/// mismatched resistors. Maps quantized samples to the slightly wrong values such a DAC would output.
+#[derive(Clone, Debug)]
+pub struct Dac {
+    /// The actual weight of each bit of the quantized code, least significant first
+    weights: Vec<f64>,
+    /// What happens to samples which the heavier bits push past full scale
+    clip_mode: ClipMode,
+}
+
+impl Dac {
+    /// Creates a DAC with the given resolution, where each bit's weight is off by up to `error`
+    /// (as a fraction of its ideal weight) in either direction
+    pub fn new<R: Rng>(bit_depth: u8, error: f64, clip_mode: ClipMode, rng: &mut R) -> Self {
+        Self {
+            weights: (0..bit_depth)
+                .map(|i| 2f64.powi(i.into()) * (1.0 + rng.gen_range(-error..=error)))
+                .collect(),
+            clip_mode,
+        }
+    }
+
+    pub fn convert(&self, sample: Sample) -> Sample {
+        let shift = 32 - self.weights.len() as u32;
+        let unsigned = sample as i64 - Sample::MIN as i64;
+        let code = unsigned >> shift;
+        let lo = unsigned & ((1 << shift) - 1);
+
+        let analog: f64 = self
+            .weights
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| (code >> i) & 1 == 1)
+            .map(|(_, weight)| weight)
+            .sum();
+
+        to_sample(
+            analog * (1u64 << shift) as f64 + lo as f64 + Sample::MIN as f64,
+            self.clip_mode,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_dac() {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        let ideal = Dac::new(8, 0.0, ClipMode::Clamp, &mut rng);
+        for sample in [Sample::MIN, -1, 0, 255, 511, 1 << 24, Sample::MAX] {
+            assert_eq!(ideal.convert(sample), sample);
+        }
+
+        let cheap = Dac::new(8, 0.05, ClipMode::Clamp, &mut rng);
+        assert_eq!(cheap.convert(Sample::MIN), Sample::MIN);
+        assert!((Sample::MIN..=Sample::MAX)
+            .step_by(1 << 24)
+            .any(|sample| cheap.convert(sample) != sample));
+    }
+}
@@ -0,0 +1,337 @@
+use std::{
+    borrow::Cow,
+    io::{self, IsTerminal},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Result};
+use krusz::{
+    resample::{Interpolation, Resampler},
+    stream::Stage,
+    Sound,
+};
+use rodio::{
+    cpal::{
+        self,
+        traits::{DeviceTrait, HostTrait, StreamTrait},
+        BufferSize, SampleFormat, SupportedBufferSize,
+    },
+    source::UniformSourceIterator,
+    Sink,
+};
+use tracing::{debug, warn};
+
+use crate::monitor::{self, Tap};
+
+/// Where and how sounds are played
+#[derive(Clone, Debug, Default)]
+pub struct DeviceConfig {
+    /// Audio host API, by name. Default: the system's default
+    pub host: Option<String>,
+    /// Frames per buffer. Default: the device's own
+    pub buffer_size: Option<u32>,
+    /// Play into a null device instead of a real one
+    pub null: bool,
+}
+
+/// Format of the null device
+const NULL_CHANNELS: u16 = 2;
+const NULL_SAMPLE_RATE: u32 = 48000;
+
+/// Frames the null device takes at a time, 10 ms worth
+const NULL_BLOCK_FRAMES: usize = NULL_SAMPLE_RATE as usize / 100;
+
+/// Keeps the sound playing until dropped
+pub struct Stream {
+    /// None on the null device
+    _device: Option<cpal::Stream>,
+    /// Stops the null device's thread and the indicators when set
+    stop: Arc<AtomicBool>,
+    /// Joined on drop, so that the indicators are left in their final state
+    display: Option<JoinHandle<()>>,
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+
+        if let Some(display) = self.display.take() {
+            let _ = display.join();
+        }
+    }
+}
+
+/// A running output stream, the sink feeding it, and the sample rate the device runs at
+pub struct Output {
+    pub stream: Stream,
+    pub sink: Sink,
+    pub sample_rate: u32,
+}
+
+/// Finds an audio host by name, ignoring case
+fn host(name: Option<&str>) -> Result<cpal::Host> {
+    let name = match name {
+        Some(name) => name,
+        None => return Ok(cpal::default_host()),
+    };
+
+    let available = cpal::available_hosts();
+    let id = available
+        .iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| {
+            let names: Vec<_> = available
+                .iter()
+                .map(|id| id.name().to_lowercase())
+                .collect();
+            eyre!(
+                "Audio host {} isn't available. Available: {}",
+                name,
+                names.join(", ")
+            )
+        })?;
+
+    Ok(cpal::host_from_id(*id)?)
+}
+
+/// The buffer size to ask for, checked against what the device supports where it says
+fn buffer_size(frames: Option<u32>, supported: &SupportedBufferSize) -> Result<BufferSize> {
+    match (frames, supported) {
+        (None, _) => Ok(BufferSize::Default),
+        (Some(frames), SupportedBufferSize::Range { min, max })
+            if frames < *min || frames > *max =>
+        {
+            Err(eyre!(
+                "The device supports buffers of {} to {} frames, not {}",
+                min,
+                max,
+                frames
+            ))
+        }
+        (Some(frames), _) => Ok(BufferSize::Fixed(frames)),
+    }
+}
+
+/// Fills a device buffer from the sink, with silence once it runs dry
+fn fill<T: cpal::Sample>(data: &mut [T], samples: &mut impl Iterator<Item = f32>) {
+    for out in data {
+        *out = T::from(&samples.next().unwrap_or(0.0));
+    }
+}
+
+fn open_device(device: &cpal::Device, config: &DeviceConfig, tap: &Tap) -> Result<Output> {
+    let supported = device.default_output_config()?;
+
+    if let Ok(configs) = device.supported_output_configs() {
+        for supported in configs {
+            debug!(
+                "{} supports {} channels of {:?} at {} to {} Hz",
+                device.name().unwrap_or_default(),
+                supported.channels(),
+                supported.sample_format(),
+                supported.min_sample_rate().0,
+                supported.max_sample_rate().0
+            );
+        }
+    }
+
+    // The device's default config runs at its native rate
+    let mut stream_config = supported.config();
+    stream_config.buffer_size = buffer_size(config.buffer_size, supported.buffer_size())?;
+
+    let (sink, queue) = Sink::new_idle();
+    let mut samples = tap.monitor(
+        UniformSourceIterator::<_, f32>::new(
+            queue,
+            stream_config.channels,
+            stream_config.sample_rate.0,
+        ),
+        stream_config.channels,
+    );
+    let on_error = |error| warn!("Audio output error: {}", error);
+
+    let stream = match supported.sample_format() {
+        SampleFormat::F32 => device.build_output_stream(
+            &stream_config,
+            move |data: &mut [f32], _: &cpal::OutputCallbackInfo| fill(data, &mut samples),
+            on_error,
+        ),
+        SampleFormat::I16 => device.build_output_stream(
+            &stream_config,
+            move |data: &mut [i16], _: &cpal::OutputCallbackInfo| fill(data, &mut samples),
+            on_error,
+        ),
+        SampleFormat::U16 => device.build_output_stream(
+            &stream_config,
+            move |data: &mut [u16], _: &cpal::OutputCallbackInfo| fill(data, &mut samples),
+            on_error,
+        ),
+    }?;
+    stream.play()?;
+
+    Ok(Output {
+        stream: Stream {
+            _device: Some(stream),
+            stop: Arc::default(),
+            display: None,
+        },
+        sink,
+        sample_rate: stream_config.sample_rate.0,
+    })
+}
+
+/// A device which discards the sound, taking it at the pace a real one would so that playback
+/// behaves the same without audio hardware
+fn open_null(tap: &Tap) -> Output {
+    let (sink, queue) = Sink::new_idle();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let mut samples = tap.monitor(
+        UniformSourceIterator::<_, f32>::new(queue, NULL_CHANNELS, NULL_SAMPLE_RATE),
+        NULL_CHANNELS,
+    );
+
+    thread::spawn(move || {
+        let block = NULL_BLOCK_FRAMES * usize::from(NULL_CHANNELS);
+        let start = Instant::now();
+        let mut frames = 0;
+
+        while !stopped.load(Ordering::Relaxed) && samples.by_ref().take(block).count() == block {
+            frames += NULL_BLOCK_FRAMES as u64;
+            let due = start + Duration::from_secs_f64(frames as f64 / f64::from(NULL_SAMPLE_RATE));
+            thread::sleep(due.saturating_duration_since(Instant::now()));
+        }
+    });
+
+    Output {
+        stream: Stream {
+            _device: None,
+            stop,
+            display: None,
+        },
+        sink,
+        sample_rate: NULL_SAMPLE_RATE,
+    }
+}
+
+/// Opens the default output device of the configured host, or failing that any other one, at its
+/// native sample rate. On a terminal, level meters and a clip indicator run for as long as the
+/// stream does.
+pub fn open(config: &DeviceConfig) -> Result<Output> {
+    let (tap, indicators) = monitor::indicators();
+
+    let mut output = if config.null {
+        open_null(&tap)
+    } else {
+        let host = host(config.host.as_deref())?;
+        let default = host
+            .default_output_device()
+            .ok_or_else(|| eyre!("There is no audio output device"))?;
+
+        open_device(&default, config, &tap).or_else(|error| {
+            host.output_devices()?
+                .find_map(|device| open_device(&device, config, &tap).ok())
+                .ok_or(error)
+        })?
+    };
+
+    if io::stderr().is_terminal() {
+        output.stream.display = Some(monitor::display(indicators, output.stream.stop.clone()));
+    }
+
+    Ok(output)
+}
+
+/// The sound at the device's sample rate, so that it plays at the right pitch and speed whatever
+/// rate the device is fixed to
+pub fn at_rate(sound: &Sound, sample_rate: u32) -> Cow<'_, Sound> {
+    if sound.sample_rate == sample_rate || sound.channels.is_empty() {
+        return Cow::Borrowed(sound);
+    }
+
+    debug!(
+        "Resampling from {} Hz to the device's {} Hz for playback",
+        sound.sample_rate, sample_rate
+    );
+    Cow::Owned(
+        Resampler::new(sound.sample_rate, sample_rate, Interpolation::Linear).run(sound.clone()),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use krusz::Channel;
+
+    use super::*;
+
+    #[test]
+    fn test_at_rate() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![1 << 20; 441],
+                };
+                2
+            ],
+            sample_rate: 44100,
+        };
+
+        assert!(matches!(at_rate(&sound, 44100), Cow::Borrowed(_)));
+
+        let resampled = at_rate(&sound, 48000);
+        assert_eq!(resampled.sample_rate, 48000);
+        assert_eq!(resampled.channels.len(), 2);
+        assert_eq!(resampled.frames(), 480);
+        assert!(resampled.channels[1].samples.iter().all(|&s| s == 1 << 20));
+    }
+
+    #[test]
+    fn test_null_device() {
+        let output = open(&DeviceConfig {
+            null: true,
+            ..DeviceConfig::default()
+        })
+        .unwrap();
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0; 2205],
+            }],
+            sample_rate: 44100,
+        };
+
+        // Plays in real time, like a device would
+        let start = Instant::now();
+        output
+            .sink
+            .append(at_rate(&sound, output.sample_rate).to_source());
+        output.sink.sleep_until_end();
+        assert!(start.elapsed() >= Duration::from_millis(40));
+    }
+
+    #[test]
+    fn test_host() {
+        assert!(host(None).is_ok());
+        assert!(host(Some("no-such-host")).is_err());
+    }
+
+    #[test]
+    fn test_buffer_size() {
+        let range = SupportedBufferSize::Range { min: 64, max: 4096 };
+
+        assert_eq!(buffer_size(None, &range).unwrap(), BufferSize::Default);
+        assert_eq!(
+            buffer_size(Some(128), &range).unwrap(),
+            BufferSize::Fixed(128)
+        );
+        assert!(buffer_size(Some(32), &range).is_err());
+        assert_eq!(
+            buffer_size(Some(32), &SupportedBufferSize::Unknown).unwrap(),
+            BufferSize::Fixed(32)
+        );
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::Result;
+use tracing::warn;
+
+use crate::{
+    output::{mix_down, Encoder},
+    sample::Sample,
+    Sound,
+};
+
+/// Sample rate of ComputerCraft's speakers
+pub const SAMPLE_RATE: u32 = 48000;
+
+/// Fractional bits of the adaptation strength
+const PRECISION: u32 = 10;
+
+/// DFPWM1a state: a one-pole filter whose charge chases each bit's target, with a strength that
+/// grows while the bits repeat and shrinks when they alternate
+#[derive(Debug, Default)]
+struct Dfpwm {
+    charge: i32,
+    strength: i32,
+    previous_bit: bool,
+}
+
+impl Dfpwm {
+    /// Encodes a signed 8-bit level as a single bit
+    fn encode(&mut self, level: i32) -> bool {
+        let bit = level > self.charge || (level == self.charge && self.charge == 127);
+        let target = if bit { 127 } else { -128 };
+
+        let mut charge = self.charge
+            + ((self.strength * (target - self.charge) + (1 << (PRECISION - 1))) >> PRECISION);
+        if charge == self.charge && charge != target {
+            charge += if bit { 1 } else { -1 };
+        }
+
+        let same = bit == self.previous_bit;
+        let limit = if same { (1 << PRECISION) - 1 } else { 0 };
+        let mut strength = self.strength;
+        if strength != limit {
+            strength += if same { 1 } else { -1 };
+        }
+
+        self.charge = charge;
+        self.strength = strength.max(2 << (PRECISION - 8));
+        self.previous_bit = bit;
+        bit
+    }
+
+    /// Encodes 8 levels as a byte, first level in the least significant bit
+    fn encode_byte(&mut self, levels: &[i32]) -> u8 {
+        levels.iter().enumerate().fold(0, |byte, (i, &level)| {
+            byte | (self.encode(level) as u8) << i
+        })
+    }
+}
+
+/// Writes DFPWM files for ComputerCraft and OpenComputers: 1-bit Dynamic Filter Pulse Width
+/// Modulation, 8 samples per byte. Sounds with several channels are mixed down to mono.
+pub struct DfpwmEncoder {
+    file: BufWriter<File>,
+    dfpwm: Dfpwm,
+    /// Levels which don't fill a byte yet
+    pending: Vec<i32>,
+}
+
+impl DfpwmEncoder {
+    pub(crate) fn create(path: &Path, sample_rate: u32) -> Result<Self> {
+        if sample_rate != SAMPLE_RATE {
+            warn!(
+                "DFPWM is played back at {} Hz, but the sound is at {} Hz",
+                SAMPLE_RATE, sample_rate
+            );
+        }
+
+        Ok(Self {
+            file: BufWriter::new(File::create(path)?),
+            dfpwm: Dfpwm::default(),
+            pending: Vec::new(),
+        })
+    }
+}
+
+impl Encoder for DfpwmEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        self.pending.extend(
+            mix_down(block)
+                .into_iter()
+                .map(|sample: Sample| sample >> 24),
+        );
+
+        let whole = self.pending.len() / 8 * 8;
+        let bytes: Vec<u8> = self.pending[..whole]
+            .chunks(8)
+            .map(|levels| self.dfpwm.encode_byte(levels))
+            .collect();
+        self.pending.drain(..whole);
+
+        self.file.write_all(&bytes)?;
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        // The last byte is filled out with silence
+        if !self.pending.is_empty() {
+            self.pending.resize(8, 0);
+            let byte = self.dfpwm.encode_byte(&self.pending);
+            self.file.write_all(&[byte])?;
+        }
+
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat, OutputOptions},
+        Channel,
+    };
+
+    #[test]
+    fn test_dfpwm() {
+        // Full scale is all ones, the other way round all zeros
+        let mut dfpwm = Dfpwm::default();
+        assert_eq!(dfpwm.encode_byte(&[127; 8]), 0xff);
+        let mut dfpwm = Dfpwm::default();
+        assert_eq!(dfpwm.encode_byte(&[-128; 8]), 0x00);
+
+        // The bits follow a square wave, mostly ones while it's up and zeros while it's down
+        let mut dfpwm = Dfpwm::default();
+        let square: Vec<u8> = (0..64)
+            .map(|i| {
+                let level = if i % 2 == 0 { 100 } else { -100 };
+                dfpwm.encode_byte(&[level; 8])
+            })
+            .collect();
+        assert_eq!(square[..4], [0xff, 0x00, 0xff, 0x00]);
+        assert!(square
+            .chunks(2)
+            .all(|pair| pair[0].count_ones() > pair[1].count_ones()));
+    }
+
+    #[test]
+    fn test_save_dfpwm() {
+        let path = std::env::temp_dir().join("krusz_test_save_dfpwm.dfpwm");
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![i32::MAX; 20],
+            }],
+            sample_rate: SAMPLE_RATE,
+        };
+
+        save(&sound, &path, OutputFormat::Dfpwm, OutputOptions::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // The last 4 bits are padding, silence is below the charge by then
+        assert_eq!(bytes, [0xff, 0xff, 0x0f]);
+    }
+}
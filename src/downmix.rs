@@ -0,0 +1,259 @@
+use std::{
+    f64::consts::FRAC_1_SQRT_2,
+    fmt::{self, Display, Formatter},
+    fs,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, eyre, Report, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::{
+    output::ChannelLayout,
+    sample::{to_sample, ClipMode},
+    Channel, Sound,
+};
+
+/// How much of each speaker goes into the left and right channels of a stereo fold-down, by the
+/// bit of the speaker in WAVE_FORMAT_EXTENSIBLE channel masks, after ITU-R BS.775: centre and
+/// surround speakers at -3 dB, the LFE left out, and speakers in the middle at -3 dB on both sides
+/// of that
+const STEREO_FOLD: [(f64, f64); 18] = [
+    // FL, FR, FC, LFE
+    (1.0, 0.0),
+    (0.0, 1.0),
+    (FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+    (0.0, 0.0),
+    // BL, BR, FLC, FRC, BC
+    (FRAC_1_SQRT_2, 0.0),
+    (0.0, FRAC_1_SQRT_2),
+    (1.0, 0.0),
+    (0.0, 1.0),
+    (0.5, 0.5),
+    // SL, SR, TC
+    (FRAC_1_SQRT_2, 0.0),
+    (0.0, FRAC_1_SQRT_2),
+    (0.5, 0.5),
+    // TFL, TFC, TFR
+    (FRAC_1_SQRT_2, 0.0),
+    (0.5, 0.5),
+    (0.0, FRAC_1_SQRT_2),
+    // TBL, TBC, TBR
+    (FRAC_1_SQRT_2, 0.0),
+    (0.5, 0.5),
+    (0.0, FRAC_1_SQRT_2),
+];
+
+#[derive(Clone, Debug, PartialEq)]
+enum Matrix {
+    /// Folded down to stereo, or further to mono, from the usual layout for the channel count
+    Standard { mono: bool },
+    /// A row of coefficients for each channel of the fold-down, with one for each input channel
+    Custom(Vec<Vec<f64>>),
+}
+
+/// How a sound is folded down to fewer channels, or spread over more, before it's KRUSZED. Given on
+/// the command line as either `stereo` or `mono`, the standard fold-downs of the usual layout for
+/// the sound's channel count, or the path to a TOML file with a `matrix` of coefficients: a row for
+/// each channel to make, with a coefficient for each channel of the sound, e.g.
+/// `matrix = [[1.0, 0.0, 0.707], [0.0, 1.0, 0.707]]` for 3.0 to stereo.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Downmix {
+    /// What the matrix was made from, to show it and store it again
+    source: String,
+    matrix: Matrix,
+}
+
+#[derive(Deserialize)]
+struct MatrixFile {
+    matrix: Vec<Vec<f64>>,
+}
+
+impl Downmix {
+    /// The coefficients for a sound with the given number of channels
+    pub fn matrix(&self, channels: usize) -> Result<DownmixMatrix> {
+        let rows = match &self.matrix {
+            Matrix::Standard { mono } => {
+                let layout = ChannelLayout::default_for(channels).ok_or_else(|| {
+                    eyre!(
+                        "There's no usual layout for {} channels to fold down from, give a matrix file instead",
+                        channels
+                    )
+                })?;
+                let speakers: Vec<(f64, f64)> = (0..STEREO_FOLD.len())
+                    .filter(|bit| layout.mask & (1 << bit) != 0)
+                    .map(|bit| STEREO_FOLD[bit])
+                    .collect();
+
+                if *mono {
+                    // Both sides averaged, as formats which only store mono sounds do
+                    vec![speakers
+                        .iter()
+                        .map(|&(left, right)| (left + right) / 2.0)
+                        .collect()]
+                } else {
+                    vec![
+                        speakers.iter().map(|&(left, _)| left).collect(),
+                        speakers.iter().map(|&(_, right)| right).collect(),
+                    ]
+                }
+            }
+            Matrix::Custom(rows) => {
+                ensure!(
+                    rows[0].len() == channels,
+                    "The downmix matrix {} has {} coefficients per row, but the input has {} channels",
+                    self.source,
+                    rows[0].len(),
+                    channels
+                );
+                rows.clone()
+            }
+        };
+
+        Ok(DownmixMatrix(rows))
+    }
+}
+
+impl FromStr for Downmix {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let matrix = match s.trim().to_lowercase().as_str() {
+            "stereo" => Matrix::Standard { mono: false },
+            "mono" => Matrix::Standard { mono: true },
+            _ => {
+                let contents = fs::read_to_string(s).wrap_err_with(|| {
+                    format!(
+                        "{} is neither a standard downmix (stereo or mono) nor a readable matrix file",
+                        s
+                    )
+                })?;
+                let file: MatrixFile = toml::from_str(&contents)
+                    .wrap_err_with(|| format!("Failed to read the downmix matrix {}", s))?;
+
+                ensure!(
+                    !file.matrix.is_empty() && !file.matrix[0].is_empty(),
+                    "The downmix matrix {} must have at least one row and column",
+                    s
+                );
+                ensure!(
+                    file.matrix
+                        .iter()
+                        .all(|row| row.len() == file.matrix[0].len()),
+                    "The rows of the downmix matrix {} must all be as long",
+                    s
+                );
+                ensure!(
+                    file.matrix.iter().flatten().all(|c| c.is_finite()),
+                    "The coefficients of the downmix matrix {} must be finite numbers",
+                    s
+                );
+
+                Matrix::Custom(file.matrix)
+            }
+        };
+
+        Ok(Self {
+            source: s.to_owned(),
+            matrix,
+        })
+    }
+}
+
+impl Display for Downmix {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Matrices are stored as their name or path, so files are read again when loading
+serde_via_str!(Downmix);
+
+/// The coefficients of a downmix for a particular channel count, a row for each channel made
+#[derive(Clone, Debug, PartialEq)]
+pub struct DownmixMatrix(pub Vec<Vec<f64>>);
+
+impl DownmixMatrix {
+    /// Channels of the folded sound
+    pub fn channels(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn apply(&self, sound: Sound, clip_mode: ClipMode) -> Sound {
+        let channels = self
+            .0
+            .iter()
+            .map(|row| Channel {
+                samples: (0..sound.frames())
+                    .map(|frame| {
+                        to_sample(
+                            row.iter()
+                                .zip(&sound.channels)
+                                .map(|(c, channel)| c * channel.samples[frame] as f64)
+                                .sum(),
+                            clip_mode,
+                        )
+                    })
+                    .collect(),
+            })
+            .collect();
+
+        Sound {
+            channels,
+            sample_rate: sound.sample_rate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_downmix() {
+        let surround = Sound {
+            channels: [1000, 2000, 3000, 4000, 5000, 6000]
+                .iter()
+                .map(|&sample| Channel {
+                    samples: vec![sample],
+                })
+                .collect(),
+            sample_rate: 48000,
+        };
+
+        // FL + FC and SL at -3 dB, LFE left out
+        let stereo = "stereo".parse::<Downmix>().unwrap().matrix(6).unwrap();
+        assert_eq!(stereo.channels(), 2);
+        let folded = stereo.apply(surround.clone(), ClipMode::Clamp);
+        let left = 1000.0 + (3000.0 + 5000.0) * FRAC_1_SQRT_2;
+        assert_eq!(folded.channels[0].samples, [left.round() as i32]);
+
+        let mono = "Mono".parse::<Downmix>().unwrap().matrix(2).unwrap();
+        assert_eq!(mono.0, [[0.5, 0.5]]);
+
+        assert!("stereo".parse::<Downmix>().unwrap().matrix(9).is_err());
+        assert!("no/such/matrix.toml".parse::<Downmix>().is_err());
+
+        let path = std::env::temp_dir().join("krusz_test_downmix.toml");
+        fs::write(&path, "matrix = [[0.0, 1.0], [1.0, 0.0], [0.5, 0.5]]").unwrap();
+        let custom: Downmix = path.to_str().unwrap().parse().unwrap();
+        let spread = custom.matrix(2).unwrap().apply(
+            Sound {
+                channels: vec![
+                    Channel { samples: vec![100] },
+                    Channel { samples: vec![300] },
+                ],
+                sample_rate: 48000,
+            },
+            ClipMode::Clamp,
+        );
+        assert_eq!(spread.channels.len(), 3);
+        assert_eq!(spread.channels[0].samples, [300]);
+        assert_eq!(spread.channels[2].samples, [200]);
+        assert!(custom.matrix(6).is_err());
+
+        fs::write(&path, "matrix = [[1.0, 0.0], [1.0]]").unwrap();
+        assert!(path.to_str().unwrap().parse::<Downmix>().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
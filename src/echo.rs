@@ -0,0 +1,245 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+
+use crate::{
+    quantize::requantize_sample,
+    sample::{to_sample, ClipMode},
+    stream::{map_channels, Stage},
+    Sound,
+};
+
+/// A lo-fi echo as given on the command line, e.g. `time=250ms,feedback=0.4,crush-feedback=true`.
+/// With `crush-feedback`, every repeat is requantized on its way around, so that each one comes
+/// back grittier than the last.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EchoSpec {
+    /// Delay between repeats, in seconds
+    pub time: f64,
+    /// Level of each repeat relative to the previous one
+    pub feedback: f64,
+    /// Level of the repeats relative to the dry sound
+    pub mix: f64,
+    pub crush_feedback: bool,
+}
+
+impl FromStr for EchoSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut time = None;
+        let mut feedback = 0.4;
+        let mut mix = 0.5;
+        let mut crush_feedback = false;
+
+        for param in s.split(',') {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| eyre!("Echo parameter {} must be of the form key=value", param))?;
+            let value = value.trim();
+
+            match key.trim().to_lowercase().as_str() {
+                "time" => time = Some(parse_duration(value)?),
+                "feedback" => feedback = value.parse()?,
+                "mix" => mix = value.parse()?,
+                "crush-feedback" => crush_feedback = value.parse()?,
+                other => bail!("Unknown echo parameter {}", other),
+            }
+        }
+
+        let time = time.ok_or_else(|| {
+            eyre!("Echo must be of the form time=<time>[,feedback=<feedback>][,mix=<mix>][,crush-feedback=<true|false>]")
+        })?;
+
+        ensure!(time > 0.0, "Echo time must be positive");
+        ensure!(
+            (0.0..1.0).contains(&feedback),
+            "Echo feedback must be at least 0 and less than 1"
+        );
+        ensure!(
+            (0.0..=1.0).contains(&mix),
+            "Echo mix must be between 0 and 1"
+        );
+
+        Ok(Self {
+            time,
+            feedback,
+            mix,
+            crush_feedback,
+        })
+    }
+}
+
+impl Display for EchoSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "time={}ms,feedback={},mix={},crush-feedback={}",
+            self.time * 1000.0,
+            self.feedback,
+            self.mix,
+            self.crush_feedback
+        )
+    }
+}
+
+serde_via_str!(EchoSpec);
+
+/// Parses a duration such as `250ms`, `0.25s` or `250`, in seconds. Plain numbers are milliseconds.
+pub fn parse_duration(s: &str) -> Result<f64> {
+    let s = s.trim().to_lowercase();
+
+    let seconds = if let Some(ms) = s.strip_suffix("ms") {
+        ms.trim().parse::<f64>()? / 1000.0
+    } else if let Some(seconds) = s.strip_suffix('s') {
+        seconds.trim().parse::<f64>()?
+    } else {
+        s.parse::<f64>()? / 1000.0
+    };
+
+    ensure!(seconds.is_finite(), "Duration must be a finite number");
+
+    Ok(seconds)
+}
+
+/// Delay line of a single channel
+struct EchoLine {
+    buffer: Vec<f64>,
+    position: usize,
+}
+
+/// A feedback delay. The repeats ring on only as long as the sound lasts, so the length is unchanged.
+pub struct EchoStage {
+    delay: usize,
+    feedback: f64,
+    mix: f64,
+    /// Bit depth the feedback is requantized to, if it is
+    crush: Option<u8>,
+    lines: Vec<EchoLine>,
+    clip_mode: ClipMode,
+}
+
+impl EchoStage {
+    pub fn new(spec: EchoSpec, sample_rate: u32, bit_depth: f64, clip_mode: ClipMode) -> Self {
+        Self {
+            delay: ((spec.time * sample_rate as f64).round() as usize).max(1),
+            feedback: spec.feedback,
+            mix: spec.mix,
+            crush: spec.crush_feedback.then(|| bit_depth.floor() as u8),
+            lines: Vec::new(),
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for EchoStage {
+    fn name(&self) -> &'static str {
+        "echo"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            delay,
+            feedback,
+            mix,
+            crush,
+            lines,
+            clip_mode,
+        } = self;
+        let (delay, feedback, mix, crush, clip_mode) =
+            (*delay, *feedback, *mix, *crush, *clip_mode);
+
+        map_channels(
+            chunk,
+            lines,
+            || EchoLine {
+                buffer: vec![0.0; delay],
+                position: 0,
+            },
+            |line, sample| {
+                let delayed = line.buffer[line.position];
+                let mut fed_back = sample as f64 + delayed * feedback;
+
+                if let Some(bit_depth) = crush {
+                    fed_back = requantize_sample(to_sample(fed_back, clip_mode), bit_depth) as f64;
+                }
+
+                line.buffer[line.position] = fed_back;
+                line.position = (line.position + 1) % delay;
+
+                to_sample(sample as f64 + delayed * mix, clip_mode)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_parse_echo() {
+        let echo: EchoSpec = "time=250ms,feedback=0.5,crush-feedback=true"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            echo,
+            EchoSpec {
+                time: 0.25,
+                feedback: 0.5,
+                mix: 0.5,
+                crush_feedback: true,
+            }
+        );
+        assert_eq!(echo.to_string().parse::<EchoSpec>().unwrap(), echo);
+
+        assert_eq!(parse_duration("0.1s").unwrap(), 0.1);
+        assert_eq!(parse_duration("40").unwrap(), 0.04);
+        assert!("feedback=0.5".parse::<EchoSpec>().is_err());
+        assert!("time=1s,feedback=1".parse::<EchoSpec>().is_err());
+        assert!("time=1s,wet=1".parse::<EchoSpec>().is_err());
+    }
+
+    #[test]
+    fn test_echo() {
+        let mut samples = vec![0; 10];
+        samples[0] = 1 << 24;
+        let sound = Sound {
+            channels: vec![Channel { samples }],
+            sample_rate: 1000,
+        };
+
+        let spec = EchoSpec {
+            time: 0.003,
+            feedback: 0.5,
+            mix: 1.0,
+            crush_feedback: false,
+        };
+        let echoed = EchoStage::new(spec, 1000, 16.0, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(
+            echoed.channels[0].samples,
+            vec![1 << 24, 0, 0, 1 << 24, 0, 0, 1 << 23, 0, 0, 1 << 22]
+        );
+
+        // Crushed to 4 bits, the impulse comes back as a whole step, and the requantizer keeps
+        // rounding the fading repeats back up to it
+        let crushed = EchoStage::new(
+            EchoSpec {
+                crush_feedback: true,
+                ..spec
+            },
+            1000,
+            4.5,
+            ClipMode::Clamp,
+        )
+        .run(sound);
+        let samples = &crushed.channels[0].samples;
+        assert_eq!(samples[3], (1 << 28) - 1);
+        assert_eq!(samples[6], samples[3]);
+        assert_eq!(samples[9], samples[3]);
+    }
+}
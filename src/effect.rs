@@ -0,0 +1,371 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+    sync::RwLock,
+};
+
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+
+use crate::{sample::ClipMode, stream::Stage};
+
+/// Makes the stage of an effect from its parameters, as given on the command line, for a sound at
+/// the given sample rate, clipping samples it pushes past full scale the given way. Fails if the
+/// parameters are invalid.
+pub type CreateEffect = fn(&str, u32, ClipMode) -> Result<Box<dyn Stage>>;
+
+struct Registration {
+    name: &'static str,
+    description: &'static str,
+    create: CreateEffect,
+}
+
+/// The effects KRUSZ comes with, each in a module behind the cargo feature of the same name
+const BUILT_IN_EFFECTS: &[Registration] = &[
+    #[cfg(feature = "chorus")]
+    Registration {
+        name: "chorus",
+        description: "Chorus, e.g. chorus:voices=3,rate=0.8,depth=4ms,mix=0.5",
+        create: chorus,
+    },
+    #[cfg(feature = "reverb")]
+    Registration {
+        name: "reverb",
+        description: "Lo-fi room reverb, e.g. reverb:30%",
+        create: reverb,
+    },
+    #[cfg(feature = "ringmod")]
+    Registration {
+        name: "ringmod",
+        description: "Ring modulation, e.g. ringmod:440,square",
+        create: ringmod,
+    },
+];
+
+#[cfg(feature = "chorus")]
+fn chorus(params: &str, sample_rate: u32, clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+    chorus_stage(params.parse()?, sample_rate, clip_mode)
+}
+
+#[cfg(feature = "chorus")]
+fn chorus_stage(
+    chorus: crate::chorus::ChorusSpec,
+    sample_rate: u32,
+    clip_mode: ClipMode,
+) -> Result<Box<dyn Stage>> {
+    Ok(Box::new(crate::chorus::ChorusStage::new(
+        chorus,
+        sample_rate,
+        clip_mode,
+    )))
+}
+
+#[cfg(feature = "reverb")]
+fn reverb(params: &str, sample_rate: u32, clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+    reverb_stage(
+        crate::stereo::parse_percent(params)?,
+        sample_rate,
+        clip_mode,
+    )
+}
+
+#[cfg(feature = "reverb")]
+fn reverb_stage(amount: f64, sample_rate: u32, clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+    ensure!(
+        (0.0..=1.0).contains(&amount),
+        "Reverb must be between 0% and 100% inclusive"
+    );
+
+    Ok(Box::new(crate::reverb::ReverbStage::new(
+        amount,
+        sample_rate,
+        clip_mode,
+    )))
+}
+
+#[cfg(feature = "ringmod")]
+fn ringmod(params: &str, sample_rate: u32, clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+    ringmod_stage(params.parse()?, sample_rate, clip_mode)
+}
+
+#[cfg(feature = "ringmod")]
+fn ringmod_stage(
+    ring_mod: crate::ringmod::RingMod,
+    sample_rate: u32,
+    clip_mode: ClipMode,
+) -> Result<Box<dyn Stage>> {
+    Ok(Box::new(ring_mod.stage(sample_rate, clip_mode)))
+}
+
+static REGISTERED: RwLock<Vec<Registration>> = RwLock::new(Vec::new());
+
+/// Adds an effect, made by `create` from the parameters given after its name, alongside the
+/// built-in ones. Fails if an effect already has the name.
+pub fn register_effect(
+    name: &'static str,
+    description: &'static str,
+    create: CreateEffect,
+) -> Result<()> {
+    ensure!(
+        find(name).is_none(),
+        "There already is an effect called {}",
+        name
+    );
+
+    REGISTERED.write().unwrap().push(Registration {
+        name,
+        description,
+        create,
+    });
+    Ok(())
+}
+
+/// The names and descriptions of every effect, the built-in ones first
+pub fn effects() -> Vec<(&'static str, &'static str)> {
+    BUILT_IN_EFFECTS
+        .iter()
+        .map(|registration| (registration.name, registration.description))
+        .chain(
+            REGISTERED
+                .read()
+                .unwrap()
+                .iter()
+                .map(|registration| (registration.name, registration.description)),
+        )
+        .collect()
+}
+
+fn find(name: &str) -> Option<(&'static str, CreateEffect)> {
+    let built_in = BUILT_IN_EFFECTS
+        .iter()
+        .find(|registration| registration.name.eq_ignore_ascii_case(name))
+        .map(|registration| (registration.name, registration.create));
+
+    built_in.or_else(|| {
+        REGISTERED
+            .read()
+            .unwrap()
+            .iter()
+            .find(|registration| registration.name.eq_ignore_ascii_case(name))
+            .map(|registration| (registration.name, registration.create))
+    })
+}
+
+/// Parameters of an effect, either as text or parsed by the option of a built-in effect
+#[derive(Clone, Debug, PartialEq)]
+pub enum Params {
+    /// As given after the name of the effect on the command line, parsed by the effect itself
+    Text(String),
+    /// A chorus given with --chorus
+    #[cfg(feature = "chorus")]
+    Chorus(crate::chorus::ChorusSpec),
+    /// The amount of reverb given with --reverb, from 0 to 1
+    #[cfg(feature = "reverb")]
+    Reverb(f64),
+    /// A ring modulator given with --ringmod
+    #[cfg(feature = "ringmod")]
+    RingMod(crate::ringmod::RingMod),
+}
+
+impl Display for Params {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Text(params) => write!(f, "{}", params),
+            #[cfg(feature = "chorus")]
+            Self::Chorus(chorus) => write!(f, "{}", chorus),
+            #[cfg(feature = "reverb")]
+            Self::Reverb(amount) => write!(f, "{}%", amount * 100.0),
+            #[cfg(feature = "ringmod")]
+            Self::RingMod(ring_mod) => write!(f, "{}", ring_mod),
+        }
+    }
+}
+
+/// An effect as given on the command line: its name, optionally followed by a colon and its
+/// parameters, e.g. `reverb:30%` or `chorus:voices=2`
+#[derive(Clone, Debug, PartialEq)]
+pub struct EffectSpec {
+    pub name: &'static str,
+    pub params: Params,
+}
+
+impl EffectSpec {
+    /// A chorus given with --chorus
+    #[cfg(feature = "chorus")]
+    pub fn chorus(chorus: crate::chorus::ChorusSpec) -> Self {
+        Self {
+            name: "chorus",
+            params: Params::Chorus(chorus),
+        }
+    }
+
+    /// Reverb given with --reverb, kept as parsed since printing and parsing it again could round it
+    #[cfg(feature = "reverb")]
+    pub fn reverb(amount: f64) -> Self {
+        Self {
+            name: "reverb",
+            params: Params::Reverb(amount),
+        }
+    }
+
+    /// A ring modulator given with --ringmod
+    #[cfg(feature = "ringmod")]
+    pub fn ring_mod(ring_mod: crate::ringmod::RingMod) -> Self {
+        Self {
+            name: "ringmod",
+            params: Params::RingMod(ring_mod),
+        }
+    }
+
+    /// Makes the stage of the effect for a sound at the given sample rate
+    pub fn stage(&self, sample_rate: u32, clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+        match &self.params {
+            Params::Text(params) => {
+                let (_, create) =
+                    find(self.name).ok_or_else(|| eyre!("Unknown effect {}", self.name))?;
+                create(params, sample_rate, clip_mode)
+            }
+            #[cfg(feature = "chorus")]
+            &Params::Chorus(chorus) => chorus_stage(chorus, sample_rate, clip_mode),
+            #[cfg(feature = "reverb")]
+            &Params::Reverb(amount) => reverb_stage(amount, sample_rate, clip_mode),
+            #[cfg(feature = "ringmod")]
+            &Params::RingMod(ring_mod) => ringmod_stage(ring_mod, sample_rate, clip_mode),
+        }
+    }
+}
+
+impl FromStr for EffectSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (name, params) = s.split_once(':').unwrap_or((s, ""));
+
+        let (name, _) = find(name.trim()).ok_or_else(|| {
+            let available: Vec<&str> = effects().into_iter().map(|(name, _)| name).collect();
+            eyre!(
+                "Unknown effect {}. Available: {}",
+                name.trim(),
+                available.join(", ")
+            )
+        })?;
+
+        Ok(Self {
+            name,
+            params: Params::Text(params.trim().to_owned()),
+        })
+    }
+}
+
+impl Display for EffectSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.params == Params::Text(String::new()) {
+            write!(f, "{}", self.name)
+        } else {
+            write!(f, "{}:{}", self.name, self.params)
+        }
+    }
+}
+
+serde_via_str!(EffectSpec);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{Channel, Sound};
+
+    /// Silences the sound
+    struct Mute;
+
+    impl Stage for Mute {
+        fn name(&self) -> &'static str {
+            "mute"
+        }
+
+        fn process(&mut self, mut chunk: Sound) -> Sound {
+            for channel in &mut chunk.channels {
+                channel.samples.fill(0);
+            }
+            chunk
+        }
+    }
+
+    #[test]
+    fn test_register_effect() {
+        fn create(params: &str, _sample_rate: u32, _clip_mode: ClipMode) -> Result<Box<dyn Stage>> {
+            ensure!(params.is_empty(), "Mute takes no parameters");
+            Ok(Box::new(Mute))
+        }
+
+        register_effect("mute", "Silence", create).unwrap();
+        assert!(register_effect("Mute", "Not silence", create).is_err());
+        assert!(effects().contains(&("mute", "Silence")));
+
+        let spec: EffectSpec = "MUTE".parse().unwrap();
+        assert_eq!(spec.name, "mute");
+        assert_eq!(spec.to_string(), "mute");
+        assert!("mute:loudly"
+            .parse::<EffectSpec>()
+            .unwrap()
+            .stage(8000, ClipMode::Clamp)
+            .is_err());
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![1 << 20; 4],
+            }],
+            sample_rate: 8000,
+        };
+        assert_eq!(
+            spec.stage(8000, ClipMode::Clamp)
+                .unwrap()
+                .process(sound)
+                .channels[0]
+                .samples,
+            [0; 4]
+        );
+    }
+
+    #[test]
+    #[cfg(all(feature = "chorus", feature = "reverb", feature = "ringmod"))]
+    fn test_built_in_effects() {
+        let error = "flanger:fast".parse::<EffectSpec>().unwrap_err();
+        assert!(error
+            .to_string()
+            .starts_with("Unknown effect flanger. Available: chorus, reverb, ringmod"));
+
+        let spec: EffectSpec = "ringmod: 1.2k , square".parse().unwrap();
+        assert_eq!(spec.params, Params::Text("1.2k , square".to_owned()));
+        let ring_mod: crate::ringmod::RingMod = spec.params.to_string().parse().unwrap();
+        assert_eq!(ring_mod.frequency, 1200.0);
+        assert_eq!(ring_mod.to_string(), "1200,square");
+
+        // Kept as given, where printing and parsing it again would round it
+        let reverb = EffectSpec::reverb(0.3);
+        assert_eq!(reverb.params, Params::Reverb(0.3));
+        assert!(reverb.stage(44100, ClipMode::Clamp).is_ok());
+        assert!(EffectSpec::reverb(1.5)
+            .stage(44100, ClipMode::Clamp)
+            .is_err());
+
+        assert!("reverb:30%"
+            .parse::<EffectSpec>()
+            .unwrap()
+            .stage(44100, ClipMode::Clamp)
+            .is_ok());
+        assert!("reverb:150%"
+            .parse::<EffectSpec>()
+            .unwrap()
+            .stage(44100, ClipMode::Clamp)
+            .is_err());
+        assert!("chorus"
+            .parse::<EffectSpec>()
+            .unwrap()
+            .stage(44100, ClipMode::Clamp)
+            .is_ok());
+        assert!("chorus:voices=9"
+            .parse::<EffectSpec>()
+            .unwrap()
+            .stage(44100, ClipMode::Clamp)
+            .is_err());
+    }
+}
@@ -0,0 +1,82 @@
+use std::fmt::{self, Display};
+
+use color_eyre::Report;
+
+/// Categories of failure, each with its own process exit code so scripts can branch on them.
+/// Attached to errors as context with `wrap_err`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The input file could not be opened or decoded
+    Input,
+    /// An output format is not supported
+    UnsupportedFormat,
+    /// A parameter is out of range or inconsistent with the others
+    Parameter,
+    /// The audio device could not be opened or played to
+    Device,
+    /// The output clipped and --fail-on-clip was given
+    Clipping,
+    /// The output checksum didn't match --verify
+    Verification,
+    /// An output file could not be written
+    Output,
+    /// The output was silent and --fail-on-silence was given
+    Silence,
+}
+
+impl ErrorKind {
+    /// Exit code used for errors without a category
+    pub const GENERIC_EXIT_CODE: i32 = 1;
+
+    pub fn exit_code(self) -> i32 {
+        // 2 is left to clap, which uses it for command line usage errors
+        match self {
+            Self::Input => 3,
+            Self::UnsupportedFormat => 4,
+            Self::Parameter => 5,
+            Self::Device => 6,
+            Self::Clipping => 7,
+            Self::Verification => 8,
+            Self::Output => 9,
+            Self::Silence => 10,
+        }
+    }
+
+    /// Finds the category of an error, if it has one
+    pub fn of(report: &Report) -> Option<Self> {
+        report.downcast_ref::<Self>().copied()
+    }
+}
+
+impl Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::Input => "Could not read the input file",
+            Self::UnsupportedFormat => "Unsupported output format",
+            Self::Parameter => "Invalid parameters",
+            Self::Device => "Audio device error",
+            Self::Clipping => "The KRUSZED sound clipped",
+            Self::Verification => "Verification failed",
+            Self::Output => "Could not write an output file",
+            Self::Silence => "The KRUSZED sound is silent",
+        })
+    }
+}
+
+impl std::error::Error for ErrorKind {}
+
+#[cfg(test)]
+mod test {
+    use color_eyre::eyre::{eyre, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn test_error_kind() {
+        let report = Err::<(), _>(eyre!("Bit depth must be between 1 and 32 bits inclusive"))
+            .wrap_err(ErrorKind::Parameter)
+            .unwrap_err();
+        assert_eq!(ErrorKind::of(&report), Some(ErrorKind::Parameter));
+        assert_eq!(ErrorKind::of(&eyre!("Oops")), None);
+    }
+}
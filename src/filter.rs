@@ -0,0 +1,408 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+
+use crate::{
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stream::{map_channels, Stage},
+    Sound,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Lowpass,
+    Highpass,
+    Bandpass,
+    Notch,
+    Allpass,
+    Peak,
+    Lowshelf,
+    Highshelf,
+}
+
+/// A biquad filter as given on the command line, e.g. `lowpass:3k,q=0.7` or `peak:1k,q=2,gain=-6`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterSpec {
+    pub kind: FilterKind,
+    pub frequency: f64,
+    pub q: f64,
+    pub gain: f64,
+}
+
+impl FromStr for FilterSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, params) = s.split_once(':').ok_or_else(|| {
+            eyre!("Filter must be of the form <type>:<frequency>[,q=<q>][,gain=<dB>]")
+        })?;
+
+        let kind = match kind.trim().to_lowercase().as_str() {
+            "lowpass" | "lp" => FilterKind::Lowpass,
+            "highpass" | "hp" => FilterKind::Highpass,
+            "bandpass" | "bp" => FilterKind::Bandpass,
+            "notch" => FilterKind::Notch,
+            "allpass" => FilterKind::Allpass,
+            "peak" | "bell" => FilterKind::Peak,
+            "lowshelf" => FilterKind::Lowshelf,
+            "highshelf" => FilterKind::Highshelf,
+            other => bail!("Unknown filter type {}", other),
+        };
+
+        let mut params = params.split(',');
+        let frequency = parse_frequency(params.next().unwrap_or(""))?;
+        let mut q = std::f64::consts::FRAC_1_SQRT_2;
+        let mut gain = 0.0;
+
+        for param in params {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| eyre!("Filter parameter {} must be of the form key=value", param))?;
+
+            match key.trim().to_lowercase().as_str() {
+                "q" => q = value.trim().parse()?,
+                "gain" => {
+                    gain = value
+                        .trim()
+                        .trim_end_matches("dB")
+                        .trim_end_matches("db")
+                        .parse()?
+                }
+                other => bail!("Unknown filter parameter {}", other),
+            }
+        }
+
+        ensure!(q > 0.0, "Filter Q must be positive");
+
+        Ok(Self {
+            kind,
+            frequency,
+            q,
+            gain,
+        })
+    }
+}
+
+impl Display for FilterSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let kind = match self.kind {
+            FilterKind::Lowpass => "lowpass",
+            FilterKind::Highpass => "highpass",
+            FilterKind::Bandpass => "bandpass",
+            FilterKind::Notch => "notch",
+            FilterKind::Allpass => "allpass",
+            FilterKind::Peak => "peak",
+            FilterKind::Lowshelf => "lowshelf",
+            FilterKind::Highshelf => "highshelf",
+        };
+
+        write!(
+            f,
+            "{}:{},q={},gain={}",
+            kind, self.frequency, self.q, self.gain
+        )
+    }
+}
+
+serde_via_str!(FilterSpec);
+
+/// Parses a frequency such as `3k`, `3khz`, `200Hz` or `440`, in Hz
+pub fn parse_frequency(s: &str) -> Result<f64> {
+    let s = s.trim().to_lowercase();
+    let s = s.strip_suffix("hz").unwrap_or(&s);
+
+    let frequency = match s.strip_suffix('k') {
+        Some(khz) => khz.parse::<f64>()? * 1000.0,
+        None => s.parse::<f64>()?,
+    };
+
+    ensure!(frequency > 0.0, "Frequency must be positive");
+
+    Ok(frequency)
+}
+
+/// A transposed direct form II biquad, with coefficients from the RBJ Audio EQ Cookbook
+#[derive(Clone, Debug)]
+pub struct Biquad {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+    z1: f64,
+    z2: f64,
+}
+
+impl Biquad {
+    pub fn new(spec: FilterSpec, sample_rate: u32) -> Result<Self> {
+        let nyquist = sample_rate as f64 / 2.0;
+
+        ensure!(
+            spec.frequency < nyquist,
+            "Filter frequency {} Hz must be below the Nyquist frequency of {} Hz",
+            spec.frequency,
+            nyquist
+        );
+
+        let w0 = 2.0 * PI * spec.frequency / sample_rate as f64;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * spec.q);
+        let a = 10f64.powf(spec.gain / 40.0);
+        let sqrt_a_alpha = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match spec.kind {
+            FilterKind::Lowpass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::Highpass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::Bandpass => (alpha, 0.0, -alpha, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterKind::Notch => (1.0, -2.0 * cos, 1.0, 1.0 + alpha, -2.0 * cos, 1.0 - alpha),
+            FilterKind::Allpass => (
+                1.0 - alpha,
+                -2.0 * cos,
+                1.0 + alpha,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::Peak => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::Lowshelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+            FilterKind::Highshelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + sqrt_a_alpha),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - sqrt_a_alpha),
+                (a + 1.0) - (a - 1.0) * cos + sqrt_a_alpha,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - sqrt_a_alpha,
+            ),
+        };
+
+        Ok(Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            z1: 0.0,
+            z2: 0.0,
+        })
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b0 * x + self.z1;
+        self.z1 = self.b1 * x - self.a1 * y + self.z2;
+        self.z2 = self.b2 * x - self.a2 * y;
+        y
+    }
+}
+
+/// A 4-pole resonant low-pass in the style of the SSM/CEM ladder filters found on vintage samplers,
+/// with a saturating nonlinearity at each pole
+#[derive(Clone, Debug)]
+pub struct Ladder {
+    g: f64,
+    k: f64,
+    stages: [f64; 4],
+}
+
+impl Ladder {
+    pub fn new(cutoff: f64, resonance: f64, sample_rate: u32) -> Result<Self> {
+        let nyquist = sample_rate as f64 / 2.0;
+
+        ensure!(
+            cutoff < nyquist,
+            "Filter frequency {} Hz must be below the Nyquist frequency of {} Hz",
+            cutoff,
+            nyquist
+        );
+
+        ensure!(
+            (0.0..=1.0).contains(&resonance),
+            "Filter resonance must be between 0 and 1 inclusive"
+        );
+
+        Ok(Self {
+            g: 1.0 - (-2.0 * PI * cutoff / sample_rate as f64).exp(),
+            k: 4.0 * resonance,
+            stages: [0.0; 4],
+        })
+    }
+
+    /// Processes one sample, normalized to the -1.0..1.0 range
+    pub fn process(&mut self, x: f64) -> f64 {
+        let mut input = (x - self.k * self.stages[3]).tanh();
+
+        for stage in &mut self.stages {
+            *stage += self.g * (input - stage.tanh());
+            input = stage.tanh();
+        }
+
+        self.stages[3]
+    }
+}
+
+/// Runs every channel of a stream through a vintage sampler ladder filter
+pub struct LadderStage {
+    ladder: Ladder,
+    channels: Vec<Ladder>,
+    clip_mode: ClipMode,
+}
+
+impl LadderStage {
+    pub fn new(cutoff: f64, resonance: f64, sample_rate: u32, clip_mode: ClipMode) -> Result<Self> {
+        Ok(Self {
+            ladder: Ladder::new(cutoff, resonance, sample_rate)?,
+            channels: Vec::new(),
+            clip_mode,
+        })
+    }
+}
+
+impl Stage for LadderStage {
+    fn name(&self) -> &'static str {
+        "ladder"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let scale = FULL_SCALE;
+        let ladder = &self.ladder;
+        let clip_mode = self.clip_mode;
+
+        map_channels(
+            chunk,
+            &mut self.channels,
+            || ladder.clone(),
+            |ladder, sample| to_sample(ladder.process(sample as f64 / scale) * scale, clip_mode),
+        )
+    }
+}
+
+/// Runs every channel of a stream through the given chain of filters, in order
+pub struct FilterStage {
+    chain: Vec<Biquad>,
+    channels: Vec<Vec<Biquad>>,
+    clip_mode: ClipMode,
+}
+
+impl FilterStage {
+    pub fn new(specs: &[FilterSpec], sample_rate: u32, clip_mode: ClipMode) -> Result<Self> {
+        Ok(Self {
+            chain: specs
+                .iter()
+                .map(|&spec| Biquad::new(spec, sample_rate))
+                .collect::<Result<Vec<_>>>()?,
+            channels: Vec::new(),
+            clip_mode,
+        })
+    }
+}
+
+impl Stage for FilterStage {
+    fn name(&self) -> &'static str {
+        "filter"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let chain = &self.chain;
+        let clip_mode = self.clip_mode;
+
+        map_channels(
+            chunk,
+            &mut self.channels,
+            || chain.clone(),
+            |chain, sample| {
+                let y = chain
+                    .iter_mut()
+                    .fold(sample as f64, |x, biquad| biquad.process(x));
+                to_sample(y, clip_mode)
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_filter() {
+        let spec: FilterSpec = "lowpass:3k,q=0.7".parse().unwrap();
+        assert_eq!(spec.kind, FilterKind::Lowpass);
+        assert_eq!(spec.frequency, 3000.0);
+        assert_eq!(spec.q, 0.7);
+
+        let spec: FilterSpec = "peak:440Hz,gain=-6dB".parse().unwrap();
+        assert_eq!(spec.kind, FilterKind::Peak);
+        assert_eq!(spec.frequency, 440.0);
+        assert_eq!(spec.gain, -6.0);
+
+        assert_eq!(spec.to_string().parse::<FilterSpec>().unwrap(), spec);
+
+        assert!("lowpass".parse::<FilterSpec>().is_err());
+        assert!("wobble:3k".parse::<FilterSpec>().is_err());
+    }
+
+    #[test]
+    fn test_lowpass() {
+        let spec: FilterSpec = "lowpass:1k".parse().unwrap();
+
+        let mut biquad = Biquad::new(spec, 44100).unwrap();
+        let dc = (0..1000).map(|_| biquad.process(1.0)).last().unwrap();
+        assert!((dc - 1.0).abs() < 1e-6);
+
+        let mut biquad = Biquad::new(spec, 44100).unwrap();
+        let nyquist = (0..1000)
+            .map(|i| biquad.process(if i % 2 == 0 { 1.0 } else { -1.0 }))
+            .last()
+            .unwrap();
+        assert!(nyquist.abs() < 1e-3);
+
+        assert!(Biquad::new(spec, 2000).is_err());
+    }
+
+    #[test]
+    fn test_ladder() {
+        let mut ladder = Ladder::new(1000.0, 0.0, 44100).unwrap();
+        let dc = (0..5000).map(|_| ladder.process(0.1)).last().unwrap();
+        assert!((dc - 0.1).abs() < 1e-3);
+
+        let mut ladder = Ladder::new(1000.0, 0.5, 44100).unwrap();
+        let nyquist = (0..5000)
+            .map(|i| ladder.process(if i % 2 == 0 { 0.5 } else { -0.5 }))
+            .last()
+            .unwrap();
+        assert!(nyquist.abs() < 1e-3);
+
+        assert!(Ladder::new(1000.0, 1.5, 44100).is_err());
+    }
+}
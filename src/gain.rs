@@ -0,0 +1,194 @@
+use color_eyre::eyre::{ensure, Result};
+
+use crate::{
+    sample::{to_sample, ClipMode, Sample, FULL_SCALE},
+    stream::Stage,
+    Sound,
+};
+
+/// Parses a level such as `-6dB` or `-6` in decibels
+pub fn parse_decibels(s: &str) -> Result<f64> {
+    let s = s.trim();
+    let s = s
+        .strip_suffix("dB")
+        .or_else(|| s.strip_suffix("db"))
+        .unwrap_or(s);
+    let decibels: f64 = s.trim().parse()?;
+    ensure!(decibels.is_finite(), "Level must be a finite number of dB");
+    Ok(decibels)
+}
+
+/// Converts a level in decibels to a linear gain
+pub fn db_to_gain(decibels: f64) -> f64 {
+    10f64.powf(decibels / 20.0)
+}
+
+/// Swing below which a sound counts as silent, relative to full scale: less than a step of 16-bit
+/// audio, about -96 dBFS. A constant offset, such as silence through a mid-rise quantizer, is
+/// silent too.
+pub const SILENCE_THRESHOLD: f64 = 1.0 / 32768.0;
+
+/// Root mean square level of all samples across all channels, relative to full scale
+pub fn rms(sound: &Sound) -> f64 {
+    let mut meter = Meter::default();
+    meter.update(sound);
+    meter.rms()
+}
+
+/// Measures the RMS level and the range of the samples of a stream block by block
+#[derive(Default)]
+pub struct Meter {
+    sum: f64,
+    count: usize,
+    /// Lowest and highest samples so far
+    range: Option<(Sample, Sample)>,
+}
+
+impl Meter {
+    pub fn update(&mut self, block: &Sound) {
+        let samples = block.channels.iter().flat_map(|channel| &channel.samples);
+
+        self.count += block.frames() * block.channels.len();
+        self.sum += samples
+            .clone()
+            .map(|&sample| (sample as f64 / FULL_SCALE).powi(2))
+            .sum::<f64>();
+        self.range = samples.fold(self.range, |range, &sample| match range {
+            Some((low, high)) => Some((low.min(sample), high.max(sample))),
+            None => Some((sample, sample)),
+        });
+    }
+
+    /// How far apart the lowest and highest samples are, relative to full scale
+    pub fn swing(&self) -> f64 {
+        self.range
+            .map_or(0.0, |(low, high)| (high as f64 - low as f64) / FULL_SCALE)
+    }
+
+    /// Whether the samples swung by less than `SILENCE_THRESHOLD`, or there were none
+    pub fn is_silent(&self) -> bool {
+        self.swing() < SILENCE_THRESHOLD
+    }
+
+    pub fn rms(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+
+        (self.sum / self.count as f64).sqrt()
+    }
+}
+
+/// Multiplies every sample by the given linear gain, in place
+pub fn apply_gain(mut sound: Sound, gain: f64, clip_mode: ClipMode) -> Sound {
+    for sample in sound
+        .channels
+        .iter_mut()
+        .flat_map(|channel| &mut channel.samples)
+    {
+        *sample = to_sample(*sample as f64 * gain, clip_mode);
+    }
+
+    sound
+}
+
+/// A fixed gain, as a stage
+pub struct GainStage {
+    gain: f64,
+    clip_mode: ClipMode,
+}
+
+impl GainStage {
+    /// A gain of the given number of decibels
+    pub fn new(decibels: f64, clip_mode: ClipMode) -> Self {
+        Self {
+            gain: db_to_gain(decibels),
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for GainStage {
+    fn name(&self) -> &'static str {
+        "gain"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        apply_gain(chunk, self.gain, self.clip_mode)
+    }
+}
+
+/// The gain needed to bring a sound at level `current` back to level `target`, if it's not silent
+pub fn makeup_gain(target: f64, current: f64) -> Option<f64> {
+    (current > 0.0).then(|| target / current)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_auto_gain() {
+        let source = Sound {
+            channels: vec![Channel {
+                samples: vec![1 << 30, -1 << 30, 1 << 30, -1 << 30],
+            }],
+            sample_rate: 44100,
+        };
+
+        assert!((rms(&source) - 0.5).abs() < 1e-9);
+
+        let quiet = apply_gain(source.clone(), 0.25, ClipMode::Clamp);
+        let gain = makeup_gain(rms(&source), rms(&quiet)).unwrap();
+        assert_eq!(
+            apply_gain(quiet, gain, ClipMode::Clamp).channels[0].samples,
+            source.channels[0].samples
+        );
+
+        assert_eq!(makeup_gain(0.5, 0.0), None);
+    }
+
+    #[test]
+    fn test_silence() {
+        let mut meter = Meter::default();
+        assert!(meter.is_silent());
+
+        let sound = |samples| Sound {
+            channels: vec![Channel { samples }],
+            sample_rate: 44100,
+        };
+        meter.update(&sound(vec![0, 1 << 14, -1 << 14]));
+        assert!(meter.is_silent());
+
+        // A constant offset can't be heard
+        let mut offset = Meter::default();
+        offset.update(&sound(vec![1 << 23; 10]));
+        assert!(offset.is_silent());
+
+        meter.update(&sound(vec![i32::MIN]));
+        assert_eq!(meter.swing(), 1.0 + 0.5f64.powi(17));
+        assert!(!meter.is_silent());
+    }
+
+    #[test]
+    fn test_gain_stage() {
+        assert_eq!(parse_decibels("-6dB").unwrap(), -6.0);
+        assert_eq!(parse_decibels(" 3.5 ").unwrap(), 3.5);
+        assert!(parse_decibels("loud").is_err());
+        assert!(parse_decibels("inf").is_err());
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![1 << 20, -1 << 20, 3 << 29],
+            }],
+            sample_rate: 44100,
+        };
+
+        let louder = GainStage::new(20.0 * 2f64.log10(), ClipMode::Clamp).run(sound);
+        assert_eq!(
+            louder.channels[0].samples,
+            vec![2 << 20, -2 << 20, i32::MAX]
+        );
+    }
+}
@@ -0,0 +1,173 @@
+use crate::{
+    gain::db_to_gain,
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stream::Stage,
+    Sound,
+};
+
+/// Default time taken by the gate to open, in seconds
+pub const DEFAULT_ATTACK: f64 = 0.001;
+/// Default time the gate stays open after the level drops below the threshold, in seconds
+pub const DEFAULT_HOLD: f64 = 0.05;
+/// Default time taken by the gate to close, in seconds
+pub const DEFAULT_RELEASE: f64 = 0.1;
+
+/// A noise gate, which silences the sound while its level stays below a threshold. Low bit depths
+/// raise the noise floor between hits, which the gate keeps out of one-shots.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Gate {
+    /// Level below which the gate closes, in dB relative to full scale
+    pub threshold: f64,
+    /// Times taken to open and close, and held open, in seconds
+    pub attack: f64,
+    pub hold: f64,
+    pub release: f64,
+    /// Whether the gate comes after KRUSZING rather than before
+    pub after: bool,
+}
+
+impl Gate {
+    pub fn new(threshold: f64) -> Self {
+        Self {
+            threshold,
+            attack: DEFAULT_ATTACK,
+            hold: DEFAULT_HOLD,
+            release: DEFAULT_RELEASE,
+            after: false,
+        }
+    }
+
+    pub fn stage(self, sample_rate: u32) -> GateStage {
+        let rate = sample_rate as f64;
+
+        let hold = (self.hold * rate).round() as usize;
+
+        // Starts closed, as if the hold had run out already
+        GateStage {
+            threshold: db_to_gain(self.threshold) * FULL_SCALE,
+            attack_step: 1.0 / (self.attack * rate).round().max(1.0),
+            release_step: 1.0 / (self.release * rate).round().max(1.0),
+            hold,
+            gain: 0.0,
+            held: hold,
+        }
+    }
+}
+
+/// Applies a gate to all channels at once, following the loudest of them so that the stereo image
+/// doesn't shift as the gate opens and closes
+pub struct GateStage {
+    /// Threshold as a sample magnitude
+    threshold: f64,
+    /// How much the gain rises or falls per frame while opening or closing
+    attack_step: f64,
+    release_step: f64,
+    /// Frames to stay open after the level drops below the threshold
+    hold: usize,
+    gain: f64,
+    /// Frames the gate has been held open for since the level dropped below the threshold
+    held: usize,
+}
+
+impl Stage for GateStage {
+    fn name(&self) -> &'static str {
+        "gate"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        for frame in 0..chunk.frames() {
+            let level = chunk
+                .channels
+                .iter()
+                .map(|channel| (channel.samples[frame] as f64).abs())
+                .fold(0.0, f64::max);
+
+            if level >= self.threshold {
+                self.held = 0;
+                self.gain = (self.gain + self.attack_step).min(1.0);
+            } else if self.held < self.hold {
+                self.held += 1;
+                self.gain = (self.gain + self.attack_step).min(1.0);
+            } else {
+                self.gain = (self.gain - self.release_step).max(0.0);
+            }
+
+            if self.gain < 1.0 {
+                for channel in &mut chunk.channels {
+                    // Attenuating never pushes samples past full scale
+                    let sample = &mut channel.samples[frame];
+                    *sample = to_sample(*sample as f64 * self.gain, ClipMode::Clamp);
+                }
+            }
+        }
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_gate() {
+        let gate = Gate {
+            threshold: -20.0,
+            attack: 0.002,
+            hold: 0.003,
+            release: 0.004,
+            after: false,
+        };
+
+        // A loud hit between stretches of quiet noise, at 1 kHz so that times are in frames
+        let mut samples = vec![1 << 20; 10];
+        samples.extend(vec![1 << 30; 10]);
+        samples.extend(vec![1 << 20; 20]);
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: samples.clone(),
+                },
+                Channel {
+                    samples: vec![0; samples.len()],
+                },
+            ],
+            sample_rate: 1000,
+        };
+
+        let gated = gate.stage(1000).run(sound.clone());
+        let left = &gated.channels[0].samples;
+
+        assert!(left[..10].iter().all(|&sample| sample == 0));
+        assert_eq!(left[10], 1 << 29);
+        assert_eq!(left[11..20], samples[11..20]);
+        // Held open for 3 frames, then released over 4
+        assert_eq!(left[20..23], samples[20..23]);
+        assert_eq!(left[23], 3 << 18);
+        assert_eq!(left[26], 0);
+        assert!(left[26..].iter().all(|&sample| sample == 0));
+        assert!(gated.channels[1].samples.iter().all(|&sample| sample == 0));
+
+        let mut stage = gate.stage(1000);
+        let mut chunked = Sound {
+            channels: vec![Channel::default(); 2],
+            sample_rate: 1000,
+        };
+        for start in (0..40).step_by(7) {
+            chunked.append(
+                stage.process(Sound {
+                    channels: sound
+                        .channels
+                        .iter()
+                        .map(|channel| Channel {
+                            samples: channel.samples[start..(start + 7).min(40)].to_vec(),
+                        })
+                        .collect(),
+                    sample_rate: 1000,
+                }),
+            );
+        }
+        assert_eq!(chunked.channels[0].samples, *left);
+    }
+}
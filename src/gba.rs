@@ -0,0 +1,266 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{eyre, Result};
+use tracing::warn;
+
+use crate::{
+    output::{mix_down, Encoder, OutputOptions},
+    Sound,
+};
+
+/// Sample rates of the GBA's m4a mixer, which samples are usually stored at so that they don't
+/// need resampling on the console
+pub const MIXER_RATES: [u32; 12] = [
+    5734, 7884, 10512, 13379, 15768, 18157, 21024, 26758, 31536, 36314, 40137, 42048,
+];
+
+/// Status of looping samples in a WaveData header
+const LOOPED: u16 = 0x4000;
+
+/// Offset of the size in a WaveData header
+const SIZE_OFFSET: u64 = 12;
+
+/// Bytes of samples on each line of assembly
+const BYTES_PER_LINE: usize = 16;
+
+/// How GBA and NDS samples are written
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GbaLayout {
+    /// GNU assembler source, to be assembled into the ROM
+    Assembly,
+    /// Raw bytes, padded to a multiple of 4 bytes, to be included with e.g. `.incbin`
+    Binary,
+}
+
+/// Writes signed 8-bit mono samples preceded by the WaveData header of the m4a sound engine used
+/// by most GBA games and homebrew: type, status, frequency in 1/1024 Hz, loop start and size.
+/// Sounds with several channels are mixed down to mono.
+pub struct GbaEncoder {
+    file: BufWriter<File>,
+    layout: GbaLayout,
+    /// Name of the sample in assembly
+    symbol: String,
+    /// Bytes which don't fill a line of assembly yet
+    pending: Vec<u8>,
+    size: u64,
+}
+
+/// Turns a file name into an assembler symbol
+fn symbol(stem: &str) -> String {
+    let mut symbol: String = stem
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+
+    if !symbol.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        symbol.insert(0, '_');
+    }
+
+    symbol
+}
+
+impl GbaEncoder {
+    /// `path` is the file to write and `name` the output's final path, which the assembly symbol
+    /// is named after
+    pub(crate) fn create(
+        path: &Path,
+        name: &Path,
+        layout: GbaLayout,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        if !MIXER_RATES.contains(&sample_rate) {
+            warn!(
+                "{} Hz is not one of the GBA's mixer rates {:?}, the sound will be resampled on the \
+                 console",
+                sample_rate,
+                MIXER_RATES
+            );
+        }
+
+        let status = if options.looped { LOOPED } else { 0 };
+        let frequency = u32::try_from(u64::from(sample_rate) * 1024)
+            .map_err(|_| eyre!("{} Hz is too high for a GBA sample", sample_rate))?;
+
+        let symbol = symbol(&name.file_stem().unwrap_or_default().to_string_lossy());
+
+        let mut file = BufWriter::new(File::create(path)?);
+        match layout {
+            GbaLayout::Assembly => {
+                // The size is worked out by the assembler, so nothing needs filling in later
+                write!(
+                    file,
+                    "@ Generated by krusz\n\
+                     \n\
+                     \t.section .rodata\n\
+                     \t.align 2\n\
+                     \t.global {symbol}\n\
+                     {symbol}:\n\
+                     \t.short 0x0000\n\
+                     \t.short {status:#06x}\n\
+                     \t.int {frequency}\n\
+                     \t.int 0\n\
+                     \t.int {symbol}_end - {symbol}_data\n\
+                     {symbol}_data:\n",
+                )?;
+            }
+            GbaLayout::Binary => {
+                file.write_all(&0u16.to_le_bytes())?;
+                file.write_all(&status.to_le_bytes())?;
+                file.write_all(&frequency.to_le_bytes())?;
+                file.write_all(&0u32.to_le_bytes())?;
+                // Filled in by finalize
+                file.write_all(&0u32.to_le_bytes())?;
+            }
+        }
+
+        Ok(Self {
+            file,
+            layout,
+            symbol,
+            pending: Vec::new(),
+            size: 0,
+        })
+    }
+
+    fn write_lines(&mut self, all: bool) -> Result<()> {
+        let whole = if all {
+            self.pending.len()
+        } else {
+            self.pending.len() / BYTES_PER_LINE * BYTES_PER_LINE
+        };
+
+        for line in self.pending[..whole].chunks(BYTES_PER_LINE) {
+            let bytes: Vec<String> = line.iter().map(|byte| format!("{:#04x}", byte)).collect();
+            writeln!(self.file, "\t.byte {}", bytes.join(","))?;
+        }
+
+        self.pending.drain(..whole);
+        Ok(())
+    }
+}
+
+impl Encoder for GbaEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let bytes = mix_down(block)
+            .into_iter()
+            .map(|sample| (sample >> 24) as i8 as u8);
+
+        match self.layout {
+            GbaLayout::Assembly => {
+                self.pending.extend(bytes);
+                self.write_lines(false)?;
+            }
+            GbaLayout::Binary => self.file.write_all(&bytes.collect::<Vec<_>>())?,
+        }
+
+        self.size += block.frames() as u64;
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        let size = u32::try_from(self.size)
+            .map_err(|_| eyre!("The sound is too long for a GBA sample"))?;
+
+        match self.layout {
+            GbaLayout::Assembly => {
+                self.write_lines(true)?;
+                writeln!(self.file, "{}_end:", self.symbol)?;
+                // Keeps whatever follows word aligned
+                writeln!(self.file, "\t.align 2")?;
+            }
+            GbaLayout::Binary => {
+                let padding = (4 - size % 4) % 4;
+                self.file.write_all(&vec![0; padding as usize])?;
+                self.file.seek(SeekFrom::Start(SIZE_OFFSET))?;
+                self.file.write_all(&size.to_le_bytes())?;
+            }
+        }
+
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    fn sound() -> Sound {
+        Sound {
+            channels: vec![
+                Channel {
+                    samples: (0..21).map(|i| i << 24).collect(),
+                },
+                Channel {
+                    samples: (0..21).map(|i| (i + 2) << 24).collect(),
+                },
+            ],
+            sample_rate: 13379,
+        }
+    }
+
+    #[test]
+    fn test_save_gba_binary() {
+        let path = std::env::temp_dir().join("krusz_test_save_gba.bin");
+        let options = OutputOptions {
+            looped: true,
+            ..OutputOptions::default()
+        };
+        save(
+            &sound(),
+            &path,
+            OutputFormat::Gba(GbaLayout::Binary),
+            options,
+        )
+        .unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(bytes[..4], [0, 0, 0, 0x40]);
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            13379 * 1024
+        );
+        assert_eq!(bytes[12..16], 21u32.to_le_bytes());
+        // Mixed down to mono, and padded to a whole word
+        assert_eq!(bytes.len(), 16 + 24);
+        assert_eq!(bytes[16..37], (1..=21).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_save_gba_assembly() {
+        let path = std::env::temp_dir().join("krusz_test_save_gba 2.s");
+        let mut sound = sound();
+        sound.channels[0].samples[0] = -1 << 24;
+        sound.channels[1].samples[0] = -1 << 24;
+        save(
+            &sound,
+            &path,
+            OutputFormat::Gba(GbaLayout::Assembly),
+            OutputOptions::default(),
+        )
+        .unwrap();
+        let assembly = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(assembly.contains("\t.global krusz_test_save_gba_2\nkrusz_test_save_gba_2:\n"));
+        assert!(
+            assembly.contains("\t.int krusz_test_save_gba_2_end - krusz_test_save_gba_2_data\n")
+        );
+        assert!(assembly.contains("\t.byte 0xff,0x02,0x03,"));
+        assert!(assembly.contains("\t.byte 0x11,0x12,0x13,0x14,0x15\nkrusz_test_save_gba_2_end:\n"));
+        assert_eq!(symbol("3-bit kick"), "_3_bit_kick");
+    }
+}
@@ -0,0 +1,672 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    fs::{self, File},
+    io::{self, BufReader, Cursor, IsTerminal, Read, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+use hound::{SampleFormat, WavReader};
+use rodio::Decoder;
+use tracing::info;
+
+use crate::{
+    output::parse_size,
+    sample::{to_sample, ClipMode, Sample, FULL_SCALE},
+    stream::{PcmSource, Widened},
+    Sound,
+};
+
+/// Whether the input is an HTTP(S) URL rather than a local path
+pub fn is_url(path: &Path) -> bool {
+    path.to_str()
+        .map(|s| s.starts_with("http://") || s.starts_with("https://"))
+        .unwrap_or(false)
+}
+
+/// A decoder reading from either a local file or a downloaded URL
+pub type Input = Box<dyn PcmSource>;
+
+/// Opens a local file or an HTTP(S) URL for decoding
+pub fn open(path: &Path) -> Result<Input> {
+    if is_url(path) {
+        let data = download(path.to_str().unwrap())?;
+
+        match HiResWav::new(Cursor::new(data.clone())) {
+            Some(wav) => Ok(Box::new(wav)),
+            None => Ok(Box::new(Widened(Decoder::new(Cursor::new(data))?))),
+        }
+    } else {
+        match File::open(path)
+            .ok()
+            .map(BufReader::new)
+            .and_then(HiResWav::new)
+        {
+            Some(wav) => Ok(Box::new(wav)),
+            None => Ok(Box::new(Widened(Decoder::new(File::open(path)?)?))),
+        }
+    }
+}
+
+/// Decodes a local file or an HTTP(S) URL
+pub fn decode(path: &Path) -> Result<Sound> {
+    Sound::from_pcm(open(path)?)
+}
+
+/// A WAV file with samples of more than 16 bits or floating point ones, which rodio would narrow to
+/// 16 bits. Read errors end the stream early, like they do with rodio.
+pub struct HiResWav<R> {
+    reader: WavReader<R>,
+}
+
+impl<R: Read + Send> HiResWav<R> {
+    /// Opens the reader as a WAV file if it is one which needs more than 16 bits
+    pub fn new(reader: R) -> Option<Self> {
+        let reader = WavReader::new(reader).ok()?;
+        let spec = reader.spec();
+
+        if spec.sample_format == SampleFormat::Int && spec.bits_per_sample <= 16 {
+            return None;
+        }
+
+        Some(Self { reader })
+    }
+}
+
+impl<R: Read + Send> Iterator for HiResWav<R> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let spec = self.reader.spec();
+
+        match spec.sample_format {
+            SampleFormat::Int => {
+                let sample = self.reader.samples::<i32>().next()?.ok()?;
+                Some(sample << (32 - spec.bits_per_sample))
+            }
+            SampleFormat::Float => {
+                let sample = self.reader.samples::<f32>().next()?.ok()?;
+                Some(to_sample(sample as f64 * FULL_SCALE, ClipMode::Clamp))
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.reader.len() as usize;
+        (len, Some(len))
+    }
+}
+
+impl<R: Read + Send> PcmSource for HiResWav<R> {
+    fn channels(&self) -> u16 {
+        self.reader.spec().channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.reader.spec().sample_rate
+    }
+}
+
+/// Sample rate of raw PCM when none is given, since it can't be told from the samples
+const RAW_SAMPLE_RATE: u32 = 44100;
+
+/// Bytes from the start of raw PCM looked at to guess its encoding and channels
+const GUESS_BYTES: usize = 1 << 18;
+
+/// How much rougher than the smoothest reading of raw PCM another may be and still be as likely,
+/// the first of them being preferred. Encodings wider than the samples fold the next sample into
+/// their low bits, where it hardly shows, so they read almost as smoothly.
+const GUESS_MARGIN: f64 = 2.0;
+
+/// How samples of headerless PCM are stored
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RawEncoding {
+    U8,
+    S8,
+    S16Le,
+    S16Be,
+    S24Le,
+    S24Be,
+    S32Le,
+    S32Be,
+    F32Le,
+    F32Be,
+}
+
+/// The encodings by name, in the order they're preferred in when guessing
+const ENCODINGS: [(&str, RawEncoding); 10] = [
+    ("s16le", RawEncoding::S16Le),
+    ("s16be", RawEncoding::S16Be),
+    ("u8", RawEncoding::U8),
+    ("s8", RawEncoding::S8),
+    ("s24le", RawEncoding::S24Le),
+    ("s24be", RawEncoding::S24Be),
+    ("s32le", RawEncoding::S32Le),
+    ("s32be", RawEncoding::S32Be),
+    ("f32le", RawEncoding::F32Le),
+    ("f32be", RawEncoding::F32Be),
+];
+
+impl RawEncoding {
+    /// Bytes of each sample
+    pub fn width(self) -> usize {
+        match self {
+            Self::U8 | Self::S8 => 1,
+            Self::S16Le | Self::S16Be => 2,
+            Self::S24Le | Self::S24Be => 3,
+            Self::S32Le | Self::S32Be | Self::F32Le | Self::F32Be => 4,
+        }
+    }
+
+    /// The sample stored in the first `width` bytes, as a float for float encodings so that
+    /// garbage can be told apart from audio when guessing
+    fn value(self, b: &[u8]) -> f64 {
+        match self {
+            Self::F32Le => f32::from_le_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            Self::F32Be => f32::from_be_bytes([b[0], b[1], b[2], b[3]]) as f64,
+            _ => self.read(b) as f64,
+        }
+    }
+
+    fn read(self, b: &[u8]) -> Sample {
+        match self {
+            Self::U8 => (b[0] as i32 - 128) << 24,
+            Self::S8 => (b[0] as i8 as i32) << 24,
+            Self::S16Le => (i16::from_le_bytes([b[0], b[1]]) as i32) << 16,
+            Self::S16Be => (i16::from_be_bytes([b[0], b[1]]) as i32) << 16,
+            Self::S24Le => i32::from_le_bytes([0, b[0], b[1], b[2]]),
+            Self::S24Be => i32::from_be_bytes([b[0], b[1], b[2], 0]),
+            Self::S32Le => i32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            Self::S32Be => i32::from_be_bytes([b[0], b[1], b[2], b[3]]),
+            Self::F32Le | Self::F32Be => to_sample(self.value(b) * FULL_SCALE, ClipMode::Clamp),
+        }
+    }
+}
+
+impl Display for RawEncoding {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let (name, _) = ENCODINGS.iter().find(|(_, e)| e == self).unwrap();
+        f.write_str(name)
+    }
+}
+
+/// How to read headerless PCM, such as dumps of ROMs and circuit-bent hardware, as given on the
+/// command line, e.g. `raw:s16le:44100:2`. The encoding and the number of channels may be left out
+/// or `auto` to guess them from the samples, and the sample rate may be left out for 44100 Hz.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RawFormat {
+    pub encoding: Option<RawEncoding>,
+    pub sample_rate: u32,
+    pub channels: Option<u16>,
+    /// Bytes skipped at the start of the file, given apart from the rest
+    pub offset: u64,
+    /// Bytes read after the offset, up to the end of the file if `None`, given apart from the rest
+    pub length: Option<u64>,
+}
+
+impl FromStr for RawFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut fields = s.trim().split(':').map(str::trim);
+        ensure!(
+            fields.next().unwrap().eq_ignore_ascii_case("raw"),
+            "Input format must be of the form raw[:<encoding>[:<rate>[:<channels>]]]"
+        );
+
+        let auto = |field: &Option<&str>| {
+            field.is_none_or(|f| f.is_empty() || f.eq_ignore_ascii_case("auto"))
+        };
+        let (encoding, sample_rate, channels) = (fields.next(), fields.next(), fields.next());
+        ensure!(
+            fields.next().is_none(),
+            "Input format must be of the form raw[:<encoding>[:<rate>[:<channels>]]]"
+        );
+
+        let encoding = if auto(&encoding) {
+            None
+        } else {
+            let name = encoding.unwrap();
+            let (_, encoding) = ENCODINGS
+                .iter()
+                .find(|(known, _)| known.eq_ignore_ascii_case(name))
+                .ok_or_else(|| {
+                    eyre!(
+                        "Unknown raw encoding {}. Encodings: auto, {}",
+                        name,
+                        ENCODINGS.map(|(name, _)| name).join(", ")
+                    )
+                })?;
+            Some(*encoding)
+        };
+
+        let sample_rate = match sample_rate {
+            None | Some("") => RAW_SAMPLE_RATE,
+            Some(rate) if rate.eq_ignore_ascii_case("auto") => bail!(
+                "The sample rate of raw PCM can't be told from its samples, give it or leave it out for {} Hz",
+                RAW_SAMPLE_RATE
+            ),
+            Some(rate) => rate.parse()?,
+        };
+        ensure!(sample_rate > 0, "Sample rate must be above 0 Hz");
+
+        let channels = if auto(&channels) {
+            None
+        } else {
+            Some(channels.unwrap().parse()?)
+        };
+        ensure!(channels != Some(0), "There must be at least one channel");
+
+        Ok(Self {
+            encoding,
+            sample_rate,
+            channels,
+            offset: 0,
+            length: None,
+        })
+    }
+}
+
+impl Display for RawFormat {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str("raw:")?;
+        match self.encoding {
+            Some(encoding) => write!(f, "{}", encoding)?,
+            None => f.write_str("auto")?,
+        }
+        write!(f, ":{}:", self.sample_rate)?;
+        match self.channels {
+            Some(channels) => write!(f, "{}", channels),
+            None => f.write_str("auto"),
+        }
+    }
+}
+
+serde_via_str!(RawFormat);
+
+/// How any file is read as PCM unless told otherwise: the signed 8-bit samples at 8 kHz of the
+/// earliest samplers and sound chips
+pub const DEFAULT_INTERPRETATION: &str = "s8:8000:1";
+
+/// Parses how any file is read as PCM, as for raw input without `raw:`, e.g. `s8:8000`
+pub fn parse_interpretation(s: &str) -> Result<RawFormat> {
+    format!("raw:{}", s).parse()
+}
+
+/// Parses a position or a length in a file, in hex such as `0x8000` or as a size such as `32KB`
+pub fn parse_offset(s: &str) -> Result<u64> {
+    let s = s.trim();
+
+    match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => Ok(u64::from_str_radix(hex, 16)?),
+        None if s == "0" => Ok(0),
+        None => parse_size(s),
+    }
+}
+
+impl RawFormat {
+    /// Opens a local file or an HTTP(S) URL as raw PCM, guessing whatever was left out
+    pub fn open(&self, path: &Path) -> Result<Input> {
+        let mut data = if is_url(path) {
+            download(path.to_str().unwrap())?
+        } else {
+            fs::read(path)?
+        };
+
+        ensure!(
+            self.offset < data.len() as u64,
+            "The offset {:#x} is past the end of the {} bytes of {}",
+            self.offset,
+            data.len(),
+            path.display()
+        );
+        let start = self.offset as usize;
+        let end = self.length.map_or(data.len(), |length| {
+            data.len().min(start.saturating_add(length as usize))
+        });
+        data.truncate(end);
+        data.drain(..start);
+
+        let format = self.guess(&data);
+        if format != *self {
+            info!("Reading {} as {}", path.display(), format);
+        }
+
+        Ok(Box::new(RawPcm::new(data, format)))
+    }
+
+    /// Fills in the encoding and channels if they were left out, by trying each of them on the
+    /// start of the data and keeping the one which reads as the smoothest sound, preferring common
+    /// encodings and fewer channels: read wrongly, samples jump about far more from one frame to the
+    /// next than audio does
+    pub fn guess(&self, data: &[u8]) -> Self {
+        let data = &data[..data.len().min(GUESS_BYTES)];
+        let encodings: Vec<RawEncoding> = match self.encoding {
+            Some(encoding) => vec![encoding],
+            None => ENCODINGS.iter().map(|&(_, encoding)| encoding).collect(),
+        };
+        let channels: Vec<u16> = match self.channels {
+            Some(channels) => vec![channels],
+            None => vec![1, 2],
+        };
+
+        let candidates: Vec<(RawEncoding, u16, f64)> = encodings
+            .iter()
+            .flat_map(|&encoding| {
+                channels.iter().map(move |&channels| {
+                    (
+                        encoding,
+                        channels,
+                        roughness(data, encoding, channels as usize),
+                    )
+                })
+            })
+            .collect();
+        let smoothest = candidates
+            .iter()
+            .map(|&(_, _, roughness)| roughness)
+            .fold(f64::INFINITY, f64::min);
+        let &(encoding, channels, _) = candidates
+            .iter()
+            .find(|&&(_, _, roughness)| roughness <= smoothest * GUESS_MARGIN)
+            .unwrap_or(&candidates[0]);
+
+        Self {
+            encoding: Some(encoding),
+            channels: Some(channels),
+            ..*self
+        }
+    }
+}
+
+/// How much the samples change from one frame to the next, relative to how much they vary at all:
+/// low for audio, around 2 for noise, and infinite for floats which can't be audio
+fn roughness(data: &[u8], encoding: RawEncoding, channels: usize) -> f64 {
+    let frame = encoding.width() * channels;
+    let frames = data.len() / frame;
+    if frames < 2 {
+        return f64::INFINITY;
+    }
+
+    let (mut change, mut variance) = (0.0, 0.0);
+    for channel in 0..channels {
+        let values: Vec<f64> = (0..frames)
+            .map(|i| encoding.value(&data[i * frame + channel * encoding.width()..]))
+            .collect();
+        if values
+            .iter()
+            .any(|x| !x.is_finite() || x.abs() > 16.0 * FULL_SCALE)
+        {
+            return f64::INFINITY;
+        }
+
+        let mean = values.iter().sum::<f64>() / frames as f64;
+        variance += values.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / frames as f64;
+        change += values
+            .windows(2)
+            .map(|pair| (pair[1] - pair[0]).powi(2))
+            .sum::<f64>()
+            / (frames - 1) as f64;
+    }
+
+    if variance > 0.0 {
+        change / variance
+    } else {
+        0.0
+    }
+}
+
+/// Headerless PCM read from memory, without any trailing partial frame
+struct RawPcm {
+    data: Vec<u8>,
+    position: usize,
+    encoding: RawEncoding,
+    channels: u16,
+    sample_rate: u32,
+}
+
+impl RawPcm {
+    /// `format` must have its encoding and channels
+    fn new(mut data: Vec<u8>, format: RawFormat) -> Self {
+        let encoding = format.encoding.unwrap();
+        let channels = format.channels.unwrap();
+        data.truncate(data.len() - data.len() % (encoding.width() * channels as usize));
+
+        Self {
+            data,
+            position: 0,
+            encoding,
+            channels,
+            sample_rate: format.sample_rate,
+        }
+    }
+}
+
+impl Iterator for RawPcm {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        let bytes = self
+            .data
+            .get(self.position..self.position + self.encoding.width())?;
+        self.position += self.encoding.width();
+
+        Some(self.encoding.read(bytes))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = (self.data.len() - self.position) / self.encoding.width();
+        (len, Some(len))
+    }
+}
+
+impl PcmSource for RawPcm {
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+/// Downloads the whole file into memory, since decoders need to seek, showing progress on a terminal
+pub(crate) fn download(url: &str) -> Result<Vec<u8>> {
+    let response = ureq::get(url).call()?;
+
+    let length: Option<usize> = response
+        .header("Content-Length")
+        .and_then(|length| length.parse().ok());
+
+    let mut reader = response.into_reader();
+    let mut data = Vec::with_capacity(length.unwrap_or(0));
+    let mut buffer = [0; 64 * 1024];
+
+    let mut stderr = io::stderr();
+    let show_progress = stderr.is_terminal();
+
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+
+        data.extend_from_slice(&buffer[..read]);
+
+        if show_progress {
+            let megabytes = data.len() as f64 / 1_000_000.0;
+
+            match length {
+                Some(length) if length > 0 => write!(
+                    stderr,
+                    "\rDownloading {}: {:.1} MB ({}%)",
+                    url,
+                    megabytes,
+                    data.len() * 100 / length
+                )?,
+                _ => write!(stderr, "\rDownloading {}: {:.1} MB", url, megabytes)?,
+            }
+        }
+    }
+
+    if show_progress {
+        writeln!(stderr)?;
+    }
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn test_is_url() {
+        assert!(is_url(Path::new("https://example.com/sample.wav")));
+        assert!(is_url(Path::new("http://example.com/sample.wav")));
+        assert!(!is_url(Path::new("sample.wav")));
+        assert!(!is_url(Path::new("/tmp/https://sample.wav")));
+    }
+
+    #[test]
+    fn test_decode_24_bits() {
+        let path = std::env::temp_dir().join("krusz_test_decode_24_bits.wav");
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: 96000,
+            bits_per_sample: 24,
+            sample_format: SampleFormat::Int,
+        };
+
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in [1, -1, 0x12_3456, -0x80_0000] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let sound = decode(&path).unwrap();
+        assert_eq!(sound.sample_rate, 96000);
+        assert_eq!(sound.channels[0].samples, vec![1 << 8, 0x12_3456 << 8]);
+        assert_eq!(sound.channels[1].samples, vec![-1 << 8, Sample::MIN]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_raw_format() {
+        let format: RawFormat = "raw:S16BE:8000:2".parse().unwrap();
+        assert_eq!(
+            format,
+            RawFormat {
+                encoding: Some(RawEncoding::S16Be),
+                sample_rate: 8000,
+                channels: Some(2),
+                offset: 0,
+                length: None,
+            }
+        );
+        assert_eq!(format.to_string().parse::<RawFormat>().unwrap(), format);
+
+        let guessed: RawFormat = "raw".parse().unwrap();
+        assert_eq!((guessed.encoding, guessed.channels), (None, None));
+        assert_eq!(guessed.sample_rate, 44100);
+        assert_eq!(
+            "raw:auto:22050:auto"
+                .parse::<RawFormat>()
+                .unwrap()
+                .sample_rate,
+            22050
+        );
+
+        assert!("raw:s16le:auto".parse::<RawFormat>().is_err());
+        assert!("raw:s12le".parse::<RawFormat>().is_err());
+        assert!("wav:s16le".parse::<RawFormat>().is_err());
+        assert!("raw:s16le:44100:0".parse::<RawFormat>().is_err());
+
+        assert_eq!(
+            parse_interpretation("s8:8000").unwrap().encoding,
+            Some(RawEncoding::S8)
+        );
+        assert_eq!(parse_offset("0x8000").unwrap(), 0x8000);
+        assert_eq!(parse_offset("32KB").unwrap(), 32 * 1024);
+        assert_eq!(parse_offset("0").unwrap(), 0);
+        assert!(parse_offset("0xg").is_err());
+    }
+
+    #[test]
+    fn test_raw_bytes() {
+        let path = std::env::temp_dir().join("krusz_test_raw_bytes.bin");
+        std::fs::write(&path, [0x7f, 0x80, 0x00, 0x01, 0xff, 0x40]).unwrap();
+
+        let format = RawFormat {
+            offset: 1,
+            length: Some(3),
+            ..parse_interpretation("s8:8000").unwrap()
+        };
+        let sound = Sound::from_pcm(format.open(&path).unwrap()).unwrap();
+        assert_eq!(sound.sample_rate, 8000);
+        assert_eq!(sound.channels[0].samples, [i32::MIN, 0, 1 << 24]);
+
+        let past = RawFormat {
+            offset: 6,
+            ..format
+        };
+        assert!(past.open(&path).is_err());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_guess_raw_format() {
+        // Two tones, one on each channel
+        let tone =
+            |i: usize, frequency: f64| (i as f64 * frequency / 44100.0 * 2.0 * PI).sin() * 0.5;
+        let stereo = |i: usize| [tone(i, 440.0), tone(i, 660.0)];
+        let guess = |data: Vec<u8>| {
+            let format = "raw".parse::<RawFormat>().unwrap().guess(&data);
+            (format.encoding.unwrap(), format.channels.unwrap())
+        };
+
+        let data = (0..4410)
+            .flat_map(stereo)
+            .flat_map(|x| ((x * 32767.0) as i16).to_be_bytes())
+            .collect();
+        assert_eq!(guess(data), (RawEncoding::S16Be, 2));
+
+        let data = (0..4410)
+            .map(|i| ((tone(i, 440.0) * 127.0) as i32 + 128) as u8)
+            .collect();
+        assert_eq!(guess(data), (RawEncoding::U8, 1));
+
+        let data = (0..4410)
+            .flat_map(|i| ((tone(i, 440.0) * 8_388_607.0) as i32).to_le_bytes()[..3].to_vec())
+            .collect();
+        assert_eq!(guess(data), (RawEncoding::S24Le, 1));
+
+        let data = (0..4410)
+            .flat_map(|i| ((tone(i, 440.0) * 2_147_483_647.0) as i32).to_le_bytes())
+            .collect();
+        assert_eq!(guess(data), (RawEncoding::S32Le, 1));
+
+        let data: Vec<u8> = (0..4410)
+            .flat_map(stereo)
+            .flat_map(|x| (x as f32).to_le_bytes())
+            .collect();
+        assert_eq!(guess(data.clone()), (RawEncoding::F32Le, 2));
+
+        // Read back whole frames only
+        let format = RawFormat {
+            encoding: Some(RawEncoding::F32Le),
+            sample_rate: 44100,
+            channels: Some(2),
+            offset: 0,
+            length: None,
+        };
+        let sound = Sound::from_pcm(RawPcm::new(data[..data.len() - 3].to_vec(), format)).unwrap();
+        assert_eq!(sound.frames(), 4409);
+        assert_eq!(sound.channels[1].samples[0], 0);
+    }
+}
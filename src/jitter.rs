@@ -0,0 +1,45 @@
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+/// How quickly the clock error follows the white noise driving it. Lower values give a slower drift
+const SMOOTHING: f64 = 0.25;
+
+/// Low-pass filtered random offsets emulating an unstable sample clock.
+/// Offsets are expressed in sample periods of the clock being emulated and never exceed `amount`.
+pub struct Jitter {
+    rng: ChaCha8Rng,
+    amount: f64,
+    state: f64,
+}
+
+impl Jitter {
+    pub fn new(rng: ChaCha8Rng, amount: f64) -> Self {
+        Self {
+            rng,
+            amount,
+            state: 0.0,
+        }
+    }
+
+    pub fn next_offset(&mut self) -> f64 {
+        let white: f64 = self.rng.gen_range(-1.0..=1.0);
+        self.state += SMOOTHING * (white - self.state);
+        self.state * self.amount
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    #[test]
+    fn test_jitter_bounds() {
+        let mut jitter = Jitter::new(ChaCha8Rng::seed_from_u64(0), 0.5);
+        let offsets: Vec<f64> = (0..10000).map(|_| jitter.next_offset()).collect();
+
+        assert!(offsets.iter().all(|o| o.abs() <= 0.5));
+        assert!(offsets.iter().any(|&o| o != 0.0));
+    }
+}
@@ -0,0 +1,272 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use clap::ArgEnum;
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+use rand_chacha::ChaCha8Rng;
+
+use krusz::{
+    echo::parse_duration,
+    sample::{to_sample, ClipMode},
+    stream::{gather, split, Stage},
+    Channel, Sound,
+};
+
+use crate::{preset::Preset, Opts};
+
+/// A point in time at which the sound is KRUSZED with a preset, or with only the options given
+/// on the command line
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keyframe {
+    /// In seconds
+    pub time: f64,
+    pub preset: Option<Preset>,
+}
+
+/// Presets to crossfade between over the duration of a sound, as given on the command line, e.g.
+/// `0s=none,10s=preset:cassette,20s=preset:digi`. Times must increase from one keyframe to the next.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keyframes(pub Vec<Keyframe>);
+
+impl FromStr for Keyframes {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let keyframes = s
+            .split(',')
+            .map(|keyframe| {
+                let (time, value) = keyframe.split_once('=').ok_or_else(|| {
+                    eyre!(
+                        "Keyframe {} must be of the form <time>=preset:<name>",
+                        keyframe
+                    )
+                })?;
+
+                let time = parse_duration(time)?;
+                ensure!(time >= 0.0, "Keyframe times must not be negative");
+
+                let value = value.trim();
+                let preset = if value.eq_ignore_ascii_case("none") {
+                    None
+                } else {
+                    let name = value
+                        .strip_prefix("preset:")
+                        .ok_or_else(|| eyre!("Keyframe {} must be preset:<name> or none", value))?;
+                    Some(Preset::from_str(name.trim(), true).map_err(|e| eyre!(e))?)
+                };
+
+                Ok(Keyframe { time, preset })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(
+            keyframes.windows(2).all(|pair| pair[0].time < pair[1].time),
+            "Keyframe times must increase from one keyframe to the next"
+        );
+
+        Ok(Self(keyframes))
+    }
+}
+
+impl Display for Keyframes {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, keyframe) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+
+            match keyframe.preset {
+                Some(preset) => {
+                    let name = preset.to_possible_value().unwrap().get_name();
+                    write!(f, "{}s=preset:{}", keyframe.time, name)?;
+                }
+                None => write!(f, "{}s=none", keyframe.time)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+krusz::serde_via_str!(Keyframes);
+
+impl Keyframes {
+    /// KRUSZES the whole sound with the settings of each keyframe, its preset filling in the options
+    /// which weren't given, then crossfades from one to the next between their times. Every
+    /// keyframe draws the same random numbers, so that noise and jitter carry on across the
+    /// crossfades.
+    pub fn render(&self, opts: &Opts, sound: &Sound, rng: &ChaCha8Rng) -> Result<Sound> {
+        let mut renders: Vec<(Option<Preset>, Sound)> = Vec::new();
+
+        for keyframe in &self.0 {
+            if renders.iter().any(|(preset, _)| *preset == keyframe.preset) {
+                continue;
+            }
+
+            let mut opts = opts.clone();
+            if let Some(preset) = keyframe.preset {
+                preset.apply(&mut opts);
+            }
+
+            let settings = opts.settings();
+            settings.validate()?;
+            let mut pipeline =
+                settings.pipeline(sound.channels.len(), sound.sample_rate, &mut rng.clone())?;
+            let render = gather(pipeline.pull(split(sound.clone(), opts.block_frames()))).unwrap();
+            renders.push((keyframe.preset, render));
+        }
+
+        ensure!(
+            renders
+                .windows(2)
+                .all(|pair| pair[0].1.sample_rate == pair[1].1.sample_rate),
+            "Keyframes must all KRUSZ the sound to the same sample rate, which presets may change with \
+             --no-restore-rate"
+        );
+
+        let keys: Vec<(f64, &Sound)> = self
+            .0
+            .iter()
+            .map(|keyframe| {
+                let (_, render) = renders
+                    .iter()
+                    .find(|(preset, _)| *preset == keyframe.preset)
+                    .unwrap();
+                (keyframe.time, render)
+            })
+            .collect();
+
+        Ok(crossfade(&keys))
+    }
+}
+
+/// Mixes sounds at the same sample rate along a timeline: the first is heard up to the time of the
+/// second, each following one fades in linearly as the one before fades out between their times,
+/// and the last is heard from its time on. Sounds shorter than the longest are padded with
+/// silence.
+fn crossfade(keys: &[(f64, &Sound)]) -> Sound {
+    let (_, first) = keys[0];
+    let frames = keys.iter().map(|(_, sound)| sound.frames()).max().unwrap();
+    let sample = |sound: &Sound, channel: usize, frame: usize| {
+        sound.channels[channel]
+            .samples
+            .get(frame)
+            .map_or(0.0, |&sample| sample as f64)
+    };
+
+    let channels = (0..first.channels.len())
+        .map(|channel| Channel {
+            samples: (0..frames)
+                .map(|frame| {
+                    let time = frame as f64 / first.sample_rate as f64;
+                    let next = keys.partition_point(|&(start, _)| start <= time);
+
+                    if next == 0 {
+                        return sample(first, channel, frame) as i32;
+                    }
+                    let (start, from) = keys[next - 1];
+                    match keys.get(next) {
+                        Some(&(end, to)) => {
+                            let mix = (time - start) / (end - start);
+                            // Weights which add up to one never leave the range of the samples
+                            to_sample(
+                                sample(from, channel, frame) * (1.0 - mix)
+                                    + sample(to, channel, frame) * mix,
+                                ClipMode::Clamp,
+                            )
+                        }
+                        None => sample(from, channel, frame) as i32,
+                    }
+                })
+                .collect(),
+        })
+        .collect();
+
+    Sound {
+        channels,
+        sample_rate: first.sample_rate,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_parse_keyframes() {
+        let keyframes: Keyframes = "0s=none, 10s=preset:Cassette,20s=preset:digi"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            keyframes.0,
+            [
+                Keyframe {
+                    time: 0.0,
+                    preset: None
+                },
+                Keyframe {
+                    time: 10.0,
+                    preset: Some(Preset::Cassette)
+                },
+                Keyframe {
+                    time: 20.0,
+                    preset: Some(Preset::Digi)
+                },
+            ]
+        );
+        assert_eq!(
+            keyframes.to_string().parse::<Keyframes>().unwrap(),
+            keyframes
+        );
+
+        assert!("10s=preset:digi,5s=none".parse::<Keyframes>().is_err());
+        assert!("0s=preset:gameboy".parse::<Keyframes>().is_err());
+        assert!("0s=digi".parse::<Keyframes>().is_err());
+        assert!("none".parse::<Keyframes>().is_err());
+    }
+
+    #[test]
+    fn test_keyframes_with_preset() {
+        let args = [
+            "krusz",
+            "-i",
+            "in.wav",
+            "-o",
+            "out.wav",
+            "--keyframes",
+            "0s=none",
+        ];
+        assert!(Opts::try_parse_from(args).is_ok());
+        assert!(Opts::try_parse_from(args.into_iter().chain(["--preset", "digi"])).is_err());
+
+        // As a batch list gives a file a preset of its own
+        let mut opts = Opts::try_parse_from(args).unwrap();
+        opts.preset = Some(Preset::Digi);
+        assert!(opts.validate().is_err());
+    }
+
+    #[test]
+    fn test_crossfade() {
+        let constant = |value, frames| Sound {
+            channels: vec![Channel {
+                samples: vec![value; frames],
+            }],
+            sample_rate: 10,
+        };
+        let (a, b) = (constant(1000, 40), constant(3000, 35));
+
+        // a until 1 s, from a to b until 3 s, then b, padded with silence past its end
+        let mixed = crossfade(&[(1.0, &a), (3.0, &b)]);
+        let samples = &mixed.channels[0].samples;
+        assert_eq!(samples.len(), 40);
+        assert_eq!(samples[5], 1000);
+        assert_eq!(samples[10], 1000);
+        assert_eq!(samples[20], 2000);
+        assert_eq!(samples[30], 3000);
+        assert_eq!(samples[35], 0);
+    }
+}
@@ -0,0 +1,207 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use krusz::{
+    crusher::Settings,
+    output::{save, write_atomically, OutputFormat, OutputOptions},
+};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tracing::{debug, debug_span, warn};
+
+use crate::{error::ErrorKind, repl::render, Opts};
+
+/// Bit depth the layers step towards as they get softer, that of CD audio
+const CLEAN_BIT_DEPTH: f64 = 16.0;
+/// Sample rate the layers step towards as they get softer, that of CD audio
+const CLEAN_SAMPLE_RATE: u32 = 44100;
+
+/// The settings of a layer, from 0 for the softest to `count - 1` for the hardest. The hardest
+/// layer is KRUSZED with `heaviest`, and the softer ones ever closer to CD quality, the bit depth
+/// evenly and the sample rate by even ratios, so that each layer sounds as much cleaner as the next.
+fn layer_settings(heaviest: &Settings, layer: usize, count: usize) -> Settings {
+    let amount = (layer + 1) as f64 / count as f64;
+    let ratio = f64::from(heaviest.sample_rate) / f64::from(CLEAN_SAMPLE_RATE);
+
+    Settings {
+        bit_depth: CLEAN_BIT_DEPTH + (heaviest.bit_depth - CLEAN_BIT_DEPTH) * amount,
+        sample_rate: (f64::from(CLEAN_SAMPLE_RATE) * ratio.powf(amount)).round() as u32,
+        ..heaviest.clone()
+    }
+}
+
+/// The velocities a layer is played at, from 0 for the softest to `count - 1` for the hardest,
+/// splitting the MIDI range evenly
+fn velocities(layer: usize, count: usize) -> (usize, usize) {
+    (layer * 128 / count, (layer + 1) * 128 / count - 1)
+}
+
+/// Where a layer goes: next to the SFZ file, numbered from 1 for the softest, e.g. `snare-v1.wav`
+fn layer_path(sfz: &Path, layer: usize) -> PathBuf {
+    let mut name = OsString::from(sfz.file_stem().unwrap_or_default());
+    name.push(format!("-v{}.wav", layer + 1));
+
+    sfz.with_file_name(name)
+}
+
+/// An SFZ instrument playing each layer once across its velocities, on every key at its original
+/// pitch as drums are. The layers are referred to by file name, so the SFZ file has to be next to
+/// them.
+fn sfz(layers: &[PathBuf]) -> String {
+    let mut sfz = String::from("<group> loop_mode=one_shot pitch_keytrack=0\n");
+
+    for (i, layer) in layers.iter().enumerate() {
+        let (low, high) = velocities(i, layers.len());
+        sfz.push_str(&format!(
+            "<region> sample={} lovel={} hivel={}\n",
+            layer.file_name().unwrap_or_default().to_string_lossy(),
+            low,
+            high
+        ));
+    }
+
+    sfz
+}
+
+/// KRUSZES the input into `count` velocity layers, the hardest with the settings of `opts` and the
+/// softer ones ever cleaner, and writes them with an SFZ instrument mapping them across velocity at
+/// `output`, or next to the input's name in the current directory
+pub fn run(opts: &Opts, input: &Path, count: usize, output: Option<&Path>) -> Result<()> {
+    if !(1..=128).contains(&count) {
+        return Err(eyre!(
+            "There must be between 1 and 128 layers inclusive, one per velocity at most"
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    }
+
+    let heaviest = opts.settings();
+    if heaviest.bit_depth == CLEAN_BIT_DEPTH && heaviest.sample_rate == CLEAN_SAMPLE_RATE {
+        warn!("Neither bit depth nor sample rate are being KRUSZED, so the layers only differ by chance");
+    }
+
+    let output = match output {
+        Some(output) => output.to_owned(),
+        None => {
+            let stem = input
+                .file_stem()
+                .ok_or_else(|| eyre!("Input {} has no file name", input.display()))
+                .wrap_err(ErrorKind::Parameter)?;
+            PathBuf::from(stem).with_extension("sfz")
+        }
+    };
+
+    let mut rng = match opts.seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+
+    let original = opts.decode(input).wrap_err(ErrorKind::Input)?;
+
+    let paths: Vec<PathBuf> = (0..count).map(|layer| layer_path(&output, layer)).collect();
+    for (layer, path) in paths.iter().enumerate() {
+        let settings = layer_settings(&heaviest, layer, count);
+        debug!(
+            "Layer {}: {} bits at {} Hz",
+            layer + 1,
+            settings.bit_depth,
+            settings.sample_rate
+        );
+
+        // One-shots, which don't loop, stored as wide as the bit depth of the layer
+        let options = OutputOptions {
+            looped: false,
+            loop_points: None,
+            ..opts.output_options_at(settings.bit_depth)
+        };
+
+        let sound = render(&original, &settings, opts.auto_gain, rng.gen())?;
+        debug_span!("encode", output = %path.display())
+            .in_scope(|| save(&sound, path, OutputFormat::Wav, options))
+            .wrap_err(ErrorKind::Output)?;
+    }
+
+    let instrument = sfz(&paths);
+    write_atomically(&output, |temp| Ok(std::fs::write(temp, instrument)?))
+        .wrap_err(ErrorKind::Output)
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use krusz::{output::save_wav, probe::probe, Channel, Sound};
+
+    use super::*;
+
+    #[test]
+    fn test_layer_settings() {
+        let opts = Opts::parse_from(["krusz", "-b", "4", "-s", "11025", "-i", "snare.wav"]);
+        let heaviest = opts.settings();
+
+        let layers: Vec<Settings> = (0..4)
+            .map(|layer| layer_settings(&heaviest, layer, 4))
+            .collect();
+        assert_eq!(layers[3], heaviest);
+        assert_eq!(
+            layers.iter().map(|s| s.bit_depth).collect::<Vec<_>>(),
+            [13.0, 10.0, 7.0, 4.0]
+        );
+        assert_eq!(
+            layers.iter().map(|s| s.sample_rate).collect::<Vec<_>>(),
+            [31183, 22050, 15592, 11025]
+        );
+    }
+
+    #[test]
+    fn test_sfz() {
+        let paths: Vec<PathBuf> = (0..3)
+            .map(|layer| layer_path(Path::new("kit/snare.sfz"), layer))
+            .collect();
+        assert_eq!(paths[0], Path::new("kit/snare-v1.wav"));
+
+        assert_eq!(
+            sfz(&paths),
+            "<group> loop_mode=one_shot pitch_keytrack=0\n\
+             <region> sample=snare-v1.wav lovel=0 hivel=41\n\
+             <region> sample=snare-v2.wav lovel=42 hivel=84\n\
+             <region> sample=snare-v3.wav lovel=85 hivel=127\n"
+        );
+        assert_eq!(velocities(0, 1), (0, 127));
+    }
+
+    #[test]
+    fn test_packed_layers() {
+        let dir = std::env::temp_dir().join("krusz_test_packed_layers");
+        std::fs::create_dir_all(&dir).unwrap();
+        let input = dir.join("snare.wav");
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..4410).map(|i| ((i % 100) - 50) << 24).collect(),
+            }],
+            sample_rate: 44100,
+        };
+        save_wav(&sound, &input, OutputOptions::default()).unwrap();
+
+        let output = dir.join("snare.sfz");
+        let opts = Opts::parse_from([
+            "krusz",
+            "--packed",
+            "-b",
+            "4",
+            "-s",
+            "11025",
+            "-i",
+            "snare.wav",
+        ]);
+        run(&opts, &input, 4, Some(&output)).unwrap();
+
+        // The layers cleaner than 8 bits keep 16 bit samples, so they sound as clean as they are
+        let bits: Vec<Option<u16>> = (0..4)
+            .map(|layer| probe(&layer_path(&output, layer)).unwrap().bits_per_sample)
+            .collect();
+        std::fs::remove_dir_all(dir).unwrap();
+        assert_eq!(bits, [Some(16), Some(16), Some(8), Some(8)]);
+    }
+}
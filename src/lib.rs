@@ -0,0 +1,168 @@
+//! Bitcrushing of sounds: requantization to lower bit depths and resampling to lower sample
+//! rates, along with the filters and imperfections of the hardware which used to do it.
+
+/// Implements `Serialize` and `Deserialize` through `Display` and `FromStr`, for options stored in
+/// sessions in the same form as they're given on the command line
+#[macro_export]
+macro_rules! serde_via_str {
+    ($type:ty) => {
+        impl ::serde::Serialize for $type {
+            fn serialize<S: ::serde::Serializer>(
+                &self,
+                serializer: S,
+            ) -> ::std::result::Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> ::serde::Deserialize<'de> for $type {
+            fn deserialize<D: ::serde::Deserializer<'de>>(
+                deserializer: D,
+            ) -> ::std::result::Result<Self, D::Error> {
+                <::std::string::String as ::serde::Deserialize>::deserialize(deserializer)?
+                    .parse()
+                    .map_err(<D::Error as ::serde::de::Error>::custom)
+            }
+        }
+    };
+}
+
+pub mod alias;
+pub mod au;
+pub mod bands;
+pub mod cache;
+pub mod caf;
+pub mod checksum;
+#[cfg(feature = "chorus")]
+pub mod chorus;
+pub mod codec;
+pub mod convolve;
+pub mod crusher;
+pub mod dac;
+pub mod dfpwm;
+pub mod downmix;
+pub mod echo;
+pub mod effect;
+pub mod filter;
+pub mod gain;
+pub mod gate;
+pub mod gba;
+pub mod input;
+pub mod jitter;
+pub mod lpc10;
+pub mod noise;
+pub mod output;
+pub mod probe;
+pub mod quantize;
+pub mod region;
+pub mod resample;
+#[cfg(feature = "reverb")]
+pub mod reverb;
+#[cfg(feature = "ringmod")]
+pub mod ringmod;
+pub mod sample;
+pub mod sds;
+pub mod search;
+pub mod sf2;
+mod simd;
+pub mod slice;
+pub mod spectral;
+pub mod stereo;
+pub mod stft;
+pub mod stream;
+pub mod svx;
+pub mod tape;
+pub mod transient;
+pub mod voc;
+pub mod waveshape;
+pub mod wavetable;
+
+use std::convert::TryInto;
+
+use color_eyre::eyre::Result;
+use rodio::{buffer::SamplesBuffer, Source};
+
+use crate::{
+    sample::{to_i16, Sample},
+    stream::{Blocks, PcmSource, Widened},
+};
+
+/// A sound split into channels. There's always at least one channel, and all channels have the same length.
+#[derive(Clone)]
+pub struct Sound {
+    pub channels: Vec<Channel>,
+    pub sample_rate: u32,
+}
+
+impl Sound {
+    pub fn new<S: Source<Item = i16> + Send>(source: S) -> Result<Self> {
+        Self::from_pcm(Widened(source))
+    }
+
+    /// Reads a whole decoded source
+    pub fn from_pcm<S: PcmSource>(source: S) -> Result<Self> {
+        let mut blocks = Blocks::new(source, usize::MAX)?;
+
+        // A single block holds the whole source, and there's always at least one
+        Ok(blocks.next().unwrap())
+    }
+
+    /// Appends the samples of another sound with the same channels
+    pub fn append(&mut self, other: Sound) {
+        assert_eq!(self.channels.len(), other.channels.len());
+
+        for (channel, other) in self.channels.iter_mut().zip(other.channels) {
+            if channel.samples.is_empty() {
+                channel.samples = other.samples;
+            } else {
+                channel.samples.extend(other.samples);
+            }
+        }
+    }
+
+    /// The number of samples in each channel
+    pub fn frames(&self) -> usize {
+        self.channels
+            .first()
+            .map_or(0, |channel| channel.samples.len())
+    }
+
+    /// Narrows the sound to 16 bits for playback
+    pub fn to_source(&self) -> SamplesBuffer<i16> {
+        SamplesBuffer::new(
+            self.channels.len().try_into().unwrap(),
+            self.sample_rate,
+            simd::interleave(&self.channels)
+                .into_iter()
+                .map(to_i16)
+                .collect::<Vec<_>>(),
+        )
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Channel {
+    pub samples: Vec<Sample>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_sound_new() {
+        let sound = Sound::new(SamplesBuffer::new(3, 44100, vec![1i16, 2, 3, 4, 5, 6, 7])).unwrap();
+        assert_eq!(sound.channels.len(), 3);
+        assert_eq!(sound.frames(), 2);
+        assert_eq!(sound.channels[2].samples, vec![3 << 16, 6 << 16]);
+
+        let empty = Sound::new(SamplesBuffer::<i16>::new(6, 44100, vec![])).unwrap();
+        assert_eq!(empty.channels.len(), 6);
+        assert_eq!(empty.frames(), 0);
+        assert_eq!(empty.to_source().count(), 0);
+
+        let path = std::env::temp_dir().join("krusz_test_sound_new_empty.wav");
+        output::save_wav(&empty, &path, output::OutputOptions::default()).unwrap();
+        std::fs::remove_file(path).unwrap();
+    }
+}
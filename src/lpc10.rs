@@ -0,0 +1,436 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::{
+    sample::{to_sample, ClipMode, Sample, FULL_SCALE},
+    stream::Stage,
+    Channel, Sound,
+};
+
+/// Sample rate the codec runs at
+pub const SAMPLE_RATE: u32 = 8000;
+
+/// Samples per frame, 22.5 ms, each of which gets a single set of parameters
+pub const FRAME_SIZE: usize = 180;
+
+/// Order of the linear predictor
+const ORDER: usize = 10;
+
+/// Bits each reflection coefficient is quantized to, the first ones mattering the most
+const COEFFICIENT_BITS: [u8; ORDER] = [5, 5, 5, 5, 4, 4, 4, 4, 3, 2];
+
+/// Largest magnitude of a reflection coefficient, which keeps the synthesis filter stable
+const MAX_COEFFICIENT: f64 = 0.98;
+
+/// Pre-emphasis applied before analysis and undone after synthesis, so that the predictor doesn't
+/// spend all its coefficients on the low end
+const EMPHASIS: f64 = 0.9375;
+
+/// Range of pitch periods searched, in samples: 50 to 400 Hz
+const MIN_PERIOD: usize = 20;
+const MAX_PERIOD: usize = 160;
+
+/// Normalized autocorrelation at the pitch period above which a frame is voiced
+const VOICING_THRESHOLD: f64 = 0.3;
+
+/// Levels of the 5-bit logarithmic quantizer of the frame loudness, in 2 dB steps from full scale
+const GAIN_LEVELS: i32 = 32;
+const GAIN_STEP_DB: f64 = 2.0;
+
+/// The parameters sent for a frame
+#[derive(Clone, Debug, PartialEq)]
+struct Frame {
+    coefficients: [f64; ORDER],
+    /// Pitch period in samples, or none for an unvoiced frame
+    period: Option<usize>,
+    /// RMS level of the excitation
+    gain: f64,
+}
+
+/// Codec state of a single channel
+struct Lpc10Channel {
+    /// Input samples not yet analyzed, as levels relative to full scale
+    input: Vec<f64>,
+    /// Last input sample, for the pre-emphasis
+    last_input: f64,
+    /// Backward prediction errors of the synthesis lattice
+    lattice: [f64; ORDER],
+    /// Samples since the last pitch pulse
+    since_pulse: usize,
+    /// Last output sample, for the de-emphasis
+    last_output: f64,
+    rng: ChaCha8Rng,
+}
+
+/// A round trip through an LPC-10 style vocoder, as used by the speech chips of 80s talking toys:
+/// each frame is reduced to a 10th order all-pole filter, a pitch and a loudness, then resynthesized
+/// from a pulse train or noise. Expects sound at 8000 Hz.
+pub struct Lpc10 {
+    rng: ChaCha8Rng,
+    channels: Vec<Lpc10Channel>,
+    received: usize,
+    emitted: usize,
+    clip_mode: ClipMode,
+}
+
+impl Lpc10 {
+    pub fn new(rng: ChaCha8Rng, clip_mode: ClipMode) -> Self {
+        Self {
+            rng,
+            channels: Vec::new(),
+            received: 0,
+            emitted: 0,
+            clip_mode,
+        }
+    }
+
+    /// Encodes and decodes every complete frame
+    fn frames(&mut self) -> Sound {
+        let clip_mode = self.clip_mode;
+        let channels = self
+            .channels
+            .par_iter_mut()
+            .map(|channel| {
+                let mut samples = Vec::new();
+                let mut input = std::mem::take(&mut channel.input);
+                let frames = input.len() / FRAME_SIZE;
+
+                for frame in input.chunks_exact(FRAME_SIZE) {
+                    let mut emphasized = [0.0; FRAME_SIZE];
+                    for (emphasized, &x) in emphasized.iter_mut().zip(frame) {
+                        *emphasized = x - EMPHASIS * channel.last_input;
+                        channel.last_input = x;
+                    }
+
+                    let parameters = analyze(frame, &emphasized);
+                    synthesize(channel, &parameters, &mut samples, clip_mode);
+                }
+
+                input.drain(..frames * FRAME_SIZE);
+                channel.input = input;
+
+                Channel { samples }
+            })
+            .collect();
+
+        Sound {
+            channels,
+            sample_rate: SAMPLE_RATE,
+        }
+    }
+}
+
+impl Stage for Lpc10 {
+    fn name(&self) -> &'static str {
+        "lpc10"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self { rng, channels, .. } = self;
+        while channels.len() < chunk.channels.len() {
+            channels.push(Lpc10Channel {
+                input: Vec::new(),
+                last_input: 0.0,
+                lattice: [0.0; ORDER],
+                since_pulse: 0,
+                last_output: 0.0,
+                rng: ChaCha8Rng::from_seed(rng.gen()),
+            });
+        }
+
+        self.received += chunk.frames();
+        for (state, channel) in self.channels.iter_mut().zip(&chunk.channels) {
+            state.input.extend(
+                channel
+                    .samples
+                    .iter()
+                    .map(|&sample| sample as f64 / FULL_SCALE),
+            );
+        }
+
+        let output = self.frames();
+        self.emitted += output.frames();
+        output
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        if self.channels.is_empty() {
+            return None;
+        }
+
+        for channel in &mut self.channels {
+            let len = channel.input.len();
+            channel
+                .input
+                .resize(len.div_ceil(FRAME_SIZE) * FRAME_SIZE, 0.0);
+        }
+
+        // The last frame was padded with silence, which isn't part of the sound
+        let mut output = self.frames();
+        for channel in &mut output.channels {
+            channel.samples.truncate(self.received - self.emitted);
+        }
+
+        Some(output)
+    }
+
+    fn latency(&self) -> f64 {
+        FRAME_SIZE as f64 / SAMPLE_RATE as f64
+    }
+}
+
+/// Works out the parameters of a frame, given both as is and pre-emphasized
+fn analyze(frame: &[f64], emphasized: &[f64]) -> Frame {
+    let windowed: Vec<f64> = emphasized
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let w = 0.54
+                - 0.46 * (2.0 * std::f64::consts::PI * i as f64 / (FRAME_SIZE - 1) as f64).cos();
+            x * w
+        })
+        .collect();
+
+    let mut reflection = levinson(&autocorrelation(&windowed, ORDER));
+    for (k, &bits) in reflection.iter_mut().zip(&COEFFICIENT_BITS) {
+        *k = quantize_coefficient(*k, bits);
+    }
+
+    // The loudness is that of the residual, which is what drives the synthesis filter
+    let residual = inverse_filter(emphasized, &reflection);
+    let rms = (residual.iter().map(|e| e * e).sum::<f64>() / FRAME_SIZE as f64).sqrt();
+
+    Frame {
+        coefficients: reflection,
+        period: pitch(frame),
+        gain: quantize_gain(rms),
+    }
+}
+
+fn autocorrelation(x: &[f64], max_lag: usize) -> Vec<f64> {
+    (0..=max_lag)
+        .map(|lag| x.iter().zip(&x[lag..]).map(|(a, b)| a * b).sum())
+        .collect()
+}
+
+/// Solves for the reflection coefficients of the predictor with the Levinson-Durbin recursion
+fn levinson(r: &[f64]) -> [f64; ORDER] {
+    let mut reflection = [0.0; ORDER];
+    if r[0] <= 0.0 {
+        return reflection;
+    }
+
+    let mut a = [0.0; ORDER + 1];
+    let mut error = r[0];
+
+    for i in 1..=ORDER {
+        let acc: f64 = (1..i).map(|j| a[j] * r[i - j]).sum();
+        let k = (r[i] - acc) / error;
+
+        let previous = a;
+        a[i] = k;
+        for j in 1..i {
+            a[j] = previous[j] - k * previous[i - j];
+        }
+
+        reflection[i - 1] = k;
+        error *= 1.0 - k * k;
+        if error <= 0.0 {
+            break;
+        }
+    }
+
+    reflection
+}
+
+fn quantize_coefficient(k: f64, bits: u8) -> f64 {
+    let levels = (1u32 << bits) as f64;
+    let step = 2.0 * MAX_COEFFICIENT / levels;
+    let index = ((k.clamp(-MAX_COEFFICIENT, MAX_COEFFICIENT) + MAX_COEFFICIENT) / step)
+        .floor()
+        .min(levels - 1.0);
+    -MAX_COEFFICIENT + (index + 0.5) * step
+}
+
+fn quantize_gain(rms: f64) -> f64 {
+    if rms <= 0.0 {
+        return 0.0;
+    }
+
+    let level = (-20.0 * rms.log10() / GAIN_STEP_DB).round() as i32;
+    if level >= GAIN_LEVELS {
+        0.0
+    } else {
+        10f64.powf(-level.max(0) as f64 * GAIN_STEP_DB / 20.0)
+    }
+}
+
+/// The prediction error of a frame, through the lattice form of the analysis filter
+fn inverse_filter(x: &[f64], reflection: &[f64; ORDER]) -> Vec<f64> {
+    let mut backward = [0.0; ORDER];
+
+    x.iter()
+        .map(|&sample| {
+            let mut forward = sample;
+            let mut previous = sample;
+
+            for (i, &k) in reflection.iter().enumerate() {
+                let next_forward = forward - k * backward[i];
+                let next_backward = backward[i] - k * forward;
+                backward[i] = previous;
+                previous = next_backward;
+                forward = next_forward;
+            }
+
+            forward
+        })
+        .collect()
+}
+
+/// The pitch period with the strongest normalized autocorrelation, if strong enough to be voiced
+fn pitch(frame: &[f64]) -> Option<usize> {
+    let energy: f64 = frame.iter().map(|x| x * x).sum();
+    if energy <= 0.0 {
+        return None;
+    }
+
+    let (period, correlation) = (MIN_PERIOD..=MAX_PERIOD)
+        .map(|lag| {
+            let correlation: f64 = frame.iter().zip(&frame[lag..]).map(|(a, b)| a * b).sum();
+            (lag, correlation / energy)
+        })
+        .fold((0, f64::MIN), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+
+    (correlation > VOICING_THRESHOLD).then_some(period)
+}
+
+/// Drives the synthesis lattice with pulses or noise, appending the frame to `output`
+fn synthesize(
+    channel: &mut Lpc10Channel,
+    frame: &Frame,
+    output: &mut Vec<Sample>,
+    clip_mode: ClipMode,
+) {
+    for _ in 0..FRAME_SIZE {
+        let excitation = match frame.period {
+            Some(period) => {
+                channel.since_pulse += 1;
+                if channel.since_pulse >= period {
+                    channel.since_pulse = 0;
+                    frame.gain * (period as f64).sqrt()
+                } else {
+                    0.0
+                }
+            }
+            // Uniform noise with the same RMS level as the pulses
+            None => frame.gain * 3f64.sqrt() * channel.rng.gen_range(-1.0..1.0),
+        };
+
+        let mut forward = excitation;
+        for i in (0..ORDER).rev() {
+            forward += frame.coefficients[i] * channel.lattice[i];
+            if i + 1 < ORDER {
+                channel.lattice[i + 1] = channel.lattice[i] - frame.coefficients[i] * forward;
+            }
+        }
+        channel.lattice[0] = forward;
+
+        channel.last_output = forward + EMPHASIS * channel.last_output;
+        output.push(to_sample(channel.last_output * FULL_SCALE, clip_mode));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_levinson() {
+        // An AR(1) process with coefficient 0.5 is fully predicted by a single coefficient
+        let r: Vec<f64> = (0..=ORDER).map(|lag| 0.5f64.powi(lag as i32)).collect();
+        let reflection = levinson(&r);
+        assert!((reflection[0] - 0.5).abs() < 1e-12);
+        assert!(reflection[1..].iter().all(|k| k.abs() < 1e-12));
+    }
+
+    #[test]
+    fn test_analysis_filters() {
+        // The synthesis lattice undoes the analysis lattice
+        let reflection = [0.5, -0.3, 0.2, 0.1, -0.1, 0.05, 0.0, 0.0, 0.1, -0.05];
+        let x: Vec<f64> = (0..200)
+            .map(|i| ((i * 7) % 13) as f64 / 13.0 - 0.5)
+            .collect();
+        let residual = inverse_filter(&x, &reflection);
+
+        let mut lattice = [0.0; ORDER];
+        for (&e, &expected) in residual.iter().zip(&x) {
+            let mut forward = e;
+            for i in (0..ORDER).rev() {
+                forward += reflection[i] * lattice[i];
+                if i + 1 < ORDER {
+                    lattice[i + 1] = lattice[i] - reflection[i] * forward;
+                }
+            }
+            lattice[0] = forward;
+            assert!((forward - expected).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_pitch() {
+        let frame: Vec<f64> = (0..FRAME_SIZE)
+            .map(|i| if i % 40 == 0 { 1.0 } else { 0.0 })
+            .collect();
+        assert_eq!(pitch(&frame), Some(40));
+
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+        let noise: Vec<f64> = (0..FRAME_SIZE).map(|_| rng.gen_range(-1.0..1.0)).collect();
+        assert_eq!(pitch(&noise), None);
+        assert_eq!(pitch(&[0.0; FRAME_SIZE]), None);
+    }
+
+    #[test]
+    fn test_lpc10() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..1000)
+                    .map(|i| {
+                        let t = i as f64 / SAMPLE_RATE as f64;
+                        to_sample(
+                            (2.0 * std::f64::consts::PI * 200.0 * t).sin() * 0.3 * FULL_SCALE,
+                            ClipMode::Clamp,
+                        )
+                    })
+                    .collect(),
+            }],
+            sample_rate: SAMPLE_RATE,
+        };
+
+        let whole = Lpc10::new(ChaCha8Rng::seed_from_u64(1), ClipMode::Clamp).run(sound.clone());
+        assert_eq!(whole.frames(), 1000);
+        assert!(whole.channels[0].samples.iter().any(|&sample| sample != 0));
+
+        let mut codec = Lpc10::new(ChaCha8Rng::seed_from_u64(1), ClipMode::Clamp);
+        let mut chunked = Sound {
+            channels: vec![Channel::default()],
+            sample_rate: SAMPLE_RATE,
+        };
+        for start in (0..1000).step_by(77) {
+            chunked.append(codec.process(Sound {
+                channels: vec![Channel {
+                    samples: sound.channels[0].samples[start..(start + 77).min(1000)].to_vec(),
+                }],
+                sample_rate: SAMPLE_RATE,
+            }));
+        }
+        chunked.append(codec.finish().unwrap());
+        assert_eq!(chunked.channels[0].samples, whole.channels[0].samples);
+    }
+}
@@ -1,15 +1,83 @@
-use std::{
-    convert::TryInto,
-    ffi::OsStr,
-    fs::File,
-    path::{Path, PathBuf},
+mod abx;
+mod audition;
+mod batch;
+mod chain;
+mod device;
+mod error;
+mod keyframes;
+mod layers;
+mod monitor;
+mod preset;
+mod queue;
+mod randomize;
+mod repl;
+mod session;
+mod sidecar;
+mod soundfont;
+mod stats;
+
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use color_eyre::eyre::{ensure, eyre, Result, WrapErr};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rodio::Sink;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, debug_span, field, info, warn, Level};
+use tracing_subscriber::{
+    filter::LevelFilter, fmt::format::FmtSpan, layer::SubscriberExt, util::SubscriberInitExt, Layer,
+};
+
+use krusz::{
+    alias::AliasMeter,
+    bands::BandsSpec,
+    cache::{cache_dir, DecodeCache},
+    checksum::{checksum, hash_bytes, Checksum},
+    codec::Codec,
+    convolve::ImpulseResponse,
+    crusher::Settings,
+    downmix::{Downmix, DownmixMatrix},
+    echo::EchoSpec,
+    effect::EffectSpec,
+    filter::{parse_frequency, FilterSpec},
+    gain::{apply_gain, makeup_gain, parse_decibels, rms, Meter},
+    gate::Gate,
+    input::{self, decode, parse_interpretation, parse_offset, RawFormat, DEFAULT_INTERPRETATION},
+    noise::{Hum, NoiseSpec, DEFAULT_HUM_LEVEL},
+    output::{
+        self, encoded_size, parse_size, pipe_path, save, save_wavetable, write_atomically,
+        ChannelLayout, OutputFormat, OutputOptions, StreamWriter,
+    },
+    probe::probe,
+    region::{
+        crossfade_loop, detect_loop, splice_bypass, splice_loop, LoopSpec, Regions,
+        LOOP_CORRELATION,
+    },
+    resample::{Decimation, Interpolation},
+    sample::{clipped_count, ClipMode},
+    sds, search,
+    slice::{cut, sfz, slice_path, slice_points, Slicing},
+    spectral::SpectralCrush,
+    stereo::{parse_percent, Invert, MonoMeter, MONO_LOSS_THRESHOLD},
+    stream::{gather, split, Blocks, Stage, BLOCK_FRAMES},
+    tape::{FlutterSpec, SpinSpec},
+    waveshape::Waveshaper,
+    wavetable::{self, wavetable_path, WavetableSpec},
+    Sound,
 };
 
-use clap::{ArgEnum, Parser};
-use color_eyre::eyre::{bail, ensure, Result};
-use hound::{SampleFormat, WavSpec, WavWriter};
-use num::NumCast;
-use rodio::{buffer::SamplesBuffer, decoder::Decoder, OutputStream, Sink, Source};
+use crate::{
+    batch::{read_batch, Manifest},
+    device::DeviceConfig,
+    error::ErrorKind,
+    keyframes::Keyframes,
+    preset::Preset,
+    stats::{CountingAllocator, Stats},
+};
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 const HELP: &str = r#"
            ││││││││││
@@ -29,138 +97,1463 @@ const HELP: &str = r#"
 A tiny utility to bitcrush sounds.
 "#;
 
-#[derive(Parser)]
-#[structopt(name = "KRUSZ", about = HELP, arg_required_else_help = true)]
+#[derive(Parser, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+#[structopt(
+    name = "KRUSZ",
+    about = HELP,
+    arg_required_else_help = true,
+    subcommand_negates_reqs = true
+)]
 struct Opts {
-    /// The input file to KRUSZ
-    #[structopt(short, long, parse(from_os_str))]
-    input: PathBuf,
+    /// The input file or HTTP(S) URL to KRUSZ. Can be repeated to KRUSZ several files in a batch, together with --output-dir
+    #[structopt(
+        short,
+        long,
+        parse(from_os_str),
+        required_unless_present_any = &["load-session", "list-formats", "batch", "input-raw-bytes"]
+    )]
+    #[serde(skip)]
+    input: Vec<PathBuf>,
 
-    /// The output KRUSZED file. Supported formats: WAV
+    /// Read the inputs as headerless PCM, such as dumps of ROMs and circuit-bent hardware, e.g. "raw:s16le:44100:2":
+    /// raw, then the encoding (u8, s8, s16le, s16be, s24le, s24be, s32le, s32be, f32le or f32be), the sample rate and
+    /// the number of channels. The encoding and channels can be left out or "auto" to guess them from how smooth the
+    /// samples read, and the sample rate left out for 44100 Hz
+    #[structopt(long, value_name = "format")]
+    input_format: Option<RawFormat>,
+
+    /// Read any file as PCM samples to KRUSZ, such as a ROM or firmware, for databending. It's read as given with
+    /// --interpret, from --offset for --length bytes
+    #[structopt(long, value_name = "file", parse(from_os_str), conflicts_with_all = &["input", "batch", "input-format"])]
+    #[serde(skip)]
+    input_raw_bytes: Option<PathBuf>,
+
+    /// How --input-raw-bytes is read: the encoding, sample rate and number of channels as with --input-format, without
+    /// raw:, e.g. "s8:8000" or "u8:11025:2". Default: s8:8000:1
+    #[structopt(long, value_name = "format", requires = "input-raw-bytes", parse(try_from_str = parse_interpretation))]
+    #[serde(skip)]
+    interpret: Option<RawFormat>,
+
+    /// Bytes at the start of raw inputs to skip, in hex such as 0x8000 or as a size such as 32KB
+    #[structopt(long, value_name = "bytes", parse(try_from_str = parse_offset))]
+    offset: Option<u64>,
+
+    /// Bytes of raw inputs to read from --offset on, in hex such as 0x8000 or as a size such as 32KB. Default: up to
+    /// the end of the file
+    #[structopt(long, value_name = "bytes", parse(try_from_str = parse_offset))]
+    length: Option<u64>,
+
+    /// The output KRUSZED file. Supported formats: WAV, 8SVX, AU, CAF, DFPWM, SDS, VOC, and GBA samples as .s assembly or .bin, see --list-formats. Can be repeated to write several files from a single pass. pipe: followed by the path of a named pipe, e.g. pipe:/tmp/krusz.fifo, writes raw PCM into the pipe as it's KRUSZED, best with --stream, for tools such as ffmpeg or liquidsoap to pick up live
     #[structopt(short, long, parse(from_os_str))]
-    output: Option<PathBuf>,
+    output: Vec<PathBuf>,
+
+    /// Format of every output by extension, such as wav or raw, instead of the one its file name has. See
+    /// --list-formats
+    #[structopt(long, value_name = "extension")]
+    format: Option<String>,
+
+    /// Directory to write the KRUSZED files to, as WAV files named after their inputs.
+    /// A manifest of the completed files is kept in the directory
+    #[structopt(long, parse(from_os_str), conflicts_with = "output")]
+    output_dir: Option<PathBuf>,
+
+    /// KRUSZ the files listed in a TOML or CSV file into --output-dir, each with options of its own on top of those
+    /// given on the command line. TOML lists have a [[file]] table per file with an input and options, CSV lists
+    /// an input column and a column per option, empty cells leaving the option as given. Options are named as on
+    /// the command line, e.g. bit-depth = 8, except those which apply to the whole run such as format or output-dir
+    #[structopt(
+        long,
+        parse(from_os_str),
+        value_name = "list",
+        requires = "output-dir",
+        conflicts_with = "input"
+    )]
+    batch: Option<PathBuf>,
+
+    /// Skip inputs which were already KRUSZED with the same settings by a previous, interrupted run into --output-dir
+    #[structopt(long, requires = "output-dir")]
+    resume: bool,
 
     /// Play the KRUSZED sound
     #[structopt(short, long)]
     play: bool,
 
-    /// Target bit depth. Default: 16-bit depth.
+    /// Play into a null device which discards the sound at the pace of a real one, to run --play and the interactive
+    /// subcommands where there is no audio device
+    #[structopt(long)]
+    #[serde(skip)]
+    play_null: bool,
+
+    /// Audio host API to play through, out of those this build supports, such as alsa, jack, wasapi, asio or
+    /// coreaudio. Default: the system's default
+    #[structopt(long)]
+    #[serde(skip)]
+    host: Option<String>,
+
+    /// Frames per playback buffer, for lower latency. Must be within the range the device supports. Default: the
+    /// device's own
+    #[structopt(long, value_name = "frames")]
+    #[serde(skip)]
+    buffer_size: Option<u32>,
+
+    /// Gain applied to the input before KRUSZING, in dB, e.g. "-6dB" to tame hot sources or "+6dB" to drive them
+    /// harder into the quantizer. Default: 0 dB
+    #[structopt(long, value_name = "dB", allow_hyphen_values = true, parse(try_from_str = parse_decibels))]
+    input_gain: Option<f64>,
+
+    /// What happens to samples pushed past full scale by gain, filters or added noise: clamp holds them at full
+    /// scale, wrap wraps them around to the other end like integer overflow, and fold reflects them back down.
+    /// Clipped samples still count as clipped for --fail-on-clip. Default: clamp
+    #[structopt(arg_enum, long, value_name = "mode")]
+    clip_mode: Option<ClipMode>,
+
+    /// Target bit depth. Fractional depths such as 3.5 randomly alternate between the adjacent whole depths. Default: 16-bit depth.
     #[structopt(short, long)]
-    bit_depth: Option<u8>,
+    bit_depth: Option<f64>,
 
     /// Target sample rate. Default: 44100 Hz
     #[structopt(short, long)]
     sample_rate: Option<u32>,
 
+    /// Signal-to-noise ratio to KRUSZE each input down to, in dB, e.g. "30dB". The whole bit depth and sample rate
+    /// which meet it with the lowest bit rate are searched for and used instead of --bit-depth and --sample-rate.
+    /// The ratio is measured on the KRUSZING alone, without any effects. Can't be used with --stream
+    #[structopt(long, value_name = "dB", parse(try_from_str = parse_decibels), conflicts_with_all = &["bit-depth", "sample-rate", "bands", "stream"])]
+    target_snr: Option<f64>,
+
+    /// Largest size each output may have, e.g. "64KB" or "1.5MB", with kilobytes of 1024 bytes. Sample rates and
+    /// bit depths up to --sample-rate and --bit-depth are tried, stored at the KRUSZED rate and packed, and the
+    /// one which fits with the highest signal-to-noise ratio is used. Outputs keep their formats, and are measured
+    /// at the bit depth those store, such as 8 bits for 8SVX. Can't be used with --stream
+    #[structopt(long, value_name = "size", parse(try_from_str = parse_size), conflicts_with_all = &["target-snr", "restore-rate", "bands", "stream"])]
+    max_size: Option<u64>,
+
+    /// Raise the bit depth and sample rate around attacks, easing off the KRUSZING just ahead of them and bringing it
+    /// back over 80 ms, so that drums keep their snap while sustains get KRUSZED. The sample rate stays as it is
+    /// with --no-restore-rate
+    #[structopt(long, conflicts_with = "bands")]
+    preserve_transients: bool,
+
+    /// Split the sound into frequency bands with Linkwitz-Riley crossovers and KRUSZ each at a bit depth of its own
+    /// instead of --bit-depth, e.g. "0-200:12bit,200-2000:8bit,2000-:4bit" to keep the low end solid while the top
+    /// gets gritty
+    #[structopt(long)]
+    bands: Option<BandsSpec>,
+
+    /// Speed the input is sampled at, after which it's played back at its original speed again, as with the trick
+    /// of sampling 33 RPM records at 45 RPM and tuning them back down on the sampler: more fits in memory, and
+    /// the aliasing and noise move down with the pitch. E.g. 1.35 for 45 RPM. Default: 1
+    #[structopt(long, value_name = "factor")]
+    speed: Option<f64>,
+
     /// Interpolation method for resampling. Available: Nearest, Linear. Default: Nearest
     #[structopt(arg_enum, long)]
     interpolation: Option<Interpolation>,
+
+    /// How content above the target Nyquist frequency is treated when lowering the sample rate. Available: alias,
+    /// which leaves it in to alias, and fft, which removes it in the frequency domain first. Default: alias
+    #[structopt(arg_enum, long, value_name = "mode")]
+    decimate: Option<Decimation>,
+
+    /// Biquad filter applied before KRUSZING, e.g. "lowpass:3k,q=0.7". Types: lowpass, highpass, bandpass, notch, allpass, peak, lowshelf, highshelf. Can be repeated
+    #[structopt(long)]
+    filter: Vec<FilterSpec>,
+
+    /// Biquad filter applied after KRUSZING, same format as --filter. Can be repeated
+    #[structopt(long)]
+    post_filter: Vec<FilterSpec>,
+
+    /// Lo-fi echo applied after KRUSZING, e.g. "time=250ms,feedback=0.4,crush-feedback=true". Parameters: time,
+    /// feedback (default 0.4), mix (default 0.5) and crush-feedback, which requantizes every repeat to the KRUSZED
+    /// bit depth so that each one degrades further
+    #[structopt(long)]
+    echo: Option<EchoSpec>,
+
+    /// Level of white noise added after KRUSZING, like tape hiss, in dB relative to full scale, e.g. "-54dB"
+    #[structopt(long, value_name = "dB", allow_hyphen_values = true, parse(try_from_str = parse_decibels))]
+    hiss: Option<f64>,
+
+    /// Noise bed mixed in after KRUSZING, e.g. "pink:-48dB", as a color and an RMS level in dB relative to full
+    /// scale. Colors: white, pink and brown. Default level: -48 dB
+    #[structopt(long, value_name = "noise")]
+    noise: Option<NoiseSpec>,
+
+    /// Frequency of mains hum mixed in after KRUSZING, with its harmonics, like a ground loop, e.g. "50Hz" or "60Hz"
+    #[structopt(long, value_name = "frequency", parse(try_from_str = parse_frequency))]
+    hum: Option<f64>,
+
+    /// RMS level of the hum in dB relative to full scale. Default: -50 dB
+    #[structopt(long, value_name = "dB", allow_hyphen_values = true, requires = "hum", parse(try_from_str = parse_decibels))]
+    hum_level: Option<f64>,
+
+    /// Mix the noise bed and hum into the input before KRUSZING rather than into the KRUSZED sound
+    #[structopt(long)]
+    noise_before: bool,
+
+    /// Curated chain of settings emulating a piece of gear. Options given on the command line take precedence over
+    /// the preset's. Available: cassette, a worn cassette deck with saturation, wow and flutter, a gentle high end
+    /// rolloff and hiss, sp1200, the 12-bit, 26.04 kHz drum sampler with its output filter, sampling records sped
+    /// up to 45 RPM, and digi, 4-bit samples played through the volume register of a C64 or Atari, with the sample
+    /// rate whining along as a carrier
+    #[structopt(arg_enum, long)]
+    preset: Option<Preset>,
+
+    /// Presets to crossfade between over the duration of the sound, as times and presets, e.g.
+    /// "0s=none,10s=preset:cassette,20s=preset:digi". Each keyframe's preset fills in the options not given on the
+    /// command line, none stands for those options alone, and the sound fades from one keyframe to the next
+    /// between their times. Can't be used with --stream or --preset
+    #[structopt(long, value_name = "keyframes", conflicts_with_all = &["stream", "preset"])]
+    keyframes: Option<Keyframes>,
+
+    /// Cutoff of a vintage sampler style 4-pole resonant low-pass applied after KRUSZING, e.g. "8k"
+    #[structopt(long, parse(try_from_str = parse_frequency))]
+    vintage_filter: Option<f64>,
+
+    /// Resonance of the vintage sampler filter, between 0 and 1. Default: 0
+    #[structopt(long, requires = "vintage-filter")]
+    vintage_resonance: Option<f64>,
+
+    /// Impulse response the KRUSZED sound is played through, to put it in a toy speaker or a handset. Either a
+    /// built-in response: speaker (a tiny plastic speaker) or telephone (a landline handset), or a sound file of
+    /// up to a second, with a channel for each channel of the sound or a single one for all of them
+    #[structopt(long, value_name = "ir")]
+    ir: Option<ImpulseResponse>,
+
+    /// Maximum error of each bit's weight in the DAC, in percent, emulating a cheap R-2R DAC. Default: 0
+    #[structopt(long)]
+    dac_error: Option<f64>,
+
+    /// Quantize the magnitude of each frequency bin of the spectrum to this many bits before resampling,
+    /// for swirly, MP3-like artifacts
+    #[structopt(long, value_name = "bits")]
+    spectral_crush: Option<u8>,
+
+    /// Quantize the phase of each frequency bin as well with --spectral-crush
+    #[structopt(long, requires = "spectral-crush")]
+    spectral_phase: bool,
+
+    /// Chorus applied before KRUSZING, for detuned ensemble textures, optionally with parameters such as
+    /// "voices=3,rate=0.8,depth=4ms,mix=0.5". Parameters: voices (1 to 8), rate of the sweep in Hz, depth of the
+    /// sweep up to 15 ms, and mix. Default: voices=3,rate=0.8,depth=4ms,mix=0.5
+    #[structopt(
+        long,
+        value_name = "params",
+        min_values = 0,
+        default_missing_value = ""
+    )]
+    #[cfg(feature = "chorus")]
+    chorus: Option<krusz::chorus::ChorusSpec>,
+
+    /// Amount of a small, lo-fi room reverb added before KRUSZING so that the ambience gets KRUSZED too, from 0%
+    /// (dry) to 100% (reverb only)
+    #[structopt(long, value_name = "amount", parse(try_from_str = parse_percent))]
+    #[cfg(feature = "reverb")]
+    reverb: Option<f64>,
+
+    /// Frequency of a carrier the sound is multiplied by before KRUSZING, e.g. "440" or "1.2k", for metallic,
+    /// inharmonic tones
+    #[structopt(long, value_name = "frequency", parse(try_from_str = parse_frequency))]
+    #[cfg(feature = "ringmod")]
+    ringmod: Option<f64>,
+
+    /// Waveform of the ring modulation carrier. Available: sine, square. Default: sine
+    #[structopt(arg_enum, long, value_name = "waveform", requires = "ringmod")]
+    #[cfg(feature = "ringmod")]
+    ringmod_wave: Option<krusz::ringmod::Waveform>,
+
+    /// Effect applied before KRUSZING by its name, optionally followed by a colon and its parameters, e.g.
+    /// "reverb:30%" or "ringmod:440,square". Effects: chorus, reverb and ringmod, with the same parameters as their
+    /// options, unless left out of the build, and any added by programs embedding KRUSZ. Can be repeated, the
+    /// effects apply in order after --chorus, --reverb and --ringmod
+    #[structopt(long, value_name = "effect")]
+    fx: Vec<EffectSpec>,
+
+    /// Transfer curve applied to every sample before KRUSZING, to distort the sound ahead of the quantizer. Either a
+    /// shape: hard (clipping), fold (wave folding), tape (soft saturation) or asym (asymmetric saturation), or a CSV file with one column of
+    /// output levels for input levels evenly spaced from -1 to 1, or two columns of input and output levels
+    #[structopt(long, value_name = "shape")]
+    waveshape: Option<Waveshaper>,
+
+    /// Wow and flutter applied before KRUSZING, the uneven speed of a tape deck, optionally with parameters such as
+    /// "wow=0.15,flutter=0.05". Both are the peak deviation of the speed in percent, up to 5%, with wow drifting at
+    /// 0.6 Hz and flutter wobbling at 7 Hz. Default: wow=0.15,flutter=0.05
+    #[structopt(
+        long,
+        value_name = "params",
+        min_values = 0,
+        default_missing_value = ""
+    )]
+    flutter: Option<FlutterSpec>,
+
+    /// Lossy codec to run the sound through and back before KRUSZING. Available: lpc10, the robotic speech vocoder of
+    /// 80s talking toys
+    #[structopt(long, value_name = "codec")]
+    codec: Option<Codec>,
+
+    /// Threshold of a noise gate, in dB relative to full scale, e.g. "-40dB". The sound is silenced while its level
+    /// stays below it, keeping the raised noise floor of low bit depths out of the gaps between hits
+    #[structopt(long, value_name = "dB", allow_hyphen_values = true, parse(try_from_str = parse_decibels))]
+    gate: Option<f64>,
+
+    /// Time taken by the gate to open, in milliseconds. Default: 1 ms
+    #[structopt(long, value_name = "ms", requires = "gate")]
+    gate_attack: Option<f64>,
+
+    /// Time the gate stays open after the level drops below the threshold, in milliseconds. Default: 50 ms
+    #[structopt(long, value_name = "ms", requires = "gate")]
+    gate_hold: Option<f64>,
+
+    /// Time taken by the gate to close, in milliseconds. Default: 100 ms
+    #[structopt(long, value_name = "ms", requires = "gate")]
+    gate_release: Option<f64>,
+
+    /// Gate the KRUSZED sound instead of the input
+    #[structopt(long, requires = "gate")]
+    gate_after: bool,
+
+    /// Sample clock jitter when KRUSZING the sample rate, in sample periods. Default: 0
+    #[structopt(long)]
+    jitter: Option<f64>,
+
+    /// Seed for the random number generator, for reproducible output. Default: random
+    #[structopt(long)]
+    #[serde(skip)]
+    seed: Option<u64>,
+
+    /// Pick random but musical values for every KRUSZING parameter not given, and print them. Plays the result
+    /// unless told to do something else with it. Use --seed to roll the same values again
+    #[structopt(long)]
+    #[serde(skip)]
+    randomize: bool,
+
+    /// Print a hash of the KRUSZED PCM data, independent of the output format
+    #[structopt(long)]
+    checksum: bool,
+
+    /// Fail if the hash of the KRUSZED PCM data doesn't match the given one
+    #[structopt(long)]
+    verify: Option<String>,
+
+    /// Fail if any stage clips the sound
+    #[structopt(long)]
+    fail_on_clip: bool,
+
+    /// Fail rather than warn when the KRUSZED sound is silent, varying by less than -96 dBFS, so that extreme
+    /// settings or empty inputs don't quietly produce empty files
+    #[structopt(long)]
+    fail_on_silence: bool,
+
+    /// Stereo width of the KRUSZED sound, from 0% (mono) to 200%. Default: 100%
+    #[structopt(long, parse(try_from_str = parse_percent))]
+    width: Option<f64>,
+
+    /// Invert the polarity of a channel of the KRUSZED sound, to cancel or layer it against the original: l (the
+    /// first channel), r (the second) or all
+    #[structopt(arg_enum, long, value_name = "channels", ignore_case = true)]
+    invert: Option<Invert>,
+
+    /// Swap the first two channels of the KRUSZED sound, left and right. --invert applies to the channels after
+    /// swapping
+    #[structopt(long)]
+    swap_channels: bool,
+
+    /// Fold the KRUSZED sound down to mono and report how much of it cancels out, warning when it will collapse on
+    /// the mono speakers of toys and phones. Only applies to stereo sounds
+    #[structopt(long)]
+    mono_check: bool,
+
+    /// Measure the images of the KRUSZED band which the resampler leaves above the KRUSZED Nyquist frequency when
+    /// restoring the sample rate, and print the level of each band the width of the KRUSZED one, to compare
+    /// interpolation modes
+    #[structopt(long)]
+    alias_report: bool,
+
+    /// Print the output formats KRUSZ can write, by extension, and exit
+    #[structopt(long)]
+    #[serde(skip)]
+    list_formats: bool,
+
+    /// Tape stop at the end of the KRUSZED sound, the tape slowing down to a halt over the given time, e.g. "2s", or
+    /// optionally with parameters such as "time=2s,direction=up,bits=4". Parameters: time, direction, down to stop
+    /// at the end or up to start from a standstill, and bits, a bit depth the sound falls to as the tape slows. The
+    /// sound gets longer by half the time
+    #[structopt(long, value_name = "params")]
+    spindown: Option<SpinSpec>,
+
+    /// Match the level of the KRUSZED sound to the input's, by RMS
+    #[structopt(long)]
+    auto_gain: bool,
+
+    /// Keep the KRUSZED sample rate in the output instead of resampling back to 44100 Hz
+    #[structopt(long)]
+    no_restore_rate: bool,
+
+    /// Sample rate to resample the KRUSZED sound back to, up to 192000 Hz. Default: 44100 Hz
+    #[structopt(long, conflicts_with = "no-restore-rate")]
+    restore_rate: Option<u32>,
+
+    /// Store WAV, AU, CAF and VOC outputs at the KRUSZED bit depth rather than 16 bits. Depths of 8 bits and below are stored as
+    /// 8-bit samples. Depths above 16 bits are always stored as 24 or 32-bit samples, or 16-bit samples in VOC files
+    #[structopt(long)]
+    packed: bool,
+
+    /// Speakers of the channels of WAV outputs with more than two channels, as a layout (quad, 4.0, 5.0, 5.1, 6.1,
+    /// 7.1, ...) or a list of speakers in WAV order such as "FL,FR,BL,BR". Default: the usual layout for the number
+    /// of channels
+    #[structopt(long, value_name = "layout")]
+    channel_layout: Option<ChannelLayout>,
+
+    /// Fold the input down to fewer channels before KRUSZING it, or spread it over more: "stereo" or "mono" for the
+    /// standard ITU fold-downs of the usual layout for its number of channels, with the centre and surrounds at
+    /// -3 dB and the LFE left out, or a TOML file with a matrix of coefficients, a row for each channel to make with
+    /// one for each input channel, e.g. matrix = [[1.0, 0.0, 0.707], [0.0, 1.0, 0.707]]
+    #[structopt(long, value_name = "matrix")]
+    downmix_matrix: Option<Downmix>,
+
+    /// Mark the whole KRUSZED sound as a loop, in formats which can store loops (8SVX, GBA, SDS, sf2)
+    #[structopt(long = "loop")]
+    looped: bool,
+
+    /// KRUSZ only the loop of WAV inputs with loop points in a smpl chunk, such as the sustain loop of a sampled
+    /// instrument, and keep the rest of the input as it is. The loop fades in and out over 10 ms just outside its
+    /// ends. Can't be used with --stream
+    #[structopt(long, conflicts_with = "stream")]
+    loop_region_only: bool,
+
+    /// Leave regions of the input untouched and KRUSZ the rest, e.g. "0-0.5s,3.2s-4.0s" to keep an intro and a tail
+    /// clean. The KRUSZED sound fades in and out over 10 ms just outside the regions. Can't be used with --stream
+    #[structopt(long, value_name = "regions", conflicts_with = "stream")]
+    bypass_regions: Option<Regions>,
+
+    /// Make a loop of the KRUSZED sound, e.g. "start=1.2s,end=2.4s,xfade=50ms": the end of the loop is crossfaded
+    /// into the sound just before its start, so that the seam doesn't click, and the loop points are stored in WAV
+    /// and SDS outputs. The crossfade defaults to 50 ms. Can't be used with --stream
+    #[structopt(long, value_name = "params", conflicts_with = "stream")]
+    make_loop: Option<LoopSpec>,
+
+    /// Find a loop in the KRUSZED sound where it repeats, at least twice, and store its loop points in WAV and SDS
+    /// outputs, so that KRUSZED loops come out ready for samplers and game engines. The loop spans as many beats or
+    /// bars as repeat about as well as the best. Can't be used with --make-loop or --stream
+    #[structopt(long, conflicts_with_all = &["make-loop", "stream"])]
+    detect_loop: bool,
+
+    /// Cut the KRUSZED sound down to the loop found with --detect-loop
+    #[structopt(long, requires = "detect-loop")]
+    crop_loop: bool,
+
+    /// KRUSZ each of the inputs as a one-shot into a single sample chain of this many even slots, saved as a WAV
+    /// --output with a cue marker at the start of each slot, for Elektron and volca sample import tools to slice it
+    /// at. Each slot is as long as the longest one-shot, and slots past the last input are left silent
+    #[structopt(long, value_name = "slots", conflicts_with_all = &["output-dir", "batch", "stream", "preview"])]
+    chain: Option<usize>,
+
+    /// Also extract a wavetable from the KRUSZED sound, e.g. "2048x64" for 64 frames of 2048 samples, written next to
+    /// each output as a WAV file which Serum and Vital read, e.g. pad-wavetable.wav. Each frame is a single cycle
+    /// at the pitch found around its point in the sound, with nothing above the Nyquist frequency of the frame.
+    /// Can't be used with --stream
+    #[structopt(long, value_name = "size>x<frames", conflicts_with = "stream")]
+    wavetable: Option<WavetableSpec>,
+
+    /// Also cut the KRUSZED sound into slices, written next to each output and numbered from 1, e.g. break-01.wav.
+    /// Either a number of equal slices, such as 16 for the steps of a bar, or "transients" to cut at each attack.
+    /// Can't be used with --stream
+    #[structopt(long, value_name = "slices", conflicts_with = "stream")]
+    slice: Option<Slicing>,
+
+    /// With --slice, also write an SFZ kit next to each output mapping the slices to consecutive keys from C1
+    #[structopt(long, requires = "slice")]
+    sfz: bool,
+
+    /// Store AU outputs as 8-bit μ-law, the telephone quality encoding of Sun and NeXT workstations
+    #[structopt(long)]
+    mu_law: bool,
+
+    /// Keep decoded inputs in a cache in the temporary directory, keyed by their contents, so that later runs on the
+    /// same files skip decoding. Not used with --stream
+    #[structopt(long)]
+    cache: bool,
+
+    /// After the run, print the time taken, the throughput in samples per second and the peak memory use of decoding,
+    /// each pipeline stage and encoding
+    #[structopt(long)]
+    #[serde(skip)]
+    stats: bool,
+
+    /// Process the input in blocks rather than loading it whole, so that memory use stays constant for long files.
+    /// --auto-gain then takes an extra pass over the input. Can't be used with --play
+    #[structopt(long, conflicts_with = "play")]
+    stream: bool,
+
+    /// Frames processed at a time. Larger blocks are processed faster, smaller ones use less memory with --stream.
+    /// The KRUSZED sound is the same either way. Default: 65536
+    #[structopt(long, value_name = "frames")]
+    block_size: Option<usize>,
+
+    /// Play a quick, rough render of the first seconds of the input, resampled with Nearest, without writing anything.
+    /// Default: 10 seconds
+    #[structopt(
+        long,
+        value_name = "seconds",
+        conflicts_with_all = &["output", "output-dir", "checksum", "verify", "stream"]
+    )]
+    #[serde(skip)]
+    preview: Option<Option<f64>>,
+
+    /// Store the settings, seed and version of KRUSZ in the INFO comment (ICMT) of WAV outputs, so that the files
+    /// carry their own provenance
+    #[structopt(long)]
+    embed_settings: bool,
+
+    /// Write a JSON file next to each output, named after it with .krusz.json appended, recording the version of
+    /// KRUSZ, the hashes of the input and output, the seed and every option, to audit the output or render it again
+    #[structopt(long)]
+    sidecar: bool,
+
+    /// Save the inputs, options and seed of this run to a TOML session file, to reproduce it later with --load-session
+    #[structopt(long, parse(from_os_str))]
+    #[serde(skip)]
+    save_session: Option<PathBuf>,
+
+    /// Load the inputs, options and seed of a session saved with --save-session. Inputs, options and --seed given on
+    /// the command line take precedence
+    #[structopt(long, parse(from_os_str))]
+    #[serde(skip)]
+    load_session: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    #[serde(skip)]
+    command: Option<Command>,
+
+    /// Log level. Available: error, warn, info, debug, trace. Debug includes the time taken by each stage. Default: info
+    #[structopt(long)]
+    #[serde(skip)]
+    log_level: Option<Level>,
 }
 
-#[derive(Clone)]
-struct Sound {
-    channels: Vec<Channel>,
-    sample_rate: u32,
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Load a sound once, then adjust the settings and listen to the result interactively.
+    /// Options given before the subcommand are the initial settings
+    Repl {
+        /// The input file or HTTP(S) URL to load
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// Play blind ABX trials between the input and its KRUSZED version, then report whether they could reliably
+    /// be told apart. Options given before the subcommand are the settings to test
+    Abx {
+        /// The input file or HTTP(S) URL to load
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+
+        /// Number of trials. Default: 10
+        #[clap(long, default_value = "10")]
+        trials: u32,
+    },
+    /// KRUSZ the first seconds of a sound with each of the presets, then play them back to back, announcing each, to
+    /// pick one quickly. Options given before the subcommand apply to every preset, which fills in the rest
+    Audition {
+        /// The input file or HTTP(S) URL to load
+        #[clap(short, long, parse(from_os_str))]
+        input: PathBuf,
+
+        /// Presets to compare, in the order they play, e.g. "cassette,digi". Default: all of them
+        #[clap(arg_enum, long, use_value_delimiter = true)]
+        presets: Vec<Preset>,
+
+        /// Seconds of the input to KRUSZ with each preset. Default: 5
+        #[clap(long, value_name = "seconds", default_value = "5")]
+        duration: f64,
+    },
+    /// Print the container, codec, channels, sample rate, bit depth and duration of a file, along with the loops and
+    /// tags stored in it, without KRUSZING anything
+    Info {
+        /// The input file or HTTP(S) URL to look at
+        #[clap(parse(from_os_str))]
+        input: PathBuf,
+    },
+    /// KRUSZ the entries of an M3U playlist one by one and play them in turn, with commands to move to the next or
+    /// previous entry or to drop one from the queue. Options given before the subcommand are the settings
+    Queue {
+        /// The M3U playlist of files or HTTP(S) URLs to review
+        #[clap(parse(from_os_str))]
+        playlist: PathBuf,
+    },
+    /// KRUSZ one or more sounds into a SoundFont with a single instrument, playable in any SoundFont player or
+    /// tracker. Each sound covers the keys around its root key. Options given before the subcommand are the settings,
+    /// and --loop loops the sounds
+    Sf2 {
+        /// The SoundFont file to write
+        #[clap(parse(from_os_str))]
+        output: PathBuf,
+
+        /// The input files or HTTP(S) URLs to KRUSZ
+        #[clap(parse(from_os_str), required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// MIDI key at which each sound plays at its original pitch, in the order of the inputs. Default:
+        /// consecutive keys from 60 (middle C)
+        #[clap(long, value_name = "key")]
+        root_key: Vec<u8>,
+
+        /// Name of the instrument. Default: the name of the output file
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// KRUSZ a sound into velocity layers for a drum kit, each heavier than the last, and write them with an SFZ
+    /// instrument playing the softest hits cleanest. Options given before the subcommand are the settings of the
+    /// hardest layer, and the softer ones step towards 16 bits at 44100 Hz
+    Layers {
+        /// The input file or HTTP(S) URL to KRUSZ
+        #[clap(short, long, parse(from_os_str))]
+        input: PathBuf,
+
+        /// Number of velocity layers. Default: 4
+        #[clap(long, default_value = "4")]
+        layers: usize,
+
+        /// The SFZ file to write, with the layers next to it, e.g. snare-v1.wav. Default: the name of the input in
+        /// the current directory, e.g. snare.sfz
+        #[clap(short, long, parse(from_os_str))]
+        output: Option<PathBuf>,
+    },
+    /// Send a MIDI Sample Dump Standard file written by KRUSZ to a hardware sampler through a raw MIDI device,
+    /// such as /dev/snd/midiC1D0. Nothing is read back from the sampler, so the transfer runs at the pace the
+    /// standard sets for senders without handshaking
+    SendSds {
+        /// The .sds file to send
+        #[clap(parse(from_os_str))]
+        dump: PathBuf,
+
+        /// The MIDI device to write to
+        #[clap(parse(from_os_str))]
+        device: PathBuf,
+    },
 }
 
-impl Sound {
-    fn new<S: Iterator<Item = i16> + Source>(mut source: S) -> Self {
-        let channels_count: usize = source.channels().try_into().unwrap();
-        let samples: Vec<i16> = source.by_ref().collect();
+fn main() -> Result<()> {
+    color_eyre::install()?;
+
+    let opts = Opts::parse();
 
+    // Stats need every span, whatever the log level
+    let stats = opts.stats.then(Stats::default);
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .with_span_events(FmtSpan::CLOSE)
+                .with_writer(std::io::stderr)
+                .with_filter(LevelFilter::from_level(
+                    opts.log_level.unwrap_or(Level::INFO),
+                )),
+        )
+        .with(stats.clone())
+        .init();
+
+    let result = run(opts);
+
+    if let Some(stats) = stats {
+        eprint!("{}", stats);
+    }
+
+    if let Err(report) = result {
+        eprintln!("Error: {:?}", report);
+        std::process::exit(
+            ErrorKind::of(&report).map_or(ErrorKind::GENERIC_EXIT_CODE, ErrorKind::exit_code),
+        );
+    }
+
+    Ok(())
+}
+
+impl Opts {
+    fn validate(&self) -> Result<()> {
+        ensure!(
+            !self.output.is_empty()
+                || self.output_dir.is_some()
+                || self.play
+                || self.checksum
+                || self.verify.is_some()
+                || self.preview.is_some(),
+            "Either --output, --output-dir, --play, --checksum, --verify or --preview must be specified"
+        );
+
+        if let Some(Some(duration)) = self.preview {
+            ensure!(duration > 0.0, "Preview duration must be positive");
+        }
+
+        ensure!(
+            self.block_size != Some(0),
+            "Block size must be at least 1 frame"
+        );
+
+        ensure!(
+            self.input_format.is_some() || (self.offset.is_none() && self.length.is_none()),
+            "--offset and --length only apply to raw inputs, read with --input-format or --input-raw-bytes"
+        );
+        ensure!(self.length != Some(0), "Length must be at least a byte");
+
+        ensure!(self.chain != Some(0), "Chains must have at least one slot");
+
+        // Also given together when a batch list gives a file a preset of its own
+        ensure!(
+            self.keyframes.is_none() || self.preset.is_none(),
+            "--keyframes can't be used with --preset, give the presets as keyframes instead"
+        );
+
+        if self.input.len() > 1 && self.chain.is_none() {
+            ensure!(
+                self.output.is_empty(),
+                "--output can't be used with several inputs, use --output-dir instead"
+            );
+
+            ensure!(
+                self.verify.is_none(),
+                "--verify can't be used with several inputs"
+            );
+        }
+
+        self.settings().validate()
+    }
+
+    /// Frames processed at a time
+    fn block_frames(&self) -> usize {
+        self.block_size.unwrap_or(BLOCK_FRAMES)
+    }
+
+    /// The KRUSZING settings given on the command line
+    fn settings(&self) -> Settings {
+        Settings {
+            input_gain: self.input_gain,
+            sample_rate: self.sample_rate.unwrap_or(44100),
+            speed: self.speed,
+            preserve_transients: self.preserve_transients,
+            bands: self.bands.clone(),
+            bit_depth: self.bit_depth.unwrap_or(16.0),
+            interpolation: self.interpolation.unwrap_or(Interpolation::Nearest),
+            decimation: self.decimate.unwrap_or(Decimation::Alias),
+            jitter: self.jitter.unwrap_or(0.0),
+            dac_error: self.dac_error,
+            spectral_crush: self.spectral_crush.map(|bits| SpectralCrush {
+                bits,
+                phase: self.spectral_phase,
+            }),
+            effects: self.effects(),
+            waveshape: self.waveshape.clone(),
+            flutter: self.flutter,
+            codec: self.codec,
+            gate: self.gate.map(|threshold| {
+                let gate = Gate::new(threshold);
+
+                Gate {
+                    attack: self.gate_attack.map_or(gate.attack, |ms| ms / 1000.0),
+                    hold: self.gate_hold.map_or(gate.hold, |ms| ms / 1000.0),
+                    release: self.gate_release.map_or(gate.release, |ms| ms / 1000.0),
+                    after: self.gate_after,
+                    ..gate
+                }
+            }),
+            filter: self.filter.clone(),
+            post_filter: self.post_filter.clone(),
+            echo: self.echo,
+            hiss: self.hiss,
+            noise: self.noise,
+            hum: self.hum.map(|frequency| Hum {
+                frequency,
+                level: self.hum_level.unwrap_or(DEFAULT_HUM_LEVEL),
+            }),
+            noise_before: self.noise_before,
+            vintage_filter: self
+                .vintage_filter
+                .map(|cutoff| (cutoff, self.vintage_resonance.unwrap_or(0.0))),
+            ir: self.ir.clone(),
+            width: self.width,
+            invert: self.invert,
+            swap_channels: self.swap_channels,
+            spin: self.spindown,
+            restore_rate: (!self.no_restore_rate).then(|| self.restore_rate.unwrap_or(44100)),
+            clip_mode: self.clip_mode.unwrap_or_default(),
+        }
+    }
+
+    /// The options which make a difference to the KRUSZED sound, with the others reset
+    fn sound_options(&self) -> Self {
         Self {
-            channels: (0..channels_count)
-                .map(|i| Channel {
-                    samples: samples
-                        .iter()
-                        .skip(i)
-                        .step_by(channels_count)
-                        .copied()
-                        .collect(),
-                })
-                .collect(),
-            sample_rate: source.sample_rate(),
+            input: Vec::new(),
+            input_raw_bytes: None,
+            output: Vec::new(),
+            output_dir: None,
+            batch: None,
+            resume: false,
+            play: false,
+            play_null: false,
+            host: None,
+            buffer_size: None,
+            checksum: false,
+            verify: None,
+            stream: false,
+            block_size: None,
+            preview: None,
+            randomize: false,
+            save_session: None,
+            load_session: None,
+            command: None,
+            log_level: None,
+            stats: false,
+            mono_check: false,
+            alias_report: false,
+            list_formats: false,
+            sidecar: false,
+            embed_settings: false,
+            ..self.clone()
+        }
+    }
+
+    /// A meter for --alias-report, when the KRUSZED sample rate gets restored to a higher one
+    fn alias_meter(&self) -> Option<AliasMeter> {
+        let settings = self.settings();
+        let output_rate = settings.output_rate();
+
+        (self.alias_report && output_rate > settings.sample_rate)
+            .then(|| AliasMeter::new(settings.sample_rate, output_rate))
+    }
+
+    /// The effects from the effect registry, those with options of their own first
+    fn effects(&self) -> Vec<EffectSpec> {
+        let mut effects = Vec::new();
+
+        #[cfg(feature = "chorus")]
+        if let Some(chorus) = self.chorus {
+            effects.push(EffectSpec::chorus(chorus));
         }
+
+        #[cfg(feature = "reverb")]
+        if let Some(reverb) = self.reverb {
+            effects.push(EffectSpec::reverb(reverb));
+        }
+
+        #[cfg(feature = "ringmod")]
+        if let Some(frequency) = self.ringmod {
+            effects.push(EffectSpec::ring_mod(krusz::ringmod::RingMod {
+                frequency,
+                waveform: self.ringmod_wave.unwrap_or(krusz::ringmod::Waveform::Sine),
+            }));
+        }
+
+        effects.extend(self.fx.iter().cloned());
+        effects
     }
 
-    fn to_source(&self) -> SamplesBuffer<i16> {
-        let c = self.channels.len();
+    /// Fingerprints what shapes the output files: the KRUSZING settings, how the input is read and
+    /// what is done around the KRUSZING, and how the outputs are stored. Anything else, such as
+    /// reporting or caching, is left out so that it doesn't stop --resume from skipping files.
+    fn settings_fingerprint(&self) -> String {
+        let input = (
+            &self.input_format,
+            self.offset,
+            self.length,
+            self.seed,
+            &self.downmix_matrix,
+            &self.keyframes,
+            self.target_snr,
+            self.max_size,
+        );
+        let around = (
+            self.auto_gain,
+            self.loop_region_only,
+            &self.bypass_regions,
+            &self.detect_loop,
+            self.crop_loop,
+            &self.wavetable,
+            &self.slice,
+            &self.sfz,
+        );
+        let output = (&self.format, self.output_options(), self.embed_settings);
+
+        hash_bytes(format!("{:?}", (self.settings(), input, around, output)))
+    }
 
-        let data: Vec<_> = (0..c * self.channels[0].samples.len())
-            .map(|i| self.channels[i % c].samples[i / c])
+    /// Describes the settings a job is KRUSZED with, for --embed-settings. Inputs of a batch draw
+    /// from the seed in turn, so their position is part of it.
+    fn settings_comment(&self, seed: u64, job: usize, jobs: usize) -> Result<String> {
+        let mut comment = format!(
+            "KRUSZED by krusz {}, seed {}",
+            env!("CARGO_PKG_VERSION"),
+            seed
+        );
+        if jobs > 1 {
+            comment += &format!(" (input {} of {})", job + 1, jobs);
+        }
+
+        let options = match toml::Value::try_from(self.sound_options())? {
+            toml::Value::Table(options) => options,
+            _ => unreachable!("Options are always serialized as a table"),
+        };
+        let settings: Vec<String> = options
+            .into_iter()
+            .filter(|(_, value)| match value {
+                toml::Value::Boolean(set) => *set,
+                toml::Value::Array(values) => !values.is_empty(),
+                _ => true,
+            })
+            .map(|(key, value)| format!("{}={}", key, value))
             .collect();
+        if !settings.is_empty() {
+            comment += &format!(": {}", settings.join(", "));
+        }
 
-        SamplesBuffer::new(
-            self.channels.len().try_into().unwrap(),
-            self.sample_rate,
-            data,
-        )
+        Ok(comment)
+    }
+
+    /// How the outputs of a job are stored
+    fn output_options_for(&self, job: &Job) -> OutputOptions {
+        OutputOptions {
+            comment: job.comment.clone(),
+            ..self.output_options()
+        }
+    }
+
+    /// Where to play sounds
+    fn device_config(&self) -> DeviceConfig {
+        DeviceConfig {
+            host: self.host.clone(),
+            buffer_size: self.buffer_size,
+            null: self.play_null,
+        }
+    }
+
+    /// How inputs are read as raw PCM, from --offset for --length bytes, if they are
+    fn raw_format(&self) -> Option<RawFormat> {
+        self.input_format.map(|format| RawFormat {
+            offset: self.offset.unwrap_or(0),
+            length: self.length,
+            ..format
+        })
+    }
+
+    /// Opens an input for decoding block by block, as raw PCM with --input-format
+    fn open(&self, path: &Path) -> Result<input::Input> {
+        match &self.raw_format() {
+            Some(raw) => raw.open(path),
+            None => input::open(path),
+        }
+    }
+
+    /// Decodes an input, through the cache with --cache unless it's raw PCM, and folds it down with
+    /// --downmix-matrix
+    fn decode(&self, path: &Path) -> Result<Sound> {
+        let span = debug_span!("decode", input = %path.display(), samples = field::Empty);
+        let _entered = span.enter();
+
+        let sound = match &self.raw_format() {
+            Some(raw) => Sound::from_pcm(raw.open(path)?),
+            None if self.cache => DecodeCache::new(cache_dir()).decode(path),
+            None => decode(path),
+        }?;
+
+        span.record("samples", &(sound.channels.len() * sound.frames()));
+
+        match &self.downmix_matrix {
+            Some(downmix) => {
+                let matrix = downmix.matrix(sound.channels.len())?;
+                Ok(debug_span!("downmix")
+                    .in_scope(|| matrix.apply(sound, self.clip_mode.unwrap_or_default())))
+            }
+            None => Ok(sound),
+        }
+    }
+
+    /// How outputs are stored. Depths above 16 bits always get wide enough WAV samples to keep
+    /// their precision.
+    fn output_options(&self) -> OutputOptions {
+        self.output_options_at(self.bit_depth.unwrap_or(16.0))
+    }
+
+    /// How outputs KRUSZED to another bit depth than --bit-depth are stored
+    fn output_options_at(&self, bit_depth: f64) -> OutputOptions {
+        OutputOptions {
+            bits_per_sample: if bit_depth > 24.0 {
+                32
+            } else if bit_depth > 16.0 {
+                24
+            } else if self.packed && bit_depth <= 8.0 {
+                8
+            } else {
+                16
+            },
+            layout: self.channel_layout,
+            looped: self.looped,
+            loop_points: self
+                .make_loop
+                .map(|spec| spec.frames(self.settings().output_rate())),
+            mu_law: self.mu_law,
+            significant_bits: bit_depth.ceil() as u8,
+            comment: None,
+        }
+    }
+
+    /// Works out which outputs to write for each input
+    fn jobs(&self) -> Result<Vec<Job>> {
+        let jobs = match &self.output_dir {
+            Some(dir) => self
+                .input
+                .iter()
+                .map(|input| {
+                    let stem = input
+                        .file_stem()
+                        .ok_or_else(|| eyre!("Input {} has no file name", input.display()))
+                        .wrap_err(ErrorKind::Parameter)?;
+
+                    // Pushed on rather than swapped in, so that dots in the name are kept
+                    let extension = self.format.as_deref().unwrap_or("wav");
+                    let mut name = stem.to_owned();
+                    name.push(format!(".{}", extension.to_lowercase()));
+
+                    Ok(Job {
+                        input: input.clone(),
+                        opts: None,
+                        outputs: vec![(
+                            dir.join(name),
+                            OutputFormat::from_extension(extension)
+                                .wrap_err(ErrorKind::UnsupportedFormat)?,
+                        )],
+                        comment: None,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![Job {
+                input: self.input[0].clone(),
+                opts: None,
+                outputs: self
+                    .output
+                    .iter()
+                    .map(|output| {
+                        let format = match &self.format {
+                            Some(extension) => OutputFormat::from_extension(extension)?,
+                            None => OutputFormat::from_path(output)?,
+                        };
+                        ensure!(
+                            pipe_path(output).is_none() || format == OutputFormat::Raw,
+                            "Only raw PCM can be written into a pipe, use --format raw"
+                        );
+                        Ok((output.clone(), format))
+                    })
+                    .collect::<Result<_>>()
+                    .wrap_err(ErrorKind::UnsupportedFormat)?,
+                comment: None,
+            }],
+        };
+
+        if self.sidecar && self.output.iter().any(|output| pipe_path(output).is_some()) {
+            return Err(eyre!(
+                "Pipes can't have a --sidecar, which hashes the output"
+            ))
+            .wrap_err(ErrorKind::Parameter);
+        }
+
+        let mut outputs: Vec<_> = jobs
+            .iter()
+            .flat_map(|job| job.outputs.iter().map(|(output, _)| output))
+            .collect();
+        outputs.sort();
+
+        if let Some(pair) = outputs.windows(2).find(|pair| pair[0] == pair[1]) {
+            return Err(eyre!(
+                "Several inputs would be KRUSZED into {}",
+                pair[0].display()
+            ))
+            .wrap_err(ErrorKind::Parameter);
+        }
+
+        Ok(jobs)
     }
 }
 
-#[derive(Clone)]
-struct Channel {
-    samples: Vec<i16>,
+/// An input file and the outputs to KRUSZ it into
+struct Job {
+    input: PathBuf,
+    /// Options of the job, when a --batch list gives it options of its own
+    opts: Option<Opts>,
+    outputs: Vec<(PathBuf, OutputFormat)>,
+    /// Stored in WAV outputs with --embed-settings
+    comment: Option<String>,
 }
 
-fn main() -> Result<()> {
-    color_eyre::install()?;
+fn run(mut opts: Opts) -> Result<()> {
+    if let Some(path) = &opts.load_session {
+        opts = session::load(path, &opts)
+            .wrap_err_with(|| format!("Could not load session {}", path.display()))
+            .wrap_err(ErrorKind::Input)?;
+    }
 
-    let opts = Opts::parse();
+    // As given, for --batch files with presets of their own to fill in instead
+    let given = opts.clone();
+    if let Some(preset) = opts.preset {
+        preset.apply(&mut opts);
+    }
 
-    let sample_rate = opts.sample_rate.unwrap_or(44100);
-    let bit_depth = opts.bit_depth.unwrap_or(16);
-    let interpolation = opts.interpolation.unwrap_or(Interpolation::Nearest);
+    if opts.list_formats {
+        for (extension, description) in output::formats() {
+            println!("{:<8} {}", extension, description);
+        }
+        return Ok(());
+    }
 
-    let mut sound = Sound::new(Decoder::new(File::open(opts.input)?)?);
+    match &opts.command {
+        Some(Command::Repl { input }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            return repl::run(&opts, input);
+        }
+        Some(Command::Abx { input, trials }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            if *trials == 0 {
+                return Err(eyre!("There must be at least one trial"))
+                    .wrap_err(ErrorKind::Parameter);
+            }
+            let rng = match opts.seed {
+                Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+                None => ChaCha8Rng::from_entropy(),
+            };
+            return abx::run(&opts, input, *trials, rng);
+        }
+        Some(Command::Audition {
+            input,
+            presets,
+            duration,
+        }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            return audition::run(&opts, input, presets, *duration);
+        }
+        Some(Command::Info { input }) => {
+            let info = probe(input)
+                .wrap_err_with(|| format!("Could not read {}", input.display()))
+                .wrap_err(ErrorKind::Input)?;
+            print!("{}", info);
+            return Ok(());
+        }
+        Some(Command::Queue { playlist }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            let rng = match opts.seed {
+                Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+                None => ChaCha8Rng::from_entropy(),
+            };
+            return queue::run(&opts, playlist, rng);
+        }
+        Some(Command::Sf2 {
+            output,
+            inputs,
+            root_key,
+            name,
+        }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            return soundfont::run(&opts, output, inputs, root_key, name.as_deref());
+        }
+        Some(Command::Layers {
+            input,
+            layers,
+            output,
+        }) => {
+            opts.settings().validate().wrap_err(ErrorKind::Parameter)?;
+            return layers::run(&opts, input, *layers, output.as_deref());
+        }
+        Some(Command::SendSds { dump, device }) => {
+            let dump = std::fs::read(dump).wrap_err(ErrorKind::Input)?;
+            return sds::send_to_device(&dump, device).wrap_err(ErrorKind::Output);
+        }
+        None => {}
+    }
 
-    ensure!(
-        opts.output.is_some() || opts.play,
-        "Either --output or --play must be specified"
-    );
+    // Always seeded, so that the run can be saved as a session and reproduced
+    let seed = opts.seed.unwrap_or_else(rand::random);
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
 
-    ensure!(
-        (1..=44100).contains(&sample_rate),
-        "Sample rate must be between 1 and 44100 Hz inclusive"
-    );
+    if opts.randomize {
+        // Rolled on a separate stream, so the KRUSZING itself draws the same numbers as without --randomize
+        let mut dice = rng.clone();
+        dice.set_stream(1);
 
-    ensure!(
-        (1..=32).contains(&bit_depth),
-        "Bit depth must be between 1 and 16 bits inclusive"
-    );
+        let flags = randomize::randomize(&mut opts, &mut dice);
+        info!("Randomized settings: {} --seed {}", flags, seed);
 
-    if bit_depth == 16 && sample_rate == 44100 {
-        println!("Warning: Neither bit depth nor sample rate are being KRUSZED");
+        if opts.output.is_empty()
+            && opts.output_dir.is_none()
+            && !opts.checksum
+            && opts.verify.is_none()
+            && opts.preview.is_none()
+        {
+            opts.play = true;
+        }
+    }
+
+    let batch = match &opts.batch {
+        Some(path) => {
+            let entries = read_batch(path)
+                .wrap_err_with(|| format!("Could not read the batch list {}", path.display()))
+                .wrap_err(ErrorKind::Input)?;
+            opts.input = entries.iter().map(|entry| entry.input.clone()).collect();
+            entries
+        }
+        None => Vec::new(),
+    };
+
+    // Read like any other raw input from here on, so that sessions store how it was read
+    if let Some(path) = opts.input_raw_bytes.clone() {
+        opts.input = vec![path];
+        opts.input_format = Some(match opts.interpret {
+            Some(interpret) => interpret,
+            None => parse_interpretation(DEFAULT_INTERPRETATION).unwrap(),
+        });
     }
 
-    sound = resample(sound, sample_rate, interpolation);
-    sound = requantize(sound, bit_depth);
-    sound = resample(sound, 44100, interpolation);
+    opts.validate().wrap_err(ErrorKind::Parameter)?;
 
-    let play_sound = sound.clone();
+    if let Some(slots) = opts.chain {
+        return chain::run(&opts, slots, rng);
+    }
 
-    let play_handles = if opts.play {
-        let (stream, stream_handle) = OutputStream::try_default()?;
-        let sink = Sink::try_new(&stream_handle)?;
-        sink.append(play_sound.to_source().buffered());
+    let mut jobs = opts.jobs()?;
+    for (job, entry) in jobs.iter_mut().zip(&batch) {
+        let job_opts = entry.opts(&opts, &given).wrap_err(ErrorKind::Parameter)?;
+        job_opts.validate().wrap_err(ErrorKind::Parameter)?;
+        job.opts = Some(job_opts);
+    }
+
+    if opts.embed_settings {
+        let count = jobs.len();
+        for (index, job) in jobs.iter_mut().enumerate() {
+            job.comment = Some(
+                job.opts
+                    .as_ref()
+                    .unwrap_or(&opts)
+                    .settings_comment(seed, index, count)
+                    .wrap_err(ErrorKind::Parameter)?,
+            );
+        }
+    }
+
+    let mut manifest = opts
+        .output_dir
+        .as_ref()
+        .map(|dir| {
+            std::fs::create_dir_all(dir)?;
+            Manifest::open(dir, opts.settings_fingerprint(), opts.resume)
+        })
+        .transpose()
+        .wrap_err(ErrorKind::Output)?;
+
+    for (index, job) in jobs.iter().enumerate() {
+        // Derived before skipping, so resumed runs KRUSZ the remaining files like a full run would
+        let job_rng = ChaCha8Rng::from_rng(&mut rng)?;
+        let opts = job.opts.as_ref().unwrap_or(&opts);
+
+        if let Some(manifest) = &mut manifest {
+            manifest.set_settings(opts.settings_fingerprint());
+            let complete = job
+                .outputs
+                .iter()
+                .map(|(output, _)| manifest.is_complete(&job.input, output))
+                .collect::<Result<Vec<_>>>()?;
 
-        Some((stream, sink))
+            if opts.resume && complete.into_iter().all(|c| c) {
+                info!(
+                    "Skipping {}, which was already KRUSZED",
+                    job.input.display()
+                );
+                continue;
+            }
+        }
+
+        if let Some(duration) = opts.preview {
+            preview(opts, job, job_rng, duration.unwrap_or(10.0))?;
+        } else if opts.stream {
+            crush_stream(opts, job, job_rng)?;
+        } else {
+            crush_job(opts, job, job_rng)?;
+        }
+
+        if opts.sidecar {
+            for (output, _) in &job.outputs {
+                sidecar::write(opts, &job.input, output, seed, index)
+                    .wrap_err_with(|| {
+                        format!("Could not write the sidecar of {}", output.display())
+                    })
+                    .wrap_err(ErrorKind::Output)?;
+            }
+        }
+
+        if let Some(manifest) = &mut manifest {
+            for (output, _) in &job.outputs {
+                manifest
+                    .record(&job.input, output)
+                    .wrap_err(ErrorKind::Output)?;
+            }
+        }
+    }
+
+    if let Some(path) = &opts.save_session {
+        session::save(path, &opts, seed)
+            .wrap_err_with(|| format!("Could not save session {}", path.display()))
+            .wrap_err(ErrorKind::Output)?;
+    }
+
+    Ok(())
+}
+
+/// Warns about settings which won't do anything to the input
+fn warn_ineffective(opts: &Opts, channels: usize) {
+    if opts.bit_depth.unwrap_or(16.0) == 16.0
+        && opts.sample_rate.unwrap_or(44100) == 44100
+        && opts.spectral_crush.is_none()
+        && opts.codec.is_none()
+        && opts.bands.is_none()
+        && opts.keyframes.is_none()
+    {
+        warn!("Neither bit depth nor sample rate are being KRUSZED");
+    }
+
+    if opts.width.is_some() && channels != 2 {
+        warn!("--width only applies to stereo sounds, ignoring it");
+    }
+
+    if channels < 2 && (opts.swap_channels || opts.invert == Some(Invert::R)) {
+        warn!("The input has a single channel, so there's no second one to swap or invert");
+    }
+
+    if channels < 2 && opts.mono_check {
+        warn!("--mono-check only applies to stereo sounds, ignoring it");
+    }
+
+    if opts.alias_report && opts.alias_meter().is_none() {
+        warn!("--alias-report needs the sample rate restored above the KRUSZED one, ignoring it");
+    }
+}
+
+/// Fails if --channel-layout doesn't fit the sound
+fn check_layout(opts: &Opts, channels: usize) -> Result<()> {
+    match opts.channel_layout {
+        Some(layout) if layout.channels() != channels => Err(eyre!(
+            "Channel layout {} has {} channels, but the input has {}",
+            layout,
+            layout.channels(),
+            channels
+        ))
+        .wrap_err(ErrorKind::Parameter),
+        _ => Ok(()),
+    }
+}
+
+fn crush_job(opts: &Opts, job: &Job, mut rng: ChaCha8Rng) -> Result<()> {
+    let mut sound = opts.decode(&job.input).wrap_err(ErrorKind::Input)?;
+
+    let searched;
+    let opts = if let Some(target) = opts.target_snr {
+        searched = search_settings(opts, job, &sound, target)?;
+        &searched
+    } else if let Some(budget) = opts.max_size {
+        searched = fit_size(opts, job, &sound, budget, &rng)?;
+        &searched
     } else {
-        None
+        opts
     };
 
-    if let Some(output) = opts.output {
-        let extension = output
-            .extension()
-            .map(OsStr::to_str)
-            .unwrap()
-            .unwrap_or("")
-            .to_lowercase();
+    let clipped_before = clipped_count();
+    let source_rms = rms(&sound);
+    let original = (opts.loop_region_only || opts.bypass_regions.is_some()).then(|| sound.clone());
+    let source_mono = opts.mono_check.then(|| {
+        let mut meter = MonoMeter::default();
+        meter.update(&sound);
+        meter
+    });
 
-        match extension.as_str() {
-            "wav" => save_wav(&sound, &output)?,
-            _ => bail!("Unsupported output format {}", extension),
+    warn_ineffective(opts, sound.channels.len());
+    check_layout(opts, sound.channels.len())?;
+    sound = match &opts.keyframes {
+        Some(keyframes) => keyframes
+            .render(opts, &sound, &rng)
+            .wrap_err(ErrorKind::Parameter)?,
+        None => {
+            let mut pipeline = opts
+                .settings()
+                .pipeline(sound.channels.len(), sound.sample_rate, &mut rng)
+                .wrap_err(ErrorKind::Parameter)?;
+            gather(pipeline.pull(split(sound, opts.block_frames()))).unwrap()
         }
+    };
+
+    if opts.auto_gain {
+        if let Some(gain) = makeup_gain(source_rms, rms(&sound)) {
+            debug!("Applying {:.2} dB of makeup gain", 20.0 * gain.log10());
+            sound = debug_span!("auto_gain")
+                .in_scope(|| apply_gain(sound, gain, opts.clip_mode.unwrap_or_default()));
+        }
+    }
+
+    if let Some(original) = original.as_ref().filter(|_| opts.loop_region_only) {
+        sound = crush_loop_only(job, original, sound)?;
+    }
+
+    if let (Some(original), Some(regions)) = (&original, &opts.bypass_regions) {
+        sound = bypass(job, original, regions, sound);
+    }
+
+    if let Some(spec) = opts.make_loop {
+        sound = make_loop(job, spec, sound)?;
+    }
+
+    let mut loop_points = None;
+    if opts.detect_loop {
+        (sound, loop_points) = find_loop(job, opts.crop_loop, sound);
+    }
+
+    check_clipping(opts, clipped_before)?;
+
+    let mut level = Meter::default();
+    level.update(&sound);
+    check_silence(opts, job, &level)?;
+
+    if let Some(source_mono) = &source_mono {
+        let mut mono = MonoMeter::default();
+        mono.update(&sound);
+        check_mono(job, source_mono, &mono);
+    }
+
+    if let Some(mut meter) = opts.alias_meter() {
+        meter.update(&sound);
+        print_alias_report(opts, job, meter);
+    }
+
+    if opts.checksum || opts.verify.is_some() {
+        let hash = debug_span!("checksum").in_scope(|| checksum(&sound));
+        check_hash(opts, job, &hash)?;
+    }
+
+    let play_handles = if opts.play {
+        Some(play(&sound, &opts.device_config())?)
+    } else {
+        None
+    };
+
+    for (output, format) in &job.outputs {
+        let samples = sound.channels.len() * sound.frames();
+        debug_span!("encode", output = %output.display(), samples)
+            .in_scope(|| {
+                let options = opts.output_options_for(job);
+                let options = OutputOptions {
+                    loop_points: loop_points.or(options.loop_points),
+                    ..options
+                };
+                save(&sound, output, *format, options)
+            })
+            .wrap_err(ErrorKind::Output)?;
+    }
+
+    if let Some(slicing) = opts.slice {
+        save_slices(opts, job, &sound, slicing)?;
+    }
+
+    if let Some(spec) = opts.wavetable {
+        save_wavetables(opts, job, &sound, spec)?;
     }
 
     if let Some((_, sink)) = play_handles {
@@ -170,147 +1563,747 @@ fn main() -> Result<()> {
     Ok(())
 }
 
-#[derive(Clone, Copy, Debug, ArgEnum)]
-enum Interpolation {
-    Nearest,
-    Linear,
+/// With --target-snr, the options with the bit depth and sample rate which KRUSZE the input to the
+/// smallest size at the target signal-to-noise ratio. Inputs which can't reach it are left at 16 bits
+/// and their own sample rate, with a warning.
+fn search_settings(opts: &Opts, job: &Job, sound: &Sound, target: f64) -> Result<Opts> {
+    let found = debug_span!("search")
+        .in_scope(|| search::smallest(sound, &opts.settings(), target))
+        .wrap_err(ErrorKind::Parameter)?;
+
+    let (bit_depth, sample_rate) = match found {
+        Some(found) => {
+            info!(
+                "KRUSZING {} at {} bits and {} Hz, for a signal-to-noise ratio of {:.1} dB",
+                job.input.display(),
+                found.bit_depth,
+                found.sample_rate,
+                found.snr
+            );
+            (found.bit_depth, found.sample_rate)
+        }
+        None => {
+            warn!(
+                "No bit depth and sample rate KRUSZE {} to a signal-to-noise ratio of {} dB, leaving it as it is",
+                job.input.display(),
+                target
+            );
+            (16, sound.sample_rate.min(44100))
+        }
+    };
+
+    Ok(Opts {
+        bit_depth: Some(f64::from(bit_depth)),
+        sample_rate: Some(sample_rate),
+        ..opts.clone()
+    })
 }
 
-fn resample(sound: Sound, sample_rate: u32, interpolation: Interpolation) -> Sound {
-    let n = sound.channels[0].samples.len();
+/// With --max-size, the options with the sample rate and bit depth which fit every output of the job
+/// within the budget with the highest signal-to-noise ratio. Each is tried by KRUSZING the input
+/// and saving it to a temporary file, and measured at the depth the outputs' formats store.
+fn fit_size(opts: &Opts, job: &Job, sound: &Sound, budget: u64, rng: &ChaCha8Rng) -> Result<Opts> {
+    // The rate given is tried too, when it's none of the usual ones
+    let mut rates: Vec<u32> = search::candidate_rates(sound.sample_rate)
+        .into_iter()
+        .filter(|&rate| opts.sample_rate.is_none_or(|max_rate| rate < max_rate))
+        .collect();
+    rates.extend(
+        opts.sample_rate
+            .filter(|&rate| rate <= sound.sample_rate.min(44100)),
+    );
+    let max_depth = opts
+        .bit_depth
+        .map_or(16, |bit_depth| bit_depth.floor() as u8);
+    // Packed, 8 bits and below take a byte a sample, and anything more two
+    let mut depths = vec![max_depth, max_depth.min(8)];
+    depths.dedup();
 
-    if n == 0 {
-        return Sound {
-            channels: sound.channels,
-            sample_rate,
-        };
+    let mut smallest = u64::MAX;
+    let mut best: Option<(Opts, search::Candidate, u64)> = None;
+
+    for sample_rate in rates {
+        for &bit_depth in &depths {
+            let candidate = Opts {
+                bit_depth: Some(f64::from(bit_depth)),
+                sample_rate: Some(sample_rate),
+                no_restore_rate: true,
+                packed: true,
+                ..opts.clone()
+            };
+            let settings = candidate.settings();
+            let crushed = settings
+                .pipeline(sound.channels.len(), sound.sample_rate, &mut rng.clone())
+                .wrap_err(ErrorKind::Parameter)?
+                .run(sound.clone());
+
+            let options = candidate.output_options_for(job);
+            let mut size = 0;
+            for (output, format) in &job.outputs {
+                // Whatever the encoders warn about is said again when the chosen one is saved
+                let encoded = tracing::subscriber::with_default(
+                    tracing_subscriber::registry().with(LevelFilter::OFF),
+                    || encoded_size(&crushed, output, *format, options.clone()),
+                )
+                .wrap_err(ErrorKind::Output)?;
+                size = size.max(encoded);
+            }
+            smallest = smallest.min(size);
+
+            // Formats which store fewer bits, such as 8SVX, lose the rest on the way
+            let stored_depth = job
+                .outputs
+                .iter()
+                .filter_map(|(_, format)| format.stored_bits(&options))
+                .fold(bit_depth, u8::min);
+
+            if size <= budget {
+                let snr = search::measure(sound, &settings, stored_depth, sample_rate)
+                    .wrap_err(ErrorKind::Parameter)?;
+                let found = search::Candidate {
+                    bit_depth: stored_depth,
+                    sample_rate,
+                    snr,
+                };
+
+                if best.as_ref().is_none_or(|(_, best, _)| snr >= best.snr) {
+                    best = Some((candidate, found, size));
+                }
+            }
+        }
     }
 
-    let r = sample_rate as f64 / sound.sample_rate as f64;
-    let q = 1.0 / r;
-    let new_sample_count = (n as f64 * r).round() as usize;
+    let (opts, found, size) = best
+        .ok_or_else(|| {
+            eyre!(
+                "{} can't be KRUSZED into {} bytes, the smallest it gets is {} bytes",
+                job.input.display(),
+                budget,
+                smallest
+            )
+        })
+        .wrap_err(ErrorKind::Parameter)?;
 
-    Sound {
-        channels: sound
-            .channels
-            .iter()
-            .map(|channel| Channel {
-                samples: (0..new_sample_count)
-                    .map(|i| {
-                        let f = i as f64 * q;
-                        lerp(&channel.samples, f, interpolation).round() as i16
-                    })
-                    .collect(),
+    // Too short to tell, when nothing is left to compare
+    let snr = if found.snr.is_finite() {
+        format!(", for a signal-to-noise ratio of {:.1} dB", found.snr)
+    } else {
+        String::new()
+    };
+    info!(
+        "KRUSZING {} at {} bits and {} Hz, into {} bytes{}",
+        job.input.display(),
+        found.bit_depth,
+        found.sample_rate,
+        size,
+        snr
+    );
+
+    Ok(opts)
+}
+
+/// With --slice, writes the slices of the KRUSZED sound next to each output, with an SFZ kit
+/// mapping them with --sfz
+fn save_slices(opts: &Opts, job: &Job, sound: &Sound, slicing: Slicing) -> Result<()> {
+    let slices = debug_span!("slice").in_scope(|| cut(sound, &slice_points(sound, slicing)));
+    debug!("Cut {} into {} slices", job.input.display(), slices.len());
+
+    // The loop of the whole sound doesn't fit any of the slices
+    let options = OutputOptions {
+        loop_points: None,
+        ..opts.output_options_for(job)
+    };
+
+    for (output, format) in &job.outputs {
+        let paths: Vec<_> = (0..slices.len())
+            .map(|i| slice_path(output, i, slices.len()))
+            .collect();
+
+        for (slice, path) in slices.iter().zip(&paths) {
+            save(slice, path, *format, options.clone()).wrap_err(ErrorKind::Output)?;
+        }
+
+        if opts.sfz {
+            let kit = sfz(&paths);
+            write_atomically(&output.with_extension("sfz"), |temp| {
+                Ok(std::fs::write(temp, kit)?)
             })
-            .collect(),
-        sample_rate,
+            .wrap_err(ErrorKind::Output)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// With --wavetable, extracts a wavetable from the KRUSZED sound and saves it next to each output
+fn save_wavetables(opts: &Opts, job: &Job, sound: &Sound, spec: WavetableSpec) -> Result<()> {
+    let table = debug_span!("wavetable").in_scope(|| wavetable::extract(sound, spec));
+
+    // The table is mono, and neither the loop of the sound nor a loop of the whole table fits it
+    let options = OutputOptions {
+        layout: None,
+        looped: false,
+        loop_points: None,
+        ..opts.output_options_for(job)
+    };
+
+    let mut paths: Vec<PathBuf> = job
+        .outputs
+        .iter()
+        .map(|(output, _)| wavetable_path(output))
+        .collect();
+    paths.dedup();
+
+    for path in paths {
+        save_wavetable(&table, &path, spec.frame_size, options.clone())
+            .wrap_err(ErrorKind::Output)?;
     }
+
+    Ok(())
 }
 
-fn lerp<T: Copy + std::fmt::Debug + NumCast>(
-    values: &[T],
-    f: f64,
-    interpolation: Interpolation,
-) -> f64 {
-    assert!(!values.is_empty());
-    assert!(
-        f >= 0.0 && f < values.len() as f64,
-        "Lerp index {} out of range: 0..{}",
-        f,
-        values.len()
+/// With --loop-region-only, keeps the KRUSZED sound within the first loop of the input, and the input
+/// everywhere else
+fn crush_loop_only(job: &Job, original: &Sound, crushed: Sound) -> Result<Sound> {
+    let info = probe(&job.input).wrap_err(ErrorKind::Input)?;
+    let region = info
+        .loops
+        .first()
+        .ok_or_else(|| {
+            eyre!(
+                "{} has no loop points. --loop-region-only needs a WAV file with a smpl chunk",
+                job.input.display()
+            )
+        })
+        .wrap_err(ErrorKind::Input)?;
+
+    if region.start > region.end || region.end as usize >= original.frames() {
+        return Err(eyre!(
+            "The loop of {}, frames {} to {}, is outside its {} frames",
+            job.input.display(),
+            region.start,
+            region.end,
+            original.frames()
+        ))
+        .wrap_err(ErrorKind::Input);
+    }
+    if info.loops.len() > 1 {
+        warn!(
+            "{} has {} loops, only the first one is KRUSZED",
+            job.input.display(),
+            info.loops.len()
+        );
+    }
+
+    Ok(debug_span!("splice_loop").in_scope(|| {
+        splice_loop(
+            original,
+            crushed,
+            region.start as usize,
+            region.end as usize,
+        )
+    }))
+}
+
+/// With --bypass-regions, puts the input back within each of the regions
+fn bypass(job: &Job, original: &Sound, regions: &Regions, crushed: Sound) -> Sound {
+    let length = original.frames() as f64 / original.sample_rate as f64;
+    for region in regions.0.iter().filter(|region| region.start >= length) {
+        warn!(
+            "The bypass region from {:.3} s starts after the {:.3} s of {}",
+            region.start,
+            length,
+            job.input.display()
+        );
+    }
+
+    debug_span!("splice_bypass").in_scope(|| splice_bypass(original, crushed, regions))
+}
+
+/// With --make-loop, crossfades the seam of the loop
+fn make_loop(job: &Job, spec: LoopSpec, sound: Sound) -> Result<Sound> {
+    let (start, end) = spec.frames(sound.sample_rate);
+
+    if end as usize >= sound.frames() {
+        return Err(eyre!(
+            "The loop ends at {:.3} s, after the {:.3} s KRUSZED {}",
+            spec.end,
+            sound.frames() as f64 / sound.sample_rate as f64,
+            job.input.display()
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    }
+
+    let xfade = (spec.xfade * sound.sample_rate as f64).round() as usize;
+    if xfade > start as usize {
+        warn!(
+            "The loop of {} starts {:.3} s in, too early for a {:.3} s crossfade, which is shortened",
+            job.input.display(),
+            spec.start,
+            spec.xfade
+        );
+    }
+
+    Ok(debug_span!("make_loop")
+        .in_scope(|| crossfade_loop(sound, start as usize, end as usize, xfade)))
+}
+
+/// With --detect-loop, the loop points found in the KRUSZED sound, which is cut down to the loop with
+/// --crop-loop
+fn find_loop(job: &Job, crop: bool, mut sound: Sound) -> (Sound, Option<(u64, u64)>) {
+    let found = match debug_span!("detect_loop").in_scope(|| detect_loop(&sound)) {
+        Some(found) => found,
+        None => {
+            warn!(
+                "The KRUSZED {} is too short to find a loop in",
+                job.input.display()
+            );
+            return (sound, None);
+        }
+    };
+
+    let time = |frame: usize| frame as f64 / sound.sample_rate as f64;
+    info!(
+        "Found a {:.3} s loop in {}, from {:.3} s to {:.3} s, with a correlation of {:.2}",
+        time(found.end + 1 - found.start),
+        job.input.display(),
+        time(found.start),
+        time(found.end + 1),
+        found.correlation
     );
+    if found.correlation < LOOP_CORRELATION {
+        warn!(
+            "{} hardly repeats, so its loop may be heard",
+            job.input.display()
+        );
+    }
 
-    let x = f as usize;
-    let y = (x + 1).min(values.len() - 1);
-    let a = f.fract();
+    if !crop {
+        return (sound, Some((found.start as u64, found.end as u64)));
+    }
 
-    match interpolation {
-        Interpolation::Nearest => {
-            if a < 0.5 {
-                num::cast(values[x]).unwrap()
-            } else {
-                num::cast(values[y]).unwrap()
-            }
+    for channel in &mut sound.channels {
+        channel.samples.truncate(found.end + 1);
+        channel.samples.drain(..found.start);
+    }
+    (sound, Some((0, (found.end - found.start) as u64)))
+}
+
+/// Starts playing the sound at the device's sample rate, returning the handles to keep alive until it's done
+fn play(sound: &Sound, device: &DeviceConfig) -> Result<(device::Stream, Sink)> {
+    let output = device::open(device).wrap_err(ErrorKind::Device)?;
+    output
+        .sink
+        .append(device::at_rate(sound, output.sample_rate).to_source());
+
+    Ok((output.stream, output.sink))
+}
+
+/// Reads the first `duration` seconds of the input, folded down with --downmix-matrix, without
+/// decoding the rest
+fn excerpt(opts: &Opts, input: &Path, duration: f64) -> Result<Sound> {
+    let mut blocks = opts
+        .open(input)
+        .and_then(|input| Blocks::new(input, opts.block_frames()))
+        .wrap_err(ErrorKind::Input)?;
+
+    let frames = (duration * blocks.sample_rate() as f64).round() as usize;
+    let mut sound = blocks.next().unwrap();
+
+    while sound.frames() < frames {
+        match blocks.next() {
+            Some(block) => sound.append(block),
+            None => break,
         }
-        Interpolation::Linear => {
-            let xv: f64 = num::cast(values[x]).unwrap();
-            let yv: f64 = num::cast(values[y]).unwrap();
-            (1.0 - a) * xv + a * yv
+    }
+
+    for channel in &mut sound.channels {
+        channel.samples.truncate(frames);
+    }
+
+    if let Some(downmix) = &opts.downmix_matrix {
+        sound = downmix
+            .matrix(sound.channels.len())
+            .wrap_err(ErrorKind::Input)?
+            .apply(sound, opts.clip_mode.unwrap_or_default());
+    }
+
+    Ok(sound)
+}
+
+/// Plays the first seconds of the input, KRUSZED with the fastest settings, so they can be
+/// tweaked before committing to a full render. Only as much of the input as needed is decoded.
+fn preview(opts: &Opts, job: &Job, mut rng: ChaCha8Rng, duration: f64) -> Result<()> {
+    let mut sound = excerpt(opts, &job.input, duration)?;
+
+    let settings = Settings {
+        interpolation: Interpolation::Nearest,
+        ..opts.settings()
+    };
+
+    warn_ineffective(opts, sound.channels.len());
+
+    let source_rms = rms(&sound);
+    sound = settings
+        .pipeline(sound.channels.len(), sound.sample_rate, &mut rng)
+        .wrap_err(ErrorKind::Parameter)?
+        .run(sound);
+
+    if opts.auto_gain {
+        if let Some(gain) = makeup_gain(source_rms, rms(&sound)) {
+            sound = apply_gain(sound, gain, settings.clip_mode);
         }
     }
+
+    info!(
+        "Previewing {:.1} seconds of {}",
+        sound.frames() as f64 / sound.sample_rate as f64,
+        job.input.display()
+    );
+
+    let (_stream, sink) = play(&sound, &opts.device_config())?;
+    sink.sleep_until_end();
+
+    Ok(())
 }
 
-fn requantize(sound: Sound, bit_depth: u8) -> Sound {
-    Sound {
-        channels: sound
-            .channels
-            .iter()
-            .map(|channel| Channel {
-                samples: channel
-                    .samples
-                    .iter()
-                    .map(|&sample| requantize_sample(sample, bit_depth))
-                    .collect(),
-            })
-            .collect(),
-        sample_rate: sound.sample_rate,
+/// KRUSZES the input block by block, so that memory use doesn't grow with its length. With
+/// --auto-gain, a first pass measures the levels and the same pipeline is then run again.
+fn crush_stream(opts: &Opts, job: &Job, mut rng: ChaCha8Rng) -> Result<()> {
+    let open = || {
+        opts.open(&job.input)
+            .and_then(|input| Blocks::new(input, opts.block_frames()))
+            .wrap_err(ErrorKind::Input)
+    };
+
+    let blocks = open()?;
+    let source_rate = blocks.sample_rate();
+    let downmix = opts
+        .downmix_matrix
+        .as_ref()
+        .map(|downmix| downmix.matrix(blocks.channels()))
+        .transpose()
+        .wrap_err(ErrorKind::Input)?;
+    let channels = downmix
+        .as_ref()
+        .map_or(blocks.channels(), DownmixMatrix::channels);
+    let fold = |block: Sound| match &downmix {
+        Some(matrix) => matrix.apply(block, opts.clip_mode.unwrap_or_default()),
+        None => block,
+    };
+
+    warn_ineffective(opts, channels);
+    check_layout(opts, channels)?;
+
+    let settings = opts.settings();
+    let (blocks, gain) = if opts.auto_gain {
+        let mut pipeline = settings
+            .pipeline(channels, source_rate, &mut rng.clone())
+            .wrap_err(ErrorKind::Parameter)?;
+        let mut source_level = Meter::default();
+        let mut level = Meter::default();
+
+        debug_span!("measure").in_scope(|| {
+            let blocks = blocks.map(fold).inspect(|block| source_level.update(block));
+
+            for block in pipeline.pull(blocks) {
+                level.update(&block);
+            }
+        });
+
+        let gain = makeup_gain(source_level.rms(), level.rms());
+        if let Some(gain) = gain {
+            debug!("Applying {:.2} dB of makeup gain", 20.0 * gain.log10());
+        }
+
+        (open()?, gain)
+    } else {
+        (blocks, None)
+    };
+
+    let mut pipeline = settings
+        .pipeline(channels, source_rate, &mut rng)
+        .wrap_err(ErrorKind::Parameter)?;
+    let clipped_before = clipped_count();
+
+    let mut hasher = (opts.checksum || opts.verify.is_some()).then(Checksum::default);
+    let mut writers = job
+        .outputs
+        .iter()
+        .map(|(output, format)| {
+            StreamWriter::create(
+                output,
+                *format,
+                channels,
+                settings.output_rate(),
+                opts.output_options_for(job),
+            )
+        })
+        .collect::<Result<Vec<_>>>()
+        .wrap_err(ErrorKind::Output)?;
+
+    let mut source_mono = MonoMeter::default();
+    let mut mono = MonoMeter::default();
+    let blocks = blocks.map(fold).inspect(|block| {
+        if opts.mono_check {
+            source_mono.update(block);
+        }
+    });
+
+    let mut alias = opts.alias_meter();
+    let mut output_level = Meter::default();
+    let mut write = |mut block: Sound| -> Result<()> {
+        if let Some(gain) = gain {
+            block = apply_gain(block, gain, opts.clip_mode.unwrap_or_default());
+        }
+
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&block);
+        }
+
+        output_level.update(&block);
+        if opts.mono_check {
+            mono.update(&block);
+        }
+        if let Some(alias) = &mut alias {
+            alias.update(&block);
+        }
+
+        let samples = block.channels.len() * block.frames();
+        for writer in &mut writers {
+            debug_span!("encode", samples)
+                .in_scope(|| writer.write(&block))
+                .wrap_err(ErrorKind::Output)?;
+        }
+
+        Ok(())
+    };
+
+    debug_span!("stream").in_scope(|| pipeline.pull(blocks).try_for_each(&mut write))?;
+
+    check_clipping(opts, clipped_before)?;
+    check_silence(opts, job, &output_level)?;
+    if opts.mono_check {
+        check_mono(job, &source_mono, &mono);
+    }
+    if let Some(meter) = alias {
+        print_alias_report(opts, job, meter);
+    }
+
+    if let Some(hasher) = hasher {
+        check_hash(opts, job, &hasher.finish())?;
+    }
+
+    // Outputs only appear once everything has been checked
+    for writer in writers {
+        writer.commit().wrap_err(ErrorKind::Output)?;
     }
+
+    Ok(())
 }
 
-fn requantize_sample(sample: i16, bit_depth: u8) -> i16 {
-    if bit_depth == 16 {
-        return sample;
+/// Fails with --fail-on-clip if any samples clipped since `clipped_before`
+fn check_clipping(opts: &Opts, clipped_before: usize) -> Result<()> {
+    if opts.fail_on_clip {
+        let clipped = clipped_count() - clipped_before;
+
+        if clipped > 0 {
+            return Err(eyre!("{} samples clipped", clipped)).wrap_err(ErrorKind::Clipping);
+        }
     }
 
-    let hi_mask = !0 << (16 - bit_depth);
-    let lo_mask = !hi_mask;
+    Ok(())
+}
 
-    let msb = sample & (1 << 15);
-    let fill: i16 = if msb == 0 { !0 } else { 0 };
+/// Warns when the KRUSZED sound is silent, or fails with --fail-on-silence
+fn check_silence(opts: &Opts, job: &Job, level: &Meter) -> Result<()> {
+    if !level.is_silent() {
+        return Ok(());
+    }
 
-    (sample & hi_mask) | (fill & lo_mask)
+    let message = format!(
+        "The KRUSZED {} is silent, its samples vary by less than -96 dBFS",
+        job.input.display()
+    );
+    if opts.fail_on_silence {
+        return Err(eyre!(message)).wrap_err(ErrorKind::Silence);
+    }
+
+    warn!("{}", message);
+    Ok(())
 }
 
-fn save_wav<P: AsRef<Path>>(sound: &Sound, path: P) -> Result<()> {
-    let spec = WavSpec {
-        channels: sound.channels.len().try_into().unwrap(),
-        sample_rate: 44100,
-        bits_per_sample: 16,
-        sample_format: SampleFormat::Int,
-    };
+/// Reports how the KRUSZED sound folds down to mono with --mono-check, and warns when it cancels
+/// out badly, pointing out when the input already did
+fn check_mono(job: &Job, source: &MonoMeter, crushed: &MonoMeter) {
+    let loss = crushed.fold_down_loss();
+    info!(
+        "Mono fold-down of the KRUSZED {}: correlation {:.2}, {:.1} dB against stereo",
+        job.input.display(),
+        crushed.correlation(),
+        loss
+    );
+
+    if loss < MONO_LOSS_THRESHOLD {
+        let source_loss = source.fold_down_loss();
+        let cause = if source_loss < MONO_LOSS_THRESHOLD {
+            format!("the input already loses {:.1} dB", source_loss)
+        } else {
+            format!("the input only loses {:.1} dB", source_loss)
+        };
+        warn!(
+            "The KRUSZED {} collapses in mono, cancelling out to {:.1} dB against stereo, where {}",
+            job.input.display(),
+            loss,
+            cause
+        );
+    }
+}
 
-    let mut writer = WavWriter::create(path, spec)?;
-    let n = sound.channels[0].samples.len() * sound.channels.len();
-    let mut i16_writer = writer.get_i16_writer(n.try_into().unwrap());
+/// Prints the --alias-report of the KRUSZED sound, headed by the input when there are several
+fn print_alias_report(opts: &Opts, job: &Job, meter: AliasMeter) {
+    if opts.input.len() > 1 {
+        eprintln!("{}", job.input.display());
+    }
+    eprint!("{}", meter.report());
+}
 
-    for sample in sound.to_source() {
-        i16_writer.write_sample(sample);
+/// Prints the hash of the KRUSZED sound with --checksum, and checks it with --verify
+fn check_hash(opts: &Opts, job: &Job, hash: &str) -> Result<()> {
+    if opts.checksum {
+        if opts.input.len() > 1 {
+            println!("{}  {}", hash, job.input.display());
+        } else {
+            println!("{}", hash);
+        }
     }
 
-    i16_writer.flush()?;
-    writer.flush()?;
+    if let Some(expected) = &opts.verify {
+        if !hash.eq_ignore_ascii_case(expected.trim()) {
+            return Err(eyre!(
+                "Checksum mismatch: expected {}, got {}",
+                expected,
+                hash
+            ))
+            .wrap_err(ErrorKind::Verification);
+        }
+    }
 
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
+    use krusz::{output, sample::Sample, Channel};
+
     use super::*;
 
     #[test]
-    fn test_lerp() {
-        let arr = [1u16, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+    fn test_stream_matches_whole() {
+        let dir = std::env::temp_dir();
+        let input = dir.join("krusz_test_stream_input.wav");
+        let whole = dir.join("krusz_test_stream_whole.wav");
+        let streamed = dir.join("krusz_test_stream_streamed.wav");
+
+        let sound = Sound {
+            channels: (0..2)
+                .map(|c| Channel {
+                    samples: (0..BLOCK_FRAMES * 2 + 123)
+                        .map(|i| (((i * (c + 3)) % 20000) as Sample - 10000) << 16)
+                        .collect(),
+                })
+                .collect(),
+            sample_rate: 48000,
+        };
+        output::save_wav(&sound, &input, output::OutputOptions::default()).unwrap();
+
+        let opts = |output: &PathBuf| {
+            Opts::parse_from([
+                "krusz".as_ref(),
+                "-i".as_ref(),
+                input.as_os_str(),
+                "-o".as_ref(),
+                output.as_os_str(),
+                "-b".as_ref(),
+                "5.5".as_ref(),
+                "-s".as_ref(),
+                "11025".as_ref(),
+                "--jitter".as_ref(),
+                "0.4".as_ref(),
+                "--vintage-filter".as_ref(),
+                "6k".as_ref(),
+                "--auto-gain".as_ref(),
+            ])
+        };
+
+        for (output, crush) in [
+            (
+                &whole,
+                crush_job as fn(&Opts, &Job, ChaCha8Rng) -> Result<()>,
+            ),
+            (&streamed, crush_stream),
+        ] {
+            let opts = opts(output);
+            let job = opts.jobs().unwrap().remove(0);
+            crush(&opts, &job, ChaCha8Rng::seed_from_u64(0)).unwrap();
+        }
+
+        assert_eq!(
+            std::fs::read(&whole).unwrap(),
+            std::fs::read(&streamed).unwrap()
+        );
+
+        for path in [input, whole, streamed] {
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_settings_fingerprint() {
+        let fingerprint = |args: &[&str]| {
+            Opts::parse_from(
+                ["krusz", "-i", "kick.wav", "--output-dir", "out"]
+                    .iter()
+                    .chain(args),
+            )
+            .settings_fingerprint()
+        };
+        let plain = fingerprint(&["-b", "8"]);
+
+        // Reporting leaves the outputs as they are
+        assert_eq!(plain, fingerprint(&["-b", "8", "--fail-on-clip"]));
+        assert_eq!(plain, fingerprint(&["-b", "8", "--fail-on-silence"]));
+        assert_eq!(plain, fingerprint(&["-b", "8", "--mono-check", "--stats"]));
+        // Decoded inputs come out of the cache as they would without it
+        assert_eq!(plain, fingerprint(&["-b", "8", "--cache"]));
 
-        assert_eq!(lerp(&arr, 4.8, Interpolation::Nearest), 6.0);
-        assert_eq!(lerp(&arr, 4.4, Interpolation::Nearest), 5.0);
-        assert_eq!(lerp(&arr, 4.8, Interpolation::Linear), 5.8);
+        assert_ne!(plain, fingerprint(&["-b", "4"]));
+        assert_ne!(plain, fingerprint(&["-b", "8", "--auto-gain"]));
+        assert_ne!(plain, fingerprint(&["-b", "8", "--packed"]));
+        assert_ne!(plain, fingerprint(&["-b", "8", "--format", "flac"]));
     }
 
     #[test]
-    fn test_requantize() {
-        assert_eq!(requantize_sample(-1, 1), i16::min_value());
-        assert_eq!(requantize_sample(0, 1), i16::max_value());
-        assert_eq!(requantize_sample(10, 8), 255);
-        assert_eq!(requantize_sample(256, 8), 511);
+    fn test_output_dir_names() {
+        let opts = Opts::parse_from([
+            "krusz",
+            "-i",
+            "kick.v2.wav",
+            "-i",
+            "kick.wav",
+            "--output-dir",
+            "out",
+        ]);
+
+        let outputs: Vec<PathBuf> = opts
+            .jobs()
+            .unwrap()
+            .into_iter()
+            .map(|job| job.outputs[0].0.clone())
+            .collect();
+        assert_eq!(
+            outputs,
+            [Path::new("out/kick.v2.wav"), Path::new("out/kick.wav")]
+        );
     }
 }
@@ -0,0 +1,280 @@
+use std::{
+    io::{self, Write},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, SyncSender},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+/// How long the clip LED stays lit after the last clipped sample
+const CLIP_HOLD: Duration = Duration::from_millis(300);
+
+/// How often the indicators are redrawn
+const REFRESH_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Frames measured for each meter reading, about 20 ms at common device rates
+const READING_FRAMES: usize = 1024;
+
+/// Readings waiting for the display. The callback drops readings rather than wait when it's full.
+const READING_BACKLOG: usize = 64;
+
+/// Channels beyond this many aren't metered
+const MAX_METERED: usize = 8;
+
+/// How much of the displayed peak is left after each refresh, so peaks fall back gradually
+const PEAK_DECAY: f32 = 0.85;
+
+/// Range of the meters, in dB below full scale
+const METER_RANGE: f32 = 48.0;
+
+/// Characters of each meter
+const METER_WIDTH: usize = 16;
+
+/// Samples which reached full scale, counted by the audio callback
+#[derive(Debug, Default)]
+struct Levels {
+    clips: AtomicUsize,
+}
+
+/// Peak and RMS level of each channel over one stretch of the output
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Reading {
+    channels: usize,
+    peak: [f32; MAX_METERED],
+    rms: [f32; MAX_METERED],
+}
+
+/// The audio callback's end of the indicators
+#[derive(Clone)]
+pub struct Tap {
+    levels: Arc<Levels>,
+    readings: SyncSender<Reading>,
+}
+
+/// The display's end of the indicators
+pub struct Indicators {
+    levels: Arc<Levels>,
+    readings: Receiver<Reading>,
+}
+
+/// Connects an audio callback to a display
+pub fn indicators() -> (Tap, Indicators) {
+    let levels = Arc::new(Levels::default());
+    let (sender, receiver) = mpsc::sync_channel(READING_BACKLOG);
+
+    (
+        Tap {
+            levels: levels.clone(),
+            readings: sender,
+        },
+        Indicators {
+            levels,
+            readings: receiver,
+        },
+    )
+}
+
+impl Tap {
+    /// Measures interleaved samples with the given number of channels on their way to the device
+    pub fn monitor<I>(&self, samples: I, channels: u16) -> Monitored<I> {
+        Monitored {
+            samples,
+            tap: self.clone(),
+            channels: usize::from(channels).max(1),
+            index: 0,
+            peak: [0.0; MAX_METERED],
+            squares: [0.0; MAX_METERED],
+        }
+    }
+}
+
+/// Passes the samples going to the device through, counting the ones at full scale and sending a
+/// meter reading every `READING_FRAMES` frames. Nothing here blocks or allocates.
+pub struct Monitored<I> {
+    samples: I,
+    tap: Tap,
+    channels: usize,
+    /// Position in the current reading, in samples
+    index: usize,
+    peak: [f32; MAX_METERED],
+    squares: [f32; MAX_METERED],
+}
+
+impl<I: Iterator<Item = f32>> Iterator for Monitored<I> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        let sample = self.samples.next()?;
+        let level = sample.abs();
+        if level >= 1.0 {
+            self.tap.levels.clips.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let channel = self.index % self.channels;
+        if channel < MAX_METERED {
+            self.peak[channel] = self.peak[channel].max(level);
+            self.squares[channel] += sample * sample;
+        }
+
+        self.index += 1;
+        if self.index == READING_FRAMES * self.channels {
+            let mut rms = [0.0; MAX_METERED];
+            for (rms, squares) in rms.iter_mut().zip(&self.squares) {
+                *rms = (squares / READING_FRAMES as f32).sqrt();
+            }
+
+            let _ = self.tap.readings.try_send(Reading {
+                channels: self.channels.min(MAX_METERED),
+                peak: self.peak,
+                rms,
+            });
+
+            self.index = 0;
+            self.peak = [0.0; MAX_METERED];
+            self.squares = [0.0; MAX_METERED];
+        }
+
+        Some(sample)
+    }
+}
+
+/// Position of a level on a meter, from 0 at the bottom of its range to `METER_WIDTH` at full scale
+fn meter_position(level: f32) -> usize {
+    let db = 20.0 * level.max(f32::MIN_POSITIVE).log10();
+    let fraction = ((db + METER_RANGE) / METER_RANGE).clamp(0.0, 1.0);
+    (fraction * METER_WIDTH as f32).round() as usize
+}
+
+/// A meter filled up to the RMS level, with a mark at the peak
+fn meter(peak: f32, rms: f32) -> String {
+    let filled = meter_position(rms);
+    let peak = meter_position(peak).max(filled);
+
+    (0..METER_WIDTH)
+        .map(|i| {
+            if i < filled {
+                '█'
+            } else if peak > filled && i == peak - 1 {
+                '│'
+            } else {
+                '·'
+            }
+        })
+        .collect()
+}
+
+/// The meters of each channel, then the clip LED, lit in red, and the number of clipped samples
+fn render(levels: &[(f32, f32)], clips: usize, lit: bool) -> String {
+    let mut line = String::new();
+
+    for (i, &(peak, rms)) in levels.iter().enumerate() {
+        let label = match (levels.len(), i) {
+            (2, 0) => "L".to_owned(),
+            (2, 1) => "R".to_owned(),
+            _ => (i + 1).to_string(),
+        };
+        line += &format!("{} {} ", label, meter(peak, rms));
+    }
+
+    if lit {
+        line += &format!("\x1b[31m●\x1b[0m clip {}", clips);
+    } else {
+        line += &format!("○ clip {}", clips);
+    }
+
+    line
+}
+
+/// Redraws the indicators on stderr until `stop` is set, then leaves the clip count up if anything
+/// clipped
+pub fn display(indicators: Indicators, stop: Arc<AtomicBool>) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut seen = 0;
+        let mut lit_until = None;
+        // Peak and RMS of each channel, as displayed
+        let mut levels: Vec<(f32, f32)> = Vec::new();
+
+        loop {
+            let stopping = stop.load(Ordering::Relaxed);
+            let clips = indicators.levels.clips.load(Ordering::Relaxed);
+            if clips > seen {
+                seen = clips;
+                lit_until = Some(Instant::now() + CLIP_HOLD);
+            }
+            let lit = lit_until.is_some_and(|until| Instant::now() < until);
+
+            for (peak, _) in &mut levels {
+                *peak *= PEAK_DECAY;
+            }
+            for reading in indicators.readings.try_iter() {
+                levels.resize(reading.channels, (0.0, 0.0));
+                for (i, (peak, rms)) in levels.iter_mut().enumerate() {
+                    *peak = peak.max(reading.peak[i]);
+                    *rms = reading.rms[i];
+                }
+            }
+
+            let mut stderr = io::stderr().lock();
+            if stopping {
+                let _ = if clips > 0 {
+                    writeln!(stderr, "\r\x1b[K{}", render(&[], clips, false))
+                } else {
+                    write!(stderr, "\r\x1b[K")
+                };
+                break;
+            }
+
+            let _ = write!(stderr, "\r\x1b[K{}", render(&levels, clips, lit));
+            let _ = stderr.flush();
+            drop(stderr);
+            thread::sleep(REFRESH_INTERVAL);
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_monitored() {
+        let (tap, indicators) = indicators();
+
+        // A full scale left channel and a quiet right one
+        let samples: Vec<f32> = (0..READING_FRAMES * 2)
+            .map(|i| if i % 2 == 0 { 1.0 } else { -0.25 })
+            .collect();
+        let passed: Vec<f32> = tap.monitor(samples.iter().copied(), 2).collect();
+        assert_eq!(passed, samples);
+        assert_eq!(
+            indicators.levels.clips.load(Ordering::Relaxed),
+            READING_FRAMES
+        );
+
+        let reading = indicators.readings.try_recv().unwrap();
+        assert_eq!(reading.channels, 2);
+        assert_eq!(reading.peak[..2], [1.0, 0.25]);
+        assert_eq!(reading.rms[..2], [1.0, 0.25]);
+        assert!(indicators.readings.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_meter() {
+        assert_eq!(meter(0.0, 0.0), "················");
+        assert_eq!(meter(1.0, 1.0), "████████████████");
+        // -24 dB RMS fills half, with the peak at -6 dB
+        assert_eq!(meter(0.5, 0.063), "████████·····│··");
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(render(&[], 0, false), "○ clip 0");
+        assert_eq!(
+            render(&[(1.0, 1.0), (0.0, 0.0)], 12, true),
+            "L ████████████████ R ················ \x1b[31m●\x1b[0m clip 12"
+        );
+    }
+}
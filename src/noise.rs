@@ -0,0 +1,331 @@
+use std::{
+    f64::consts::TAU,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use clap::ArgEnum;
+use color_eyre::eyre::{eyre, Report, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    gain::{db_to_gain, parse_decibels},
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stream::{map_channels, Stage},
+    Sound,
+};
+
+/// Level of the noise bed unless one is given, in dB relative to full scale
+pub const DEFAULT_NOISE_LEVEL: f64 = -48.0;
+/// Level of the hum unless one is given, in dB relative to full scale
+pub const DEFAULT_HUM_LEVEL: f64 = -50.0;
+
+/// Levels of the harmonics of the hum relative to its fundamental, from the fundamental up. Mains
+/// hum picked up through a ground loop is rich in harmonics, which make it buzz rather than drone.
+const HUM_HARMONICS: [f64; 5] = [1.0, 0.5, 0.35, 0.2, 0.1];
+
+/// Frames of the impulse response of the coloring filters summed up to find their gain
+const GAIN_FRAMES: usize = 1 << 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum)]
+pub enum NoiseColor {
+    /// The same level at every frequency
+    White,
+    /// 3 dB less per octave, the same level in every octave
+    Pink,
+    /// 6 dB less per octave, a rumble
+    Brown,
+}
+
+/// Noise mixed into the sound as given on the command line, e.g. `pink:-48dB`. The level is its RMS
+/// level in dB relative to full scale, and is optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct NoiseSpec {
+    pub color: NoiseColor,
+    pub level: f64,
+}
+
+impl FromStr for NoiseSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (color, level) = s.split_once(':').unwrap_or((s, ""));
+
+        Ok(Self {
+            color: NoiseColor::from_str(color.trim(), true).map_err(|e| eyre!(e))?,
+            level: match level.trim() {
+                "" => DEFAULT_NOISE_LEVEL,
+                level => parse_decibels(level)?,
+            },
+        })
+    }
+}
+
+impl Display for NoiseSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let color = self.color.to_possible_value().unwrap().get_name();
+        write!(f, "{}:{}dB", color, self.level)
+    }
+}
+
+serde_via_str!(NoiseSpec);
+
+/// Colors white noise, with Paul Kellett's filter for pink noise and a leaky integrator for brown.
+/// Both are tuned for 44.1 kHz, and only roughly right at other rates.
+#[derive(Clone, Copy, Debug)]
+struct Coloring {
+    color: NoiseColor,
+    state: [f64; 7],
+}
+
+impl Coloring {
+    fn new(color: NoiseColor) -> Self {
+        Self {
+            color,
+            state: [0.0; 7],
+        }
+    }
+
+    fn next(&mut self, white: f64) -> f64 {
+        let b = &mut self.state;
+
+        match self.color {
+            NoiseColor::White => white,
+            NoiseColor::Pink => {
+                b[0] = 0.99886 * b[0] + white * 0.0555179;
+                b[1] = 0.99332 * b[1] + white * 0.0750759;
+                b[2] = 0.96900 * b[2] + white * 0.1538520;
+                b[3] = 0.86650 * b[3] + white * 0.3104856;
+                b[4] = 0.55000 * b[4] + white * 0.5329522;
+                b[5] = -0.7616 * b[5] - white * 0.0168980;
+                let pink = b.iter().sum::<f64>() + white * 0.5362;
+                b[6] = white * 0.115926;
+                pink
+            }
+            NoiseColor::Brown => {
+                b[0] = 0.995 * b[0] + white;
+                b[0]
+            }
+        }
+    }
+
+    /// How much the coloring raises the RMS level of white noise
+    fn gain(color: NoiseColor) -> f64 {
+        let mut coloring = Self::new(color);
+
+        (0..GAIN_FRAMES)
+            .map(|i| coloring.next(if i == 0 { 1.0 } else { 0.0 }).powi(2))
+            .sum::<f64>()
+            .sqrt()
+    }
+}
+
+impl NoiseSpec {
+    pub fn stage(self, rng: ChaCha8Rng, clip_mode: ClipMode) -> NoiseStage {
+        NoiseStage {
+            // Uniform noise peaks at √3 times its RMS level
+            amplitude: db_to_gain(self.level) * 3f64.sqrt() / Coloring::gain(self.color)
+                * FULL_SCALE,
+            color: self.color,
+            rng,
+            channels: Vec::new(),
+            clip_mode,
+        }
+    }
+}
+
+/// Mixes colored noise into the sound at a steady level, a noise bed for the KRUSZING to chew on or
+/// to sit under the KRUSZED sound. Each channel gets noise of its own.
+pub struct NoiseStage {
+    /// Peak of the white noise before coloring, in samples
+    amplitude: f64,
+    color: NoiseColor,
+    rng: ChaCha8Rng,
+    channels: Vec<(ChaCha8Rng, Coloring)>,
+    clip_mode: ClipMode,
+}
+
+impl Stage for NoiseStage {
+    fn name(&self) -> &'static str {
+        "noise"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            amplitude,
+            color,
+            rng,
+            channels,
+            clip_mode,
+        } = self;
+        let (amplitude, clip_mode) = (*amplitude, *clip_mode);
+
+        map_channels(
+            chunk,
+            channels,
+            || {
+                (
+                    ChaCha8Rng::from_rng(&mut *rng).unwrap(),
+                    Coloring::new(*color),
+                )
+            },
+            |(rng, coloring), sample| {
+                let noise = coloring.next(amplitude * rng.gen_range(-1.0..=1.0));
+                to_sample(sample as f64 + noise, clip_mode)
+            },
+        )
+    }
+}
+
+/// Mains hum mixed into the sound, like a ground loop in the studio
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Hum {
+    /// Frequency of the mains, usually 50 or 60 Hz
+    pub frequency: f64,
+    /// RMS level in dB relative to full scale
+    pub level: f64,
+}
+
+impl Hum {
+    pub fn stage(self, sample_rate: u32, clip_mode: ClipMode) -> HumStage {
+        let nyquist = sample_rate as f64 / 2.0;
+        let harmonics: Vec<(f64, f64)> = HUM_HARMONICS
+            .iter()
+            .enumerate()
+            .map(|(i, &level)| (self.frequency * (i + 1) as f64, level))
+            .filter(|&(frequency, _)| frequency < nyquist)
+            .collect();
+
+        // Sines peak at √2 times their RMS level
+        let rms = (harmonics
+            .iter()
+            .map(|(_, level)| level * level)
+            .sum::<f64>()
+            / 2.0)
+            .sqrt();
+        let scale = if rms > 0.0 {
+            db_to_gain(self.level) / rms * FULL_SCALE
+        } else {
+            0.0
+        };
+
+        HumStage {
+            harmonics: harmonics
+                .into_iter()
+                .map(|(frequency, level)| (TAU * frequency / sample_rate as f64, level * scale))
+                .collect(),
+            time: 0,
+            clip_mode,
+        }
+    }
+}
+
+/// Adds hum to all channels alike, as it comes in through the wiring they share
+pub struct HumStage {
+    /// Phase increment per frame and amplitude in samples of each harmonic
+    harmonics: Vec<(f64, f64)>,
+    /// Frames processed so far
+    time: u64,
+    clip_mode: ClipMode,
+}
+
+impl Stage for HumStage {
+    fn name(&self) -> &'static str {
+        "hum"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        let frames = chunk.frames();
+        let hum: Vec<f64> = (0..frames as u64)
+            .map(|i| {
+                let t = (self.time + i) as f64;
+                self.harmonics
+                    .iter()
+                    .map(|&(step, amplitude)| amplitude * (step * t).sin())
+                    .sum()
+            })
+            .collect();
+        self.time += frames as u64;
+
+        for channel in &mut chunk.channels {
+            for (sample, hum) in channel.samples.iter_mut().zip(&hum) {
+                *sample = to_sample(*sample as f64 + hum, self.clip_mode);
+            }
+        }
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{gain::rms, Channel};
+
+    fn silence() -> Sound {
+        Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0; 88200]
+                };
+                2
+            ],
+            sample_rate: 44100,
+        }
+    }
+
+    #[test]
+    fn test_parse_noise() {
+        assert_eq!(
+            "Pink:-48dB".parse::<NoiseSpec>().unwrap(),
+            NoiseSpec {
+                color: NoiseColor::Pink,
+                level: -48.0
+            }
+        );
+        assert_eq!(
+            "brown".parse::<NoiseSpec>().unwrap().level,
+            DEFAULT_NOISE_LEVEL
+        );
+        assert!("purple:-40".parse::<NoiseSpec>().is_err());
+        assert!("white:loud".parse::<NoiseSpec>().is_err());
+
+        let spec: NoiseSpec = "white:-60".parse().unwrap();
+        assert_eq!(spec.to_string().parse::<NoiseSpec>().unwrap(), spec);
+    }
+
+    #[test]
+    fn test_noise_levels() {
+        for color in [NoiseColor::White, NoiseColor::Pink, NoiseColor::Brown] {
+            let spec = NoiseSpec {
+                color,
+                level: -30.0,
+            };
+            let noise = spec
+                .stage(ChaCha8Rng::seed_from_u64(0), ClipMode::Clamp)
+                .run(silence());
+
+            let level = 20.0 * rms(&noise).log10();
+            assert!((level + 30.0).abs() < 1.0, "{:?} at {} dB", color, level);
+            assert_ne!(noise.channels[0].samples, noise.channels[1].samples);
+        }
+    }
+
+    #[test]
+    fn test_hum() {
+        let hum = Hum {
+            frequency: 50.0,
+            level: -20.0,
+        }
+        .stage(44100, ClipMode::Clamp)
+        .run(silence());
+
+        let level = 20.0 * rms(&hum).log10();
+        assert!((level + 20.0).abs() < 0.1);
+        assert_eq!(hum.channels[0].samples, hum.channels[1].samples);
+
+        // A cycle of 50 Hz later, it's back where it was
+        assert!((hum.channels[0].samples[100] - hum.channels[0].samples[982]).abs() <= 1);
+    }
+}
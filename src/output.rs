@@ -0,0 +1,1274 @@
+use std::{
+    convert::TryInto,
+    ffi::{OsStr, OsString},
+    fmt::{self, Display, Formatter},
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::RwLock,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+use hound::{SampleFormat, WavSpec, WavWriter};
+
+use crate::{
+    au::AuEncoder,
+    caf::CafEncoder,
+    dfpwm::DfpwmEncoder,
+    gba::{GbaEncoder, GbaLayout},
+    sample::{to_i16, Sample},
+    sds::SdsEncoder,
+    simd,
+    svx::SvxEncoder,
+    voc::VocEncoder,
+    Sound,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Wav,
+    /// Amiga IFF 8SVX
+    Svx,
+    /// Sun/NeXT AU
+    Au,
+    /// Creative Voice
+    Voc,
+    /// Core Audio Format
+    Caf,
+    /// GBA sample, as assembly or binary
+    Gba(GbaLayout),
+    /// MIDI Sample Dump Standard
+    Sds,
+    /// ComputerCraft's 1-bit DFPWM
+    Dfpwm,
+    /// Headerless interleaved PCM
+    Raw,
+    /// A format added with `register_encoder`, by its extension
+    Registered(&'static str),
+}
+
+/// The formats KRUSZ writes out of the box, by extension
+const BUILT_IN_FORMATS: [(&str, &str, OutputFormat); 12] = [
+    ("wav", "WAV", OutputFormat::Wav),
+    ("8svx", "Amiga IFF 8SVX", OutputFormat::Svx),
+    ("iff", "Amiga IFF 8SVX", OutputFormat::Svx),
+    ("au", "Sun/NeXT AU", OutputFormat::Au),
+    ("snd", "Sun/NeXT AU", OutputFormat::Au),
+    ("voc", "Creative Voice", OutputFormat::Voc),
+    ("caf", "Core Audio Format", OutputFormat::Caf),
+    (
+        "s",
+        "GBA sample as assembly",
+        OutputFormat::Gba(GbaLayout::Assembly),
+    ),
+    (
+        "bin",
+        "GBA sample as binary",
+        OutputFormat::Gba(GbaLayout::Binary),
+    ),
+    ("dfpwm", "ComputerCraft's 1-bit DFPWM", OutputFormat::Dfpwm),
+    ("sds", "MIDI Sample Dump Standard", OutputFormat::Sds),
+    (
+        "raw",
+        "Headerless interleaved little endian PCM",
+        OutputFormat::Raw,
+    ),
+];
+
+/// Marks outputs written straight into a named pipe, e.g. `pipe:/tmp/krusz.fifo`
+const PIPE_PREFIX: &str = "pipe:";
+
+/// The named pipe an output is written into, for outputs given as `pipe:` and a path. Pipes are
+/// written as the sound is KRUSZED rather than moved into place once complete, and can't be
+/// seeked, so they only take raw output.
+pub fn pipe_path(path: &Path) -> Option<&Path> {
+    path.to_str()?.strip_prefix(PIPE_PREFIX).map(Path::new)
+}
+
+/// Starts writing a file at the given path, with the given number of channels and sample rate
+pub type CreateEncoder = fn(&Path, usize, u32, OutputOptions) -> Result<Box<dyn Encoder>>;
+
+/// A format added with `register_encoder`
+struct Registration {
+    extension: &'static str,
+    description: &'static str,
+    create: CreateEncoder,
+}
+
+static REGISTERED: RwLock<Vec<Registration>> = RwLock::new(Vec::new());
+
+/// Adds an output format, written to files with the given extension by the encoders `create`
+/// makes, alongside the built-in ones. Fails if a format already has the extension.
+pub fn register_encoder(
+    extension: &'static str,
+    description: &'static str,
+    create: CreateEncoder,
+) -> Result<()> {
+    ensure!(
+        OutputFormat::find(extension).is_none(),
+        "There already is an output format for .{}",
+        extension
+    );
+
+    REGISTERED.write().unwrap().push(Registration {
+        extension,
+        description,
+        create,
+    });
+    Ok(())
+}
+
+/// The extensions and descriptions of every output format, the built-in ones first
+pub fn formats() -> Vec<(&'static str, &'static str)> {
+    BUILT_IN_FORMATS
+        .iter()
+        .map(|&(extension, description, _)| (extension, description))
+        .chain(
+            REGISTERED
+                .read()
+                .unwrap()
+                .iter()
+                .map(|registration| (registration.extension, registration.description)),
+        )
+        .collect()
+}
+
+impl OutputFormat {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let path = pipe_path(path).unwrap_or(path);
+        Self::from_extension(path.extension().and_then(OsStr::to_str).unwrap_or(""))
+    }
+
+    /// The format written to files with an extension, such as `wav`
+    pub fn from_extension(extension: &str) -> Result<Self> {
+        Self::find(extension)
+            .ok_or_else(|| eyre!("Unsupported output format {}", extension.to_lowercase()))
+    }
+
+    /// Bits of each sample the format keeps when stored with `options`, or none for formats added
+    /// with `register_encoder`. μ-law keeps as much as 8 bits, and DFPWM a single one.
+    pub fn stored_bits(self, options: &OutputOptions) -> Option<u8> {
+        Some(match self {
+            Self::Wav | Self::Caf | Self::Raw => options.bits_per_sample as u8,
+            Self::Au if options.mu_law => 8,
+            Self::Au => options.bits_per_sample as u8,
+            Self::Voc if options.bits_per_sample == 8 => 8,
+            Self::Voc => 16,
+            Self::Svx | Self::Gba(_) => 8,
+            Self::Sds => options.significant_bits.clamp(8, 28),
+            Self::Dfpwm => 1,
+            Self::Registered(_) => return None,
+        })
+    }
+
+    fn find(extension: &str) -> Option<Self> {
+        let built_in = BUILT_IN_FORMATS
+            .iter()
+            .find(|(known, _, _)| known.eq_ignore_ascii_case(extension))
+            .map(|&(_, _, format)| format);
+
+        built_in.or_else(|| {
+            REGISTERED
+                .read()
+                .unwrap()
+                .iter()
+                .find(|registration| registration.extension.eq_ignore_ascii_case(extension))
+                .map(|registration| Self::Registered(registration.extension))
+        })
+    }
+}
+
+/// Speaker positions of WAVE_FORMAT_EXTENSIBLE channel masks, in channel order
+const SPEAKERS: [&str; 18] = [
+    "FL", "FR", "FC", "LFE", "BL", "BR", "FLC", "FRC", "BC", "SL", "SR", "TC", "TFL", "TFC", "TFR",
+    "TBL", "TBC", "TBR",
+];
+
+/// Common layouts and their channel masks
+const LAYOUTS: [(&str, u32); 10] = [
+    ("mono", 0x4),
+    ("stereo", 0x3),
+    ("2.1", 0xb),
+    ("3.0", 0x7),
+    ("quad", 0x33),
+    ("4.0", 0x107),
+    ("5.0", 0x37),
+    ("5.1", 0x3f),
+    ("6.1", 0x70f),
+    ("7.1", 0x63f),
+];
+
+/// The speakers each channel of a WAV file is meant for, e.g. `5.1` or `FL,FR,BL,BR`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelLayout {
+    pub mask: u32,
+}
+
+impl ChannelLayout {
+    /// The usual layout for a number of channels, if there is one
+    pub fn default_for(channels: usize) -> Option<Self> {
+        let name = match channels {
+            1 => "mono",
+            2 => "stereo",
+            3 => "3.0",
+            4 => "quad",
+            5 => "5.0",
+            6 => "5.1",
+            7 => "6.1",
+            8 => "7.1",
+            _ => return None,
+        };
+
+        name.parse().ok()
+    }
+
+    pub fn channels(&self) -> usize {
+        self.mask.count_ones() as usize
+    }
+}
+
+impl FromStr for ChannelLayout {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if let Some(&(_, mask)) = LAYOUTS
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(s))
+        {
+            return Ok(Self { mask });
+        }
+
+        let mut mask = 0u32;
+        for speaker in s.split(',') {
+            let bit = SPEAKERS
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(speaker.trim()))
+                .ok_or_else(|| {
+                    eyre!(
+                        "Unknown channel layout or speaker {}. Layouts: {}. Speakers: {}",
+                        speaker,
+                        LAYOUTS.map(|(name, _)| name).join(", "),
+                        SPEAKERS.join(", ")
+                    )
+                })?;
+
+            // WAV files store the channels in the order of the speakers' bits
+            ensure!(
+                mask >> bit == 0,
+                "Speakers must be listed in the order {}",
+                SPEAKERS.join(", ")
+            );
+            mask |= 1 << bit;
+        }
+
+        Ok(Self { mask })
+    }
+}
+
+impl Display for ChannelLayout {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if let Some((name, _)) = LAYOUTS.iter().find(|&&(_, mask)| mask == self.mask) {
+            return f.write_str(name);
+        }
+
+        let speakers: Vec<_> = (0..SPEAKERS.len())
+            .filter(|bit| self.mask & (1 << bit) != 0)
+            .map(|bit| SPEAKERS[bit])
+            .collect();
+        f.write_str(&speakers.join(","))
+    }
+}
+
+serde_via_str!(ChannelLayout);
+
+/// How outputs are stored, for the formats which have a choice
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputOptions {
+    /// Sample size of PCM formats, one of 8, 16, 24 or 32 bits. VOC files store anything above 8
+    /// bits as 16 bits.
+    pub bits_per_sample: u16,
+    /// Layout of WAV files with more than two channels, instead of the usual one for their channel count
+    pub layout: Option<ChannelLayout>,
+    /// Whether the whole sound is marked as a loop, in formats which can store loops
+    pub looped: bool,
+    /// First and last frame of a loop within the sound, in formats which can store one anywhere:
+    /// WAV, in a smpl chunk, and SDS. Takes precedence over `looped`.
+    pub loop_points: Option<(u64, u64)>,
+    /// Whether AU files are stored as 8-bit μ-law rather than linear PCM
+    pub mu_law: bool,
+    /// Whole bits of the KRUSZED bit depth, for formats which can store any sample size
+    pub significant_bits: u8,
+    /// Comment stored in the INFO list (ICMT) of WAV files, such as the settings they were KRUSZED
+    /// with
+    pub comment: Option<String>,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            bits_per_sample: 16,
+            layout: None,
+            looped: false,
+            loop_points: None,
+            mu_law: false,
+            significant_bits: 16,
+            comment: None,
+        }
+    }
+}
+
+/// Writes a file block by block
+pub trait Encoder {
+    fn write(&mut self, block: &Sound) -> Result<()>;
+
+    /// Completes the file once all the blocks are written
+    fn finalize(self: Box<Self>) -> Result<()>;
+}
+
+/// Averages the channels of a block, for formats which can only store mono sounds
+pub fn mix_down(block: &Sound) -> Vec<Sample> {
+    let channels = block.channels.len() as i64;
+
+    (0..block.frames())
+        .map(|frame| {
+            let sum: i64 = block
+                .channels
+                .iter()
+                .map(|channel| i64::from(channel.samples[frame]))
+                .sum();
+            (sum / channels) as Sample
+        })
+        .collect()
+}
+
+/// Starts writing the output at `path` into `temp_path`, in the given format
+fn encoder(
+    path: &Path,
+    temp_path: &Path,
+    format: OutputFormat,
+    channels: usize,
+    sample_rate: u32,
+    options: OutputOptions,
+) -> Result<Box<dyn Encoder>> {
+    Ok(match format {
+        OutputFormat::Wav => Box::new(WavEncoder::create(
+            temp_path,
+            channels,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Svx => Box::new(SvxEncoder::create(
+            temp_path,
+            channels,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Au => Box::new(AuEncoder::create(
+            temp_path,
+            channels,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Voc => Box::new(VocEncoder::create(
+            temp_path,
+            channels,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Caf => Box::new(CafEncoder::create(
+            temp_path,
+            channels,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Gba(layout) => Box::new(GbaEncoder::create(
+            temp_path,
+            path,
+            layout,
+            sample_rate,
+            options,
+        )?),
+        OutputFormat::Dfpwm => Box::new(DfpwmEncoder::create(temp_path, sample_rate)?),
+        OutputFormat::Sds => Box::new(SdsEncoder::create(temp_path, sample_rate, options)?),
+        OutputFormat::Raw => Box::new(RawEncoder::create(temp_path, options)?),
+        OutputFormat::Registered(extension) => {
+            let create = REGISTERED
+                .read()
+                .unwrap()
+                .iter()
+                .find(|registration| registration.extension == extension)
+                .map(|registration| registration.create)
+                .ok_or_else(|| eyre!("Unsupported output format {}", extension))?;
+            create(temp_path, channels, sample_rate, options)?
+        }
+    })
+}
+
+/// Encodes the sound into the given format
+pub fn save(
+    sound: &Sound,
+    path: &Path,
+    format: OutputFormat,
+    options: OutputOptions,
+) -> Result<()> {
+    let write = |temp_path: &Path| {
+        let channels = sound.channels.len();
+        let mut encoder = encoder(
+            path,
+            temp_path,
+            format,
+            channels,
+            sound.sample_rate,
+            options,
+        )?;
+        encoder.write(sound)?;
+        encoder.finalize()
+    };
+
+    match pipe_path(path) {
+        Some(pipe) => write(pipe),
+        None => write_atomically(path, write),
+    }
+}
+
+/// Calls `write` to write the file to a temporary path in the same directory, then renames it into place.
+/// Interrupted or failed writes never leave a partial file at `path`.
+pub fn write_atomically<F: FnOnce(&Path) -> Result<()>>(path: &Path, write: F) -> Result<()> {
+    let temp_path = temp_path(path);
+
+    match write(&temp_path).and_then(|()| Ok(fs::rename(&temp_path, path)?)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp_path);
+            Err(e)
+        }
+    }
+}
+
+fn temp_path(path: &Path) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_else(|| OsStr::new("output")));
+    name.push(format!(".{}.tmp", process::id()));
+
+    path.with_file_name(name)
+}
+
+/// Size in bytes of a sound once saved, found by saving it to a temporary file
+pub fn encoded_size(
+    sound: &Sound,
+    path: &Path,
+    format: OutputFormat,
+    options: OutputOptions,
+) -> Result<u64> {
+    let mut name = OsString::from(path.file_name().unwrap_or_else(|| OsStr::new("output")));
+    name.push(".size");
+    let temp_path = temp_path(&std::env::temp_dir().join(name));
+
+    let size =
+        save(sound, &temp_path, format, options).and_then(|()| Ok(fs::metadata(&temp_path)?.len()));
+    let _ = fs::remove_file(&temp_path);
+    size
+}
+
+/// Parses a size in bytes such as `64KB`, `1.5MB` or `65536`. Kilobytes and megabytes are 1024
+/// bytes and 1024 kilobytes, as with flash and ROM sizes.
+pub fn parse_size(s: &str) -> Result<u64> {
+    let s = s.trim().to_lowercase();
+    let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match s[digits.len()..]
+        .trim_end_matches("ib")
+        .trim_end_matches('b')
+    {
+        "" => 1.0,
+        "k" => 1024.0,
+        "m" => 1024.0 * 1024.0,
+        unit => bail!("Unknown size unit {}, must be B, KB or MB", unit),
+    };
+
+    let size = digits.trim().parse::<f64>()? * multiplier;
+    ensure!(
+        size.is_finite() && size >= 1.0,
+        "Size must be at least a byte"
+    );
+
+    Ok(size as u64)
+}
+
+/// Saves a wavetable, its frames one after the other in a mono sound, as a WAV file with the clm
+/// chunk Serum writes, which Serum, Vital and other wavetable synths read the frame size from
+pub fn save_wavetable(
+    table: &Sound,
+    path: &Path,
+    frame_size: usize,
+    options: OutputOptions,
+) -> Result<()> {
+    write_atomically(path, |temp_path| {
+        save_wav(table, temp_path, options)?;
+        let clm = format!("<!>{} 01000000 wavetable (krusz)", frame_size);
+        append_chunk(temp_path, b"clm ", clm.as_bytes())
+    })
+}
+
+/// Saves a sample chain, one-shots one after the other, as a WAV file with a cue marker at the start
+/// of each, named by its label, which Elektron and volca sample tools slice the chain at
+pub fn save_chain(
+    chain: &Sound,
+    path: &Path,
+    slices: &[(u64, String)],
+    options: OutputOptions,
+) -> Result<()> {
+    write_atomically(path, |temp_path| {
+        save_wav(chain, temp_path, options)?;
+        append_chunk(temp_path, b"cue ", &cue_chunk(slices)?)?;
+        append_labels(temp_path, slices)
+    })
+}
+
+/// Saves the sound as a WAV file. 8-bit files keep only the most significant byte of each sample.
+pub fn save_wav<P: AsRef<Path>>(sound: &Sound, path: P, options: OutputOptions) -> Result<()> {
+    let channels = sound.channels.len();
+    let mut encoder = WavEncoder::create(path.as_ref(), channels, sound.sample_rate, options)?;
+    encoder.write(sound)?;
+    Box::new(encoder).finalize()
+}
+
+struct WavEncoder {
+    writer: WavWriter<BufWriter<File>>,
+    path: PathBuf,
+    channels: usize,
+    sample_rate: u32,
+    options: OutputOptions,
+}
+
+impl WavEncoder {
+    fn create(
+        path: &Path,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        Ok(Self {
+            writer: WavWriter::create(path, wav_spec(channels, sample_rate, &options)?)?,
+            path: path.to_owned(),
+            channels,
+            sample_rate,
+            options,
+        })
+    }
+}
+
+impl Encoder for WavEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        write_wav_samples(&mut self.writer, block, self.options.bits_per_sample)
+    }
+
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.writer.finalize()?;
+        write_channel_mask(&self.path, self.channels, &self.options)?;
+
+        if let Some((start, end)) = self.options.loop_points {
+            append_chunk(
+                &self.path,
+                b"smpl",
+                &smpl_chunk(self.sample_rate, start, end)?,
+            )?;
+        }
+
+        match &self.options.comment {
+            Some(comment) => append_comment(&self.path, comment),
+            None => Ok(()),
+        }
+    }
+}
+
+fn wav_spec(channels: usize, sample_rate: u32, wav: &OutputOptions) -> Result<WavSpec> {
+    ensure!(
+        matches!(wav.bits_per_sample, 8 | 16 | 24 | 32),
+        "Unsupported WAV sample size {}",
+        wav.bits_per_sample
+    );
+
+    if let Some(layout) = wav.layout {
+        ensure!(
+            layout.channels() == channels,
+            "Channel layout {} has {} channels, but the sound has {}",
+            layout,
+            layout.channels(),
+            channels
+        );
+    }
+
+    Ok(WavSpec {
+        channels: channels.try_into()?,
+        sample_rate,
+        bits_per_sample: wav.bits_per_sample,
+        sample_format: SampleFormat::Int,
+    })
+}
+
+/// Offset of dwChannelMask in the WAVE_FORMAT_EXTENSIBLE header hound writes
+const CHANNEL_MASK_OFFSET: u64 = 40;
+
+/// Replaces the channel mask of a finished file. hound writes WAVE_FORMAT_EXTENSIBLE headers for
+/// more than two channels, but always assigns them to the first speakers in order, so quad files
+/// would come out as front left, right, center and LFE.
+fn write_channel_mask(path: &Path, channels: usize, wav: &OutputOptions) -> Result<()> {
+    if channels <= 2 {
+        return Ok(());
+    }
+
+    // No speakers at all is the way to say the channels aren't meant for particular ones
+    let mask = wav
+        .layout
+        .or_else(|| ChannelLayout::default_for(channels))
+        .map_or(0, |layout| layout.mask);
+
+    let mut file = OpenOptions::new().write(true).open(path)?;
+    file.seek(SeekFrom::Start(CHANNEL_MASK_OFFSET))?;
+    file.write_all(&mask.to_le_bytes())?;
+
+    Ok(())
+}
+
+/// Appends a LIST chunk holding an INFO comment (ICMT) to a finished file
+fn append_comment(path: &Path, comment: &str) -> Result<()> {
+    let mut text = comment.as_bytes().to_vec();
+    text.push(0);
+
+    let mut list = b"INFO".to_vec();
+    list.extend_from_slice(b"ICMT");
+    list.extend_from_slice(&u32::try_from(text.len())?.to_le_bytes());
+    list.extend_from_slice(&text);
+    if text.len() % 2 == 1 {
+        list.push(0);
+    }
+
+    append_chunk(path, b"LIST", &list)
+}
+
+/// A cue chunk with a marker at each of the frames, numbered from 1
+fn cue_chunk(points: &[(u64, String)]) -> Result<Vec<u8>> {
+    let mut cue = u32::try_from(points.len())?.to_le_bytes().to_vec();
+    for (id, (frame, _)) in (1u32..).zip(points) {
+        let frame = u32::try_from(*frame)?;
+        // ID, position, the data chunk it's in, chunk and block start, and the sample offset
+        cue.extend_from_slice(&id.to_le_bytes());
+        cue.extend_from_slice(&frame.to_le_bytes());
+        cue.extend_from_slice(b"data");
+        cue.extend_from_slice(&[0; 8]);
+        cue.extend_from_slice(&frame.to_le_bytes());
+    }
+
+    Ok(cue)
+}
+
+/// Appends a LIST chunk holding a label (labl) for each cue marker to a finished file
+fn append_labels(path: &Path, points: &[(u64, String)]) -> Result<()> {
+    let mut list = b"adtl".to_vec();
+    for (id, (_, label)) in (1u32..).zip(points) {
+        let mut text = label.as_bytes().to_vec();
+        text.push(0);
+
+        list.extend_from_slice(b"labl");
+        list.extend_from_slice(&u32::try_from(4 + text.len())?.to_le_bytes());
+        list.extend_from_slice(&id.to_le_bytes());
+        list.extend_from_slice(&text);
+        if text.len() % 2 == 1 {
+            list.push(0);
+        }
+    }
+
+    append_chunk(path, b"LIST", &list)
+}
+
+/// A smpl chunk with a single forward loop from frame `start` to frame `end`, both included, which
+/// plays until the note is released
+fn smpl_chunk(sample_rate: u32, start: u64, end: u64) -> Result<Vec<u8>> {
+    let period = (1e9 / f64::from(sample_rate)).round() as u32;
+
+    // Manufacturer, product, sample period in ns, unity note (middle C), pitch fraction, SMPTE
+    // format and offset, number of loops and extra sampler data, then the loop: ID, type,
+    // start, end, fraction and play count
+    let fields = [
+        0,
+        0,
+        period,
+        60,
+        0,
+        0,
+        0,
+        1,
+        0,
+        0,
+        0,
+        u32::try_from(start)?,
+        u32::try_from(end)?,
+        0,
+        0,
+    ];
+
+    Ok(fields
+        .iter()
+        .flat_map(|field| field.to_le_bytes())
+        .collect())
+}
+
+/// Appends a chunk to a finished file, and makes the RIFF chunk cover it. Readers which don't know
+/// the chunk skip it.
+fn append_chunk(path: &Path, id: &[u8; 4], data: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new().read(true).write(true).open(path)?;
+    // Chunks start at even offsets, after the pad byte of an odd sized data chunk
+    if file.seek(SeekFrom::End(0))? % 2 == 1 {
+        file.write_all(&[0])?;
+    }
+    file.write_all(id)?;
+    file.write_all(&u32::try_from(data.len())?.to_le_bytes())?;
+    file.write_all(data)?;
+
+    let riff_size = u32::try_from(file.stream_position()? - 8)?;
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&riff_size.to_le_bytes())?;
+
+    Ok(())
+}
+
+fn write_wav_samples<W: Write + Seek>(
+    writer: &mut WavWriter<W>,
+    sound: &Sound,
+    bits_per_sample: u16,
+) -> Result<()> {
+    let samples = simd::interleave(&sound.channels);
+
+    match bits_per_sample {
+        8 => {
+            for sample in samples {
+                writer.write_sample((sample >> 24) as i8)?;
+            }
+        }
+        16 => {
+            let mut i16_writer = writer.get_i16_writer(samples.len().try_into()?);
+
+            for sample in samples {
+                i16_writer.write_sample(to_i16(sample));
+            }
+
+            i16_writer.flush()?;
+        }
+        _ => {
+            let shift = 32 - bits_per_sample;
+            for sample in samples {
+                writer.write_sample(sample >> shift)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes headerless interleaved PCM, little endian, with samples of `bits_per_sample` bits. 8-bit
+/// samples are signed, unlike in WAV files.
+struct RawEncoder {
+    writer: BufWriter<File>,
+    bits_per_sample: u16,
+}
+
+impl RawEncoder {
+    fn create(path: &Path, options: OutputOptions) -> Result<Self> {
+        ensure!(
+            matches!(options.bits_per_sample, 8 | 16 | 24 | 32),
+            "Unsupported raw sample size {}",
+            options.bits_per_sample
+        );
+
+        // Named pipes block here until something opens them for reading
+        Ok(Self {
+            writer: BufWriter::new(File::create(path)?),
+            bits_per_sample: options.bits_per_sample,
+        })
+    }
+}
+
+impl Encoder for RawEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        // The most significant bytes of each sample, as with the other PCM formats
+        let bytes = usize::from(self.bits_per_sample / 8);
+
+        for sample in simd::interleave(&block.channels) {
+            self.writer.write_all(&sample.to_le_bytes()[4 - bytes..])?;
+        }
+
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+}
+
+/// Encodes a stream block by block into a temporary file, which `commit` then moves into place.
+/// Dropping the writer without committing removes the temporary file.
+pub struct StreamWriter {
+    encoder: Option<Box<dyn Encoder>>,
+    path: PathBuf,
+    temp_path: PathBuf,
+    /// Whether the output is a named pipe, written into directly
+    piped: bool,
+}
+
+impl StreamWriter {
+    pub fn create(
+        path: &Path,
+        format: OutputFormat,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        let pipe = pipe_path(path);
+        let temp_path = pipe.map_or_else(|| temp_path(path), Path::to_owned);
+        let encoder = encoder(path, &temp_path, format, channels, sample_rate, options)?;
+
+        Ok(Self {
+            encoder: Some(encoder),
+            path: path.to_owned(),
+            temp_path,
+            piped: pipe.is_some(),
+        })
+    }
+
+    pub fn write(&mut self, block: &Sound) -> Result<()> {
+        self.encoder.as_mut().unwrap().write(block)
+    }
+
+    pub fn commit(mut self) -> Result<()> {
+        self.encoder.take().unwrap().finalize()?;
+        if !self.piped {
+            fs::rename(&self.temp_path, &self.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for StreamWriter {
+    fn drop(&mut self) {
+        // The temporary file is gone once committed, so this only cleans up after failures
+        drop(self.encoder.take());
+        if !self.piped {
+            let _ = fs::remove_file(&self.temp_path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{quantize::requantize_sample, Channel};
+
+    /// Writes the number of frames written to it, and nothing else
+    struct CountEncoder {
+        file: File,
+        frames: usize,
+    }
+
+    impl Encoder for CountEncoder {
+        fn write(&mut self, block: &Sound) -> Result<()> {
+            self.frames += block.frames();
+            Ok(())
+        }
+
+        fn finalize(mut self: Box<Self>) -> Result<()> {
+            Ok(write!(self.file, "{}", self.frames)?)
+        }
+    }
+
+    #[test]
+    fn test_register_encoder() {
+        fn create(
+            path: &Path,
+            _channels: usize,
+            _sample_rate: u32,
+            _options: OutputOptions,
+        ) -> Result<Box<dyn Encoder>> {
+            Ok(Box::new(CountEncoder {
+                file: File::create(path)?,
+                frames: 0,
+            }))
+        }
+
+        register_encoder("count", "Frame count", create).unwrap();
+        assert!(register_encoder("WAV", "Not WAV", create).is_err());
+        assert!(formats().contains(&("count", "Frame count")));
+
+        let path = std::env::temp_dir().join("krusz_test_register_encoder.COUNT");
+        let format = OutputFormat::from_path(&path).unwrap();
+        assert_eq!(format, OutputFormat::Registered("count"));
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0; 1234],
+            }],
+            sample_rate: 8000,
+        };
+        save(&sound, &path, format, OutputOptions::default()).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "1234");
+
+        fs::remove_file(path).unwrap();
+        assert!(OutputFormat::from_path(Path::new("out.xyz")).is_err());
+    }
+
+    #[test]
+    fn test_raw_pipe() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0x1234_5678, -1 << 16],
+                },
+                Channel {
+                    samples: vec![i32::MIN, 0x0100_0000],
+                },
+            ],
+            sample_rate: 8000,
+        };
+
+        // Written straight into the path after pipe:, which stays in place if the writer is dropped
+        let path = std::env::temp_dir().join("krusz_test_raw_pipe.fifo");
+        let pipe = PathBuf::from(format!("pipe:{}", path.display()));
+        assert_eq!(pipe_path(&pipe), Some(path.as_path()));
+        assert_eq!(pipe_path(&path), None);
+
+        let format = OutputFormat::from_extension("RAW").unwrap();
+        save(&sound, &pipe, format, OutputOptions::default()).unwrap();
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            [0x34, 0x12, 0x00, 0x80, 0xff, 0xff, 0x00, 0x01]
+        );
+
+        let options = OutputOptions {
+            bits_per_sample: 8,
+            ..OutputOptions::default()
+        };
+        let mut writer = StreamWriter::create(&pipe, format, 2, 8000, options).unwrap();
+        writer.write(&sound).unwrap();
+        drop(writer);
+        assert_eq!(fs::read(&path).unwrap(), [0x12, 0x80, 0xff, 0x01]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_stored_bits() {
+        let packed = OutputOptions {
+            bits_per_sample: 8,
+            significant_bits: 6,
+            ..OutputOptions::default()
+        };
+        assert_eq!(OutputFormat::Wav.stored_bits(&packed), Some(8));
+        assert_eq!(OutputFormat::Sds.stored_bits(&packed), Some(8));
+        assert_eq!(
+            OutputFormat::Svx.stored_bits(&OutputOptions::default()),
+            Some(8)
+        );
+        assert_eq!(
+            OutputFormat::Dfpwm.stored_bits(&OutputOptions::default()),
+            Some(1)
+        );
+
+        let mu_law = OutputOptions {
+            mu_law: true,
+            ..OutputOptions::default()
+        };
+        assert_eq!(OutputFormat::Au.stored_bits(&mu_law), Some(8));
+        assert_eq!(
+            OutputFormat::Au.stored_bits(&OutputOptions::default()),
+            Some(16)
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("64KB").unwrap(), 65536);
+        assert_eq!(parse_size("1.5 MiB").unwrap(), 1572864);
+        assert_eq!(parse_size("512b").unwrap(), 512);
+        assert_eq!(parse_size("1000").unwrap(), 1000);
+        assert!(parse_size("64GB").is_err());
+        assert!(parse_size("0KB").is_err());
+    }
+
+    #[test]
+    fn test_save_wav_packed() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![
+                    requantize_sample(-20000 << 16, 4),
+                    requantize_sample(0, 4),
+                    requantize_sample(20000 << 16, 4),
+                ],
+            }],
+            sample_rate: 44100,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_save_wav_packed.wav");
+        let wav = OutputOptions {
+            bits_per_sample: 8,
+            ..OutputOptions::default()
+        };
+        save_wav(&sound, &path, wav).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 8);
+
+        let samples: Vec<i8> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(
+            samples,
+            sound.channels[0]
+                .samples
+                .iter()
+                .map(|&s| (s >> 24) as i8)
+                .collect::<Vec<_>>()
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_wav_24_bits() {
+        let samples = vec![i32::MIN, -256, 0x1234_5600, i32::MAX];
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: samples.clone(),
+            }],
+            sample_rate: 96000,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_save_wav_24_bits.wav");
+        let wav = OutputOptions {
+            bits_per_sample: 24,
+            ..OutputOptions::default()
+        };
+        save_wav(&sound, &path, wav).unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().bits_per_sample, 24);
+
+        let read: Vec<i32> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(read, samples.iter().map(|&s| s >> 8).collect::<Vec<_>>());
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_wav_comment() {
+        // An odd number of 8-bit samples, so the data chunk needs a pad byte
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0, 1 << 24, -1 << 24],
+            }],
+            sample_rate: 8000,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_save_wav_comment.wav");
+        let wav = OutputOptions {
+            bits_per_sample: 8,
+            comment: Some("KRUSZED by krusz, seed 1: bit_depth=4.0".to_owned()),
+            ..OutputOptions::default()
+        };
+        save_wav(&sound, &path, wav).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize,
+            bytes.len() - 8
+        );
+        assert_eq!(
+            crate::probe::probe(&path).unwrap().tags,
+            [(
+                "ICMT".to_owned(),
+                "KRUSZED by krusz, seed 1: bit_depth=4.0".to_owned()
+            )]
+        );
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        let samples: Vec<i8> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples, [0, 1, -1]);
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_save_wav_loop() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0; 1000],
+            }],
+            sample_rate: 8000,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_save_wav_loop.wav");
+        let wav = OutputOptions {
+            loop_points: Some((200, 799)),
+            comment: Some("looped".to_owned()),
+            ..OutputOptions::default()
+        };
+        save_wav(&sound, &path, wav).unwrap();
+
+        let info = crate::probe::probe(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(info.frames, 1000);
+        assert_eq!(info.loops.len(), 1);
+        assert_eq!((info.loops[0].start, info.loops[0].end), (200, 799));
+        assert_eq!(info.loops[0].play_count, 0);
+        assert_eq!(info.tags.len(), 1);
+    }
+
+    #[test]
+    fn test_save_chain() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0; 300],
+            }],
+            sample_rate: 8000,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_save_chain.wav");
+        let slices = [(0, "kick".to_owned()), (150, "hat".to_owned())];
+        save_chain(&sound, &path, &slices, OutputOptions::default()).unwrap();
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(path).unwrap();
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize,
+            bytes.len() - 8
+        );
+
+        let at = |id: &[u8]| bytes.windows(4).position(|window| window == id).unwrap();
+        let cue = &bytes[at(b"cue ") + 8..];
+        assert_eq!(u32::from_le_bytes(cue[..4].try_into().unwrap()), 2);
+        // The second point: its ID, position, and sample offset within the data chunk
+        let point = &cue[4 + 24..4 + 48];
+        assert_eq!(u32::from_le_bytes(point[..4].try_into().unwrap()), 2);
+        assert_eq!(u32::from_le_bytes(point[4..8].try_into().unwrap()), 150);
+        assert_eq!(&point[8..12], b"data");
+        assert_eq!(u32::from_le_bytes(point[20..24].try_into().unwrap()), 150);
+
+        // The label of the second point, padded to an even size
+        let labels = &bytes[at(b"adtl")..];
+        let labl = &labels[4 + 8 + 4 + 6..];
+        assert_eq!(&labl[..4], b"labl");
+        assert_eq!(u32::from_le_bytes(labl[4..8].try_into().unwrap()), 8);
+        assert_eq!(&labl[8..16], b"\x02\0\0\0hat\0");
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let path = std::env::temp_dir().join("krusz_test_write_atomically.txt");
+        fs::write(&path, "old").unwrap();
+
+        let result = write_atomically(&path, |temp_path| {
+            fs::write(temp_path, "partial")?;
+            bail!("Encoder failed");
+        });
+
+        assert!(result.is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+        assert!(!temp_path(&path).exists());
+
+        write_atomically(&path, |temp_path| Ok(fs::write(temp_path, "new")?)).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        assert!(!temp_path(&path).exists());
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_stream_writer() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![1 << 16, 2 << 16, 3 << 16],
+                },
+                Channel {
+                    samples: vec![-1 << 16, -2 << 16, -3 << 16],
+                },
+            ],
+            sample_rate: 8000,
+        };
+
+        let path = std::env::temp_dir().join("krusz_test_stream_writer.wav");
+
+        let wav = OutputOptions::default();
+        let mut writer =
+            StreamWriter::create(&path, OutputFormat::Wav, 2, 8000, wav.clone()).unwrap();
+        writer.write(&sound).unwrap();
+        drop(writer);
+        assert!(!path.exists());
+        assert!(!temp_path(&path).exists());
+
+        let mut writer = StreamWriter::create(&path, OutputFormat::Wav, 2, 8000, wav).unwrap();
+        writer.write(&sound).unwrap();
+        writer.write(&sound).unwrap();
+        writer.commit().unwrap();
+
+        let mut reader = hound::WavReader::open(&path).unwrap();
+        assert_eq!(reader.spec().sample_rate, 8000);
+        let samples: Vec<i16> = reader.samples().map(Result::unwrap).collect();
+        assert_eq!(samples, vec![1, -1, 2, -2, 3, -3, 1, -1, 2, -2, 3, -3]);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_channel_layout() {
+        assert_eq!("5.1".parse::<ChannelLayout>().unwrap().mask, 0x3f);
+        assert_eq!(
+            "fl,fr,bl,br".parse::<ChannelLayout>().unwrap(),
+            "quad".parse().unwrap()
+        );
+        assert_eq!(
+            "FL,FR,BC".parse::<ChannelLayout>().unwrap().to_string(),
+            "FL,FR,BC"
+        );
+        assert!("FR,FL".parse::<ChannelLayout>().is_err());
+        assert!("7.2".parse::<ChannelLayout>().is_err());
+        assert_eq!(ChannelLayout::default_for(8).unwrap().to_string(), "7.1");
+        assert_eq!(ChannelLayout::default_for(9), None);
+
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0; 4]
+                };
+                4
+            ],
+            sample_rate: 8000,
+        };
+        let path = std::env::temp_dir().join("krusz_test_channel_layout.wav");
+        let mask = |path: &Path| {
+            let header = fs::read(path).unwrap();
+            assert_eq!(header[20..22], [0xfe, 0xff]);
+            u32::from_le_bytes(header[40..44].try_into().unwrap())
+        };
+
+        save_wav(&sound, &path, OutputOptions::default()).unwrap();
+        assert_eq!(mask(&path), 0x33);
+
+        let wav = OutputOptions {
+            layout: Some("4.0".parse().unwrap()),
+            ..OutputOptions::default()
+        };
+        save_wav(&sound, &path, wav).unwrap();
+        assert_eq!(mask(&path), 0x107);
+        assert_eq!(hound::WavReader::open(&path).unwrap().spec().channels, 4);
+
+        let wav = OutputOptions {
+            layout: Some("5.1".parse().unwrap()),
+            ..OutputOptions::default()
+        };
+        assert!(save_wav(&sound, &path, wav).is_err());
+
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,148 @@
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+use krusz::{
+    filter::{FilterKind, FilterSpec},
+    resample::{Decimation, Interpolation},
+    tape::FlutterSpec,
+};
+
+use crate::Opts;
+
+/// RMS level of the carrier of digis, in dB relative to full scale
+const DIGI_CARRIER_LEVEL: f64 = -42.0;
+
+/// Curated chains of settings, each emulating a particular piece of gear
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    /// A worn consumer cassette deck: saturation, wow and flutter, a gentle high end rolloff and hiss
+    Cassette,
+    /// The E-mu SP-1200 drum sampler: 12 bits at 26.04 kHz, played back without interpolation through
+    /// its 4-pole output filter, with records sampled sped up to 45 RPM and tuned back down
+    Sp1200,
+    /// Digis on 8-bit home computers, played by writing 4-bit samples to the volume register of the
+    /// C64's SID or the Atari's POKEY from a timer interrupt. Without a reconstruction filter the
+    /// sample rate whines along as a carrier, with images of the sound around it, and the video
+    /// chip stealing cycles from the CPU makes the timing jitter.
+    Digi,
+}
+
+impl Preset {
+    /// Fills in the settings of the preset which weren't given on the command line, so that any of
+    /// them can be overridden
+    pub fn apply(self, opts: &mut Opts) {
+        match self {
+            Self::Cassette => {
+                // Tape has no aliasing, just a limited bandwidth
+                opts.sample_rate.get_or_insert(32000);
+                opts.interpolation.get_or_insert(Interpolation::Linear);
+                opts.decimate.get_or_insert(Decimation::Fft);
+                opts.waveshape
+                    .get_or_insert_with(|| "tape".parse().expect("tape is a built-in shape"));
+                opts.flutter.get_or_insert_with(FlutterSpec::default);
+                opts.hiss.get_or_insert(-54.0);
+
+                if opts.post_filter.is_empty() {
+                    opts.post_filter.push(FilterSpec {
+                        kind: FilterKind::Lowpass,
+                        frequency: 10000.0,
+                        q: 0.5,
+                        gain: 0.0,
+                    });
+                }
+            }
+            Self::Sp1200 => {
+                opts.sample_rate.get_or_insert(26040);
+                opts.bit_depth.get_or_insert(12.0);
+                opts.interpolation.get_or_insert(Interpolation::Nearest);
+                opts.decimate.get_or_insert(Decimation::Alias);
+                opts.speed.get_or_insert(1.35);
+
+                // The SSM2044 filters on its outputs, wide open
+                if opts.vintage_filter.is_none() {
+                    opts.vintage_filter = Some(12000.0);
+                    opts.vintage_resonance.get_or_insert(0.1);
+                }
+            }
+            Self::Digi => {
+                // The C64's usual rate, a timer period of 125 cycles
+                let rate = *opts.sample_rate.get_or_insert(7882);
+                opts.bit_depth.get_or_insert(4.0);
+                // Held until the next write to the register, in and out
+                opts.interpolation.get_or_insert(Interpolation::Nearest);
+                opts.decimate.get_or_insert(Decimation::Alias);
+                opts.jitter.get_or_insert(0.2);
+
+                // Each write to the register glitches the output, a whine at the sample rate
+                // which nothing filters out
+                if opts.hum.is_none() {
+                    opts.hum = Some(f64::from(rate));
+                    opts.hum_level.get_or_insert(DIGI_CARRIER_LEVEL);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use krusz::noise::Hum;
+
+    use super::*;
+
+    #[test]
+    fn test_cassette() {
+        let mut opts = Opts::parse_from(["krusz", "-i", "in.wav", "--preset", "cassette"]);
+        Preset::Cassette.apply(&mut opts);
+        opts.settings().validate().unwrap();
+        assert_eq!(opts.sample_rate, Some(32000));
+        assert_eq!(opts.post_filter.len(), 1);
+
+        // Options given on the command line win over the preset's
+        let mut opts = Opts::parse_from([
+            "krusz",
+            "-i",
+            "in.wav",
+            "--preset",
+            "cassette",
+            "--hiss=-70dB",
+            "--post-filter",
+            "highshelf:8k,gain=-3",
+        ]);
+        Preset::Cassette.apply(&mut opts);
+        assert_eq!(opts.hiss, Some(-70.0));
+        assert_eq!(opts.post_filter[0].kind, FilterKind::Highshelf);
+        assert_eq!(opts.post_filter.len(), 1);
+    }
+
+    #[test]
+    fn test_sp1200() {
+        let mut opts = Opts::parse_from(["krusz", "-i", "in.wav", "--speed", "1"]);
+        Preset::Sp1200.apply(&mut opts);
+        let settings = opts.settings();
+        settings.validate().unwrap();
+        assert_eq!(settings.bit_depth, 12.0);
+        assert_eq!(settings.capture_rate(), 26040);
+        assert_eq!(settings.vintage_filter, Some((12000.0, 0.1)));
+    }
+
+    #[test]
+    fn test_digi() {
+        let mut opts = Opts::parse_from(["krusz", "-i", "in.wav", "-s", "7990"]);
+        Preset::Digi.apply(&mut opts);
+        let settings = opts.settings();
+        settings.validate().unwrap();
+        assert_eq!(settings.bit_depth, 4.0);
+        assert_eq!(settings.sample_rate, 7990);
+        assert_eq!(settings.interpolation, Interpolation::Nearest);
+        assert_eq!(
+            settings.hum,
+            Some(Hum {
+                frequency: 7990.0,
+                level: DIGI_CARRIER_LEVEL
+            })
+        );
+    }
+}
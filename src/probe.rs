@@ -0,0 +1,431 @@
+use std::{
+    convert::TryInto,
+    fmt::{self, Display, Formatter},
+    fs::File,
+    io::{BufReader, Cursor, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use claxon::FlacReader;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use lewton::inside_ogg::OggStreamReader;
+use rodio::{Decoder, Source as _};
+
+use crate::input::{download, is_url};
+
+/// How a loop of a WAV smpl chunk plays
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LoopKind {
+    Forward,
+    Alternating,
+    Backward,
+    Other(u32),
+}
+
+impl Display for LoopKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Forward => write!(f, "forward"),
+            Self::Alternating => write!(f, "alternating"),
+            Self::Backward => write!(f, "backward"),
+            Self::Other(kind) => write!(f, "type {}", kind),
+        }
+    }
+}
+
+/// A loop of a WAV smpl chunk. Both ends are frame numbers, and the end frame is part of the loop.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Loop {
+    pub kind: LoopKind,
+    pub start: u32,
+    pub end: u32,
+    /// 0 for a loop which plays forever
+    pub play_count: u32,
+}
+
+/// What an input holds, read from its headers without KRUSZING anything
+#[derive(Clone, Debug, PartialEq)]
+pub struct Info {
+    pub container: &'static str,
+    pub codec: String,
+    pub channels: u16,
+    pub sample_rate: u32,
+    /// None for lossy codecs, which have no bit depth of their own
+    pub bits_per_sample: Option<u16>,
+    pub frames: u64,
+    pub loops: Vec<Loop>,
+    /// Tags as the container names them: RIFF INFO IDs for WAV, Vorbis comment fields for FLAC and
+    /// Ogg
+    pub tags: Vec<(String, String)>,
+}
+
+impl Info {
+    /// Duration in seconds
+    pub fn duration(&self) -> f64 {
+        if self.sample_rate == 0 {
+            return 0.0;
+        }
+
+        self.frames as f64 / f64::from(self.sample_rate)
+    }
+}
+
+impl Display for Info {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Container    {}", self.container)?;
+        writeln!(f, "Codec        {}", self.codec)?;
+        writeln!(f, "Channels     {}", self.channels)?;
+        writeln!(f, "Sample rate  {} Hz", self.sample_rate)?;
+        if let Some(bits) = self.bits_per_sample {
+            writeln!(f, "Bit depth    {} bits", bits)?;
+        }
+        writeln!(
+            f,
+            "Duration     {:.3} s ({} frames)",
+            self.duration(),
+            self.frames
+        )?;
+
+        for l in &self.loops {
+            write!(
+                f,
+                "Loop         {}, frames {} to {}",
+                l.kind, l.start, l.end
+            )?;
+            match l.play_count {
+                0 => writeln!(f)?,
+                count => writeln!(f, ", {} times", count)?,
+            }
+        }
+
+        for (key, value) in &self.tags {
+            writeln!(f, "Tag {:<8} {}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Anything the decoders can read from
+trait Reader: Read + Seek + Send + Sync {}
+
+impl<R: Read + Seek + Send + Sync> Reader for R {}
+
+/// A local file, or a downloaded URL, which can be read from the start as many times as needed
+enum Source {
+    File(PathBuf),
+    Memory(Vec<u8>),
+}
+
+impl Source {
+    fn open(&self) -> Result<Box<dyn Reader>> {
+        match self {
+            Self::File(path) => Ok(Box::new(BufReader::new(File::open(path)?))),
+            Self::Memory(data) => Ok(Box::new(Cursor::new(data.clone()))),
+        }
+    }
+}
+
+/// Reads the container, codec and format of a local file or an HTTP(S) URL, along with its loops and
+/// tags. WAV and FLAC files are only read as far as their headers, while MP3 and Ogg Vorbis files
+/// are decoded to count their frames, which their headers don't give.
+pub fn probe(path: &Path) -> Result<Info> {
+    let source = if is_url(path) {
+        Source::Memory(download(path.to_str().unwrap())?)
+    } else {
+        Source::File(path.to_owned())
+    };
+
+    let mut magic = Vec::with_capacity(12);
+    source.open()?.take(12).read_to_end(&mut magic)?;
+
+    if magic.starts_with(b"RIFF") && magic.get(8..12) == Some(b"WAVE") {
+        probe_wav(&mut source.open()?)
+    } else if magic.starts_with(b"fLaC") {
+        probe_flac(&source)
+    } else if magic.starts_with(b"OggS") {
+        probe_ogg(&source)
+    } else if magic.starts_with(b"ID3")
+        || (magic.len() >= 2 && magic[0] == 0xFF && magic[1] & 0xE0 == 0xE0)
+    {
+        let (channels, sample_rate, frames) = count_frames(&source)?;
+        Ok(Info {
+            container: "MP3",
+            codec: "MPEG Layer III".to_owned(),
+            channels,
+            sample_rate,
+            bits_per_sample: None,
+            frames,
+            loops: Vec::new(),
+            tags: Vec::new(),
+        })
+    } else {
+        Err(eyre!("Not a WAV, FLAC, Ogg Vorbis or MP3 file"))
+    }
+}
+
+/// Channels, sample rate and length in frames, by decoding the whole input
+fn count_frames(source: &Source) -> Result<(u16, u32, u64)> {
+    let decoder = Decoder::new(source.open()?)?;
+    let (channels, sample_rate) = (decoder.channels(), decoder.sample_rate());
+    let samples = decoder.count() as u64;
+
+    Ok((channels, sample_rate, samples / u64::from(channels.max(1))))
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn wav_codec(format: u16) -> String {
+    match format {
+        1 => "PCM".to_owned(),
+        2 => "Microsoft ADPCM".to_owned(),
+        3 => "IEEE float".to_owned(),
+        6 => "A-law".to_owned(),
+        7 => "μ-law".to_owned(),
+        0x11 => "IMA ADPCM".to_owned(),
+        0x55 => "MPEG Layer III".to_owned(),
+        format => format!("format 0x{:04X}", format),
+    }
+}
+
+/// The loops of a WAV smpl chunk
+pub fn parse_smpl(chunk: &[u8]) -> Vec<Loop> {
+    if chunk.len() < 36 {
+        return Vec::new();
+    }
+
+    chunk[36..]
+        .chunks_exact(24)
+        .take(u32_at(chunk, 28) as usize)
+        .map(|l| Loop {
+            kind: match u32_at(l, 4) {
+                0 => LoopKind::Forward,
+                1 => LoopKind::Alternating,
+                2 => LoopKind::Backward,
+                kind => LoopKind::Other(kind),
+            },
+            start: u32_at(l, 8),
+            end: u32_at(l, 12),
+            play_count: u32_at(l, 20),
+        })
+        .collect()
+}
+
+/// The tags of a WAV LIST chunk, if it's an INFO list
+fn parse_info(chunk: &[u8]) -> Vec<(String, String)> {
+    let mut tags = Vec::new();
+    if !chunk.starts_with(b"INFO") {
+        return tags;
+    }
+
+    let mut rest = &chunk[4..];
+    while rest.len() >= 8 {
+        let size = (u32_at(rest, 4) as usize).min(rest.len() - 8);
+        let value = String::from_utf8_lossy(&rest[8..8 + size]);
+        tags.push((
+            String::from_utf8_lossy(&rest[..4]).into_owned(),
+            value.trim_end_matches('\0').to_owned(),
+        ));
+
+        // Chunks are padded to an even size
+        rest = &rest[(8 + size + size % 2).min(rest.len())..];
+    }
+
+    tags
+}
+
+/// Walks the chunks of a RIFF WAVE file, reading the small ones and skipping over the sample data
+fn probe_wav<R: Read + Seek + ?Sized>(reader: &mut R) -> Result<Info> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(12))?;
+
+    let mut fmt = None;
+    let mut data_size = None;
+    let mut loops = Vec::new();
+    let mut tags = Vec::new();
+
+    let mut header = [0; 8];
+    while reader.read_exact(&mut header).is_ok() {
+        let position = reader.stream_position()?;
+        // Files written as streams may claim more data than they hold
+        let size = u64::from(u32_at(&header, 4)).min(len - position);
+
+        match &header[..4] {
+            b"data" => data_size = Some(size),
+            id @ (b"fmt " | b"smpl" | b"LIST") => {
+                let mut chunk = vec![0; size as usize];
+                reader.read_exact(&mut chunk)?;
+                match id {
+                    b"fmt " => fmt = Some(chunk),
+                    b"smpl" => loops = parse_smpl(&chunk),
+                    _ => tags.extend(parse_info(&chunk)),
+                }
+            }
+            _ => {}
+        }
+
+        reader.seek(SeekFrom::Start(position + size + size % 2))?;
+    }
+
+    let fmt = fmt
+        .filter(|fmt| fmt.len() >= 16)
+        .ok_or_else(|| eyre!("The WAV file has no fmt chunk"))?;
+    let mut format = u16_at(&fmt, 0);
+    // WAVE_FORMAT_EXTENSIBLE gives the actual format in the first bytes of its subformat GUID
+    if format == 0xFFFE && fmt.len() >= 26 {
+        format = u16_at(&fmt, 24);
+    }
+    let block_align = u64::from(u16_at(&fmt, 12).max(1));
+
+    Ok(Info {
+        container: "WAV",
+        codec: wav_codec(format),
+        channels: u16_at(&fmt, 2),
+        sample_rate: u32_at(&fmt, 4),
+        bits_per_sample: Some(u16_at(&fmt, 14)),
+        frames: data_size.ok_or_else(|| eyre!("The WAV file has no data chunk"))? / block_align,
+        loops,
+        tags,
+    })
+}
+
+fn probe_flac(source: &Source) -> Result<Info> {
+    let reader = FlacReader::new(source.open()?).wrap_err("Could not read the FLAC headers")?;
+    let info = reader.streaminfo();
+
+    let frames = match info.samples {
+        Some(frames) => frames,
+        // The encoder didn't know the length when it wrote the headers
+        None => count_frames(source)?.2,
+    };
+
+    Ok(Info {
+        container: "FLAC",
+        codec: "FLAC".to_owned(),
+        channels: info.channels as u16,
+        sample_rate: info.sample_rate,
+        bits_per_sample: Some(info.bits_per_sample as u16),
+        frames,
+        loops: Vec::new(),
+        tags: reader
+            .tags()
+            .map(|(key, value)| (key.to_owned(), value.to_owned()))
+            .collect(),
+    })
+}
+
+fn probe_ogg(source: &Source) -> Result<Info> {
+    let reader = OggStreamReader::new(source.open()?)
+        .wrap_err("Could not read the Ogg file. Only Vorbis is supported in Ogg")?;
+    let (_, _, frames) = count_frames(source)?;
+
+    Ok(Info {
+        container: "Ogg",
+        codec: "Vorbis".to_owned(),
+        channels: u16::from(reader.ident_hdr.audio_channels),
+        sample_rate: reader.ident_hdr.audio_sample_rate,
+        bits_per_sample: None,
+        frames,
+        loops: Vec::new(),
+        tags: reader.comment_hdr.comment_list,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    #[test]
+    fn test_probe_wav() {
+        let mut fmt = Vec::new();
+        for field in [1u16, 2] {
+            fmt.extend_from_slice(&field.to_le_bytes());
+        }
+        fmt.extend_from_slice(&22050u32.to_le_bytes());
+        fmt.extend_from_slice(&(22050u32 * 4).to_le_bytes());
+        for field in [4u16, 16] {
+            fmt.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let mut smpl = vec![0; 28];
+        for field in [1u32, 0, 0, 1, 10, 99, 0, 3] {
+            smpl.extend_from_slice(&field.to_le_bytes());
+        }
+
+        let mut info = b"INFO".to_vec();
+        info.extend(chunk(b"INAM", b"Kick\0"));
+        info.extend(chunk(b"ISFT", b"krusz\0"));
+
+        let mut wav = b"WAVE".to_vec();
+        wav.extend(chunk(b"fmt ", &fmt));
+        wav.extend(chunk(b"LIST", &info));
+        wav.extend(chunk(b"data", &[0; 400]));
+        wav.extend(chunk(b"smpl", &smpl));
+        let wav = chunk(b"RIFF", &wav);
+
+        assert_eq!(
+            probe_wav(&mut Cursor::new(wav)).unwrap(),
+            Info {
+                container: "WAV",
+                codec: "PCM".to_owned(),
+                channels: 2,
+                sample_rate: 22050,
+                bits_per_sample: Some(16),
+                frames: 100,
+                loops: vec![Loop {
+                    kind: LoopKind::Alternating,
+                    start: 10,
+                    end: 99,
+                    play_count: 3,
+                }],
+                tags: vec![
+                    ("INAM".to_owned(), "Kick".to_owned()),
+                    ("ISFT".to_owned(), "krusz".to_owned()),
+                ],
+            }
+        );
+    }
+
+    #[test]
+    fn test_probe() {
+        let path = std::env::temp_dir().join("krusz_test_probe.wav");
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 24,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for _ in 0..4000 {
+            writer.write_sample(0).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let info = probe(&path).unwrap();
+        assert_eq!((info.channels, info.bits_per_sample), (1, Some(24)));
+        assert_eq!(info.duration(), 0.5);
+        assert!(info
+            .to_string()
+            .contains("Duration     0.500 s (4000 frames)"));
+
+        std::fs::write(&path, b"not a sound").unwrap();
+        assert!(probe(&path).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,143 @@
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::{
+    crusher::Settings,
+    dac::Dac,
+    sample::Sample,
+    simd,
+    stream::{map_channels, Stage, MIN_BLOCK},
+    Sound,
+};
+
+/// Requantizes a stream to the given bit depth. For fractional bit depths, each sample is
+/// randomly quantized to one of the two adjacent whole depths, weighted by the fractional part.
+pub struct Quantizer {
+    lower: u8,
+    upper: u8,
+    p_upper: f64,
+    dac: Option<Dac>,
+    rng: ChaCha8Rng,
+    /// Each channel draws from its own generator, so the draws don't depend on how the stream is chunked
+    channel_rngs: Vec<ChaCha8Rng>,
+}
+
+impl Quantizer {
+    pub fn new(bit_depth: f64, dac: Option<Dac>, rng: ChaCha8Rng) -> Self {
+        Self {
+            lower: bit_depth.floor() as u8,
+            upper: bit_depth.ceil() as u8,
+            p_upper: bit_depth.fract(),
+            dac,
+            rng,
+            channel_rngs: Vec::new(),
+        }
+    }
+}
+
+impl Stage for Quantizer {
+    fn name(&self) -> &'static str {
+        "quantize"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        let Self {
+            lower,
+            upper,
+            p_upper,
+            dac,
+            rng,
+            channel_rngs,
+        } = self;
+
+        if lower == upper {
+            chunk
+                .channels
+                .par_iter_mut()
+                .flat_map(|channel| channel.samples.par_chunks_mut(MIN_BLOCK))
+                .for_each(|block| simd::requantize(block, *lower));
+        } else {
+            chunk = map_channels(
+                chunk,
+                channel_rngs,
+                || ChaCha8Rng::from_seed(rng.gen()),
+                |channel_rng, sample| {
+                    let bit_depth = if channel_rng.gen_bool(*p_upper) {
+                        *upper
+                    } else {
+                        *lower
+                    };
+
+                    requantize_sample(sample, bit_depth)
+                },
+            );
+        }
+
+        if let Some(dac) = dac {
+            chunk
+                .channels
+                .par_iter_mut()
+                .flat_map(|channel| channel.samples.par_iter_mut().with_min_len(MIN_BLOCK))
+                .for_each(|sample| *sample = dac.convert(*sample));
+        }
+
+        chunk
+    }
+
+    /// Follows the bit depth. The DAC keeps the resolution it was created with.
+    fn retune(&mut self, settings: &Settings) {
+        self.lower = settings.bit_depth.floor() as u8;
+        self.upper = settings.bit_depth.ceil() as u8;
+        self.p_upper = settings.bit_depth.fract();
+    }
+}
+
+pub fn requantize_sample(sample: Sample, bit_depth: u8) -> Sample {
+    if bit_depth == 32 {
+        return sample;
+    }
+
+    let hi_mask = !0 << (32 - bit_depth);
+    let lo_mask = !hi_mask;
+
+    let fill: Sample = if sample >= 0 { !0 } else { 0 };
+
+    (sample & hi_mask) | (fill & lo_mask)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_requantize_fractional() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![10; 1000],
+            }],
+            sample_rate: 44100,
+        };
+
+        let crushed = Quantizer::new(7.5, None, ChaCha8Rng::seed_from_u64(0)).run(sound);
+        let samples = &crushed.channels[0].samples;
+
+        assert!(samples.contains(&requantize_sample(10, 7)));
+        assert!(samples.contains(&requantize_sample(10, 8)));
+        assert!(samples
+            .iter()
+            .all(|&s| s == requantize_sample(10, 7) || s == requantize_sample(10, 8)));
+    }
+
+    #[test]
+    fn test_requantize() {
+        assert_eq!(requantize_sample(-1, 1), Sample::MIN);
+        assert_eq!(requantize_sample(0, 1), Sample::MAX);
+        assert_eq!(requantize_sample(10 << 16, 8), (256 << 16) - 1);
+        assert_eq!(requantize_sample(256 << 16, 8), (512 << 16) - 1);
+        assert_eq!(requantize_sample(10, 24), 255);
+        assert_eq!(requantize_sample(-10, 24), -256);
+        assert_eq!(requantize_sample(-10, 32), -10);
+    }
+}
@@ -0,0 +1,265 @@
+use std::{
+    fs,
+    io::{self, BufRead},
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use krusz::{input::is_url, Sound};
+use rand::Rng;
+use rand_chacha::ChaCha8Rng;
+
+use crate::{error::ErrorKind, play, repl::render, Opts};
+
+const HELP: &str = "\
+Entries are KRUSZED and played one after the other. Commands:
+    next, n       Play the next entry
+    previous, p   Play the previous entry
+    skip, s       Drop the current entry from the queue, and play the next one
+    replay, r     Play the current entry again
+    quit          Stop reviewing";
+
+/// How often to check whether the current entry finished playing
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The entries of an M3U playlist: every line but blank ones and #EXTM3U directives or comments.
+/// Relative paths are relative to the playlist's directory.
+pub fn parse_m3u(playlist: &str, dir: &Path) -> Vec<PathBuf> {
+    playlist
+        .lines()
+        .map(|line| line.trim_start_matches('\u{feff}').trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let path = Path::new(line);
+            if is_url(path) || path.is_absolute() {
+                path.to_owned()
+            } else {
+                dir.join(path)
+            }
+        })
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Control {
+    Next,
+    Previous,
+    Skip,
+    Replay,
+    Quit,
+}
+
+impl Control {
+    fn parse(line: &str) -> Option<Self> {
+        match line.trim().to_lowercase().as_str() {
+            "next" | "n" => Some(Self::Next),
+            "previous" | "prev" | "p" => Some(Self::Previous),
+            "skip" | "s" => Some(Self::Skip),
+            "replay" | "r" => Some(Self::Replay),
+            "quit" | "exit" | "q" => Some(Self::Quit),
+            _ => None,
+        }
+    }
+}
+
+/// The entries still in the queue, and which one is playing
+#[derive(Debug)]
+pub struct Queue<T> {
+    entries: Vec<T>,
+    current: usize,
+}
+
+impl<T> Queue<T> {
+    pub fn new(entries: Vec<T>) -> Self {
+        Self {
+            entries,
+            current: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn position(&self) -> usize {
+        self.current
+    }
+
+    pub fn current(&mut self) -> Option<&mut T> {
+        self.entries.get_mut(self.current)
+    }
+
+    /// Moves through the queue. Returns false once it's over, past its end or on Quit.
+    pub fn apply(&mut self, control: Control) -> bool {
+        match control {
+            Control::Next => self.current += 1,
+            Control::Previous => self.current = self.current.saturating_sub(1),
+            Control::Skip => {
+                if self.current < self.entries.len() {
+                    self.entries.remove(self.current);
+                }
+            }
+            Control::Replay => {}
+            Control::Quit => return false,
+        }
+
+        self.current < self.entries.len()
+    }
+}
+
+/// An entry of the playlist, KRUSZED the first time it's reached
+struct Entry {
+    path: PathBuf,
+    seed: u64,
+    crushed: Option<Sound>,
+}
+
+impl Entry {
+    fn crushed(&mut self, opts: &Opts) -> Result<&Sound> {
+        if self.crushed.is_none() {
+            let original = opts.decode(&self.path).wrap_err(ErrorKind::Input)?;
+            self.crushed = Some(render(
+                &original,
+                &opts.settings(),
+                opts.auto_gain,
+                self.seed,
+            )?);
+        }
+
+        Ok(self.crushed.as_ref().unwrap())
+    }
+}
+
+/// Reads stdin on its own thread, so that commands can interrupt playback
+fn read_lines() -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines().map_while(Result::ok) {
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    receiver
+}
+
+/// KRUSZES the entries of the playlist with the settings of `opts` as they come up, and plays
+/// them one after the other, taking commands from stdin. Entries which fail are left out.
+pub fn run(opts: &Opts, playlist: &Path, mut rng: ChaCha8Rng) -> Result<()> {
+    let text = fs::read_to_string(playlist)
+        .wrap_err_with(|| format!("Could not read {}", playlist.display()))
+        .wrap_err(ErrorKind::Input)?;
+    let paths = parse_m3u(&text, playlist.parent().unwrap_or_else(|| Path::new("")));
+    if paths.is_empty() {
+        return Err(eyre!("{} has no entries", playlist.display())).wrap_err(ErrorKind::Input);
+    }
+
+    // Seeds are rolled up front, so each entry sounds the same whichever order they're played in
+    let mut queue = Queue::new(
+        paths
+            .into_iter()
+            .map(|path| Entry {
+                path,
+                seed: rng.gen(),
+                crushed: None,
+            })
+            .collect(),
+    );
+
+    println!("{}", HELP);
+    let device = opts.device_config();
+    let lines = read_lines();
+
+    loop {
+        let (position, len) = (queue.position() + 1, queue.len());
+        let entry = queue.current().unwrap();
+        println!("[{}/{}] {}", position, len, entry.path.display());
+
+        let sound = match entry.crushed(opts) {
+            Ok(sound) => sound,
+            Err(report) => {
+                eprintln!("Error: {:#}", report);
+                if queue.apply(Control::Skip) {
+                    continue;
+                }
+                break;
+            }
+        };
+
+        let (_stream, sink) = play(sound, &device)?;
+        let control = loop {
+            match lines.recv_timeout(POLL_INTERVAL) {
+                Ok(line) if line.trim().is_empty() => {}
+                Ok(line) => match Control::parse(&line) {
+                    Some(control) => break control,
+                    None => println!("{}", HELP),
+                },
+                Err(RecvTimeoutError::Timeout) if sink.empty() => break Control::Next,
+                Err(RecvTimeoutError::Timeout) => {}
+                // Without commands, the rest of the queue simply plays through
+                Err(RecvTimeoutError::Disconnected) => {
+                    sink.sleep_until_end();
+                    break Control::Next;
+                }
+            }
+        };
+
+        if !queue.apply(control) {
+            break;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_m3u() {
+        let playlist = "\
+#EXTM3U
+#EXTINF:12,Artist - Title
+drums/kick.wav
+
+/samples/snare.flac
+https://example.com/hat.wav
+";
+        assert_eq!(
+            parse_m3u(playlist, Path::new("/music")),
+            [
+                PathBuf::from("/music/drums/kick.wav"),
+                PathBuf::from("/samples/snare.flac"),
+                PathBuf::from("https://example.com/hat.wav"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_queue() {
+        let mut queue = Queue::new(vec!['a', 'b', 'c']);
+        assert_eq!(Control::parse(" N "), Some(Control::Next));
+        assert_eq!(Control::parse("louder"), None);
+
+        assert!(queue.apply(Control::Previous));
+        assert_eq!(queue.current(), Some(&mut 'a'));
+        assert!(queue.apply(Control::Next));
+        assert!(queue.apply(Control::Skip));
+        assert_eq!(queue.current(), Some(&mut 'c'));
+        assert!(queue.apply(Control::Previous));
+        assert_eq!(queue.current(), Some(&mut 'a'));
+        assert!(queue.apply(Control::Replay));
+        assert_eq!(queue.len(), 2);
+
+        assert!(queue.apply(Control::Next));
+        assert!(!queue.apply(Control::Next));
+        assert!(!Queue::new(vec!['a']).apply(Control::Quit));
+        assert!(!Queue::new(vec!['a']).apply(Control::Skip));
+    }
+}
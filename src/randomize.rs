@@ -0,0 +1,115 @@
+use rand::{seq::SliceRandom, Rng};
+
+use krusz::{
+    filter::{FilterKind, FilterSpec},
+    resample::Interpolation,
+};
+
+use crate::Opts;
+
+/// Sample rates of classic samplers, consoles and telephony
+const SAMPLE_RATES: [u32; 7] = [4000, 6000, 8000, 11025, 16000, 22050, 32000];
+
+/// Rounds to the given number of decimals, so the printed values are easy to type back in
+fn round(x: f64, decimals: i32) -> f64 {
+    let scale = 10f64.powi(decimals);
+    (x * scale).round() / scale
+}
+
+/// Rolls the dice for every KRUSZING parameter not given on the command line, picking values in
+/// ranges which tend to sound good. Returns the randomized parameters as command line flags.
+pub fn randomize<R: Rng>(opts: &mut Opts, rng: &mut R) -> String {
+    let mut flags = Vec::new();
+
+    if opts.bit_depth.is_none() {
+        let mut bit_depth = rng.gen_range(3..=12) as f64;
+        if rng.gen_bool(0.25) {
+            bit_depth += 0.5;
+        }
+
+        opts.bit_depth = Some(bit_depth);
+        flags.push(format!("--bit-depth {}", bit_depth));
+    }
+
+    if opts.sample_rate.is_none() {
+        let sample_rate = *SAMPLE_RATES.choose(rng).unwrap();
+        opts.sample_rate = Some(sample_rate);
+        flags.push(format!("--sample-rate {}", sample_rate));
+    }
+
+    if opts.interpolation.is_none() {
+        let interpolation = if rng.gen() {
+            Interpolation::Nearest
+        } else {
+            Interpolation::Linear
+        };
+        opts.interpolation = Some(interpolation);
+        flags.push(format!("--interpolation {:?}", interpolation).to_lowercase());
+    }
+
+    if opts.jitter.is_none() && rng.gen_bool(0.5) {
+        let jitter = round(rng.gen_range(0.05..0.5), 2);
+        opts.jitter = Some(jitter);
+        flags.push(format!("--jitter {}", jitter));
+    }
+
+    if opts.dac_error.is_none() && rng.gen_bool(0.3) {
+        let dac_error = round(rng.gen_range(0.5..5.0), 1);
+        opts.dac_error = Some(dac_error);
+        flags.push(format!("--dac-error {}", dac_error));
+    }
+
+    if opts.filter.is_empty() && rng.gen_bool(0.3) {
+        let frequency = round(rng.gen_range(40.0..200.0), -1);
+        opts.filter.push(FilterSpec {
+            kind: FilterKind::Highpass,
+            frequency,
+            q: std::f64::consts::FRAC_1_SQRT_2,
+            gain: 0.0,
+        });
+        flags.push(format!("--filter highpass:{}", frequency));
+    }
+
+    if opts.vintage_filter.is_none() && rng.gen_bool(0.5) {
+        // Somewhere below the KRUSZED Nyquist frequency, where it tames the harshest aliasing
+        let nyquist = opts.sample_rate.unwrap_or(44100) as f64 / 2.0;
+        let cutoff = round(nyquist * rng.gen_range(0.6..1.0), -2);
+        let resonance = round(rng.gen_range(0.0..0.7), 1);
+
+        opts.vintage_filter = Some(cutoff);
+        opts.vintage_resonance = Some(resonance);
+        flags.push(format!(
+            "--vintage-filter {} --vintage-resonance {}",
+            cutoff, resonance
+        ));
+    }
+
+    flags.join(" ")
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    #[test]
+    fn test_randomize() {
+        for seed in 0..50 {
+            let mut opts = Opts::parse_from(["krusz", "-i", "in.wav", "-p", "--randomize"]);
+            let flags = randomize(&mut opts, &mut ChaCha8Rng::seed_from_u64(seed));
+            opts.settings().validate().unwrap();
+
+            let args = format!("krusz -i in.wav -p {}", flags);
+            let parsed = Opts::parse_from(args.split_whitespace());
+            assert_eq!(parsed.settings(), opts.settings());
+        }
+
+        let mut opts = Opts::parse_from(["krusz", "-i", "in.wav", "-p", "-b", "5", "--randomize"]);
+        randomize(&mut opts, &mut ChaCha8Rng::seed_from_u64(0));
+        assert_eq!(opts.bit_depth, Some(5.0));
+        assert!(opts.sample_rate.is_some());
+    }
+}
@@ -0,0 +1,636 @@
+use std::{
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+use num::Complex;
+
+use crate::{
+    echo::parse_duration,
+    resample::{Interpolation, Resampler},
+    sample::{to_sample, ClipMode},
+    stft::fft,
+    stream::Stage,
+    Channel, Sound,
+};
+
+/// A loop to make out of the KRUSZED sound, as given on the command line, e.g.
+/// `start=1.2s,end=2.4s,xfade=50ms`. All times are in seconds, and the crossfade is optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoopSpec {
+    pub start: f64,
+    /// Where the loop ends and jumps back to the start
+    pub end: f64,
+    pub xfade: f64,
+}
+
+/// Default length of the crossfade across the seam of a loop, in seconds
+const LOOP_XFADE: f64 = 0.05;
+
+impl LoopSpec {
+    /// The first and last frame of the loop at the given sample rate, as in WAV smpl chunks
+    pub fn frames(&self, sample_rate: u32) -> (u64, u64) {
+        let frame = |time: f64| (time * sample_rate as f64).round() as u64;
+        let start = frame(self.start);
+
+        (start, frame(self.end).saturating_sub(1).max(start))
+    }
+}
+
+impl FromStr for LoopSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (mut start, mut end) = (None, None);
+        let mut xfade = LOOP_XFADE;
+
+        for param in s.split(',') {
+            let (key, value) = param
+                .split_once('=')
+                .ok_or_else(|| eyre!("Loop parameter {} must be of the form key=value", param))?;
+
+            match key.trim().to_lowercase().as_str() {
+                "start" => start = Some(parse_duration(value)?),
+                "end" => end = Some(parse_duration(value)?),
+                "xfade" => xfade = parse_duration(value)?,
+                other => bail!("Unknown loop parameter {}", other),
+            }
+        }
+
+        let (start, end) = start.zip(end).ok_or_else(|| {
+            eyre!("Loop must be of the form start=<time>,end=<time>[,xfade=<time>]")
+        })?;
+
+        ensure!(
+            start >= 0.0 && end > start,
+            "The loop must start at or after 0 and end after it starts"
+        );
+        ensure!(xfade >= 0.0, "Loop crossfade must not be negative");
+
+        Ok(Self { start, end, xfade })
+    }
+}
+
+impl Display for LoopSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "start={}s,end={}s,xfade={}ms",
+            self.start,
+            self.end,
+            self.xfade * 1000.0
+        )
+    }
+}
+
+serde_via_str!(LoopSpec);
+
+/// Crossfades the end of a loop into the sound just before its start, so that the jump from its
+/// last frame back to its first carries on smoothly instead of clicking. `start` and `end` are the
+/// first and last frame of the loop. The crossfade is shortened to fit within the loop and the
+/// sound before it.
+pub fn crossfade_loop(mut sound: Sound, start: usize, end: usize, xfade: usize) -> Sound {
+    let xfade = xfade.min(start).min(end + 1 - start);
+
+    for channel in &mut sound.channels {
+        let samples = &mut channel.samples;
+
+        for i in 0..xfade {
+            // Reaches the sound just before the start on the last frame, which the start follows
+            let weight = (i + 1) as f64 / xfade as f64;
+            let (tail, lead_in) = (end + 1 - xfade + i, start - xfade + i);
+            let (tail_sample, lead_in_sample) = (samples[tail] as f64, samples[lead_in] as f64);
+
+            // Fading between two samples never leaves their range
+            samples[tail] = to_sample(
+                tail_sample + (lead_in_sample - tail_sample) * weight,
+                ClipMode::Clamp,
+            );
+        }
+    }
+
+    sound
+}
+
+/// Time over which the KRUSZED loop fades in and out of the untouched sound around it, in seconds
+pub const CROSSFADE: f64 = 0.01;
+
+/// Puts the KRUSZED loop of `crushed` into the untouched `original`, crossfading just outside the
+/// loop so that the loop itself is KRUSZED throughout and still loops seamlessly. `start` and `end`
+/// are frames of the original, and the end frame is part of the loop as in WAV smpl chunks. The
+/// original is resampled to the KRUSZED sample rate if they differ, and the result is as long as
+/// the original.
+pub fn splice_loop(original: &Sound, crushed: Sound, start: usize, end: usize) -> Sound {
+    let ratio = crushed.sample_rate as f64 / original.sample_rate.max(1) as f64;
+    let start = (start as f64 * ratio).round() as usize;
+    let end = (end as f64 * ratio).round() as usize;
+    let fade = (CROSSFADE * crushed.sample_rate as f64).round().max(1.0);
+
+    // How much of the KRUSZED sound is heard at each frame
+    splice(original, crushed, false, |i| {
+        if i < start {
+            (1.0 - (start - i) as f64 / fade).max(0.0)
+        } else if i > end {
+            (1.0 - (i - end) as f64 / fade).max(0.0)
+        } else {
+            1.0
+        }
+    })
+}
+
+/// Puts the untouched `original` back into the KRUSZED sound within each of the regions, crossfading
+/// just outside them so that they're untouched throughout. The inverse of [`splice_loop`], with the
+/// regions in seconds. The result is as long as the longer of the two.
+pub fn splice_bypass(original: &Sound, crushed: Sound, regions: &Regions) -> Sound {
+    let frames: Vec<(usize, usize)> = regions
+        .0
+        .iter()
+        .map(|region| {
+            let (start, end) = region.frames(crushed.sample_rate);
+            (start as usize, end as usize)
+        })
+        .collect();
+    let fade = (CROSSFADE * crushed.sample_rate as f64).round().max(1.0);
+
+    splice(original, crushed, true, |i| {
+        frames
+            .iter()
+            .map(|&(start, end)| {
+                if i < start {
+                    (start - i) as f64 / fade
+                } else if i > end {
+                    (i - end) as f64 / fade
+                } else {
+                    0.0
+                }
+            })
+            .fold(1.0, f64::min)
+    })
+}
+
+/// Mixes `crushed` into `original` by `weight`, how much of the KRUSZED sound is heard at each frame
+/// from 0 to 1. The original is resampled to the KRUSZED sample rate if they differ. The result is
+/// as long as the original, or as the longer of the two with `whole`, frames past the end of the
+/// KRUSZED sound being left as in the original and the original being silent past its end.
+fn splice(original: &Sound, crushed: Sound, whole: bool, weight: impl Fn(usize) -> f64) -> Sound {
+    if crushed.channels.is_empty() {
+        return crushed;
+    }
+
+    let original = if original.sample_rate == crushed.sample_rate {
+        original.clone()
+    } else {
+        Resampler::new(
+            original.sample_rate,
+            crushed.sample_rate,
+            Interpolation::Linear,
+        )
+        .run(original.clone())
+    };
+    let frames = if whole {
+        original.frames().max(crushed.frames())
+    } else {
+        original.frames()
+    };
+
+    let channels = original
+        .channels
+        .iter()
+        .enumerate()
+        .map(|(c, channel)| {
+            // Extra original channels take the last KRUSZED one, should the pipeline have dropped some
+            let wet = &crushed.channels[c.min(crushed.channels.len() - 1)].samples;
+
+            Channel {
+                samples: (0..frames)
+                    .map(|i| {
+                        let dry = channel.samples.get(i).copied().unwrap_or(0);
+                        match (weight(i).min(1.0), wet.get(i)) {
+                            (weight, Some(&wet)) if weight > 0.0 => to_sample(
+                                dry as f64 + (wet as f64 - dry as f64) * weight,
+                                ClipMode::Clamp,
+                            ),
+                            _ => dry,
+                        }
+                    })
+                    .collect(),
+            }
+        })
+        .collect();
+
+    Sound {
+        channels,
+        sample_rate: crushed.sample_rate,
+    }
+}
+
+/// Shortest loop worth finding, in seconds
+const MIN_LOOP: f64 = 0.1;
+/// Most frames the autocorrelation is computed over, the sound being averaged down to fit
+const MAX_ANALYSIS: usize = 1 << 20;
+/// How much less alike than the best loop length a longer one may be and still be preferred
+const LOOP_TOLERANCE: f64 = 0.05;
+/// Correlation below which a sound hardly repeats, so that a loop found in it is likely to be heard
+pub const LOOP_CORRELATION: f64 = 0.5;
+/// Frames leading up to the end of a loop which are compared with those leading up to its start
+const SEAM: usize = 256;
+
+/// A loop found in a sound, in frames, with the end frame part of the loop as in WAV smpl chunks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DetectedLoop {
+    pub start: usize,
+    pub end: usize,
+    /// How alike the sound is one loop apart near the end, from 1 when it repeats exactly
+    pub correlation: f64,
+}
+
+/// Finds the length over which a sound repeats by autocorrelation, then places a loop of that
+/// length at the end of the sound, where the frames leading up to its end best match those leading
+/// up to its start. The longest length about as alike as the best one is chosen, so that a
+/// musical loop spans whole bars rather than a single note, and the sound must repeat at least
+/// twice. `None` for sounds too short for a loop.
+pub fn detect_loop(sound: &Sound) -> Option<DetectedLoop> {
+    let frames = sound.frames();
+    let mono: Vec<f64> = (0..frames)
+        .map(|i| {
+            sound
+                .channels
+                .iter()
+                .map(|channel| channel.samples[i] as f64)
+                .sum()
+        })
+        .collect();
+
+    // The autocorrelation of the sound averaged down, around its mean
+    let step = frames.div_ceil(MAX_ANALYSIS).max(1);
+    let mut averaged: Vec<f64> = mono
+        .chunks(step)
+        .map(|chunk| chunk.iter().sum::<f64>() / chunk.len() as f64)
+        .collect();
+    let mean = averaged.iter().sum::<f64>() / averaged.len().max(1) as f64;
+    averaged.iter_mut().for_each(|x| *x -= mean);
+
+    let len = averaged.len();
+    let shortest = ((MIN_LOOP * sound.sample_rate as f64 / step as f64).ceil() as usize).max(1);
+    if len / 2 <= shortest {
+        return None;
+    }
+
+    let mut spectrum: Vec<Complex<f64>> = averaged
+        .iter()
+        .map(|&x| Complex::new(x, 0.0))
+        .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+        .take((2 * len).next_power_of_two())
+        .collect();
+    fft(&mut spectrum, false);
+    spectrum
+        .iter_mut()
+        .for_each(|bin| *bin = bin.norm_sqr().into());
+    fft(&mut spectrum, true);
+
+    // Energy of the frames before each one, to normalize over the overlap at each lag
+    let mut energy = vec![0.0; len + 1];
+    for (i, x) in averaged.iter().enumerate() {
+        energy[i + 1] = energy[i] + x * x;
+    }
+    let similarity: Vec<f64> = (0..=len / 2 + 1)
+        .map(|lag| {
+            let (head, tail) = (energy[len - lag], energy[len] - energy[lag]);
+            let norm = (head * tail).sqrt();
+            if norm > 0.0 {
+                spectrum[lag].re / norm
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let peaks: Vec<usize> = (shortest..=len / 2)
+        .filter(|&lag| {
+            similarity[lag] >= similarity[lag - 1] && similarity[lag] >= similarity[lag + 1]
+        })
+        .collect();
+    let best = peaks
+        .iter()
+        .map(|&lag| similarity[lag])
+        .fold(f64::NEG_INFINITY, f64::max);
+    let lag = peaks
+        .into_iter()
+        .rev()
+        .find(|&lag| similarity[lag] >= best - LOOP_TOLERANCE)
+        .unwrap_or(shortest);
+
+    // Refined at full resolution against the last second one loop length earlier
+    let window = sound.sample_rate.max(1) as usize;
+    let candidates = (lag * step).saturating_sub(step).max(1)..=(lag * step + step).min(frames / 2);
+    let (length, correlation) = candidates
+        .map(|length| {
+            let window = window.min(frames - length);
+            let (earlier, later) = (
+                &mono[frames - length - window..frames - length],
+                &mono[frames - window..],
+            );
+            (length, correlation(earlier, later))
+        })
+        .fold((lag * step, f64::NEG_INFINITY), |best, candidate| {
+            if candidate.1 > best.1 {
+                candidate
+            } else {
+                best
+            }
+        });
+
+    // The end within the last tenth of a second, or the last loop if shorter, with the best seam
+    let search = (window / 10).min(length - 1);
+    let seam = SEAM.min(frames - length);
+    let end = (frames - 1 - search..frames)
+        .min_by(|&a, &b| {
+            let mismatch = |end: usize| {
+                (0..seam)
+                    .map(|k| (mono[end - k] - mono[end - length - k]).powi(2))
+                    .sum::<f64>()
+            };
+            mismatch(a).total_cmp(&mismatch(b))
+        })
+        .unwrap();
+
+    Some(DetectedLoop {
+        start: end + 1 - length,
+        end,
+        correlation,
+    })
+}
+
+/// Normalized correlation of two equally long stretches of a sound
+fn correlation(a: &[f64], b: &[f64]) -> f64 {
+    let (mean_a, mean_b) = (
+        a.iter().sum::<f64>() / a.len() as f64,
+        b.iter().sum::<f64>() / b.len() as f64,
+    );
+    let (mut product, mut energy_a, mut energy_b) = (0.0, 0.0, 0.0);
+    for (&a, &b) in a.iter().zip(b) {
+        let (a, b) = (a - mean_a, b - mean_b);
+        product += a * b;
+        energy_a += a * a;
+        energy_b += b * b;
+    }
+
+    let norm = (energy_a * energy_b).sqrt();
+    if norm > 0.0 {
+        product / norm
+    } else {
+        1.0
+    }
+}
+
+/// A stretch of time, in seconds
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub start: f64,
+    pub end: f64,
+}
+
+impl Region {
+    /// The first and last frame of the region at the given sample rate
+    pub fn frames(&self, sample_rate: u32) -> (u64, u64) {
+        LoopSpec {
+            start: self.start,
+            end: self.end,
+            xfade: 0.0,
+        }
+        .frames(sample_rate)
+    }
+}
+
+/// Regions of a sound to leave untouched, as given on the command line, e.g. `0-0.5s,3.2s-4.0s`.
+/// A start without a unit takes the unit of its end.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Regions(pub Vec<Region>);
+
+impl FromStr for Regions {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let regions = s
+            .split(',')
+            .map(|region| {
+                let (start, end) = region.split_once('-').ok_or_else(|| {
+                    eyre!("Region {} must be of the form <start>-<end>", region.trim())
+                })?;
+
+                let (start, end) = (start.trim(), end.trim());
+                let start = if start.ends_with(|c: char| c.is_ascii_digit() || c == '.') {
+                    let unit = end.trim_start_matches(|c: char| !c.is_ascii_alphabetic());
+                    parse_duration(&format!("{}{}", start, unit))?
+                } else {
+                    parse_duration(start)?
+                };
+                let end = parse_duration(end)?;
+
+                ensure!(
+                    start >= 0.0 && end > start,
+                    "Region {} must start at or after 0 and end after it starts",
+                    region.trim()
+                );
+
+                Ok(Region { start, end })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self(regions))
+    }
+}
+
+impl Display for Regions {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        for (i, region) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}s-{}s", region.start, region.end)?;
+        }
+
+        Ok(())
+    }
+}
+
+serde_via_str!(Regions);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn constant(value: i32, frames: usize, sample_rate: u32) -> Sound {
+        Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![value; frames],
+                };
+                2
+            ],
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn test_splice_loop() {
+        let spliced = splice_loop(
+            &constant(0, 10000, 1000),
+            constant(1000, 10000, 1000),
+            2000,
+            5999,
+        );
+        let samples = &spliced.channels[1].samples;
+
+        assert_eq!(spliced.frames(), 10000);
+        assert!(samples[..1991].iter().all(|&s| s == 0));
+        assert!(samples[2000..6000].iter().all(|&s| s == 1000));
+        assert!(samples[6010..].iter().all(|&s| s == 0));
+        // Crossfaded on either side of the loop
+        assert_eq!(samples[1995], 500);
+        assert_eq!(samples[6004], 500);
+    }
+
+    #[test]
+    fn test_loop_spec() {
+        let spec: LoopSpec = "start=1.2s,end=2.4s,xfade=50ms".parse().unwrap();
+        assert_eq!(
+            spec,
+            LoopSpec {
+                start: 1.2,
+                end: 2.4,
+                xfade: 0.05
+            }
+        );
+        assert_eq!(spec.frames(1000), (1200, 2399));
+        assert_eq!(spec.to_string().parse::<LoopSpec>().unwrap(), spec);
+
+        assert_eq!("start=0s,end=1s".parse::<LoopSpec>().unwrap().xfade, 0.05);
+        assert!("start=2s,end=1s".parse::<LoopSpec>().is_err());
+        assert!("end=1s".parse::<LoopSpec>().is_err());
+    }
+
+    #[test]
+    fn test_crossfade_loop() {
+        let ramp = Sound {
+            channels: vec![Channel {
+                samples: (0..100).map(|i| i * 1000).collect(),
+            }],
+            sample_rate: 1000,
+        };
+        let looped = crossfade_loop(ramp, 40, 79, 10);
+        let samples = &looped.channels[0].samples;
+
+        // Untouched up to the crossfade, then heading for the frame before the start
+        assert_eq!(
+            samples[..70],
+            (0..70).map(|i| i * 1000).collect::<Vec<_>>()[..]
+        );
+        assert_eq!(samples[79], 39000);
+        assert_eq!(samples[74], 54000);
+        assert_eq!(samples[80], 80000);
+    }
+
+    #[test]
+    fn test_splice_bypass() {
+        let regions: Regions = "0-1s,5s-6s".parse().unwrap();
+        let spliced = splice_bypass(
+            &constant(0, 10000, 1000),
+            constant(1000, 12000, 1000),
+            &regions,
+        );
+        let samples = &spliced.channels[0].samples;
+
+        // As long as the longer KRUSZED sound, untouched within the regions
+        assert_eq!(spliced.frames(), 12000);
+        assert!(samples[..1000].iter().all(|&s| s == 0));
+        assert!(samples[1010..4990].iter().all(|&s| s == 1000));
+        assert!(samples[5000..6000].iter().all(|&s| s == 0));
+        assert!(samples[6010..].iter().all(|&s| s == 1000));
+        // Crossfaded on either side of the regions
+        assert_eq!(samples[1004], 500);
+        assert_eq!(samples[4995], 500);
+    }
+
+    #[test]
+    fn test_regions() {
+        let regions: Regions = "0-0.5s, 3.2s-4.0s,100-250ms".parse().unwrap();
+        assert_eq!(
+            regions.0,
+            [
+                Region {
+                    start: 0.0,
+                    end: 0.5
+                },
+                Region {
+                    start: 3.2,
+                    end: 4.0
+                },
+                Region {
+                    start: 0.1,
+                    end: 0.25
+                },
+            ]
+        );
+        assert_eq!(regions.0[1].frames(1000), (3200, 3999));
+        assert_eq!(regions.to_string().parse::<Regions>().unwrap(), regions);
+
+        assert!("2s-1s".parse::<Regions>().is_err());
+        assert!("1s".parse::<Regions>().is_err());
+    }
+
+    #[test]
+    fn test_detect_loop() {
+        // Noise repeating every 1000 frames, for 4.5 repeats
+        let mut state = 1u32;
+        let pattern: Vec<i32> = (0..1000)
+            .map(|_| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                (state >> 8) as i32 - (1 << 23)
+            })
+            .collect();
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: pattern.iter().copied().cycle().take(4500).collect(),
+            }],
+            sample_rate: 10000,
+        };
+
+        let found = detect_loop(&sound).unwrap();
+        let length = found.end + 1 - found.start;
+        // The longest whole number of repeats which still repeats twice
+        assert_eq!(length, 2000);
+        assert!(found.correlation > 0.999);
+        assert!(found.end < 4500);
+        // The frame before the start is the last frame of the loop, so the jump is seamless
+        let samples = &sound.channels[0].samples;
+        assert_eq!(samples[found.start - 1], samples[found.end]);
+
+        assert!(detect_loop(&Sound {
+            channels: vec![Channel {
+                samples: vec![0; 1000]
+            }],
+            sample_rate: 10000
+        })
+        .is_none());
+    }
+
+    #[test]
+    fn test_splice_loop_resampled() {
+        // The KRUSZED sound was left at a lower rate, so the loop points are moved to match
+        let spliced = splice_loop(
+            &constant(0, 4000, 4000),
+            constant(1000, 2000, 2000),
+            2000,
+            3999,
+        );
+
+        assert_eq!(spliced.sample_rate, 2000);
+        assert_eq!(spliced.frames(), 2000);
+        assert_eq!(spliced.channels[0].samples[980], 0);
+        assert!(spliced.channels[0].samples[1000..]
+            .iter()
+            .all(|&s| s == 1000));
+    }
+}
@@ -0,0 +1,257 @@
+use std::{
+    io::{self, BufRead, Write},
+    path::Path,
+};
+
+use clap::ArgEnum;
+use color_eyre::eyre::{bail, eyre, Result, WrapErr};
+use krusz::{
+    crusher::Settings,
+    gain::{apply_gain, makeup_gain, parse_decibels, rms},
+    output::{save, OutputFormat, OutputOptions},
+    resample::Interpolation,
+    stereo::parse_percent,
+    stream::Stage,
+    Sound,
+};
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use tracing::debug_span;
+
+use crate::{device::DeviceConfig, error::ErrorKind, play, Opts};
+
+const HELP: &str = "\
+Commands:
+    input-gain <dB>                Set the gain applied to the input, e.g. -6dB, or \"off\"
+    bits <bit-depth>               Set the target bit depth
+    rate <sample-rate>             Set the target sample rate
+    interpolation <interpolation>  Set the interpolation method: nearest or linear
+    jitter <jitter>                Set the sample clock jitter, in sample periods
+    dac <dac-error>                Set the DAC error, in percent, or \"off\"
+    width <width>                  Set the stereo width, e.g. 150%, or \"off\"
+    auto-gain <on|off>             Match the level of the KRUSZED sound to the input's
+    show                           Show the current settings
+    play                           Play the KRUSZED sound
+    ab                             Play the original sound, then the KRUSZED one
+    save <output>                  Save the KRUSZED sound
+    help                           Show this help
+    quit                           Leave";
+
+/// An interactive session on a single input, which is only decoded once
+pub struct Repl {
+    original: Sound,
+    settings: Settings,
+    auto_gain: bool,
+    seed: u64,
+    output: OutputOptions,
+    device: DeviceConfig,
+    /// The KRUSZED sound, until the settings change
+    crushed: Option<Sound>,
+}
+
+/// What to do after a command
+#[derive(Debug, PartialEq)]
+pub enum Flow {
+    Continue,
+    Quit,
+}
+
+impl Repl {
+    pub fn new(original: Sound, opts: &Opts) -> Self {
+        Self {
+            original,
+            settings: opts.settings(),
+            auto_gain: opts.auto_gain,
+            seed: opts.seed.unwrap_or_else(rand::random),
+            output: opts.output_options(),
+            device: opts.device_config(),
+            crushed: None,
+        }
+    }
+
+    pub fn execute(&mut self, line: &str) -> Result<Flow> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command.to_lowercase(),
+            None => return Ok(Flow::Continue),
+        };
+        let arg = words.collect::<Vec<_>>().join(" ");
+
+        let mut settings = self.settings.clone();
+        let mut auto_gain = self.auto_gain;
+
+        match command.as_str() {
+            "input-gain" => settings.input_gain = parse_optional(&arg, parse_decibels)?,
+            "bits" => settings.bit_depth = arg.parse()?,
+            "rate" => settings.sample_rate = arg.parse()?,
+            "interpolation" => {
+                settings.interpolation =
+                    Interpolation::from_str(&arg, true).map_err(|e| eyre!(e))?
+            }
+            "jitter" => settings.jitter = arg.parse()?,
+            "dac" => settings.dac_error = parse_optional(&arg, str::parse)?,
+            "width" => settings.width = parse_optional(&arg, parse_percent)?,
+            "auto-gain" => {
+                auto_gain = match arg.as_str() {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!("Expected on or off"),
+                }
+            }
+            "show" => println!("{:#?}\nauto gain: {}", self.settings, self.auto_gain),
+            "play" => {
+                let device = self.device.clone();
+                play_to_end(self.crushed()?, &device)?
+            }
+            "ab" => {
+                let device = self.device.clone();
+                play_to_end(&self.original, &device)?;
+                play_to_end(self.crushed()?, &device)?;
+            }
+            "save" => {
+                let path = Path::new(&arg);
+                let format =
+                    OutputFormat::from_path(path).wrap_err(ErrorKind::UnsupportedFormat)?;
+                let options = self.output.clone();
+                save(self.crushed()?, path, format, options).wrap_err(ErrorKind::Output)?;
+            }
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return Ok(Flow::Quit),
+            other => bail!("Unknown command {}, try help", other),
+        }
+
+        settings.validate().wrap_err(ErrorKind::Parameter)?;
+
+        if settings != self.settings || auto_gain != self.auto_gain {
+            self.settings = settings;
+            self.auto_gain = auto_gain;
+            self.crushed = None;
+        }
+
+        Ok(Flow::Continue)
+    }
+
+    /// The KRUSZED sound, rendered again if the settings changed since it last was
+    fn crushed(&mut self) -> Result<&Sound> {
+        if self.crushed.is_none() {
+            self.crushed = Some(render(
+                &self.original,
+                &self.settings,
+                self.auto_gain,
+                self.seed,
+            )?);
+        }
+
+        Ok(self.crushed.as_ref().unwrap())
+    }
+}
+
+/// KRUSZES a whole sound in one go
+pub fn render(original: &Sound, settings: &Settings, auto_gain: bool, seed: u64) -> Result<Sound> {
+    let mut sound = debug_span!("render").in_scope(|| -> Result<_> {
+        Ok(settings
+            .pipeline(
+                original.channels.len(),
+                original.sample_rate,
+                &mut ChaCha8Rng::seed_from_u64(seed),
+            )
+            .wrap_err(ErrorKind::Parameter)?
+            .run(original.clone()))
+    })?;
+
+    if auto_gain {
+        if let Some(gain) = makeup_gain(rms(original), rms(&sound)) {
+            sound = apply_gain(sound, gain, settings.clip_mode);
+        }
+    }
+
+    Ok(sound)
+}
+
+fn parse_optional<T, F: Fn(&str) -> Result<T, E>, E: Into<color_eyre::Report>>(
+    arg: &str,
+    parse: F,
+) -> Result<Option<T>> {
+    match arg {
+        "off" => Ok(None),
+        _ => parse(arg).map(Some).map_err(Into::into),
+    }
+}
+
+pub fn play_to_end(sound: &Sound, device: &DeviceConfig) -> Result<()> {
+    let (_stream, sink) = play(sound, device)?;
+    sink.sleep_until_end();
+    Ok(())
+}
+
+/// Loads the input, then reads commands from stdin until it's closed or told to quit
+pub fn run(opts: &Opts, input: &Path) -> Result<()> {
+    let sound = opts.decode(input).wrap_err(ErrorKind::Input)?;
+
+    let mut repl = Repl::new(sound, opts);
+
+    println!(
+        "Loaded {}. Type help for the list of commands",
+        input.display()
+    );
+
+    let stdin = io::stdin();
+    let mut lines = stdin.lock().lines();
+
+    loop {
+        print!("krusz> ");
+        io::stdout().flush()?;
+
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+
+        match repl.execute(&line) {
+            Ok(Flow::Quit) => break,
+            Ok(Flow::Continue) => {}
+            Err(report) => eprintln!("Error: {:#}", report),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+    use krusz::Channel;
+
+    use super::*;
+
+    #[test]
+    fn test_repl() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..4410).map(|i| (i * 7 % 2000) << 16).collect(),
+            }],
+            sample_rate: 44100,
+        };
+        let opts = Opts::parse_from(["krusz", "--no-restore-rate", "repl", "in.wav"]);
+        let mut repl = Repl::new(sound, &opts);
+
+        assert_eq!(repl.execute("rate 8000").unwrap(), Flow::Continue);
+        assert_eq!(repl.crushed().unwrap().sample_rate, 8000);
+
+        repl.execute("bits 4").unwrap();
+        assert!(repl.crushed.is_none());
+        assert_eq!(repl.settings.bit_depth, 4.0);
+
+        repl.execute("width 50%").unwrap();
+        assert_eq!(repl.settings.width, Some(0.5));
+        repl.execute("width off").unwrap();
+        assert_eq!(repl.settings.width, None);
+
+        assert!(repl.execute("bits 100").is_err());
+        assert_eq!(repl.settings.bit_depth, 4.0);
+        assert!(repl.execute("wobble").is_err());
+
+        assert_eq!(repl.execute("").unwrap(), Flow::Continue);
+        assert_eq!(repl.execute("quit").unwrap(), Flow::Quit);
+    }
+}
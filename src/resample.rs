@@ -0,0 +1,453 @@
+use clap::ArgEnum;
+use num::{Complex, NumCast};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    crusher::Settings,
+    sample::{to_sample, ClipMode, Sample},
+    stft::Stft,
+    stream::{Stage, MIN_BLOCK},
+    Channel, Sound,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Interpolation {
+    Nearest,
+    Linear,
+}
+
+/// How content above the target Nyquist frequency is treated when lowering the sample rate
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Decimation {
+    /// Left in, so that it aliases
+    Alias,
+    /// Removed in the frequency domain beforehand, for the cleanest possible result
+    Fft,
+}
+
+/// Frame size of the brickwall filter, enough for a sharp cutoff without smearing transients much
+pub const BRICKWALL_FRAME_SIZE: usize = 4096;
+
+/// Removes everything at or above `cutoff` Hz by zeroing the frequency bins
+pub fn brickwall(
+    cutoff: f64,
+    sample_rate: u32,
+) -> Stft<impl Fn(&mut [Complex<f64>]) + Send + Sync> {
+    let size = BRICKWALL_FRAME_SIZE;
+    let bin_width = sample_rate as f64 / size as f64;
+
+    Stft::new(size, sample_rate, move |spectrum: &mut [Complex<f64>]| {
+        for (k, bin) in spectrum.iter_mut().enumerate() {
+            if k.min(size - k) as f64 * bin_width >= cutoff {
+                *bin = Complex::new(0.0, 0.0);
+            }
+        }
+    })
+}
+
+/// Which of a resampler's rates follows the settings when it's retuned mid-stream
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Retune {
+    Fixed,
+    /// The target rate follows the rate the sound is sampled at
+    Target,
+    /// The source rate follows the KRUSZED sample rate
+    Source,
+    /// The source rate follows the rate the sound is sampled at and the target rate the KRUSZED
+    /// sample rate, as when a sampler plays a sped up sound back at its original speed
+    Playback,
+}
+
+/// Resamples a stream to another sample rate, optionally offsetting each read position by a
+/// fraction of the target sample period. The offsets are shared across all channels, as they would
+/// be with a single unstable clock.
+pub struct Resampler {
+    source_rate: u32,
+    target_rate: u32,
+    ratio: f64,
+    step: f64,
+    interpolation: Interpolation,
+    retune: Retune,
+    max_offset: f64,
+    offset: Option<Box<dyn FnMut() -> f64 + Send>>,
+    /// Input samples which may still be read, starting at the absolute index `base`
+    buffers: Vec<Vec<Sample>>,
+    base: usize,
+    received: usize,
+    /// Index of the next output sample, and its read position once it has been drawn
+    next: usize,
+    pending: Option<f64>,
+    /// Read position of output sample `origin_next`, from which the following ones are stepped.
+    /// Only moves when the rates change.
+    origin: f64,
+    origin_next: usize,
+    clip_mode: ClipMode,
+}
+
+impl Resampler {
+    pub fn new(source_rate: u32, target_rate: u32, interpolation: Interpolation) -> Self {
+        let ratio = target_rate as f64 / source_rate as f64;
+
+        Self {
+            source_rate,
+            target_rate,
+            ratio,
+            step: 1.0 / ratio,
+            interpolation,
+            retune: Retune::Fixed,
+            max_offset: 0.0,
+            offset: None,
+            buffers: Vec::new(),
+            base: 0,
+            received: 0,
+            next: 0,
+            pending: None,
+            origin: 0.0,
+            origin_next: 0,
+            clip_mode: ClipMode::Clamp,
+        }
+    }
+
+    /// Brings interpolated samples overshooting full scale back into range with `clip_mode`,
+    /// rather than clamping them
+    pub fn with_clip_mode(mut self, clip_mode: ClipMode) -> Self {
+        self.clip_mode = clip_mode;
+        self
+    }
+
+    /// Makes one of the rates follow the settings when retuned
+    pub fn with_retune(mut self, retune: Retune) -> Self {
+        self.retune = retune;
+        self
+    }
+
+    /// Changes the rates from the next output sample on, carrying on from the current read position
+    pub fn set_rates(&mut self, source_rate: u32, target_rate: u32) {
+        self.origin += (self.next - self.origin_next) as f64 * self.step;
+        self.origin_next = self.next;
+
+        self.source_rate = source_rate;
+        self.target_rate = target_rate;
+        self.ratio = target_rate as f64 / source_rate as f64;
+        self.step = 1.0 / self.ratio;
+    }
+
+    /// The number of output samples covering the input received so far
+    fn expected(&self) -> usize {
+        self.origin_next
+            + ((self.received as f64 - self.origin).max(0.0) * self.ratio).round() as usize
+    }
+
+    /// Offsets each read position by the values returned by `offset`, in target sample periods,
+    /// which must never exceed `max_offset` in either direction
+    pub fn with_jitter<F: FnMut() -> f64 + Send + 'static>(
+        mut self,
+        max_offset: f64,
+        offset: F,
+    ) -> Self {
+        self.max_offset = max_offset;
+        self.offset = Some(Box::new(offset));
+        self
+    }
+
+    fn position(&mut self) -> f64 {
+        let offset = match &mut self.offset {
+            Some(offset) => offset(),
+            None => 0.0,
+        };
+
+        (self.origin + ((self.next - self.origin_next) as f64 + offset) * self.step).max(0.0)
+    }
+
+    /// Schedules the next output sample, reading at the given position
+    fn advance(&mut self, f: f64, positions: &mut Vec<f64>) {
+        positions.push(f);
+        self.next += 1;
+        self.pending = None;
+    }
+
+    /// Reads every channel at the given positions, in parallel
+    fn render(&self, positions: &[f64]) -> Sound {
+        let base = self.base as f64;
+        let (interpolation, clip_mode) = (self.interpolation, self.clip_mode);
+
+        Sound {
+            channels: self
+                .buffers
+                .par_iter()
+                .map(|buffer| Channel {
+                    samples: positions
+                        .iter()
+                        .map(|&f| to_sample(lerp(buffer, f - base, interpolation), clip_mode))
+                        .collect(),
+                })
+                .collect(),
+            sample_rate: self.target_rate,
+        }
+    }
+}
+
+impl Stage for Resampler {
+    fn name(&self) -> &'static str {
+        "resample"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        if self.buffers.is_empty() {
+            self.buffers = vec![Vec::new(); chunk.channels.len()];
+        }
+
+        self.received += chunk.frames();
+        for (buffer, channel) in self.buffers.iter_mut().zip(chunk.channels) {
+            if buffer.is_empty() {
+                *buffer = channel.samples;
+            } else {
+                buffer.extend(channel.samples);
+            }
+        }
+
+        let mut positions = Vec::new();
+
+        // Only emit samples which are known to exist and whose neighbours have been received,
+        // so that nothing depends on where the stream ends
+        while self.next < self.expected() {
+            let f = match self.pending {
+                Some(f) => f,
+                None => self.position(),
+            };
+
+            if f as usize + 1 >= self.received {
+                self.pending = Some(f);
+                break;
+            }
+
+            self.advance(f, &mut positions);
+        }
+
+        let output = self.render(&positions);
+
+        let mut keep_from = ((self.origin
+            + ((self.next - self.origin_next) as f64 - self.max_offset) * self.step)
+            .max(0.0) as usize)
+            .saturating_sub(1);
+        if let Some(f) = self.pending {
+            keep_from = keep_from.min(f as usize);
+        }
+        // When downsampling, the next read position can be past the samples received so far
+        keep_from = keep_from.min(self.received.saturating_sub(1));
+
+        if keep_from > self.base {
+            for buffer in &mut self.buffers {
+                buffer.drain(..keep_from - self.base);
+
+                // Don't hold on to the memory of a large chunk once it's been read
+                if buffer.capacity() > 4 * buffer.len().max(MIN_BLOCK) {
+                    buffer.shrink_to_fit();
+                }
+            }
+            self.base = keep_from;
+        }
+
+        output
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        if self.received == 0 {
+            return None;
+        }
+
+        let mut positions = Vec::new();
+        let max_f = (self.received - 1) as f64;
+
+        while self.next < self.expected() {
+            let f = match self.pending {
+                Some(f) => f,
+                None => self.position(),
+            };
+
+            self.advance(f.min(max_f), &mut positions);
+        }
+
+        Some(self.render(&positions))
+    }
+
+    fn retune(&mut self, settings: &Settings) {
+        match self.retune {
+            Retune::Fixed => {}
+            Retune::Target => self.set_rates(self.source_rate, settings.capture_rate()),
+            Retune::Source => self.set_rates(settings.sample_rate, self.target_rate),
+            Retune::Playback => self.set_rates(settings.capture_rate(), settings.sample_rate),
+        }
+    }
+
+    fn latency(&self) -> f64 {
+        // An output sample is only emitted once the input sample after its read position has been
+        // received, which can be up to one input period plus the offset away, plus rounding
+        (self.ratio + self.max_offset + 1.5) / self.target_rate as f64
+    }
+}
+
+pub fn lerp<T: Copy + std::fmt::Debug + NumCast>(
+    values: &[T],
+    f: f64,
+    interpolation: Interpolation,
+) -> f64 {
+    assert!(!values.is_empty());
+    assert!(
+        f >= 0.0 && f < values.len() as f64,
+        "Lerp index {} out of range: 0..{}",
+        f,
+        values.len()
+    );
+
+    let x = f as usize;
+    let y = (x + 1).min(values.len() - 1);
+    let a = f.fract();
+
+    match interpolation {
+        Interpolation::Nearest => {
+            if a < 0.5 {
+                num::cast(values[x]).unwrap()
+            } else {
+                num::cast(values[y]).unwrap()
+            }
+        }
+        Interpolation::Linear => {
+            let xv: f64 = num::cast(values[x]).unwrap();
+            let yv: f64 = num::cast(values[y]).unwrap();
+            (1.0 - a) * xv + a * yv
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn test_lerp() {
+        let arr = [1u16, 2, 3, 4, 5, 6, 7, 8, 9, 10];
+
+        assert_eq!(lerp(&arr, 4.8, Interpolation::Nearest), 6.0);
+        assert_eq!(lerp(&arr, 4.4, Interpolation::Nearest), 5.0);
+        assert_eq!(lerp(&arr, 4.8, Interpolation::Linear), 5.8);
+    }
+
+    #[test]
+    fn test_resample_jittered() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..100).collect(),
+            }],
+            sample_rate: 100,
+        };
+
+        let plain = Resampler::new(100, 50, Interpolation::Linear).run(sound.clone());
+        assert_eq!(plain.channels[0].samples.len(), 50);
+        assert_eq!(plain.channels[0].samples[10], 20);
+
+        let jittered = Resampler::new(100, 50, Interpolation::Linear)
+            .with_jitter(0.5, || 0.5)
+            .run(sound);
+        assert_eq!(jittered.channels[0].samples[10], 21);
+        assert_eq!(jittered.channels[0].samples[49], 99);
+    }
+
+    #[test]
+    fn test_resample_chunked() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..1000).collect(),
+            }],
+            sample_rate: 44100,
+        };
+
+        let whole = Resampler::new(44100, 8000, Interpolation::Linear).run(sound.clone());
+
+        let mut resampler = Resampler::new(44100, 8000, Interpolation::Linear);
+        let mut chunked = Vec::new();
+        for &sample in &sound.channels[0].samples {
+            let chunk = Sound {
+                channels: vec![Channel {
+                    samples: vec![sample],
+                }],
+                sample_rate: 44100,
+            };
+            chunked.extend(resampler.process(chunk).channels.remove(0).samples);
+        }
+        chunked.extend(resampler.finish().unwrap().channels.remove(0).samples);
+
+        assert_eq!(chunked, whole.channels[0].samples);
+    }
+
+    #[test]
+    fn test_resample_retuned() {
+        let sound = |range: std::ops::Range<Sample>| Sound {
+            channels: vec![Channel {
+                samples: range.collect(),
+            }],
+            sample_rate: 100,
+        };
+
+        let mut resampler = Resampler::new(100, 50, Interpolation::Linear);
+        let mut output = resampler.process(sound(0..100)).channels.remove(0).samples;
+        resampler.set_rates(100, 25);
+        output.extend(
+            resampler
+                .process(sound(100..200))
+                .channels
+                .remove(0)
+                .samples,
+        );
+        output.extend(resampler.finish().unwrap().channels.remove(0).samples);
+
+        // Every other sample at first, then every fourth, without skipping or repeating any
+        assert_eq!(output.len(), 75);
+        assert_eq!(output[..50], (0..100).step_by(2).collect::<Vec<_>>()[..]);
+        assert_eq!(output[50..], (100..200).step_by(4).collect::<Vec<_>>()[..]);
+    }
+
+    #[test]
+    fn test_brickwall() {
+        let tone = |frequency: f64| Sound {
+            channels: vec![Channel {
+                samples: (0..20000)
+                    .map(|i| {
+                        (10000.0 * (2.0 * PI * frequency * i as f64 / 44100.0).sin()) as Sample
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        };
+        let peak = |sound: &Sound| {
+            sound.channels[0].samples[5000..15000]
+                .iter()
+                .map(|sample| sample.abs())
+                .max()
+                .unwrap()
+        };
+
+        let low = brickwall(4000.0, 44100).run(tone(1000.0));
+        assert_eq!(low.frames(), 20000);
+        assert!((9900..=10100).contains(&peak(&low)));
+
+        let high = brickwall(4000.0, 44100).run(tone(6000.0));
+        assert!(peak(&high) < 100);
+    }
+
+    #[test]
+    fn test_resample_empty() {
+        let empty = Sound {
+            channels: vec![Channel::default(); 6],
+            sample_rate: 44100,
+        };
+        let resampled = Resampler::new(44100, 8000, Interpolation::Linear).run(empty);
+        assert_eq!(resampled.channels.len(), 6);
+        assert_eq!(resampled.frames(), 0);
+    }
+}
@@ -0,0 +1,215 @@
+use crate::{
+    sample::{to_sample, ClipMode},
+    stream::{map_channels, Stage},
+    Sound,
+};
+
+/// Comb filter delays of the reverb at 44100 Hz, the first four of Freeverb's
+const COMB_DELAYS: [usize; 4] = [1116, 1188, 1277, 1356];
+
+/// Allpass filter delays at 44100 Hz
+const ALLPASS_DELAYS: [usize; 2] = [556, 441];
+
+/// How much longer the delays get for each channel, so that channels don't decay identically
+const CHANNEL_SPREAD: usize = 23;
+
+/// Feedback of the combs, which sets the decay time
+const FEEDBACK: f64 = 0.82;
+
+/// How much high frequencies are damped on each trip around a comb
+const DAMPING: f64 = 0.3;
+
+/// Feedback of the allpasses, which diffuse the echoes of the combs
+const ALLPASS_FEEDBACK: f64 = 0.5;
+
+/// A feedback comb filter with a low-pass in the loop
+struct Comb {
+    buffer: Vec<f64>,
+    position: usize,
+    filtered: f64,
+}
+
+impl Comb {
+    fn process(&mut self, input: f64) -> f64 {
+        let output = self.buffer[self.position];
+        self.filtered = output * (1.0 - DAMPING) + self.filtered * DAMPING;
+        self.buffer[self.position] = input + self.filtered * FEEDBACK;
+        self.position = (self.position + 1) % self.buffer.len();
+        output
+    }
+}
+
+/// A Schroeder allpass filter
+struct Allpass {
+    buffer: Vec<f64>,
+    position: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f64) -> f64 {
+        let delayed = self.buffer[self.position];
+        self.buffer[self.position] = input + delayed * ALLPASS_FEEDBACK;
+        self.position = (self.position + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+/// Reverb state of a single channel
+struct ReverbChannel {
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+}
+
+impl ReverbChannel {
+    fn new(index: usize, sample_rate: u32) -> Self {
+        let scale = |delay: usize| {
+            let delay = (delay + index * CHANNEL_SPREAD) as f64 * sample_rate as f64 / 44100.0;
+            vec![0.0; (delay.round() as usize).max(1)]
+        };
+
+        Self {
+            combs: COMB_DELAYS
+                .iter()
+                .map(|&delay| Comb {
+                    buffer: scale(delay),
+                    position: 0,
+                    filtered: 0.0,
+                })
+                .collect(),
+            allpasses: ALLPASS_DELAYS
+                .iter()
+                .map(|&delay| Allpass {
+                    buffer: scale(delay),
+                    position: 0,
+                })
+                .collect(),
+        }
+    }
+
+    fn process(&mut self, input: f64) -> f64 {
+        // Averaged, and scaled back by the gain of the feedback, so that the wet sound is about as
+        // loud as the dry one
+        let combs = self
+            .combs
+            .iter_mut()
+            .map(|comb| comb.process(input))
+            .sum::<f64>()
+            * (1.0 - FEEDBACK)
+            / COMB_DELAYS.len() as f64;
+
+        self.allpasses
+            .iter_mut()
+            .fold(combs, |sample, allpass| allpass.process(sample))
+    }
+}
+
+/// A small, deliberately cheap Schroeder reverb: four damped combs in parallel followed by two
+/// allpasses, like the ones built into old samplers and effects units. The tail only rings on as
+/// long as the sound lasts, so the length is unchanged.
+pub struct ReverbStage {
+    /// How much of the output is reverb, from 0 (dry) to 1 (wet only)
+    amount: f64,
+    sample_rate: u32,
+    channels: Vec<ReverbChannel>,
+    clip_mode: ClipMode,
+}
+
+impl ReverbStage {
+    pub fn new(amount: f64, sample_rate: u32, clip_mode: ClipMode) -> Self {
+        Self {
+            amount,
+            sample_rate,
+            channels: Vec::new(),
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for ReverbStage {
+    fn name(&self) -> &'static str {
+        "reverb"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            amount,
+            sample_rate,
+            channels,
+            clip_mode,
+        } = self;
+        let (amount, sample_rate, clip_mode) = (*amount, *sample_rate, *clip_mode);
+        let mut index = channels.len();
+
+        map_channels(
+            chunk,
+            channels,
+            || {
+                index += 1;
+                ReverbChannel::new(index - 1, sample_rate)
+            },
+            |channel, sample| {
+                let dry = sample as f64;
+                to_sample(
+                    dry * (1.0 - amount) + channel.process(dry) * amount,
+                    clip_mode,
+                )
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_reverb() {
+        let mut samples = vec![0; 5000];
+        samples[0] = 1 << 28;
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: samples.clone(),
+                },
+                Channel { samples },
+            ],
+            sample_rate: 44100,
+        };
+
+        let dry = ReverbStage::new(0.0, 44100, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(dry.channels[0].samples, sound.channels[0].samples);
+
+        let wet = ReverbStage::new(0.5, 44100, ClipMode::Clamp).run(sound.clone());
+        let left = &wet.channels[0].samples;
+        let right = &wet.channels[1].samples;
+
+        // Nothing comes back until the first comb echo
+        assert_eq!(left[0], 1 << 27);
+        assert!(left[1..COMB_DELAYS[0]].iter().all(|&sample| sample == 0));
+        assert_ne!(left[COMB_DELAYS[0]], 0);
+        assert_ne!(left, right);
+
+        let mut stage = ReverbStage::new(0.5, 44100, ClipMode::Clamp);
+        let mut chunked = Sound {
+            channels: vec![Channel::default(); 2],
+            sample_rate: 44100,
+        };
+        for start in (0..5000).step_by(999) {
+            chunked.append(
+                stage.process(Sound {
+                    channels: sound
+                        .channels
+                        .iter()
+                        .map(|channel| Channel {
+                            samples: channel.samples[start..(start + 999).min(5000)].to_vec(),
+                        })
+                        .collect(),
+                    sample_rate: 44100,
+                }),
+            );
+        }
+        assert_eq!(chunked.channels[0].samples, *left);
+        assert_eq!(chunked.channels[1].samples, *right);
+    }
+}
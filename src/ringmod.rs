@@ -0,0 +1,178 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use clap::ArgEnum;
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    filter::parse_frequency,
+    sample::{to_sample, ClipMode},
+    stream::Stage,
+    Sound,
+};
+
+/// Shape of the ring modulator's carrier
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Waveform {
+    Sine,
+    /// Flips the sign of the sound back and forth, for harsher sidebands
+    Square,
+}
+
+/// Ring modulation: the sound is multiplied by a carrier, replacing each of its frequencies with
+/// their sum and difference with the carrier's, for metallic, inharmonic tones
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RingMod {
+    /// Frequency of the carrier, in Hz
+    pub frequency: f64,
+    pub waveform: Waveform,
+}
+
+/// Parses the frequency of the carrier, optionally followed by its waveform, e.g. `440` or
+/// `1.2k,square`
+impl FromStr for RingMod {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (frequency, waveform) = match s.split_once(',') {
+            Some((frequency, waveform)) => (
+                frequency,
+                Waveform::from_str(waveform.trim(), true).map_err(|e| eyre!(e))?,
+            ),
+            None => (s, Waveform::Sine),
+        };
+
+        let frequency = parse_frequency(frequency)?;
+        ensure!(
+            frequency.is_finite(),
+            "Ring modulation frequency must be finite"
+        );
+
+        Ok(Self {
+            frequency,
+            waveform,
+        })
+    }
+}
+
+impl Display for RingMod {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{}",
+            self.frequency,
+            self.waveform.to_possible_value().unwrap().get_name()
+        )
+    }
+}
+
+impl RingMod {
+    pub fn stage(self, sample_rate: u32, clip_mode: ClipMode) -> RingModStage {
+        RingModStage {
+            ring_mod: self,
+            sample_rate,
+            time: 0,
+            clip_mode,
+        }
+    }
+}
+
+pub struct RingModStage {
+    ring_mod: RingMod,
+    sample_rate: u32,
+    /// Frames processed so far, which set the phase of the carrier
+    time: u64,
+    clip_mode: ClipMode,
+}
+
+impl RingModStage {
+    fn carrier(&self, frame: u64) -> f64 {
+        let cycles = frame as f64 * self.ring_mod.frequency / self.sample_rate as f64;
+
+        match self.ring_mod.waveform {
+            Waveform::Sine => (2.0 * PI * cycles).sin(),
+            Waveform::Square => {
+                if cycles.fract() < 0.5 {
+                    1.0
+                } else {
+                    -1.0
+                }
+            }
+        }
+    }
+}
+
+impl Stage for RingModStage {
+    fn name(&self) -> &'static str {
+        "ringmod"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        // Every channel is multiplied by the same carrier
+        let carrier: Vec<f64> = (0..chunk.frames() as u64)
+            .map(|frame| self.carrier(self.time + frame))
+            .collect();
+        self.time += carrier.len() as u64;
+
+        for channel in &mut chunk.channels {
+            for (sample, carrier) in channel.samples.iter_mut().zip(&carrier) {
+                *sample = to_sample(*sample as f64 * carrier, self.clip_mode);
+            }
+        }
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_ring_mod() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![1 << 20; 8],
+            }],
+            sample_rate: 8,
+        };
+
+        let square = RingMod {
+            frequency: 2.0,
+            waveform: Waveform::Square,
+        };
+        let modulated = square.stage(8, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(
+            modulated.channels[0].samples,
+            [1, 1, -1, -1, 1, 1, -1, -1].map(|sign| sign << 20)
+        );
+
+        let sine = RingMod {
+            frequency: 2.0,
+            waveform: Waveform::Sine,
+        };
+        let mut stage = sine.stage(8, ClipMode::Clamp);
+        let mut chunked = stage.process(Sound {
+            channels: vec![Channel {
+                samples: sound.channels[0].samples[..3].to_vec(),
+            }],
+            sample_rate: 8,
+        });
+        chunked.append(stage.process(Sound {
+            channels: vec![Channel {
+                samples: sound.channels[0].samples[3..].to_vec(),
+            }],
+            sample_rate: 8,
+        }));
+        assert_eq!(
+            chunked.channels[0].samples,
+            [0, 1, 0, -1, 0, 1, 0, -1].map(|sign| sign << 20)
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use clap::ArgEnum;
+use serde::{Deserialize, Serialize};
+
+static CLIPPED: AtomicUsize = AtomicUsize::new(0);
+
+/// A sample as processed internally, wide enough for bit depths up to 32 bits. 16-bit samples
+/// are widened into the upper half.
+pub type Sample = i32;
+
+/// The magnitude of the most negative sample, which maps to -1.0
+pub const FULL_SCALE: f64 = 2147483648.0;
+
+/// What happens to samples which exceed full scale
+#[derive(Clone, Copy, Debug, Default, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ClipMode {
+    /// Held at full scale
+    #[default]
+    Clamp,
+    /// Wrapped around to the other end of the range, like integer overflow
+    Wrap,
+    /// Reflected back down from full scale
+    Fold,
+}
+
+/// Rounds a processed sample back to an integer. Samples exceeding full scale are counted as clipped
+/// and brought back into range the way `mode` says.
+pub fn to_sample(x: f64, mode: ClipMode) -> Sample {
+    let rounded = x.round();
+
+    if rounded <= Sample::MAX as f64 && rounded >= Sample::MIN as f64 {
+        return rounded as Sample;
+    }
+
+    CLIPPED.fetch_add(1, Ordering::Relaxed);
+    clip(rounded, mode)
+}
+
+/// Brings a sample exceeding full scale back into range
+fn clip(x: f64, mode: ClipMode) -> Sample {
+    match mode {
+        ClipMode::Clamp => x as Sample,
+        ClipMode::Wrap => ((x + FULL_SCALE).rem_euclid(2.0 * FULL_SCALE) - FULL_SCALE) as Sample,
+        ClipMode::Fold => {
+            // A triangle wave through -1 and 1, in full scales
+            let t = (x / FULL_SCALE + 1.0).rem_euclid(4.0);
+            let folded = if t < 2.0 { t - 1.0 } else { 3.0 - t };
+            (folded * FULL_SCALE).round() as Sample
+        }
+    }
+}
+
+/// Widens a 16-bit sample
+pub fn from_i16(sample: i16) -> Sample {
+    Sample::from(sample) << 16
+}
+
+/// Keeps the 16 most significant bits of a sample, like the 16-bit quantizer does
+pub fn to_i16(sample: Sample) -> i16 {
+    (sample >> 16) as i16
+}
+
+/// How many samples have clipped so far in any stage
+pub fn clipped_count() -> usize {
+    CLIPPED.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_to_sample() {
+        assert_eq!(to_sample(1.4, ClipMode::Clamp), 1);
+        assert_eq!(to_sample(-1.6, ClipMode::Clamp), -2);
+        assert_eq!(to_sample(3e9, ClipMode::Clamp), Sample::MAX);
+        assert_eq!(to_sample(-3e9, ClipMode::Clamp), Sample::MIN);
+        assert_eq!(
+            to_sample(FULL_SCALE + 10.0, ClipMode::Wrap),
+            Sample::MIN + 10
+        );
+    }
+
+    #[test]
+    fn test_clip_modes() {
+        let over = FULL_SCALE + 10.0;
+        let under = -FULL_SCALE - 11.0;
+
+        assert_eq!(clip(over, ClipMode::Clamp), Sample::MAX);
+        assert_eq!(clip(under, ClipMode::Clamp), Sample::MIN);
+        assert_eq!(clip(over, ClipMode::Wrap), Sample::MIN + 10);
+        assert_eq!(clip(under, ClipMode::Wrap), Sample::MAX - 10);
+        assert_eq!(clip(over, ClipMode::Fold), Sample::MAX - 9);
+        assert_eq!(clip(under, ClipMode::Fold), Sample::MIN + 11);
+        assert_eq!(clip(2.5 * FULL_SCALE, ClipMode::Fold), -(1 << 30));
+    }
+
+    #[test]
+    fn test_i16() {
+        for sample in [i16::MIN, -1, 0, 1, i16::MAX] {
+            assert_eq!(to_i16(from_i16(sample)), sample);
+        }
+
+        assert_eq!(to_i16(from_i16(-3) + 0xffff), -3);
+    }
+}
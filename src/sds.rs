@@ -0,0 +1,261 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+    thread,
+    time::Duration,
+};
+
+use color_eyre::eyre::{ensure, Result};
+
+use crate::{
+    output::{mix_down, Encoder, OutputOptions},
+    Sound,
+};
+
+/// Start and end of a System Exclusive message
+const SYSEX_START: u8 = 0xf0;
+const SYSEX_END: u8 = 0xf7;
+
+/// Non-realtime universal System Exclusive messages, which SDS is part of
+const NON_REALTIME: u8 = 0x7e;
+
+const DUMP_HEADER: u8 = 0x01;
+const DATA_PACKET: u8 = 0x02;
+
+/// Bytes of sample data in each data packet
+const PACKET_DATA: usize = 120;
+
+/// Loop types of the dump header
+const LOOP_FORWARD: u8 = 0x00;
+const LOOP_OFF: u8 = 0x7f;
+
+/// Offset of the length in the dump header
+const LENGTH_OFFSET: u64 = 10;
+
+/// How long to wait for a sampler to get ready after the dump header, and after each data packet,
+/// when nothing answers
+const HEADER_PAUSE: Duration = Duration::from_secs(2);
+const PACKET_PAUSE: Duration = Duration::from_millis(20);
+
+/// Splits a number into 7-bit bytes, least significant first
+fn seven_bits(value: u32, bytes: usize) -> Vec<u8> {
+    (0..bytes)
+        .map(|i| (value >> (7 * i)) as u8 & 0x7f)
+        .collect()
+}
+
+/// Writes MIDI Sample Dump Standard files: the System Exclusive messages which transfer a sample to
+/// a hardware sampler, a dump header followed by data packets. Samples are sent at the KRUSZED bit
+/// depth, between 8 and 28 bits. Sounds with several channels are mixed down to mono.
+pub struct SdsEncoder {
+    file: BufWriter<File>,
+    bits: u8,
+    /// Bytes of each sample, 7 bits at a time
+    word_size: usize,
+    /// Data which doesn't fill a packet yet
+    pending: Vec<u8>,
+    packet: u8,
+    words: u64,
+    looped: bool,
+    loop_points: Option<(u64, u64)>,
+}
+
+impl SdsEncoder {
+    pub(crate) fn create(path: &Path, sample_rate: u32, options: OutputOptions) -> Result<Self> {
+        let bits = options.significant_bits.clamp(8, 28);
+        let period = (1e9 / f64::from(sample_rate)).round() as u32;
+        ensure!(
+            period < 1 << 21,
+            "SDS can't store sample rates below 477 Hz"
+        );
+
+        let mut file = BufWriter::new(File::create(path)?);
+        // Device 0, sample 0. The length and the loop are filled in by finalize.
+        file.write_all(&[SYSEX_START, NON_REALTIME, 0, DUMP_HEADER, 0, 0, bits])?;
+        file.write_all(&seven_bits(period, 3))?;
+        file.write_all(&[0; 9])?;
+        file.write_all(&[LOOP_OFF, SYSEX_END])?;
+
+        Ok(Self {
+            file,
+            bits,
+            word_size: usize::from(bits).div_ceil(7),
+            pending: Vec::new(),
+            packet: 0,
+            words: 0,
+            looped: options.looped,
+            loop_points: options.loop_points,
+        })
+    }
+
+    /// Encodes a sample as an unsigned word, left justified in 7-bit bytes, most significant first
+    fn word(&self, sample: i32) -> impl Iterator<Item = u8> {
+        let unsigned = (sample as u32 ^ 0x8000_0000) >> (32 - self.bits);
+        let justified = unsigned << (7 * self.word_size - usize::from(self.bits));
+        let word_size = self.word_size;
+
+        (0..word_size)
+            .rev()
+            .map(move |i| (justified >> (7 * i)) as u8 & 0x7f)
+    }
+
+    fn write_packet(&mut self, data: &[u8]) -> Result<()> {
+        let header = [NON_REALTIME, 0, DATA_PACKET, self.packet];
+        let checksum = header.iter().chain(data).fold(0, |sum, byte| sum ^ byte) & 0x7f;
+
+        self.file.write_all(&[SYSEX_START])?;
+        self.file.write_all(&header)?;
+        self.file.write_all(data)?;
+        self.file.write_all(&[checksum, SYSEX_END])?;
+
+        self.packet = (self.packet + 1) & 0x7f;
+        Ok(())
+    }
+}
+
+impl Encoder for SdsEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let mono = mix_down(block);
+        let data: Vec<u8> = mono.iter().flat_map(|&sample| self.word(sample)).collect();
+        self.pending.extend(data);
+        self.words += mono.len() as u64;
+
+        let whole = self.pending.len() / PACKET_DATA * PACKET_DATA;
+        let pending = std::mem::take(&mut self.pending);
+        for packet in pending[..whole].chunks(PACKET_DATA) {
+            self.write_packet(packet)?;
+        }
+        self.pending = pending[whole..].to_vec();
+
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        ensure!(self.words < 1 << 21, "The sound is too long for SDS");
+
+        if !self.pending.is_empty() {
+            let mut packet = std::mem::take(&mut self.pending);
+            packet.resize(PACKET_DATA, 0);
+            self.write_packet(&packet)?;
+        }
+
+        let words = self.words as u32;
+        let last = words.saturating_sub(1);
+        let (loop_start, loop_end, loop_type) = match self.loop_points {
+            Some((start, end)) => (
+                (start as u32).min(last),
+                (end as u32).min(last),
+                LOOP_FORWARD,
+            ),
+            None if self.looped => (0, last, LOOP_FORWARD),
+            None => (0, 0, LOOP_OFF),
+        };
+
+        self.file.seek(SeekFrom::Start(LENGTH_OFFSET))?;
+        self.file.write_all(&seven_bits(words, 3))?;
+        self.file.write_all(&seven_bits(loop_start, 3))?;
+        self.file.write_all(&seven_bits(loop_end, 3))?;
+        self.file.write_all(&[loop_type])?;
+        self.file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Sends an SDS dump to a sampler through a raw MIDI device, one message at a time. Nothing is read
+/// back, so this waits as long as the standard asks senders to when nothing answers. `pause` is
+/// called with each wait.
+pub fn send<W: Write, P: FnMut(Duration)>(dump: &[u8], device: &mut W, mut pause: P) -> Result<()> {
+    ensure!(
+        dump.len() > 3
+            && dump[0] == SYSEX_START
+            && dump[1] == NON_REALTIME
+            && dump[3] == DUMP_HEADER,
+        "Not an SDS dump"
+    );
+
+    let mut rest = dump;
+    while !rest.is_empty() {
+        let end = rest
+            .iter()
+            .position(|&byte| byte == SYSEX_END)
+            .map_or(rest.len(), |end| end + 1);
+        let (message, next) = rest.split_at(end);
+
+        device.write_all(message)?;
+        device.flush()?;
+        pause(if message.get(3) == Some(&DUMP_HEADER) {
+            HEADER_PAUSE
+        } else {
+            PACKET_PAUSE
+        });
+
+        rest = next;
+    }
+
+    Ok(())
+}
+
+/// Sends an SDS dump to the MIDI device at `device`, pausing for real
+pub fn send_to_device(dump: &[u8], device: &Path) -> Result<()> {
+    let mut device = OpenOptions::new().write(true).open(device)?;
+    send(dump, &mut device, thread::sleep)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    #[test]
+    fn test_save_sds() {
+        let path = std::env::temp_dir().join("krusz_test_save_sds.sds");
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![i32::MIN, 0, i32::MAX, 0x1230_0000],
+            }],
+            sample_rate: 31250,
+        };
+
+        let options = OutputOptions {
+            significant_bits: 12,
+            looped: true,
+            ..OutputOptions::default()
+        };
+        save(&sound, &path, OutputFormat::Sds, options).unwrap();
+        let dump = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // 32000 ns period, 4 words long, looping from 0 to 3
+        assert_eq!(
+            dump[..21],
+            [
+                0xf0, 0x7e, 0, 0x01, 0, 0, 12, 0x00, 0x7a, 0x01, 4, 0, 0, 0, 0, 0, 3, 0, 0, 0x00,
+                0xf7
+            ]
+        );
+
+        let packet = &dump[21..];
+        assert_eq!(packet.len(), 127);
+        assert_eq!(packet[..5], [0xf0, 0x7e, 0, 0x02, 0]);
+        // 12 bits left justified in two 7-bit bytes, offset binary
+        assert_eq!(
+            packet[5..13],
+            [0x00, 0x00, 0x40, 0x00, 0x7f, 0x7c, 0x49, 0x0c]
+        );
+        assert!(packet[13..125].iter().all(|&byte| byte == 0));
+        let checksum = packet[1..125].iter().fold(0, |sum, byte| sum ^ byte) & 0x7f;
+        assert_eq!(packet[125..], [checksum, 0xf7]);
+
+        let mut sent = Vec::new();
+        let mut pauses = Vec::new();
+        send(&dump, &mut sent, |pause| pauses.push(pause)).unwrap();
+        assert_eq!(sent, dump);
+        assert_eq!(pauses, [HEADER_PAUSE, PACKET_PAUSE]);
+        assert!(send(b"RIFF", &mut sent, |_| ()).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use color_eyre::eyre::Result;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use rayon::prelude::*;
+
+use crate::{crusher::Settings, sample::FULL_SCALE, stream::Stage, Sound};
+
+/// Sample rates tried when searching for the smallest settings, the usual ones of samplers, consoles
+/// and game engines
+const CANDIDATE_RATES: [u32; 8] = [4000, 6000, 8000, 11025, 16000, 22050, 32000, 44100];
+
+/// Highest bit depth tried
+const MAX_BIT_DEPTH: u8 = 16;
+
+/// A bit depth and sample rate, along with the signal-to-noise ratio they KRUSZE a sound to
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Candidate {
+    pub bit_depth: u8,
+    pub sample_rate: u32,
+    /// In dB
+    pub snr: f64,
+}
+
+impl Candidate {
+    /// Bits per second per channel, which sets the size of the KRUSZED sound when stored packed
+    pub fn bit_rate(&self) -> u64 {
+        u64::from(self.bit_depth) * u64::from(self.sample_rate)
+    }
+}
+
+/// Signal-to-noise ratio of a KRUSZED sound against its original at the same sample rate, in dB,
+/// counting everything which differs from the original as noise. Sounds identical to their
+/// original, silent or too short to compare included, have an infinite ratio.
+pub fn snr(original: &Sound, crushed: &Sound) -> f64 {
+    let (mut signal, mut noise) = (0.0, 0.0);
+
+    for (original, crushed) in original.channels.iter().zip(&crushed.channels) {
+        for (&x, &y) in original.samples.iter().zip(&crushed.samples) {
+            let (x, y) = (x as f64 / FULL_SCALE, y as f64 / FULL_SCALE);
+            signal += x * x;
+            noise += (x - y) * (x - y);
+        }
+    }
+
+    if noise == 0.0 {
+        return f64::INFINITY;
+    }
+
+    10.0 * (signal / noise).log10()
+}
+
+/// The sample rates worth trying for a sound at `source_rate`, from the lowest up to its own or
+/// 44100 Hz
+pub fn candidate_rates(source_rate: u32) -> Vec<u32> {
+    let top_rate = source_rate.min(44100);
+    let mut rates: Vec<u32> = CANDIDATE_RATES
+        .iter()
+        .copied()
+        .filter(|&rate| rate < top_rate)
+        .collect();
+    rates.push(top_rate);
+    rates
+}
+
+/// KRUSZES a sound at a bit depth and sample rate, and back to its own rate, with the resampling
+/// and quantization of the settings but none of their effects, and measures the signal-to-noise
+/// ratio of what the KRUSZING alone does to it
+pub fn measure(sound: &Sound, settings: &Settings, bit_depth: u8, sample_rate: u32) -> Result<f64> {
+    let settings = Settings {
+        sample_rate,
+        bit_depth: f64::from(bit_depth),
+        interpolation: settings.interpolation,
+        decimation: settings.decimation,
+        jitter: settings.jitter,
+        dac_error: settings.dac_error,
+        restore_rate: Some(sound.sample_rate),
+        ..Settings::default()
+    };
+
+    // Seeded the same way every time, so that the search always comes to the same answer
+    let mut pipeline = settings.pipeline(
+        sound.channels.len(),
+        sound.sample_rate,
+        &mut ChaCha8Rng::seed_from_u64(0),
+    )?;
+
+    Ok(snr(sound, &pipeline.run(sound.clone())))
+}
+
+/// Searches for the bit depth and sample rate which KRUSZE a sound to the smallest size, while
+/// keeping its signal-to-noise ratio at `target` dB or above. Each sample rate up to the sound's
+/// own, or 44100 Hz, gets the lowest whole bit depth which meets the target, found by bisection,
+/// and the rate and depth with the lowest bit rate win. None is returned when even 16 bits at the
+/// highest rate fall short.
+pub fn smallest(sound: &Sound, settings: &Settings, target: f64) -> Result<Option<Candidate>> {
+    let candidates = candidate_rates(sound.sample_rate)
+        .into_par_iter()
+        .map(|sample_rate| {
+            let candidate = |bit_depth| -> Result<Candidate> {
+                Ok(Candidate {
+                    bit_depth,
+                    sample_rate,
+                    snr: measure(sound, settings, bit_depth, sample_rate)?,
+                })
+            };
+
+            let best = candidate(MAX_BIT_DEPTH)?;
+            if best.snr < target {
+                return Ok(None);
+            }
+
+            // More bits only ever lower the noise, so the lowest depth meeting the target is at the
+            // boundary
+            let (mut low, mut high) = (0, best);
+            while high.bit_depth - low > 1 {
+                let middle = candidate((low + high.bit_depth) / 2)?;
+                if middle.snr >= target {
+                    high = middle;
+                } else {
+                    low = middle.bit_depth;
+                }
+            }
+
+            Ok(Some(high))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(candidates.into_iter().flatten().min_by(|a, b| {
+        a.bit_rate()
+            .cmp(&b.bit_rate())
+            .then(b.snr.total_cmp(&a.snr))
+    }))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    /// A low tone at half full scale, which loses little at low sample rates
+    fn tone() -> Sound {
+        Sound {
+            channels: vec![Channel {
+                samples: (0..22050)
+                    .map(|i| {
+                        let t = i as f64 / 44100.0;
+                        ((t * 220.0 * std::f64::consts::TAU).sin() * 0.5 * FULL_SCALE) as i32
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        }
+    }
+
+    #[test]
+    fn test_snr() {
+        assert_eq!(snr(&tone(), &tone()), f64::INFINITY);
+        let empty = Sound {
+            channels: vec![Channel {
+                samples: Vec::new(),
+            }],
+            sample_rate: 44100,
+        };
+        assert_eq!(snr(&tone(), &empty), f64::INFINITY);
+
+        let mut half = tone();
+        for sample in &mut half.channels[0].samples {
+            *sample /= 2;
+        }
+        assert!((snr(&tone(), &half) - 6.02).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_smallest() {
+        let settings = Settings {
+            interpolation: crate::resample::Interpolation::Linear,
+            ..Settings::default()
+        };
+        let found = smallest(&tone(), &settings, 30.0).unwrap().unwrap();
+        assert!(found.snr >= 30.0);
+
+        // A bit less at the same rate falls short
+        let fewer = measure(&tone(), &settings, found.bit_depth - 1, found.sample_rate).unwrap();
+        assert!(fewer < 30.0);
+
+        assert_eq!(smallest(&tone(), &settings, 200.0).unwrap(), None);
+    }
+}
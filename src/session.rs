@@ -0,0 +1,160 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+use toml::{value::Table, Value};
+use tracing::warn;
+
+use krusz::{checksum::hash_file, input::is_url, output::write_atomically};
+
+use crate::Opts;
+
+/// Everything needed to reproduce a run: its inputs, options and seed
+#[derive(Debug, Serialize, Deserialize)]
+struct Session {
+    seed: u64,
+    inputs: Vec<SessionInput>,
+    options: Table,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SessionInput {
+    path: PathBuf,
+    /// Hash of the file when the session was saved. Not recorded for URLs.
+    sha256: Option<String>,
+}
+
+/// Saves the inputs, options and seed of a run as TOML
+pub fn save(path: &Path, opts: &Opts, seed: u64) -> Result<()> {
+    let session = Session {
+        seed,
+        inputs: opts
+            .input
+            .iter()
+            .map(|input| {
+                Ok(SessionInput {
+                    path: input.clone(),
+                    sha256: (!is_url(input)).then(|| hash_file(input)).transpose()?,
+                })
+            })
+            .collect::<Result<_>>()?,
+        options: match Value::try_from(opts)? {
+            Value::Table(options) => options,
+            _ => unreachable!("Options are always serialized as a table"),
+        },
+    };
+
+    let contents = toml::to_string(&session)?;
+    write_atomically(path, |temp| Ok(std::fs::write(temp, contents)?))
+}
+
+/// Loads a session saved with `save`. Options given on the command line take precedence over
+/// the session's, and so do inputs and the seed if any were given.
+pub fn load(path: &Path, cli: &Opts) -> Result<Opts> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut session: Session = toml::from_str(&contents)
+        .wrap_err_with(|| format!("Invalid session file {}", path.display()))?;
+
+    if let Value::Table(given) = Value::try_from(cli)? {
+        for (key, value) in given {
+            // Flags which weren't given and empty lists are indistinguishable from defaults
+            let given = match &value {
+                Value::Boolean(flag) => *flag,
+                Value::Array(values) => !values.is_empty(),
+                _ => true,
+            };
+
+            if given {
+                session.options.insert(key, value);
+            }
+        }
+    }
+
+    let mut opts: Opts = Value::Table(session.options)
+        .try_into()
+        .wrap_err_with(|| format!("Invalid options in session file {}", path.display()))?;
+
+    opts.input = cli.input.clone();
+    if opts.input.is_empty() {
+        for input in &session.inputs {
+            if let (Some(saved), false) = (&input.sha256, is_url(&input.path)) {
+                let current = hash_file(&input.path)
+                    .wrap_err_with(|| eyre!("Could not read {}", input.path.display()))?;
+
+                if &current != saved {
+                    warn!(
+                        "{} changed since the session was saved, the output will differ",
+                        input.path.display()
+                    );
+                }
+            }
+        }
+
+        opts.input = session.inputs.into_iter().map(|input| input.path).collect();
+    }
+
+    opts.seed = cli.seed.or(Some(session.seed));
+    opts.preview = cli.preview;
+    opts.command = cli.command.clone();
+    opts.log_level = cli.log_level;
+    opts.save_session = cli.save_session.clone();
+
+    Ok(opts)
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_session_roundtrip() {
+        let dir = std::env::temp_dir().join(format!("krusz-session-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let input = dir.join("input.wav");
+        std::fs::write(&input, b"not really a WAV").unwrap();
+        let session = dir.join("session.toml");
+
+        let saved = Opts::parse_from([
+            "krusz",
+            "-i",
+            input.to_str().unwrap(),
+            "-o",
+            "out.wav",
+            "-b",
+            "4.5",
+            "--filter",
+            "lowpass:3k,q=0.5",
+            "--interpolation",
+            "linear",
+            "--auto-gain",
+        ]);
+        save(&session, &saved, 1234).unwrap();
+
+        let cli = Opts::parse_from(["krusz", "--load-session", session.to_str().unwrap()]);
+        let loaded = load(&session, &cli).unwrap();
+        assert_eq!(loaded.input, saved.input);
+        assert_eq!(loaded.seed, Some(1234));
+        assert_eq!(loaded.settings(), saved.settings());
+        assert_eq!(loaded.output, saved.output);
+        assert!(loaded.auto_gain);
+
+        let cli = Opts::parse_from([
+            "krusz",
+            "--load-session",
+            session.to_str().unwrap(),
+            "-b",
+            "8",
+            "--seed",
+            "5",
+        ]);
+        let tweaked = load(&session, &cli).unwrap();
+        assert_eq!(tweaked.bit_depth, Some(8.0));
+        assert_eq!(tweaked.seed, Some(5));
+        assert_eq!(tweaked.filter, saved.filter);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::{convert::TryFrom, fs, path::Path};
+
+use color_eyre::eyre::{ensure, eyre, Result};
+
+use crate::{
+    output::{mix_down, write_atomically},
+    sample::to_i16,
+    Sound,
+};
+
+/// Zero samples which must follow every sample, for interpolating players
+const SAMPLE_PADDING: usize = 46;
+
+/// Names are zero terminated within 20 bytes
+const NAME_SIZE: usize = 20;
+
+/// Generator selecting a preset zone's instrument
+const INSTRUMENT: u16 = 41;
+
+/// Generator limiting a zone to a range of keys
+const KEY_RANGE: u16 = 43;
+
+/// Generator selecting an instrument zone's sample
+const SAMPLE_ID: u16 = 53;
+
+/// Generator choosing whether a sample loops
+const SAMPLE_MODES: u16 = 54;
+
+/// Sample mode looping continuously
+const LOOP_CONTINUOUSLY: u16 = 1;
+
+/// Sample type of mono samples
+const MONO_SAMPLE: u16 = 1;
+
+/// A KRUSZED sound to put in a SoundFont
+pub struct Sf2Sample {
+    pub name: String,
+    pub sound: Sound,
+    /// MIDI key at which the sound plays at its original pitch
+    pub root_key: u8,
+}
+
+fn name(name: &str) -> [u8; NAME_SIZE] {
+    let mut bytes = [0; NAME_SIZE];
+    let ascii: Vec<u8> = name
+        .chars()
+        .map(|c| if c.is_ascii() { c as u8 } else { b'_' })
+        .take(NAME_SIZE - 1)
+        .collect();
+    bytes[..ascii.len()].copy_from_slice(&ascii);
+    bytes
+}
+
+fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(data.len() + 9);
+    chunk.extend_from_slice(id);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if data.len() % 2 == 1 {
+        chunk.push(0);
+    }
+    chunk
+}
+
+fn list(kind: &[u8; 4], chunks: &[Vec<u8>]) -> Vec<u8> {
+    let mut data = kind.to_vec();
+    for chunk in chunks {
+        data.extend_from_slice(chunk);
+    }
+    chunk(b"LIST", &data)
+}
+
+/// A generator record: an operator and its amount
+fn generator(operator: u16, amount: [u8; 2]) -> Vec<u8> {
+    let mut record = operator.to_le_bytes().to_vec();
+    record.extend_from_slice(&amount);
+    record
+}
+
+/// Key ranges splitting the keyboard halfway between each pair of neighbouring root keys, given
+/// root keys in ascending order
+fn key_ranges(root_keys: &[u8]) -> Vec<(u8, u8)> {
+    let midpoints: Vec<u8> = root_keys
+        .windows(2)
+        .map(|pair| ((u16::from(pair[0]) + u16::from(pair[1])) / 2) as u8)
+        .collect();
+
+    (0..root_keys.len())
+        .map(|i| {
+            let low = if i == 0 { 0 } else { midpoints[i - 1] + 1 };
+            let high = midpoints.get(i).copied().unwrap_or(127);
+            (low, high)
+        })
+        .collect()
+}
+
+/// Saves the samples as a SoundFont 2 file with a single preset playing a single instrument, in
+/// which each sample covers the keys around its root key. Sounds with several channels are mixed
+/// down to mono, and `looped` loops every sample whole.
+pub fn save_sf2(path: &Path, instrument: &str, samples: &[Sf2Sample], looped: bool) -> Result<()> {
+    ensure!(!samples.is_empty(), "A SoundFont needs at least one sample");
+    ensure!(
+        samples.iter().all(|sample| sample.root_key <= 127),
+        "Root keys must be MIDI keys, between 0 and 127 inclusive"
+    );
+
+    let mut order: Vec<usize> = (0..samples.len()).collect();
+    order.sort_by_key(|&i| samples[i].root_key);
+    let root_keys: Vec<u8> = order.iter().map(|&i| samples[i].root_key).collect();
+    ensure!(
+        root_keys.windows(2).all(|pair| pair[0] != pair[1]),
+        "Each sample needs its own root key"
+    );
+
+    let mut smpl = Vec::new();
+    let mut shdr = Vec::new();
+    for sample in samples {
+        let start = u32::try_from(smpl.len() / 2)?;
+        for level in mix_down(&sample.sound) {
+            smpl.extend_from_slice(&to_i16(level).to_le_bytes());
+        }
+        let end = u32::try_from(smpl.len() / 2)
+            .map_err(|_| eyre!("The samples are too long for a SoundFont"))?;
+        smpl.resize(smpl.len() + 2 * SAMPLE_PADDING, 0);
+
+        shdr.extend_from_slice(&name(&sample.name));
+        for value in [start, end, start, end, sample.sound.sample_rate] {
+            shdr.extend_from_slice(&value.to_le_bytes());
+        }
+        shdr.extend_from_slice(&[sample.root_key, 0]);
+        shdr.extend_from_slice(&0u16.to_le_bytes());
+        shdr.extend_from_slice(&MONO_SAMPLE.to_le_bytes());
+    }
+    shdr.extend_from_slice(&name("EOS"));
+    shdr.resize(shdr.len() + 26, 0);
+
+    // One instrument zone per sample, each with its key range, loop mode and sample
+    let mut ibag = Vec::new();
+    let mut igen = Vec::new();
+    for (&i, (low, high)) in order.iter().zip(key_ranges(&root_keys)) {
+        ibag.extend_from_slice(&((igen.len() / 4) as u16).to_le_bytes());
+        ibag.extend_from_slice(&0u16.to_le_bytes());
+
+        igen.extend(generator(KEY_RANGE, [low, high]));
+        if looped {
+            igen.extend(generator(SAMPLE_MODES, LOOP_CONTINUOUSLY.to_le_bytes()));
+        }
+        igen.extend(generator(SAMPLE_ID, (i as u16).to_le_bytes()));
+    }
+    ibag.extend_from_slice(&((igen.len() / 4) as u16).to_le_bytes());
+    ibag.extend_from_slice(&0u16.to_le_bytes());
+    igen.extend_from_slice(&[0; 4]);
+
+    let mut inst = name(instrument).to_vec();
+    inst.extend_from_slice(&0u16.to_le_bytes());
+    inst.extend_from_slice(&name("EOI"));
+    inst.extend_from_slice(&(samples.len() as u16).to_le_bytes());
+
+    let mut phdr = name(instrument).to_vec();
+    // Preset 0 of bank 0, starting at bag 0, then library, genre and morphology
+    phdr.extend_from_slice(&[0; 6]);
+    phdr.extend_from_slice(&[0; 12]);
+    phdr.extend_from_slice(&name("EOP"));
+    phdr.extend_from_slice(&[0, 0, 0, 0]);
+    phdr.extend_from_slice(&1u16.to_le_bytes());
+    phdr.extend_from_slice(&[0; 12]);
+
+    let mut pgen = generator(INSTRUMENT, [0, 0]);
+    pgen.extend_from_slice(&[0; 4]);
+
+    let mut info_name = instrument.as_bytes().to_vec();
+    info_name.push(0);
+
+    let riff = list(
+        b"sfbk",
+        &[
+            list(
+                b"INFO",
+                &[
+                    // Version 2.01
+                    chunk(b"ifil", &[2, 0, 1, 0]),
+                    chunk(b"isng", b"EMU8000\0"),
+                    chunk(b"INAM", &info_name),
+                ],
+            ),
+            list(b"sdta", &[chunk(b"smpl", &smpl)]),
+            list(
+                b"pdta",
+                &[
+                    chunk(b"phdr", &phdr),
+                    chunk(b"pbag", &[0, 0, 0, 0, 1, 0, 0, 0]),
+                    chunk(b"pmod", &[0; 10]),
+                    chunk(b"pgen", &pgen),
+                    chunk(b"inst", &inst),
+                    chunk(b"ibag", &ibag),
+                    chunk(b"imod", &[0; 10]),
+                    chunk(b"igen", &igen),
+                    chunk(b"shdr", &shdr),
+                ],
+            ),
+        ],
+    );
+
+    // The outermost chunk is a RIFF rather than a LIST
+    let mut file = riff;
+    file[..4].copy_from_slice(b"RIFF");
+
+    write_atomically(path, |temp_path| Ok(fs::write(temp_path, &file)?))
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+
+    use super::*;
+    use crate::Channel;
+
+    /// Finds a chunk by ID, anywhere in the file
+    fn find<'a>(bytes: &'a [u8], id: &[u8; 4]) -> &'a [u8] {
+        let at = bytes.windows(4).position(|window| window == id).unwrap();
+        let size = u32::from_le_bytes(bytes[at + 4..at + 8].try_into().unwrap()) as usize;
+        &bytes[at + 8..at + 8 + size]
+    }
+
+    #[test]
+    fn test_key_ranges() {
+        assert_eq!(key_ranges(&[60]), [(0, 127)]);
+        assert_eq!(key_ranges(&[48, 60, 61]), [(0, 54), (55, 60), (61, 127)]);
+    }
+
+    #[test]
+    fn test_save_sf2() {
+        let path = std::env::temp_dir().join("krusz_test_save_sf2.sf2");
+        let sample = |name: &str, root_key, level| Sf2Sample {
+            name: name.to_owned(),
+            sound: Sound {
+                channels: vec![Channel {
+                    samples: vec![level << 16; 10],
+                }],
+                sample_rate: 22050,
+            },
+            root_key,
+        };
+        let samples = [sample("high", 72, 2), sample("low", 48, 1)];
+
+        save_sf2(&path, "Crushed", &samples, true).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(
+            u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize,
+            bytes.len() - 8
+        );
+        assert_eq!(&bytes[8..12], b"sfbk");
+
+        let smpl = find(&bytes, b"smpl");
+        assert_eq!(smpl.len(), 2 * 2 * (10 + SAMPLE_PADDING));
+        assert_eq!(smpl[..4], [2, 0, 2, 0]);
+
+        let shdr = find(&bytes, b"shdr");
+        assert_eq!(shdr.len(), 3 * 46);
+        assert_eq!(&shdr[..5], b"high\0");
+        let low = &shdr[46..92];
+        let fields: Vec<u32> = low[20..40]
+            .chunks(4)
+            .map(|word| u32::from_le_bytes(word.try_into().unwrap()))
+            .collect();
+        assert_eq!(fields, [56, 66, 56, 66, 22050]);
+        assert_eq!(low[40], 48);
+
+        // The low sample comes first on the keyboard, and both loop
+        let igen = find(&bytes, b"igen");
+        assert_eq!(
+            igen,
+            [
+                43, 0, 0, 60, 54, 0, 1, 0, 53, 0, 1, 0, //
+                43, 0, 61, 127, 54, 0, 1, 0, 53, 0, 0, 0, //
+                0, 0, 0, 0
+            ]
+        );
+        assert_eq!(find(&bytes, b"ibag"), [0, 0, 0, 0, 3, 0, 0, 0, 6, 0, 0, 0]);
+
+        assert!(save_sf2(
+            &path,
+            "Clash",
+            &[sample("a", 60, 0), sample("b", 60, 0)],
+            false
+        )
+        .is_err());
+    }
+}
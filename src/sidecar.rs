@@ -0,0 +1,167 @@
+use std::{
+    ffi::OsString,
+    fmt::Write,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::Result;
+use toml::Value;
+
+use krusz::{checksum::hash_file, input::is_url, output::write_atomically};
+
+use crate::Opts;
+
+/// Where the sidecar of an output goes: next to it, named after it
+pub fn sidecar_path(output: &Path) -> PathBuf {
+    let mut name = OsString::from(output.as_os_str());
+    name.push(".krusz.json");
+    PathBuf::from(name)
+}
+
+/// A JSON string, with the characters JSON doesn't allow as they are escaped
+fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+
+    json.push('"');
+    json
+}
+
+/// Writes an option value as JSON, indented to the given depth. Dates are written as strings, and
+/// infinite or NaN numbers, which JSON can't represent, as null.
+fn write_json(json: &mut String, value: &Value, depth: usize) {
+    let indent = "  ".repeat(depth + 1);
+
+    match value {
+        Value::String(s) => json.push_str(&json_string(s)),
+        Value::Integer(i) => write!(json, "{}", i).unwrap(),
+        Value::Float(f) if f.is_finite() => write!(json, "{:?}", f).unwrap(),
+        Value::Float(_) => json.push_str("null"),
+        Value::Boolean(b) => write!(json, "{}", b).unwrap(),
+        Value::Datetime(date) => json.push_str(&json_string(&date.to_string())),
+        Value::Array(array) if array.is_empty() => json.push_str("[]"),
+        Value::Array(array) => {
+            json.push('[');
+            for (i, value) in array.iter().enumerate() {
+                json.push_str(if i == 0 { "\n" } else { ",\n" });
+                json.push_str(&indent);
+                write_json(json, value, depth + 1);
+            }
+            write!(json, "\n{}]", &indent[2..]).unwrap();
+        }
+        Value::Table(table) if table.is_empty() => json.push_str("{}"),
+        Value::Table(table) => {
+            json.push('{');
+            for (i, (key, value)) in table.iter().enumerate() {
+                json.push_str(if i == 0 { "\n" } else { ",\n" });
+                write!(json, "{}{}: ", indent, json_string(key)).unwrap();
+                write_json(json, value, depth + 1);
+            }
+            write!(json, "\n{}}}", &indent[2..]).unwrap();
+        }
+    }
+}
+
+/// A path and, for local files, the hash of its contents
+fn file_json(path: &Path) -> Result<String> {
+    let path_json = json_string(&path.to_string_lossy());
+
+    Ok(if is_url(path) {
+        format!("{{ \"path\": {} }}", path_json)
+    } else {
+        format!(
+            "{{ \"path\": {}, \"sha256\": {} }}",
+            path_json,
+            json_string(&hash_file(path)?)
+        )
+    })
+}
+
+/// Describes how an output was KRUSZED, in enough detail to audit it or render it again exactly:
+/// the version of KRUSZ, the input and output with their hashes, the seed of the run along with
+/// the position of the input in it, since each input draws from the run's random numbers in turn,
+/// and every option
+pub fn sidecar(opts: &Opts, input: &Path, output: &Path, seed: u64, job: usize) -> Result<String> {
+    let mut options = String::new();
+    write_json(&mut options, &Value::try_from(opts)?, 1);
+
+    Ok(format!(
+        "{{\n  \"version\": {},\n  \"input\": {},\n  \"output\": {},\n  \"seed\": {},\n  \"job\": {},\n  \"options\": {}\n}}\n",
+        json_string(concat!("krusz ", env!("CARGO_PKG_VERSION"))),
+        file_json(input)?,
+        file_json(output)?,
+        seed,
+        job,
+        options
+    ))
+}
+
+/// Writes the sidecar of an output next to it
+pub fn write(opts: &Opts, input: &Path, output: &Path, seed: u64, job: usize) -> Result<()> {
+    let contents = sidecar(opts, input, output, seed, job)?;
+    write_atomically(&sidecar_path(output), |temp| Ok(fs::write(temp, contents)?))
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_sidecar_path() {
+        assert_eq!(
+            sidecar_path(Path::new("out/kick.wav")),
+            Path::new("out/kick.wav.krusz.json")
+        );
+    }
+
+    #[test]
+    fn test_json() {
+        assert_eq!(json_string("a \"b\"\\\n\u{1}"), r#""a \"b\"\\\n\u0001""#);
+
+        let value: Value = toml::from_str(
+            "bit_depth = 4.0\nfilter = [\"lowpass:3k\"]\nempty = []\nflag = true\n[nested]\nrate = 8000",
+        )
+        .unwrap();
+        let mut json = String::new();
+        write_json(&mut json, &value, 0);
+        assert_eq!(
+            json,
+            "{\n  \"bit_depth\": 4.0,\n  \"empty\": [],\n  \"filter\": [\n    \"lowpass:3k\"\n  ],\n  \"flag\": true,\n  \"nested\": {\n    \"rate\": 8000\n  }\n}"
+        );
+    }
+
+    #[test]
+    fn test_sidecar() {
+        let dir = std::env::temp_dir().join(format!("krusz_test_sidecar_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let (input, output) = (dir.join("in.wav"), dir.join("out.wav"));
+        fs::write(&input, b"input").unwrap();
+        fs::write(&output, b"output").unwrap();
+
+        let opts = Opts::parse_from(["krusz", "-i", "in.wav", "-b", "4", "-s", "8000"]);
+        write(&opts, &input, &output, 42, 1).unwrap();
+
+        let json = fs::read_to_string(sidecar_path(&output)).unwrap();
+        assert!(json.contains(&format!("\"sha256\": \"{}\"", hash_file(&input).unwrap())));
+        assert!(json.contains("\"seed\": 42,\n  \"job\": 1,"));
+        assert!(json.contains("\"bit_depth\": 4.0"));
+        assert!(json.contains("\"sample_rate\": 8000"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,187 @@
+//! Vectorized kernels for the per-sample loops which dominate long batch runs, using SSE2 on
+//! x86_64 and NEON on aarch64 (both always available on those targets), with a scalar fallback.
+
+use crate::{quantize::requantize_sample, sample::Sample, Channel};
+
+#[cfg(target_arch = "aarch64")]
+use std::arch::aarch64::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Number of sample lanes in a vector register
+const LANES: usize = 4;
+
+/// Requantizes every sample in place, exactly like `requantize_sample`
+pub fn requantize(samples: &mut [Sample], bit_depth: u8) {
+    if bit_depth == 32 {
+        return;
+    }
+
+    let hi_mask: Sample = !0 << (32 - bit_depth);
+    let mut chunks = samples.chunks_exact_mut(LANES);
+
+    for chunk in &mut chunks {
+        requantize_lanes(chunk, hi_mask);
+    }
+
+    for sample in chunks.into_remainder() {
+        *sample = requantize_sample(*sample, bit_depth);
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn requantize_lanes(chunk: &mut [Sample], hi_mask: Sample) {
+    debug_assert_eq!(chunk.len(), LANES);
+
+    // SAFETY: SSE2 is part of the x86_64 baseline, and the chunk holds exactly 4 samples, read and
+    // written unaligned
+    unsafe {
+        let x = _mm_loadu_si128(chunk.as_ptr() as *const __m128i);
+        let hi = _mm_set1_epi32(hi_mask);
+        let sign = _mm_srai_epi32::<31>(x);
+        // The low bits are filled with ones for non-negative samples and zeros for negative ones
+        let fill = _mm_andnot_si128(sign, _mm_andnot_si128(hi, _mm_set1_epi32(-1)));
+        let y = _mm_or_si128(_mm_and_si128(x, hi), fill);
+        _mm_storeu_si128(chunk.as_mut_ptr() as *mut __m128i, y);
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn requantize_lanes(chunk: &mut [Sample], hi_mask: Sample) {
+    debug_assert_eq!(chunk.len(), LANES);
+
+    // SAFETY: NEON is part of the aarch64 baseline, and the chunk holds exactly 4 samples
+    unsafe {
+        let x = vld1q_s32(chunk.as_ptr());
+        let hi = vdupq_n_s32(hi_mask);
+        let sign = vshrq_n_s32::<31>(x);
+        let fill = vbicq_s32(vdupq_n_s32(!hi_mask), sign);
+        vst1q_s32(chunk.as_mut_ptr(), vorrq_s32(vandq_s32(x, hi), fill));
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn requantize_lanes(chunk: &mut [Sample], hi_mask: Sample) {
+    let bit_depth = hi_mask.count_ones() as u8;
+
+    for sample in chunk {
+        *sample = requantize_sample(*sample, bit_depth);
+    }
+}
+
+/// Interleaves the channels into frames, as expected by players and encoders
+pub fn interleave(channels: &[Channel]) -> Vec<Sample> {
+    match channels {
+        [left, right] => interleave_stereo(&left.samples, &right.samples),
+        _ => {
+            let c = channels.len();
+            let frames = channels.first().map_or(0, |channel| channel.samples.len());
+
+            (0..c * frames)
+                .map(|i| channels[i % c].samples[i / c])
+                .collect()
+        }
+    }
+}
+
+fn interleave_stereo(left: &[Sample], right: &[Sample]) -> Vec<Sample> {
+    assert_eq!(left.len(), right.len());
+
+    let mut data = vec![0; left.len() * 2];
+    let split = left.len() - left.len() % LANES;
+
+    interleave_stereo_lanes(&left[..split], &right[..split], &mut data[..split * 2]);
+
+    for (i, (&l, &r)) in left[split..].iter().zip(&right[split..]).enumerate() {
+        data[(split + i) * 2] = l;
+        data[(split + i) * 2 + 1] = r;
+    }
+
+    data
+}
+
+#[cfg(target_arch = "x86_64")]
+fn interleave_stereo_lanes(left: &[Sample], right: &[Sample], data: &mut [Sample]) {
+    for ((l, r), out) in left
+        .chunks_exact(LANES)
+        .zip(right.chunks_exact(LANES))
+        .zip(data.chunks_exact_mut(LANES * 2))
+    {
+        // SAFETY: SSE2 is part of the x86_64 baseline, each input chunk holds exactly 4 samples and
+        // each output chunk 8, read and written unaligned
+        unsafe {
+            let l = _mm_loadu_si128(l.as_ptr() as *const __m128i);
+            let r = _mm_loadu_si128(r.as_ptr() as *const __m128i);
+            let out = out.as_mut_ptr() as *mut __m128i;
+            _mm_storeu_si128(out, _mm_unpacklo_epi32(l, r));
+            _mm_storeu_si128(out.add(1), _mm_unpackhi_epi32(l, r));
+        }
+    }
+}
+
+#[cfg(target_arch = "aarch64")]
+fn interleave_stereo_lanes(left: &[Sample], right: &[Sample], data: &mut [Sample]) {
+    for ((l, r), out) in left
+        .chunks_exact(LANES)
+        .zip(right.chunks_exact(LANES))
+        .zip(data.chunks_exact_mut(LANES * 2))
+    {
+        // SAFETY: NEON is part of the aarch64 baseline, each input chunk holds exactly 4 samples and
+        // each output chunk 8
+        unsafe {
+            let pair = int32x4x2_t(vld1q_s32(l.as_ptr()), vld1q_s32(r.as_ptr()));
+            vst2q_s32(out.as_mut_ptr(), pair);
+        }
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn interleave_stereo_lanes(left: &[Sample], right: &[Sample], data: &mut [Sample]) {
+    for (frame, (&l, &r)) in data.chunks_exact_mut(2).zip(left.iter().zip(right)) {
+        frame[0] = l;
+        frame[1] = r;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_requantize() {
+        // Every 16-bit sample, with a few different patterns in the low bits
+        let all: Vec<Sample> = (i16::MIN..=i16::MAX)
+            .map(|sample| {
+                (Sample::from(sample) << 16) | ((sample as Sample & 0xffff) * 7 % 0x10000)
+            })
+            .collect();
+
+        for bit_depth in 1..=32 {
+            let mut samples = all.clone();
+            requantize(&mut samples[3..], bit_depth);
+
+            assert_eq!(&samples[..3], &all[..3]);
+            for (&sample, &original) in samples.iter().zip(&all).skip(3) {
+                assert_eq!(sample, requantize_sample(original, bit_depth));
+            }
+        }
+    }
+
+    #[test]
+    fn test_interleave() {
+        for frames in [0, 5, 8, 21] {
+            let channels: Vec<_> = (0..2)
+                .map(|c| Channel {
+                    samples: (0..frames).map(|i| i * 2 + c).collect(),
+                })
+                .collect();
+
+            assert_eq!(interleave(&channels), (0..frames * 2).collect::<Vec<_>>());
+        }
+
+        let mono = [Channel {
+            samples: vec![1, 2, 3],
+        }];
+        assert_eq!(interleave(&mono), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,243 @@
+use std::{
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, Report, Result};
+
+use crate::{sample::FULL_SCALE, Channel, Sound};
+
+/// How to cut a sound into slices, as given on the command line: a number of equal slices, e.g.
+/// `16`, or `transients` to cut it at each attack
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Slicing {
+    Even(usize),
+    Transients,
+}
+
+impl FromStr for Slicing {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+
+        if s.eq_ignore_ascii_case("transients") {
+            return Ok(Self::Transients);
+        }
+
+        let count: usize = s.parse()?;
+        ensure!(
+            (1..=128).contains(&count),
+            "The number of slices must be between 1 and 128 inclusive"
+        );
+
+        Ok(Self::Even(count))
+    }
+}
+
+impl Display for Slicing {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Even(count) => write!(f, "{}", count),
+            Self::Transients => write!(f, "transients"),
+        }
+    }
+}
+
+serde_via_str!(Slicing);
+
+/// Length of the windows the level is followed in to find attacks, in seconds
+const ONSET_WINDOW: f64 = 0.01;
+/// Windows before an attack its level is compared to
+const ONSET_HISTORY: usize = 4;
+/// How much louder than the windows before it a window has to be to start an attack, as a ratio of
+/// mean squares (6 dB)
+const ONSET_RISE: f64 = 4.0;
+/// Level below which nothing counts as an attack, relative to full scale (-40 dB)
+const ONSET_FLOOR: f64 = 1e-4;
+/// Shortest time between two attacks, in seconds
+const ONSET_GAP: f64 = 0.05;
+
+/// The first frames of the slices, starting with 0
+pub fn slice_points(sound: &Sound, slicing: Slicing) -> Vec<usize> {
+    match slicing {
+        Slicing::Even(count) => {
+            let mut points: Vec<_> = (0..count).map(|i| sound.frames() * i / count).collect();
+            // Sounds shorter than the number of slices get fewer of them
+            points.dedup();
+            points
+        }
+        Slicing::Transients => onsets(sound),
+    }
+}
+
+/// Finds where attacks start: windows much louder than the ones just before them
+pub fn onsets(sound: &Sound) -> Vec<usize> {
+    let window = ((ONSET_WINDOW * sound.sample_rate as f64).round() as usize).max(1);
+    let gap = (ONSET_GAP * sound.sample_rate as f64).round() as usize;
+
+    // Mean square of each window, across all channels
+    let levels: Vec<f64> = (0..sound.frames())
+        .step_by(window)
+        .map(|start| {
+            let end = (start + window).min(sound.frames());
+            let sum: f64 = sound
+                .channels
+                .iter()
+                .flat_map(|channel| &channel.samples[start..end])
+                .map(|&sample| (sample as f64 / FULL_SCALE).powi(2))
+                .sum();
+
+            sum / ((end - start) * sound.channels.len()) as f64
+        })
+        .collect();
+
+    let mut onsets = vec![0];
+
+    for (i, &level) in levels.iter().enumerate().skip(1) {
+        let history = &levels[i.saturating_sub(ONSET_HISTORY)..i];
+        let before = history.iter().sum::<f64>() / history.len() as f64;
+        let frame = i * window;
+
+        if level > ONSET_FLOOR
+            && level > before * ONSET_RISE
+            && frame - onsets.last().unwrap() >= gap
+        {
+            onsets.push(frame);
+        }
+    }
+
+    onsets
+}
+
+/// Cuts a sound at the given first frames of its slices, which start with 0
+pub fn cut(sound: &Sound, points: &[usize]) -> Vec<Sound> {
+    points
+        .iter()
+        .enumerate()
+        .map(|(i, &start)| {
+            let end = points.get(i + 1).copied().unwrap_or_else(|| sound.frames());
+
+            Sound {
+                channels: sound
+                    .channels
+                    .iter()
+                    .map(|channel| Channel {
+                        samples: channel.samples[start..end].to_vec(),
+                    })
+                    .collect(),
+                sample_rate: sound.sample_rate,
+            }
+        })
+        .collect()
+}
+
+/// Where a slice of an output goes: next to it, numbered from 1, e.g. `break-01.wav`
+pub fn slice_path(output: &Path, index: usize, count: usize) -> PathBuf {
+    let digits = count.to_string().len().max(2);
+    let mut name = OsString::from(output.file_stem().unwrap_or_default());
+    name.push(format!("-{:0width$}", index + 1, width = digits));
+
+    if let Some(extension) = output.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+
+    output.with_file_name(name)
+}
+
+/// MIDI key the first slice is mapped to in SFZ kits, C1 as on most pad controllers
+pub const FIRST_KEY: u8 = 36;
+
+/// An SFZ instrument playing each slice once on consecutive keys, from `FIRST_KEY` on. The slices
+/// are referred to by file name, so the SFZ file has to be next to them.
+pub fn sfz(slices: &[PathBuf]) -> String {
+    let mut sfz = String::from("<group> loop_mode=one_shot\n");
+
+    for (i, slice) in slices.iter().enumerate() {
+        sfz.push_str(&format!(
+            "<region> sample={} key={}\n",
+            slice.file_name().unwrap_or_default().to_string_lossy(),
+            usize::from(FIRST_KEY) + i
+        ));
+    }
+
+    sfz
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_slicing() {
+        assert_eq!("16".parse::<Slicing>().unwrap(), Slicing::Even(16));
+        assert_eq!(
+            "Transients".parse::<Slicing>().unwrap(),
+            Slicing::Transients
+        );
+        assert!("0".parse::<Slicing>().is_err());
+        assert!("beats".parse::<Slicing>().is_err());
+    }
+
+    #[test]
+    fn test_even() {
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..10).collect(),
+            }],
+            sample_rate: 8000,
+        };
+
+        let points = slice_points(&sound, Slicing::Even(3));
+        assert_eq!(points, [0, 3, 6]);
+
+        let slices = cut(&sound, &points);
+        assert_eq!(slices[1].channels[0].samples, [3, 4, 5]);
+        assert_eq!(slices[2].channels[0].samples, [6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn test_onsets() {
+        // Four decaying hits a quarter of a second apart, in quiet noise
+        let samples = (0..44100)
+            .map(|i| {
+                let since_hit = (i % 11025) as f64 / 44100.0;
+                let hit = (-since_hit * 40.0).exp() * if i % 2 == 0 { 0.5 } else { -0.5 };
+                ((hit + if i % 3 == 0 { 1e-4 } else { -1e-4 }) * FULL_SCALE) as i32
+            })
+            .collect();
+        let sound = Sound {
+            channels: vec![Channel { samples }],
+            sample_rate: 44100,
+        };
+
+        let onsets = onsets(&sound);
+        assert_eq!(onsets.len(), 4);
+        for (i, onset) in onsets.iter().enumerate() {
+            assert!(onset.abs_diff(i * 11025) <= 441, "onset {} at {}", i, onset);
+        }
+    }
+
+    #[test]
+    fn test_slice_path() {
+        assert_eq!(
+            slice_path(Path::new("out/break.wav"), 0, 16),
+            Path::new("out/break-01.wav")
+        );
+        assert_eq!(
+            slice_path(Path::new("break.wav"), 99, 128),
+            Path::new("break-100.wav")
+        );
+    }
+
+    #[test]
+    fn test_sfz() {
+        assert_eq!(
+            sfz(&[PathBuf::from("out/a-01.wav"), PathBuf::from("out/a-02.wav")]),
+            "<group> loop_mode=one_shot\n<region> sample=a-01.wav key=36\n<region> sample=a-02.wav key=37\n"
+        );
+    }
+}
@@ -0,0 +1,87 @@
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use krusz::sf2::{save_sf2, Sf2Sample};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use tracing::debug_span;
+
+use crate::{error::ErrorKind, repl::render, Opts};
+
+/// Root key of the first sound when none are given, middle C
+const DEFAULT_ROOT_KEY: u8 = 60;
+
+fn stem(path: &Path) -> String {
+    path.file_stem()
+        .map_or_else(String::new, |stem| stem.to_string_lossy().into_owned())
+}
+
+/// KRUSZES the inputs with the settings of `opts`, and wraps them into a SoundFont at `output`
+pub fn run(
+    opts: &Opts,
+    output: &Path,
+    inputs: &[PathBuf],
+    root_keys: &[u8],
+    name: Option<&str>,
+) -> Result<()> {
+    let root_keys: Vec<u8> = if root_keys.is_empty() {
+        (0..inputs.len())
+            .map(|i| DEFAULT_ROOT_KEY.saturating_add(i as u8))
+            .collect()
+    } else if root_keys.len() == inputs.len() {
+        root_keys.to_vec()
+    } else {
+        return Err(eyre!(
+            "Got {} root keys for {} inputs, give either one per input or none",
+            root_keys.len(),
+            inputs.len()
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    };
+
+    if root_keys.iter().any(|&key| key > 127) {
+        return Err(eyre!(
+            "Root keys must be MIDI keys, between 0 and 127 inclusive"
+        ))
+        .wrap_err(ErrorKind::Parameter);
+    }
+
+    let mut rng = match opts.seed {
+        Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+        None => ChaCha8Rng::from_entropy(),
+    };
+
+    let samples = inputs
+        .iter()
+        .zip(root_keys)
+        .map(|(input, root_key)| {
+            let original = opts.decode(input).wrap_err(ErrorKind::Input)?;
+
+            Ok(Sf2Sample {
+                name: stem(input),
+                sound: render(&original, &opts.settings(), opts.auto_gain, rng.gen())?,
+                root_key,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let name = name.map_or_else(|| stem(output), str::to_owned);
+    debug_span!("encode", output = %output.display())
+        .in_scope(|| save_sf2(output, &name, &samples, opts.looped))
+        .wrap_err(ErrorKind::Output)
+}
+
+#[cfg(test)]
+mod test {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn test_root_key_count() {
+        let opts = Opts::parse_from(["krusz", "sf2", "out.sf2", "a.wav", "b.wav"]);
+        let inputs = [PathBuf::from("a.wav"), PathBuf::from("b.wav")];
+        let error = run(&opts, Path::new("out.sf2"), &inputs, &[60], None).unwrap_err();
+        assert_eq!(ErrorKind::of(&error), Some(ErrorKind::Parameter));
+    }
+}
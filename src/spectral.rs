@@ -0,0 +1,87 @@
+use std::f64::consts::PI;
+
+use num::Complex;
+
+use crate::{sample::ClipMode, stft::Stft};
+
+/// Frame size of the spectral crusher, about 46 ms at 44100 Hz
+pub const FRAME_SIZE: usize = 2048;
+
+/// Bit crushing in the frequency domain: the magnitude of each frequency bin, and optionally its
+/// phase, is quantized to a number of bits. Quiet bins drop out and loud ones smear, which sounds
+/// more like a starved MP3 than a cheap sampler.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpectralCrush {
+    pub bits: u8,
+    pub phase: bool,
+}
+
+impl SpectralCrush {
+    pub fn stage(
+        self,
+        sample_rate: u32,
+        clip_mode: ClipMode,
+    ) -> Stft<impl Fn(&mut [Complex<f64>]) + Send + Sync> {
+        Stft::new(
+            FRAME_SIZE,
+            sample_rate,
+            move |spectrum: &mut [Complex<f64>]| crush_spectrum(spectrum, self.bits, self.phase),
+        )
+        .with_clip_mode(clip_mode)
+    }
+}
+
+/// Quantizes magnitudes in steps of the loudest bin's, and phases in steps of the full circle
+pub fn crush_spectrum(spectrum: &mut [Complex<f64>], bits: u8, phase: bool) {
+    let peak = spectrum.iter().map(|bin| bin.norm()).fold(0.0, f64::max);
+    if peak == 0.0 {
+        return;
+    }
+
+    let levels = ((1u32 << bits) - 1) as f64;
+    let magnitude_step = peak / levels;
+    let phase_step = 2.0 * PI / (1u32 << bits) as f64;
+
+    for bin in spectrum {
+        let (magnitude, mut angle) = bin.to_polar();
+
+        if phase {
+            angle = (angle / phase_step).round() * phase_step;
+        }
+
+        *bin = Complex::from_polar((magnitude / magnitude_step).round() * magnitude_step, angle);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_crush_spectrum() {
+        let mut spectrum = vec![
+            Complex::new(8.0, 0.0),
+            Complex::new(0.0, 2.9),
+            Complex::new(0.5, 0.5),
+            Complex::new(0.0, -2.9),
+        ];
+        crush_spectrum(&mut spectrum, 2, false);
+
+        assert!((spectrum[0] - Complex::new(8.0, 0.0)).norm() < 1e-12);
+        assert!((spectrum[1] - Complex::new(0.0, 8.0 / 3.0)).norm() < 1e-12);
+        assert!(spectrum[2].norm() < 1e-12);
+        assert!((spectrum[3] - spectrum[1].conj()).norm() < 1e-12);
+
+        let mut spectrum = vec![
+            Complex::from_polar(1.0, 1.2),
+            Complex::from_polar(1.0, -1.2),
+        ];
+        crush_spectrum(&mut spectrum, 2, true);
+        assert!((spectrum[0] - Complex::new(0.0, 1.0)).norm() < 1e-12);
+        assert!((spectrum[1] - Complex::new(0.0, -1.0)).norm() < 1e-12);
+
+        let mut silence = vec![Complex::new(0.0, 0.0); 4];
+        crush_spectrum(&mut silence, 4, true);
+        assert!(silence.iter().all(|bin| bin.norm() == 0.0));
+    }
+}
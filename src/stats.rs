@@ -0,0 +1,285 @@
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    fmt::{self, Display, Formatter},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use tracing::{
+    field::{Field, Visit},
+    span::{Attributes, Id, Record},
+    Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+
+/// Bytes currently allocated, and the most allocated at once since the innermost span was entered
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, keeping count of how much memory is in use for --stats
+pub struct CountingAllocator;
+
+impl CountingAllocator {
+    fn allocated(size: usize) {
+        let current = CURRENT.fetch_add(size, Ordering::Relaxed) + size;
+        PEAK.fetch_max(current, Ordering::Relaxed);
+    }
+
+    fn freed(size: usize) {
+        CURRENT.fetch_sub(size, Ordering::Relaxed);
+    }
+}
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            Self::allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        Self::freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            Self::freed(layout.size());
+            Self::allocated(new_size);
+        }
+        new_ptr
+    }
+}
+
+/// What a single span measured, kept in its extensions until it closes
+struct Timing {
+    label: String,
+    samples: u64,
+    busy: Duration,
+    peak: usize,
+    entered: Option<Instant>,
+    /// The peak of the enclosing span, put back on exit
+    outer_peak: usize,
+}
+
+/// Picks the stage name and the sample count out of span fields
+struct Fields<'a>(&'a mut Timing);
+
+impl Visit for Fields<'_> {
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        if field.name() == "samples" {
+            self.0.samples += value;
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "name" {
+            self.0.label = value.to_owned();
+        }
+    }
+
+    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+}
+
+/// Totals of all the spans with the same label
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Totals {
+    count: usize,
+    samples: u64,
+    busy: Duration,
+    peak: usize,
+}
+
+/// A tracing layer adding up how long each kind of span was busy, how many samples went through
+/// it, and the most memory in use while it was. Pipeline stages are told apart by their names.
+/// Spans running on several threads at once share the memory count, so their peaks overlap.
+#[derive(Clone, Default)]
+pub struct Stats {
+    /// Labels in the order they first appeared
+    totals: Arc<Mutex<Vec<(String, Totals)>>>,
+}
+
+impl<S> Layer<S> for Stats
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut timing = Timing {
+            label: attrs.metadata().name().to_owned(),
+            samples: 0,
+            busy: Duration::ZERO,
+            peak: 0,
+            entered: None,
+            outer_peak: 0,
+        };
+        attrs.record(&mut Fields(&mut timing));
+
+        let mut totals = self.totals.lock().unwrap();
+        if !totals.iter().any(|(label, _)| *label == timing.label) {
+            totals.push((timing.label.clone(), Totals::default()));
+        }
+
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(timing);
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                values.record(&mut Fields(timing));
+            }
+        }
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                timing.outer_peak = PEAK.swap(CURRENT.load(Ordering::Relaxed), Ordering::Relaxed);
+                timing.entered = Some(Instant::now());
+            }
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if let Some(span) = ctx.span(id) {
+            if let Some(timing) = span.extensions_mut().get_mut::<Timing>() {
+                if let Some(entered) = timing.entered.take() {
+                    timing.busy += entered.elapsed();
+                }
+
+                // Whatever this span reached also counts for the span around it
+                let peak = PEAK.fetch_max(timing.outer_peak, Ordering::Relaxed);
+                timing.peak = timing.peak.max(peak);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let timing = match ctx.span(&id) {
+            Some(span) => span.extensions_mut().remove::<Timing>(),
+            None => None,
+        };
+
+        if let Some(timing) = timing {
+            let mut totals = self.totals.lock().unwrap();
+            if let Some((_, totals)) = totals.iter_mut().find(|(label, _)| *label == timing.label) {
+                totals.count += 1;
+                totals.samples += timing.samples;
+                totals.busy += timing.busy;
+                totals.peak = totals.peak.max(timing.peak);
+            }
+        }
+    }
+}
+
+fn format_bytes(bytes: usize) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// One line per label: time busy, throughput where samples were counted, and peak memory
+impl Display for Stats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<16} {:>12} {:>16} {:>12}",
+            "stage", "time", "samples/s", "peak memory"
+        )?;
+
+        for (label, totals) in self.totals.lock().unwrap().iter() {
+            if totals.count == 0 {
+                continue;
+            }
+
+            let seconds = totals.busy.as_secs_f64();
+            let throughput = if totals.samples > 0 && seconds > 0.0 {
+                format!("{:.0}", totals.samples as f64 / seconds)
+            } else {
+                "-".to_owned()
+            };
+
+            writeln!(
+                f,
+                "{:<16} {:>12} {:>16} {:>12}",
+                label,
+                format!("{:.1} ms", seconds * 1000.0),
+                throughput,
+                format_bytes(totals.peak)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tracing::{debug_span, field};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let stats = Stats::default();
+        let subscriber = tracing_subscriber::registry().with(stats.clone());
+
+        tracing::subscriber::with_default(subscriber, || {
+            let decode = debug_span!("decode", samples = field::Empty);
+            decode.in_scope(|| {
+                // Memory freed before the span ends still counts towards its peak
+                drop(vec![0u8; 1 << 20]);
+            });
+            decode.record("samples", &100u64);
+            drop(decode);
+
+            for _ in 0..2 {
+                debug_span!("stage", name = "resample", samples = 50u64).in_scope(|| {});
+            }
+        });
+
+        let totals = stats.totals.lock().unwrap().clone();
+        let labels: Vec<&str> = totals.iter().map(|(label, _)| label.as_str()).collect();
+        assert_eq!(labels, ["decode", "resample"]);
+        assert_eq!((totals[0].1.count, totals[0].1.samples), (1, 100));
+        assert!(totals[0].1.peak >= 1 << 20);
+        assert_eq!((totals[1].1.count, totals[1].1.samples), (2, 100));
+
+        let report = stats.to_string();
+        assert!(report.starts_with("stage"));
+        assert!(report.contains("resample"));
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(512), "512 B");
+        assert_eq!(format_bytes(3 << 19), "1.5 MiB");
+    }
+}
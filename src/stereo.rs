@@ -0,0 +1,265 @@
+use clap::ArgEnum;
+use color_eyre::eyre::{ensure, Result};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    sample::{to_sample, ClipMode},
+    stream::{Stage, MIN_BLOCK},
+    Sound,
+};
+
+/// Splits a left/right pair into mid and side
+pub fn to_mid_side(left: f64, right: f64) -> (f64, f64) {
+    ((left + right) / 2.0, (left - right) / 2.0)
+}
+
+/// Joins mid and side back into a left/right pair
+pub fn from_mid_side(mid: f64, side: f64) -> (f64, f64) {
+    (mid + side, mid - side)
+}
+
+/// Parses a percentage such as `150%` or `150` into a fraction
+pub fn parse_percent(s: &str) -> Result<f64> {
+    let percent: f64 = s.trim().trim_end_matches('%').parse()?;
+    ensure!(percent.is_finite(), "Percentage must be a finite number");
+    Ok(percent / 100.0)
+}
+
+/// Scales the side signal of a stereo stream: 0 collapses it to mono, 1 leaves it untouched, 2 doubles the width
+pub struct WidthStage {
+    width: f64,
+    clip_mode: ClipMode,
+}
+
+impl WidthStage {
+    pub fn new(width: f64, clip_mode: ClipMode) -> Self {
+        Self { width, clip_mode }
+    }
+}
+
+impl Stage for WidthStage {
+    fn name(&self) -> &'static str {
+        "width"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        if let [left, right] = &mut chunk.channels[..] {
+            left.samples
+                .par_iter_mut()
+                .zip(right.samples.par_iter_mut())
+                .with_min_len(MIN_BLOCK)
+                .for_each(|(left, right)| {
+                    let (mid, side) = to_mid_side(*left as f64, *right as f64);
+                    let (l, r) = from_mid_side(mid, side * self.width);
+                    *left = to_sample(l, self.clip_mode);
+                    *right = to_sample(r, self.clip_mode);
+                });
+        }
+
+        chunk
+    }
+}
+
+/// Channels whose polarity is inverted
+#[derive(Clone, Copy, Debug, PartialEq, ArgEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Invert {
+    /// The first channel
+    L,
+    /// The second channel
+    R,
+    All,
+}
+
+/// Swaps the first two channels and then inverts the polarity of some of them, so that inverting
+/// the left channel inverts the channel which ends up on the left
+pub struct PolarityStage {
+    invert: Option<Invert>,
+    swap: bool,
+    clip_mode: ClipMode,
+}
+
+impl PolarityStage {
+    pub fn new(invert: Option<Invert>, swap: bool, clip_mode: ClipMode) -> Self {
+        Self {
+            invert,
+            swap,
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for PolarityStage {
+    fn name(&self) -> &'static str {
+        "polarity"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        if self.swap && chunk.channels.len() >= 2 {
+            chunk.channels.swap(0, 1);
+        }
+
+        let inverted = match self.invert {
+            Some(Invert::L) => 0..1,
+            Some(Invert::R) => 1..2,
+            Some(Invert::All) => 0..chunk.channels.len(),
+            None => 0..0,
+        };
+
+        let clip_mode = self.clip_mode;
+        for channel in chunk
+            .channels
+            .iter_mut()
+            .take(inverted.end)
+            .skip(inverted.start)
+        {
+            channel
+                .samples
+                .par_iter_mut()
+                .with_min_len(MIN_BLOCK)
+                .for_each(|sample| *sample = to_sample(-(*sample as f64), clip_mode));
+        }
+
+        chunk
+    }
+}
+
+/// How much quieter the mono fold-down may get than the stereo sound, in dB, before its mono
+/// compatibility is poor. Channels which have nothing in common lose 3 dB, so this is well into
+/// cancellation.
+pub const MONO_LOSS_THRESHOLD: f64 = -6.0;
+
+/// Measures how well a stereo stream survives being folded down to mono, block by block, as on the
+/// single speaker of toys and phones. Only the first two channels count.
+#[derive(Default)]
+pub struct MonoMeter {
+    left: f64,
+    right: f64,
+    /// Sum of the products of the left and right samples
+    product: f64,
+}
+
+impl MonoMeter {
+    pub fn update(&mut self, block: &Sound) {
+        if let [left, right, ..] = &block.channels[..] {
+            for (&l, &r) in left.samples.iter().zip(&right.samples) {
+                let (l, r) = (l as f64, r as f64);
+                self.left += l * l;
+                self.right += r * r;
+                self.product += l * r;
+            }
+        }
+    }
+
+    /// Correlation of the channels, from 1 when they're the same through 0 when they have nothing
+    /// in common to -1 when they cancel out. Silence counts as the same on both.
+    pub fn correlation(&self) -> f64 {
+        let energy = (self.left * self.right).sqrt();
+        if energy == 0.0 {
+            return 1.0;
+        }
+
+        (self.product / energy).clamp(-1.0, 1.0)
+    }
+
+    /// Level of the mono fold-down relative to the stereo sound, in dB: 0 when nothing cancels out,
+    /// down to -∞ when everything does
+    pub fn fold_down_loss(&self) -> f64 {
+        let stereo = (self.left + self.right) / 2.0;
+        if stereo == 0.0 {
+            return 0.0;
+        }
+
+        let mono = (self.left + self.right + 2.0 * self.product) / 4.0;
+        10.0 * (mono / stereo).log10()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{sample::Sample, Channel};
+
+    #[test]
+    fn test_width() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![100, 0, -50],
+                },
+                Channel {
+                    samples: vec![0, 100, 50],
+                },
+            ],
+            sample_rate: 44100,
+        };
+
+        let mono = WidthStage::new(0.0, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(mono.channels[0].samples, vec![50, 50, 0]);
+        assert_eq!(mono.channels[0].samples, mono.channels[1].samples);
+
+        let same = WidthStage::new(1.0, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(same.channels[0].samples, sound.channels[0].samples);
+
+        let wide = WidthStage::new(2.0, ClipMode::Clamp).run(sound);
+        assert_eq!(wide.channels[0].samples, vec![150, -50, -100]);
+        assert_eq!(wide.channels[1].samples, vec![-50, 150, 100]);
+
+        assert_eq!(parse_percent("150%").unwrap(), 1.5);
+        assert_eq!(parse_percent("50").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn test_polarity() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![100, i32::MIN],
+                },
+                Channel {
+                    samples: vec![-7, 50],
+                },
+            ],
+            sample_rate: 44100,
+        };
+
+        let inverted =
+            PolarityStage::new(Some(Invert::L), false, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(inverted.channels[0].samples, vec![-100, i32::MAX]);
+        assert_eq!(inverted.channels[1].samples, vec![-7, 50]);
+
+        let swapped = PolarityStage::new(Some(Invert::L), true, ClipMode::Clamp).run(sound.clone());
+        assert_eq!(swapped.channels[0].samples, vec![7, -50]);
+        assert_eq!(swapped.channels[1].samples, vec![100, i32::MIN]);
+
+        let all = PolarityStage::new(Some(Invert::All), false, ClipMode::Clamp).run(sound);
+        assert_eq!(all.channels[1].samples, vec![7, -50]);
+    }
+
+    #[test]
+    fn test_mono_meter() {
+        let meter = |left: Vec<Sample>, right: Vec<Sample>| {
+            let mut meter = MonoMeter::default();
+            meter.update(&Sound {
+                channels: vec![Channel { samples: left }, Channel { samples: right }],
+                sample_rate: 44100,
+            });
+            meter
+        };
+
+        let same = meter(vec![100, -50, 30], vec![100, -50, 30]);
+        assert_eq!(same.correlation(), 1.0);
+        assert_eq!(same.fold_down_loss(), 0.0);
+
+        let cancelled = meter(vec![100, -50, 30], vec![-100, 50, -30]);
+        assert_eq!(cancelled.correlation(), -1.0);
+        assert_eq!(cancelled.fold_down_loss(), f64::NEG_INFINITY);
+
+        let apart = meter(vec![100, 0, 100, 0], vec![0, 100, 0, 100]);
+        assert_eq!(apart.correlation(), 0.0);
+        assert!((apart.fold_down_loss() + 3.01).abs() < 0.01);
+
+        assert_eq!(MonoMeter::default().fold_down_loss(), 0.0);
+    }
+}
@@ -0,0 +1,330 @@
+use std::f64::consts::PI;
+
+use num::Complex;
+use rayon::prelude::*;
+
+use crate::{
+    sample::{to_sample, ClipMode},
+    stream::Stage,
+    Channel, Sound,
+};
+
+/// Transforms a buffer whose length is a power of two in place, with an iterative radix-2 FFT.
+/// The inverse transform is scaled by `1 / len`, so that a round trip gives back the input.
+pub fn fft(buffer: &mut [Complex<f64>], inverse: bool) {
+    let len = buffer.len();
+    assert!(
+        len.is_power_of_two(),
+        "FFT length {} is not a power of two",
+        len
+    );
+
+    let bits = len.trailing_zeros();
+    for i in 0..len {
+        let j = i.reverse_bits() >> (usize::BITS - bits) as usize;
+        if i < j {
+            buffer.swap(i, j);
+        }
+    }
+
+    let sign = if inverse { 1.0 } else { -1.0 };
+    let mut size = 2;
+    while size <= len {
+        let step = Complex::from_polar(1.0, sign * 2.0 * PI / size as f64);
+
+        for block in buffer.chunks_mut(size) {
+            let (even, odd) = block.split_at_mut(size / 2);
+            let mut twiddle = Complex::new(1.0, 0.0);
+
+            for (even, odd) in even.iter_mut().zip(odd) {
+                let t = *odd * twiddle;
+                *odd = *even - t;
+                *even += t;
+                twiddle *= step;
+            }
+        }
+
+        size *= 2;
+    }
+
+    if inverse {
+        let scale = 1.0 / len as f64;
+        for value in buffer {
+            *value *= scale;
+        }
+    }
+}
+
+/// Buffers of a single channel
+#[derive(Clone)]
+struct StftChannel {
+    /// Input samples not yet shifted out of the analysis frame
+    input: Vec<f64>,
+    /// Overlap-added output of the frames so far, starting at the next sample to emit
+    output: Vec<f64>,
+}
+
+/// A short-time Fourier transform stage: the sound is cut into overlapping windowed frames, whose
+/// spectra are modified in place by `f` before being resynthesized by overlap-add. The spectrum
+/// handed to `f` is the full complex one, so `f` must keep it conjugate-symmetric for the output to
+/// be real.
+pub struct Stft<F> {
+    size: usize,
+    hop: usize,
+    sample_rate: u32,
+    /// Square root of a periodic Hann window, used for both analysis and synthesis
+    window: Vec<f64>,
+    /// Scale which undoes the gain of overlapping the windows
+    scale: f64,
+    channels: Vec<StftChannel>,
+    received: usize,
+    emitted: usize,
+    clip_mode: ClipMode,
+    f: F,
+}
+
+impl<F: Fn(&mut [Complex<f64>]) + Send + Sync> Stft<F> {
+    /// Frames of `size` samples, which must be a power of two, overlapping by three quarters
+    pub fn new(size: usize, sample_rate: u32, f: F) -> Self {
+        assert!(size.is_power_of_two() && size >= 4);
+
+        let hop = size / 4;
+        let window: Vec<f64> = (0..size)
+            .map(|i| (0.5 - 0.5 * (2.0 * PI * i as f64 / size as f64).cos()).sqrt())
+            .collect();
+        let scale = hop as f64 / window.iter().map(|w| w * w).sum::<f64>();
+
+        Self {
+            size,
+            hop,
+            sample_rate,
+            window,
+            scale,
+            channels: Vec::new(),
+            received: 0,
+            emitted: 0,
+            clip_mode: ClipMode::Clamp,
+            f,
+        }
+    }
+
+    /// Brings resynthesized samples exceeding full scale back into range with `clip_mode`, rather
+    /// than clamping them
+    pub fn with_clip_mode(mut self, clip_mode: ClipMode) -> Self {
+        self.clip_mode = clip_mode;
+        self
+    }
+
+    /// Runs every complete frame of each channel, returning the samples which no later frame overlaps
+    fn frames(&mut self) -> Sound {
+        let Self {
+            size,
+            hop,
+            sample_rate,
+            window,
+            scale,
+            channels,
+            clip_mode,
+            f,
+            ..
+        } = self;
+        let (size, hop, scale, clip_mode) = (*size, *hop, *scale, *clip_mode);
+        let (window, f) = (&*window, &*f);
+
+        let channels = channels
+            .par_iter_mut()
+            .map(|channel| {
+                let mut samples = Vec::new();
+                let mut frame = vec![Complex::new(0.0, 0.0); size];
+                let mut start = 0;
+
+                while channel.input.len() - start >= size {
+                    for ((bin, &sample), &w) in frame
+                        .iter_mut()
+                        .zip(&channel.input[start..start + size])
+                        .zip(window.iter())
+                    {
+                        *bin = Complex::new(sample * w, 0.0);
+                    }
+
+                    fft(&mut frame, false);
+                    f(&mut frame);
+                    fft(&mut frame, true);
+
+                    for ((output, bin), &w) in
+                        channel.output.iter_mut().zip(&frame).zip(window.iter())
+                    {
+                        *output += bin.re * w;
+                    }
+
+                    samples.extend(
+                        channel
+                            .output
+                            .drain(..hop)
+                            .map(|sample| to_sample(sample * scale, clip_mode)),
+                    );
+                    channel.output.resize(size, 0.0);
+                    start += hop;
+                }
+
+                channel.input.drain(..start);
+
+                Channel { samples }
+            })
+            .collect();
+
+        Sound {
+            channels,
+            sample_rate: *sample_rate,
+        }
+    }
+
+    /// Drops the output of the padding before the first sample, and anything past the last one
+    fn trim(&mut self, mut sound: Sound) -> Sound {
+        let padding = self.size - self.hop;
+        let frames = sound.frames();
+        let start = padding.saturating_sub(self.emitted).min(frames);
+        let end = (padding + self.received)
+            .saturating_sub(self.emitted)
+            .clamp(start, frames);
+
+        for channel in &mut sound.channels {
+            channel.samples.truncate(end);
+            channel.samples.drain(..start);
+        }
+
+        self.emitted += frames;
+        sound
+    }
+}
+
+impl<F: Fn(&mut [Complex<f64>]) + Send + Sync> Stage for Stft<F> {
+    fn name(&self) -> &'static str {
+        "stft"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        if self.channels.is_empty() {
+            // Start with a frame's worth of silence less one hop, so that the first samples are
+            // covered by as many frames as all the others
+            self.channels = vec![
+                StftChannel {
+                    input: vec![0.0; self.size - self.hop],
+                    output: vec![0.0; self.size],
+                };
+                chunk.channels.len()
+            ];
+        }
+
+        self.received += chunk.frames();
+        for (state, channel) in self.channels.iter_mut().zip(&chunk.channels) {
+            state
+                .input
+                .extend(channel.samples.iter().map(|&sample| sample as f64));
+        }
+
+        let output = self.frames();
+        self.trim(output)
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        if self.channels.is_empty() {
+            return None;
+        }
+
+        for channel in &mut self.channels {
+            let len = channel.input.len();
+            channel.input.resize(len + self.size, 0.0);
+        }
+
+        let output = self.frames();
+        Some(self.trim(output))
+    }
+
+    fn latency(&self) -> f64 {
+        self.size as f64 / self.sample_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_fft() {
+        let mut impulse = vec![Complex::new(0.0, 0.0); 8];
+        impulse[0] = Complex::new(1.0, 0.0);
+        fft(&mut impulse, false);
+        assert!(impulse
+            .iter()
+            .all(|&bin| (bin - Complex::new(1.0, 0.0)).norm() < 1e-12));
+
+        let signal: Vec<_> = (0..64)
+            .map(|i| Complex::new((i as f64 * 0.3).sin(), 0.0))
+            .collect();
+        let mut spectrum = signal.clone();
+        fft(&mut spectrum, false);
+
+        // A cosine at bin 3 only shows up in bins 3 and 61
+        let mut cosine: Vec<_> = (0..64)
+            .map(|i| Complex::new((2.0 * PI * 3.0 * i as f64 / 64.0).cos(), 0.0))
+            .collect();
+        fft(&mut cosine, false);
+        for (i, bin) in cosine.iter().enumerate() {
+            let expected = if i == 3 || i == 61 { 32.0 } else { 0.0 };
+            assert!((bin.norm() - expected).abs() < 1e-9);
+        }
+
+        fft(&mut spectrum, true);
+        for (a, b) in spectrum.iter().zip(&signal) {
+            assert!((a - b).norm() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn test_stft_identity() {
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: (0..5000).map(|i| ((i * 37) % 2000) - 1000).collect(),
+                },
+                Channel {
+                    samples: (0..5000).map(|i| i % 300).collect(),
+                },
+            ],
+            sample_rate: 44100,
+        };
+
+        let whole = Stft::new(256, 44100, |_: &mut [Complex<f64>]| {}).run(sound.clone());
+        assert_eq!(whole.frames(), sound.frames());
+        for (output, input) in whole.channels.iter().zip(&sound.channels) {
+            for (a, b) in output.samples.iter().zip(&input.samples) {
+                assert!((a - b).abs() <= 1);
+            }
+        }
+
+        let mut stft = Stft::new(256, 44100, |_: &mut [Complex<f64>]| {});
+        let mut chunked = Sound {
+            channels: vec![Channel::default(); 2],
+            sample_rate: 44100,
+        };
+        for start in (0..5000).step_by(777) {
+            let chunk = Sound {
+                channels: sound
+                    .channels
+                    .iter()
+                    .map(|channel| Channel {
+                        samples: channel.samples[start..(start + 777).min(5000)].to_vec(),
+                    })
+                    .collect(),
+                sample_rate: 44100,
+            };
+            chunked.append(stft.process(chunk));
+        }
+        chunked.append(stft.finish().unwrap());
+
+        for (a, b) in chunked.channels.iter().zip(&whole.channels) {
+            assert_eq!(a.samples, b.samples);
+        }
+    }
+}
@@ -0,0 +1,485 @@
+use color_eyre::eyre::{ensure, Result};
+use rayon::prelude::*;
+use rodio::Source;
+use tracing::{debug_span, field, warn};
+
+use crate::{
+    crusher::Settings,
+    sample::{from_i16, Sample},
+    Channel, Sound,
+};
+
+/// A stage of the processing chain, fed the sound in consecutive chunks.
+///
+/// Stages carry whatever state they need across chunk boundaries, so that how a sound is split into
+/// chunks never changes the result: the concatenated outputs of `process` followed by `finish` are
+/// bit-identical to processing the whole sound as a single chunk.
+pub trait Stage: Send {
+    fn name(&self) -> &'static str;
+
+    /// Processes the next chunk, returning whatever output is ready. Stages which need to look ahead
+    /// may hold samples back, returning them from later calls or from `finish`.
+    fn process(&mut self, chunk: Sound) -> Sound;
+
+    /// Returns the samples held back at the end of the stream, if any
+    fn finish(&mut self) -> Option<Sound> {
+        None
+    }
+
+    /// Follows new settings mid-stream, for stages whose parameters can change without starting
+    /// over. Stages which can't ignore it.
+    fn retune(&mut self, _settings: &Settings) {}
+
+    /// The longest the output can lag behind the input while samples are held back, in seconds
+    fn latency(&self) -> f64 {
+        0.0
+    }
+
+    /// Processes a whole sound in one go
+    fn run(&mut self, sound: Sound) -> Sound {
+        let mut output = self.process(sound);
+
+        if let Some(tail) = self.finish() {
+            output.append(tail);
+        }
+
+        output
+    }
+
+    /// Processes blocks as they're pulled from the returned iterator, so that only one block at a
+    /// time is in memory, followed by the samples held back at the end
+    fn pull<I: Iterator<Item = Sound>>(&mut self, blocks: I) -> Pull<'_, I, Self>
+    where
+        Self: Sized,
+    {
+        Pull {
+            blocks,
+            stage: self,
+            finished: false,
+        }
+    }
+}
+
+/// Blocks pulled through a stage, see [`Stage::pull`]
+pub struct Pull<'a, I, S> {
+    blocks: I,
+    stage: &'a mut S,
+    finished: bool,
+}
+
+impl<I: Iterator<Item = Sound>, S: Stage> Iterator for Pull<'_, I, S> {
+    type Item = Sound;
+
+    fn next(&mut self) -> Option<Sound> {
+        if let Some(block) = self.blocks.next() {
+            return Some(self.stage.process(block));
+        }
+
+        if self.finished {
+            None
+        } else {
+            self.finished = true;
+            self.stage.finish()
+        }
+    }
+}
+
+/// Splits a sound into consecutive blocks of frames, the last of which may be shorter. An empty
+/// sound still yields one empty block, like [`Blocks`].
+pub fn split(sound: Sound, block_frames: usize) -> impl Iterator<Item = Sound> {
+    let sample_rate = sound.sample_rate;
+    let frames = sound.frames();
+    let mut channels: Vec<_> = sound
+        .channels
+        .into_iter()
+        .map(|channel| channel.samples.into_iter())
+        .collect();
+    let block_frames = block_frames.max(1);
+
+    (0..frames.max(1))
+        .step_by(block_frames)
+        .map(move |_| Sound {
+            channels: channels
+                .iter_mut()
+                .map(|samples| Channel {
+                    samples: samples.by_ref().take(block_frames).collect(),
+                })
+                .collect(),
+            sample_rate,
+        })
+}
+
+/// Joins consecutive blocks back into a single sound, or returns None if there are none
+pub fn gather<I: IntoIterator<Item = Sound>>(blocks: I) -> Option<Sound> {
+    blocks.into_iter().reduce(|mut sound, block| {
+        sound.sample_rate = block.sample_rate;
+        sound.append(block);
+        sound
+    })
+}
+
+/// A chain of stages, itself usable as a stage
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn Stage>>,
+}
+
+impl Pipeline {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn push<S: Stage + 'static>(&mut self, stage: S) {
+        self.stages.push(Box::new(stage));
+    }
+
+    /// Adds a stage made elsewhere, such as by the effect registry
+    pub fn push_boxed(&mut self, stage: Box<dyn Stage>) {
+        self.stages.push(stage);
+    }
+
+    /// Adds the stages of another pipeline after these
+    pub fn append(&mut self, other: Pipeline) {
+        self.stages.extend(other.stages);
+    }
+}
+
+impl Stage for Pipeline {
+    fn name(&self) -> &'static str {
+        "pipeline"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        self.stages.iter_mut().fold(chunk, |chunk, stage| {
+            let samples = chunk.channels.len() * chunk.frames();
+            debug_span!("stage", name = stage.name(), samples).in_scope(|| stage.process(chunk))
+        })
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        // Whatever a stage flushes still has to go through the stages after it
+        self.stages.iter_mut().fold(None, |carry, stage| {
+            let mut output = carry.map(|chunk| stage.process(chunk));
+
+            match (&mut output, stage.finish()) {
+                (Some(output), Some(tail)) => output.append(tail),
+                (None, Some(tail)) => output = Some(tail),
+                (_, None) => {}
+            }
+
+            output
+        })
+    }
+
+    fn retune(&mut self, settings: &Settings) {
+        for stage in &mut self.stages {
+            stage.retune(settings);
+        }
+    }
+
+    fn latency(&self) -> f64 {
+        self.stages.iter().map(|stage| stage.latency()).sum()
+    }
+}
+
+/// Smallest number of samples worth handing over to another thread
+pub const MIN_BLOCK: usize = 1 << 14;
+
+/// Maps every sample of the chunk in place through a per-channel state, creating the states of
+/// channels not seen before with `init`. Channels are processed in parallel.
+pub fn map_channels<T, I, F>(mut chunk: Sound, states: &mut Vec<T>, mut init: I, f: F) -> Sound
+where
+    T: Send,
+    I: FnMut() -> T,
+    F: Fn(&mut T, Sample) -> Sample + Sync,
+{
+    while states.len() < chunk.channels.len() {
+        states.push(init());
+    }
+
+    chunk
+        .channels
+        .par_iter_mut()
+        .zip(states.par_iter_mut())
+        .for_each(|(channel, state)| {
+            for sample in &mut channel.samples {
+                *sample = f(state, *sample);
+            }
+        });
+
+    chunk
+}
+
+/// Number of frames in each block processed at a time, unless given with --block-size
+pub const BLOCK_FRAMES: usize = 1 << 16;
+
+/// A decoded stream of interleaved samples
+pub trait PcmSource: Iterator<Item = Sample> + Send {
+    fn channels(&self) -> u16;
+    fn sample_rate(&self) -> u32;
+}
+
+impl<S: PcmSource + ?Sized> PcmSource for Box<S> {
+    fn channels(&self) -> u16 {
+        (**self).channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        (**self).sample_rate()
+    }
+}
+
+/// Widens the 16-bit samples of a rodio source
+pub struct Widened<S>(pub S);
+
+impl<S: Source<Item = i16>> Iterator for Widened<S> {
+    type Item = Sample;
+
+    fn next(&mut self) -> Option<Sample> {
+        self.0.next().map(from_i16)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.0.size_hint()
+    }
+}
+
+impl<S: Source<Item = i16> + Send> PcmSource for Widened<S> {
+    fn channels(&self) -> u16 {
+        self.0.channels()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.0.sample_rate()
+    }
+}
+
+/// Reads a decoded source as consecutive blocks of frames, deinterleaving them into channels.
+/// Incomplete frames at the end of the source are dropped.
+pub struct Blocks<S> {
+    source: S,
+    channels: usize,
+    sample_rate: u32,
+    block_frames: usize,
+    started: bool,
+}
+
+impl<S: PcmSource> Blocks<S> {
+    pub fn new(source: S, block_frames: usize) -> Result<Self> {
+        let channels: usize = source.channels().into();
+        let sample_rate = source.sample_rate();
+
+        ensure!(channels > 0, "The input has no audio channels");
+        ensure!(sample_rate > 0, "The input has a sample rate of 0 Hz");
+
+        Ok(Self {
+            source,
+            channels,
+            sample_rate,
+            block_frames,
+            started: false,
+        })
+    }
+
+    pub fn channels(&self) -> usize {
+        self.channels
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+impl<S: PcmSource> Iterator for Blocks<S> {
+    type Item = Sound;
+
+    fn next(&mut self) -> Option<Sound> {
+        let span = debug_span!("decode", samples = field::Empty).entered();
+        let capacity = (self.source.size_hint().0 / self.channels).min(self.block_frames);
+        let mut block = Sound {
+            channels: vec![
+                Channel {
+                    samples: Vec::with_capacity(capacity),
+                };
+                self.channels
+            ],
+            sample_rate: self.sample_rate,
+        };
+
+        for (i, sample) in self
+            .source
+            .by_ref()
+            .take(self.block_frames.saturating_mul(self.channels))
+            .enumerate()
+        {
+            block.channels[i % self.channels].samples.push(sample);
+        }
+
+        let frames = block.channels[self.channels - 1].samples.len();
+        let partial_frame = block.channels[0].samples.len() - frames;
+        if partial_frame != 0 {
+            warn!(
+                "The input ends with an incomplete frame, dropping its last {} samples",
+                partial_frame
+            );
+
+            for channel in &mut block.channels {
+                channel.samples.truncate(frames);
+            }
+        }
+
+        // An empty input still yields one empty block, so that its channels reach the pipeline
+        if frames == 0 {
+            if self.started {
+                return None;
+            }
+
+            warn!("The input contains no audio");
+        }
+
+        self.started = true;
+        span.record("samples", &(frames * self.channels));
+        Some(block)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::{
+        dac::Dac,
+        filter::{FilterStage, LadderStage},
+        quantize::Quantizer,
+        resample::{Interpolation, Resampler},
+        sample::ClipMode,
+        stereo::WidthStage,
+        Channel,
+    };
+
+    fn pipeline(source_rate: u32, interpolation: Interpolation) -> Pipeline {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut offsets = ChaCha8Rng::seed_from_u64(2);
+
+        let mut pipeline = Pipeline::new();
+        pipeline.push(
+            FilterStage::new(
+                &["lowpass:3k,q=2".parse().unwrap()],
+                source_rate,
+                ClipMode::Clamp,
+            )
+            .unwrap(),
+        );
+        pipeline.push(
+            Resampler::new(source_rate, 11025, interpolation)
+                .with_jitter(1.5, move || offsets.gen_range(-1.5..=1.5)),
+        );
+        pipeline.push(Quantizer::new(
+            5.5,
+            Some(Dac::new(6, 0.05, ClipMode::Clamp, &mut rng)),
+            ChaCha8Rng::seed_from_u64(3),
+        ));
+        pipeline.push(Resampler::new(11025, 44100, interpolation));
+        pipeline.push(LadderStage::new(8000.0, 0.7, 44100, ClipMode::Clamp).unwrap());
+        pipeline.push(
+            FilterStage::new(&["highpass:100".parse().unwrap()], 44100, ClipMode::Clamp).unwrap(),
+        );
+        pipeline.push(WidthStage::new(1.5, ClipMode::Clamp));
+        pipeline
+    }
+
+    fn slice(sound: &Sound, range: std::ops::Range<usize>) -> Sound {
+        Sound {
+            channels: sound
+                .channels
+                .iter()
+                .map(|channel| Channel {
+                    samples: channel.samples[range.clone()].to_vec(),
+                })
+                .collect(),
+            sample_rate: sound.sample_rate,
+        }
+    }
+
+    fn test_sound(sample_rate: u32) -> Sound {
+        let mut rng = ChaCha8Rng::seed_from_u64(0);
+
+        Sound {
+            channels: (0..2)
+                .map(|c| Channel {
+                    samples: (0..5000)
+                        .map(|i| {
+                            let t = i as f64 / sample_rate as f64;
+                            let tone = (t * 440.0 * (c + 1) as f64 * std::f64::consts::TAU).sin();
+                            ((tone * 20000.0) as Sample + rng.gen_range(-2000..2000)) << 16
+                        })
+                        .collect(),
+                })
+                .collect(),
+            sample_rate,
+        }
+    }
+
+    #[test]
+    fn test_chunked_matches_whole() {
+        for (source_rate, interpolation) in [
+            (44100, Interpolation::Nearest),
+            (48000, Interpolation::Linear),
+            (8000, Interpolation::Linear),
+        ] {
+            let sound = test_sound(source_rate);
+            let whole = pipeline(source_rate, interpolation).run(sound.clone());
+
+            let mut rng = ChaCha8Rng::seed_from_u64(4);
+            for max_chunk in [1, 7, 64, 1000] {
+                let mut chunked_pipeline = pipeline(source_rate, interpolation);
+                let mut chunked = Sound {
+                    channels: vec![Channel::default(); 2],
+                    sample_rate: 44100,
+                };
+                let mut start = 0;
+
+                while start < sound.frames() {
+                    let end = (start + rng.gen_range(1..=max_chunk)).min(sound.frames());
+                    chunked.append(chunked_pipeline.process(slice(&sound, start..end)));
+                    start = end;
+                }
+
+                if let Some(tail) = chunked_pipeline.finish() {
+                    chunked.append(tail);
+                }
+
+                assert_eq!(chunked.frames(), whole.frames());
+                for (a, b) in chunked.channels.iter().zip(&whole.channels) {
+                    assert_eq!(a.samples, b.samples, "max chunk size {}", max_chunk);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_pull() {
+        let sound = test_sound(44100);
+        let whole = pipeline(44100, Interpolation::Linear).run(sound.clone());
+
+        for block_frames in [1, 999, 5000, 100000] {
+            let blocks = split(sound.clone(), block_frames);
+            let pulled = gather(pipeline(44100, Interpolation::Linear).pull(blocks)).unwrap();
+
+            assert_eq!(pulled.sample_rate, 44100);
+            for (a, b) in pulled.channels.iter().zip(&whole.channels) {
+                assert_eq!(a.samples, b.samples, "block size {}", block_frames);
+            }
+        }
+
+        let empty = Sound {
+            channels: vec![Channel::default(); 2],
+            sample_rate: 8000,
+        };
+        let blocks: Vec<_> = split(empty, 10).collect();
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(blocks[0].channels.len(), 2);
+    }
+}
@@ -0,0 +1,254 @@
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{ensure, eyre, Result};
+
+use crate::{
+    output::{Encoder, OutputOptions},
+    Sound,
+};
+
+/// Offset of the VHDR chunk's data, after the FORM header and the VHDR chunk header
+const VHDR_OFFSET: u64 = 20;
+
+/// Unity volume, as a 16.16 fixed point number
+const FULL_VOLUME: u32 = 0x10000;
+
+/// CHAN value for stereo sounds
+const STEREO: u32 = 6;
+
+/// Writes Amiga IFF 8SVX files: uncompressed 8-bit signed samples, big endian chunks. Stereo files
+/// store the whole left channel followed by the whole right channel, so the right channel is kept
+/// in memory until the end, a byte per frame.
+pub struct SvxEncoder {
+    file: BufWriter<File>,
+    stereo: bool,
+    looped: bool,
+    frames: u64,
+    /// Offset of the BODY chunk's size, which is only known once everything is written
+    body_size_offset: u64,
+    right: Vec<u8>,
+}
+
+impl SvxEncoder {
+    pub(crate) fn create(
+        path: &Path,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        ensure!(
+            (1..=2).contains(&channels),
+            "8SVX files can only store mono or stereo sounds, not {} channels",
+            channels
+        );
+        let samples_per_sec: u16 = sample_rate
+            .try_into()
+            .map_err(|_| eyre!("8SVX files can't store sample rates above {} Hz", u16::MAX))?;
+
+        let mut file = BufWriter::new(File::create(path)?);
+        // The sizes and the loop are filled in by finalize
+        file.write_all(b"FORM\0\0\0\08SVX")?;
+        file.write_all(b"VHDR")?;
+        file.write_all(&20u32.to_be_bytes())?;
+        file.write_all(&0u32.to_be_bytes())?; // oneShotHiSamples
+        file.write_all(&0u32.to_be_bytes())?; // repeatHiSamples
+        file.write_all(&0u32.to_be_bytes())?; // samplesPerHiCycle
+        file.write_all(&samples_per_sec.to_be_bytes())?;
+        file.write_all(&[1, 0])?; // ctOctave, sCompression
+        file.write_all(&FULL_VOLUME.to_be_bytes())?;
+
+        let stereo = channels == 2;
+        if stereo {
+            file.write_all(b"CHAN")?;
+            file.write_all(&4u32.to_be_bytes())?;
+            file.write_all(&STEREO.to_be_bytes())?;
+        }
+
+        file.write_all(b"BODY\0\0\0\0")?;
+        let body_size_offset = file.stream_position()? - 4;
+
+        Ok(Self {
+            file,
+            stereo,
+            looped: options.looped,
+            frames: 0,
+            body_size_offset,
+            right: Vec::new(),
+        })
+    }
+}
+
+/// Keeps the most significant byte of a sample
+fn to_u8(sample: i32) -> u8 {
+    (sample >> 24) as i8 as u8
+}
+
+impl Encoder for SvxEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let left: Vec<u8> = block.channels[0]
+            .samples
+            .iter()
+            .copied()
+            .map(to_u8)
+            .collect();
+        self.file.write_all(&left)?;
+
+        if self.stereo {
+            self.right
+                .extend(block.channels[1].samples.iter().copied().map(to_u8));
+        }
+
+        self.frames += block.frames() as u64;
+        Ok(())
+    }
+
+    fn finalize(self: Box<Self>) -> Result<()> {
+        let Self {
+            mut file,
+            stereo,
+            looped,
+            frames,
+            body_size_offset,
+            right,
+        } = *self;
+
+        file.write_all(&right)?;
+
+        let frames: u32 = frames
+            .try_into()
+            .map_err(|_| eyre!("The sound is too long for an 8SVX file"))?;
+        let body_size = if stereo {
+            2 * frames as u64
+        } else {
+            frames as u64
+        };
+        let body_size: u32 = body_size
+            .try_into()
+            .map_err(|_| eyre!("The sound is too long for an 8SVX file"))?;
+
+        // Chunks are padded to an even length
+        if body_size % 2 == 1 {
+            file.write_all(&[0])?;
+        }
+        let form_size = file.stream_position()? - 8;
+
+        let (one_shot, repeat) = if looped { (0, frames) } else { (frames, 0) };
+
+        file.seek(SeekFrom::Start(4))?;
+        file.write_all(&(form_size as u32).to_be_bytes())?;
+        file.seek(SeekFrom::Start(VHDR_OFFSET))?;
+        file.write_all(&one_shot.to_be_bytes())?;
+        file.write_all(&repeat.to_be_bytes())?;
+        file.seek(SeekFrom::Start(body_size_offset))?;
+        file.write_all(&body_size.to_be_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    /// ID and data of an IFF chunk
+    type Chunk<'a> = (&'a [u8], &'a [u8]);
+
+    /// Splits an IFF FORM into its type and its chunks
+    fn parse_form(bytes: &[u8]) -> (&[u8], Vec<Chunk<'_>>) {
+        assert_eq!(&bytes[..4], b"FORM");
+        let size = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        assert_eq!(size, bytes.len() - 8);
+
+        let mut chunks = Vec::new();
+        let mut rest = &bytes[12..];
+        while !rest.is_empty() {
+            let size = u32::from_be_bytes(rest[4..8].try_into().unwrap()) as usize;
+            chunks.push((&rest[..4], &rest[8..8 + size]));
+            rest = &rest[8 + size + size % 2..];
+        }
+
+        (&bytes[8..12], chunks)
+    }
+
+    #[test]
+    fn test_save_8svx() {
+        let path = std::env::temp_dir().join("krusz_test_save_8svx.8svx");
+        let sound = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![1 << 24, -1 << 24, i32::MAX],
+                },
+                Channel {
+                    samples: vec![2 << 24, -2 << 24, i32::MIN],
+                },
+            ],
+            sample_rate: 22050,
+        };
+
+        let options = OutputOptions {
+            looped: true,
+            ..OutputOptions::default()
+        };
+        save(&sound, &path, OutputFormat::Svx, options).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (form_type, chunks) = parse_form(&bytes);
+        assert_eq!(form_type, b"8SVX");
+        assert_eq!(
+            chunks.iter().map(|(id, _)| *id).collect::<Vec<_>>(),
+            [&b"VHDR"[..], b"CHAN", b"BODY"]
+        );
+
+        let vhdr = chunks[0].1;
+        assert_eq!(vhdr[..4], 0u32.to_be_bytes());
+        assert_eq!(vhdr[4..8], 3u32.to_be_bytes());
+        assert_eq!(vhdr[12..14], 22050u16.to_be_bytes());
+        assert_eq!(chunks[1].1, STEREO.to_be_bytes());
+        assert_eq!(chunks[2].1, [1, 0xff, 0x7f, 2, 0xfe, 0x80]);
+    }
+
+    #[test]
+    fn test_save_8svx_mono() {
+        let path = std::env::temp_dir().join("krusz_test_save_8svx_mono.8svx");
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![3 << 24; 5],
+            }],
+            sample_rate: 8000,
+        };
+
+        save(&sound, &path, OutputFormat::Svx, OutputOptions::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let (_, chunks) = parse_form(&bytes);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].1[..4], 5u32.to_be_bytes());
+        assert_eq!(chunks[0].1[4..8], 0u32.to_be_bytes());
+        assert_eq!(chunks[1].1, [3; 5]);
+        // The odd length body is padded
+        assert_eq!(bytes.len() % 2, 0);
+
+        let quad = Sound {
+            channels: vec![Channel::default(); 4],
+            sample_rate: 8000,
+        };
+        assert!(save(&quad, &path, OutputFormat::Svx, OutputOptions::default()).is_err());
+        let fast = Sound {
+            sample_rate: 96000,
+            ..sound
+        };
+        assert!(save(&fast, &path, OutputFormat::Svx, OutputOptions::default()).is_err());
+    }
+}
@@ -0,0 +1,588 @@
+use std::{
+    f64::consts::PI,
+    fmt::{self, Display, Formatter},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{bail, ensure, eyre, Report, Result};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use crate::{
+    echo::parse_duration,
+    gain::db_to_gain,
+    quantize::requantize_sample,
+    resample::{lerp, Interpolation},
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stream::{map_channels, Stage},
+    Channel, Sound,
+};
+
+/// Rate of the slow drift in tape speed, from eccentric capstans and reels, in Hz
+const WOW_RATE: f64 = 0.6;
+/// Rate of the fast wobble in tape speed, from the tape catching on the heads and guides, in Hz
+const FLUTTER_RATE: f64 = 7.0;
+
+/// Wow and flutter as given on the command line, e.g. `wow=0.15,flutter=0.05`. Both are the peak
+/// deviation of the tape speed, in percent, and both are optional.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlutterSpec {
+    pub wow: f64,
+    pub flutter: f64,
+}
+
+impl Default for FlutterSpec {
+    /// About what a worn consumer cassette deck measures
+    fn default() -> Self {
+        Self {
+            wow: 0.15,
+            flutter: 0.05,
+        }
+    }
+}
+
+impl FromStr for FlutterSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut spec = Self::default();
+
+        for param in s.split(',').filter(|param| !param.trim().is_empty()) {
+            let (key, value) = param.split_once('=').ok_or_else(|| {
+                eyre!("Flutter parameter {} must be of the form key=value", param)
+            })?;
+            let value: f64 = value.trim().trim_end_matches('%').parse()?;
+
+            match key.trim().to_lowercase().as_str() {
+                "wow" => spec.wow = value,
+                "flutter" => spec.flutter = value,
+                other => bail!("Unknown flutter parameter {}", other),
+            }
+        }
+
+        ensure!(
+            (0.0..=5.0).contains(&spec.wow) && (0.0..=5.0).contains(&spec.flutter),
+            "Wow and flutter must be between 0% and 5% inclusive"
+        );
+
+        Ok(spec)
+    }
+}
+
+impl Display for FlutterSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "wow={},flutter={}", self.wow, self.flutter)
+    }
+}
+
+serde_via_str!(FlutterSpec);
+
+/// Flutter state of a single channel
+struct FlutterChannel {
+    /// The most recent input samples, oldest first, with at least the longest delay's worth
+    history: Vec<f64>,
+    /// Frames processed so far, which drive the modulation
+    time: u64,
+}
+
+/// Plays the sound back at a speed which drifts and wobbles, like a tape running unevenly past the
+/// head, by reading it through a delay which sweeps back and forth. All channels share the tape, so
+/// they waver together.
+pub struct FlutterStage {
+    spec: FlutterSpec,
+    sample_rate: u32,
+    channels: Vec<FlutterChannel>,
+}
+
+impl FlutterStage {
+    pub fn new(spec: FlutterSpec, sample_rate: u32) -> Self {
+        Self {
+            spec,
+            sample_rate,
+            channels: Vec::new(),
+        }
+    }
+}
+
+impl Stage for FlutterStage {
+    fn name(&self) -> &'static str {
+        "flutter"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            spec,
+            sample_rate,
+            channels,
+        } = self;
+        let rate = *sample_rate as f64;
+
+        // A delay sweeping by A sin(2πft) changes the speed by up to 2πfA, so these give the
+        // deviations asked for
+        let wow = spec.wow / 100.0 / (2.0 * PI * WOW_RATE) * rate;
+        let flutter = spec.flutter / 100.0 / (2.0 * PI * FLUTTER_RATE) * rate;
+        let base = wow + flutter + 1.0;
+        let longest = (2.0 * base).ceil() as usize + 2;
+
+        map_channels(
+            chunk,
+            channels,
+            || FlutterChannel {
+                history: vec![0.0; longest],
+                time: 0,
+            },
+            |channel, sample| {
+                channel.history.push(sample as f64);
+
+                // Dropping the old samples only now and then keeps the cost down
+                if channel.history.len() >= 2 * longest {
+                    channel.history.drain(..longest);
+                }
+
+                let now = (channel.history.len() - 1) as f64;
+                let t = channel.time as f64 / rate;
+                channel.time += 1;
+
+                let delay = base
+                    + wow * (2.0 * PI * WOW_RATE * t).sin()
+                    + flutter * (2.0 * PI * FLUTTER_RATE * t).sin();
+                // Interpolating linearly between samples never leaves their range
+                to_sample(
+                    lerp(&channel.history, now - delay, Interpolation::Linear),
+                    ClipMode::Clamp,
+                )
+            },
+        )
+    }
+}
+
+/// Adds white noise at a steady level, like the hiss of tape or a noisy analog stage. Each channel
+/// gets noise of its own.
+pub struct HissStage {
+    /// Peak of the noise, in samples
+    amplitude: f64,
+    rng: ChaCha8Rng,
+    channels: Vec<ChaCha8Rng>,
+    clip_mode: ClipMode,
+}
+
+impl HissStage {
+    /// Noise with an RMS level of `level` dB relative to full scale
+    pub fn new(level: f64, rng: ChaCha8Rng, clip_mode: ClipMode) -> Self {
+        Self {
+            // Uniform noise peaks at √3 times its RMS level
+            amplitude: db_to_gain(level) * 3f64.sqrt() * FULL_SCALE,
+            rng,
+            channels: Vec::new(),
+            clip_mode,
+        }
+    }
+}
+
+impl Stage for HissStage {
+    fn name(&self) -> &'static str {
+        "hiss"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        let Self {
+            amplitude,
+            rng,
+            channels,
+            clip_mode,
+        } = self;
+        let (amplitude, clip_mode) = (*amplitude, *clip_mode);
+
+        map_channels(
+            chunk,
+            channels,
+            || ChaCha8Rng::from_rng(&mut *rng).unwrap(),
+            |rng, sample| {
+                to_sample(
+                    sample as f64 + amplitude * rng.gen_range(-1.0..=1.0),
+                    clip_mode,
+                )
+            },
+        )
+    }
+}
+
+/// A tape stop as given on the command line, e.g. `2s` or `time=2s,direction=up,bits=4`: the tape
+/// slowing down to a halt at the end of the sound, or speeding up from a standstill at its start,
+/// over the given time. The bit depth optionally falls to `bits` as the tape slows down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpinSpec {
+    /// Time taken to stop or get up to speed, in seconds
+    pub time: f64,
+    /// Whether the tape speeds up at the start rather than slowing down at the end
+    pub up: bool,
+    pub bits: Option<f64>,
+}
+
+impl FromStr for SpinSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut time = None;
+        let mut up = false;
+        let mut bits = None;
+
+        for (i, param) in s.split(',').enumerate() {
+            let (key, value) = match param.split_once('=') {
+                Some(pair) => pair,
+                // The time can be given on its own, first
+                None if i == 0 => ("time", param),
+                None => bail!("Spin parameter {} must be of the form key=value", param),
+            };
+
+            match key.trim().to_lowercase().as_str() {
+                "time" => time = Some(parse_duration(value)?),
+                "direction" => {
+                    up = match value.trim().to_lowercase().as_str() {
+                        "down" => false,
+                        "up" => true,
+                        other => bail!("Unknown spin direction {}, must be down or up", other),
+                    }
+                }
+                "bits" => {
+                    let value = value.trim().to_lowercase();
+                    bits = Some(value.strip_suffix("bit").unwrap_or(&value).trim().parse()?);
+                }
+                other => bail!("Unknown spin parameter {}", other),
+            }
+        }
+
+        let time = time.ok_or_else(|| {
+            eyre!("Spin must be of the form <time>[,direction=up][,bits=<bits>], e.g. \"2s\"")
+        })?;
+
+        ensure!(
+            time > 0.0 && time <= 60.0,
+            "Spin time must be more than 0 and at most 60 seconds"
+        );
+        if let Some(bits) = bits {
+            ensure!(
+                (1.0..=32.0).contains(&bits),
+                "Spin bit depth must be between 1 and 32 bits inclusive"
+            );
+        }
+
+        Ok(Self { time, up, bits })
+    }
+}
+
+impl Display for SpinSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "time={}s,direction={}",
+            self.time,
+            if self.up { "up" } else { "down" }
+        )?;
+
+        if let Some(bits) = self.bits {
+            write!(f, ",bits={}", bits)?;
+        }
+
+        Ok(())
+    }
+}
+
+serde_via_str!(SpinSpec);
+
+/// Plays the sound at a speed ramping linearly between a standstill and full speed. Over the spin
+/// time the tape only gets through half of that time's worth of the sound, so the sound gets longer
+/// by half the spin time, and all of it is still heard. To stop right at the end, the last half of
+/// the spin time's worth of input is held back until the end of the sound.
+pub struct SpinStage {
+    spec: SpinSpec,
+    /// Frames of input the tape gets through while spinning
+    ramp: usize,
+    /// Bit depth at full speed
+    bit_depth: f64,
+    sample_rate: u32,
+    /// Input not yet played
+    queue: Vec<Channel>,
+    /// Frames of the spin played so far
+    spun: usize,
+}
+
+impl SpinStage {
+    pub fn new(spec: SpinSpec, bit_depth: f64, sample_rate: u32) -> Self {
+        Self {
+            ramp: ((spec.time / 2.0 * sample_rate as f64).round() as usize).max(1),
+            spec,
+            bit_depth,
+            sample_rate,
+            queue: Vec::new(),
+            spun: 0,
+        }
+    }
+
+    /// Plays frames of the spin over `input` frames of the queue, which it gets through in
+    /// `2 * input` frames, while the position they read from is below `limit`
+    fn spin(&mut self, input: usize, limit: f64) -> Sound {
+        let frames = 2 * input;
+        let mut channels = vec![Channel::default(); self.queue.len()];
+
+        while self.spun < frames {
+            let n = self.spun as f64;
+            let length = frames as f64;
+            let (position, slowed) = if self.spec.up {
+                (n * n / (2.0 * length), 1.0 - n / length)
+            } else {
+                (n - n * n / (2.0 * length), n / length)
+            };
+
+            if position >= limit {
+                break;
+            }
+
+            let bit_depth = self.spec.bits.map(|bits| {
+                (self.bit_depth + (bits - self.bit_depth) * slowed)
+                    .round()
+                    .clamp(1.0, 32.0) as u8
+            });
+
+            for (output, queue) in channels.iter_mut().zip(&self.queue) {
+                // Interpolating linearly between samples never leaves their range
+                let sample = to_sample(
+                    lerp(&queue.samples, position, Interpolation::Linear),
+                    ClipMode::Clamp,
+                );
+                output.samples.push(match bit_depth {
+                    Some(bit_depth) => requantize_sample(sample, bit_depth),
+                    None => sample,
+                });
+            }
+
+            self.spun += 1;
+        }
+
+        Sound {
+            channels,
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.queue
+            .first()
+            .map_or(0, |channel| channel.samples.len())
+    }
+
+    /// Passes on all but the last `keep` frames of the queue
+    fn pass_on(&mut self, keep: usize) -> Sound {
+        let end = self.queued().saturating_sub(keep);
+
+        Sound {
+            channels: self
+                .queue
+                .iter_mut()
+                .map(|channel| Channel {
+                    samples: channel.samples.drain(..end).collect(),
+                })
+                .collect(),
+            sample_rate: self.sample_rate,
+        }
+    }
+
+    /// Spins up over the queue as far as `limit`, then plays the rest once up to speed
+    fn spin_up(&mut self, limit: f64) -> Sound {
+        if self.spun == 2 * self.ramp {
+            return self.pass_on(0);
+        }
+
+        let mut output = self.spin(self.ramp, limit);
+
+        if self.spun == 2 * self.ramp {
+            for channel in &mut self.queue {
+                channel.samples.drain(..self.ramp);
+            }
+            output.append(self.pass_on(0));
+        }
+
+        output
+    }
+}
+
+impl Stage for SpinStage {
+    fn name(&self) -> &'static str {
+        "spin"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        if self.queue.len() < chunk.channels.len() {
+            self.queue.resize(chunk.channels.len(), Channel::default());
+        }
+
+        for (queue, channel) in self.queue.iter_mut().zip(chunk.channels) {
+            queue.samples.extend(channel.samples);
+        }
+
+        if self.spec.up {
+            // Reads between a frame and the next, so each frame only once the next one has arrived
+            self.spin_up(self.queued() as f64 - 1.0)
+        } else {
+            self.pass_on(self.ramp)
+        }
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        let queued = self.queued();
+
+        // Sounds shorter than the spin never get up to speed, or stop sooner
+        Some(if self.spec.up {
+            self.spin_up(queued as f64)
+        } else {
+            self.spin(queued, queued as f64)
+        })
+    }
+
+    fn latency(&self) -> f64 {
+        if self.spec.up {
+            0.0
+        } else {
+            self.ramp as f64 / self.sample_rate as f64
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{gain::rms, Channel};
+
+    #[test]
+    fn test_parse_flutter() {
+        assert_eq!("".parse::<FlutterSpec>().unwrap(), FlutterSpec::default());
+        assert_eq!(
+            "wow=0.3%, flutter=0".parse::<FlutterSpec>().unwrap(),
+            FlutterSpec {
+                wow: 0.3,
+                flutter: 0.0
+            }
+        );
+        assert!("wow=10".parse::<FlutterSpec>().is_err());
+        assert!("scrape=1".parse::<FlutterSpec>().is_err());
+
+        let spec = FlutterSpec::default();
+        assert_eq!(spec.to_string().parse::<FlutterSpec>().unwrap(), spec);
+    }
+
+    #[test]
+    fn test_flutter() {
+        // A ramp comes out as a ramp, only delayed by a varying amount
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: (0..88200).map(|i| i << 8).collect(),
+            }],
+            sample_rate: 44100,
+        };
+        let wavered = FlutterStage::new(FlutterSpec::default(), 44100).run(sound);
+
+        let delays: Vec<f64> = wavered.channels[0].samples[1000..]
+            .iter()
+            .enumerate()
+            .map(|(i, &s)| (i + 1000) as f64 - s as f64 / 256.0)
+            .collect();
+        let (min, max) = delays.iter().fold((f64::MAX, f64::MIN), |(min, max), &d| {
+            (min.min(d), max.max(d))
+        });
+
+        assert!(min > 0.0);
+        // Over more than a cycle of wow, 0.15% at 0.6 Hz sweeps the delay by about ±17.5 samples
+        assert!(max - min > 30.0 && max - min < 40.0);
+    }
+
+    #[test]
+    fn test_hiss() {
+        let silence = Sound {
+            channels: vec![
+                Channel {
+                    samples: vec![0; 44100]
+                };
+                2
+            ],
+            sample_rate: 44100,
+        };
+        let hiss =
+            HissStage::new(-40.0, ChaCha8Rng::seed_from_u64(0), ClipMode::Clamp).run(silence);
+
+        let level = 20.0 * rms(&hiss).log10();
+        assert!((level + 40.0).abs() < 0.1);
+        assert_ne!(hiss.channels[0].samples, hiss.channels[1].samples);
+    }
+
+    #[test]
+    fn test_parse_spin() {
+        assert_eq!(
+            "2s".parse::<SpinSpec>().unwrap(),
+            SpinSpec {
+                time: 2.0,
+                up: false,
+                bits: None
+            }
+        );
+
+        let spec: SpinSpec = "time=500ms,direction=up,bits=4bit".parse().unwrap();
+        assert_eq!(
+            spec,
+            SpinSpec {
+                time: 0.5,
+                up: true,
+                bits: Some(4.0)
+            }
+        );
+        assert_eq!(spec.to_string().parse::<SpinSpec>().unwrap(), spec);
+
+        assert!("0s".parse::<SpinSpec>().is_err());
+        assert!("2s,sideways".parse::<SpinSpec>().is_err());
+        assert!("2s,direction=sideways".parse::<SpinSpec>().is_err());
+    }
+
+    #[test]
+    fn test_spin() {
+        use crate::stream::{gather, split};
+
+        let ramp = || Sound {
+            channels: vec![Channel {
+                samples: (0..20000).map(|i| i << 12).collect(),
+            }],
+            sample_rate: 10000,
+        };
+
+        for up in [false, true] {
+            let spec = SpinSpec {
+                time: 1.0,
+                up,
+                bits: Some(4.0),
+            };
+            let stage = || SpinStage::new(spec, 16.0, 10000);
+            let spun = stage().run(ramp());
+
+            // Half of the spin time longer, with all of the input played
+            assert_eq!(spun.frames(), 25000);
+            let samples = &spun.channels[0].samples;
+            let (first, last) = (samples[0], samples[samples.len() - 1]);
+            assert!(
+                first.abs() < 1 << 28 && last > 19990 << 12,
+                "{} {}",
+                first,
+                last
+            );
+
+            // The tape crawls where it's slow, and plays at full speed away from the spin
+            if up {
+                assert_eq!(samples[24000] - samples[23999], 1 << 12);
+                assert!(samples[..100].iter().all(|&s| s == samples[0]));
+            } else {
+                assert_eq!(samples[1000] - samples[999], 1 << 12);
+                assert_eq!(samples[24999], requantize_sample(samples[24999], 4));
+            }
+
+            let chunked = gather(stage().pull(split(ramp(), 777))).unwrap();
+            assert_eq!(chunked.channels[0].samples, spun.channels[0].samples);
+        }
+    }
+}
@@ -0,0 +1,308 @@
+use std::collections::VecDeque;
+
+use crate::{
+    crusher::Settings,
+    sample::FULL_SCALE,
+    stream::{Pipeline, Stage},
+    Channel, Sound,
+};
+
+/// Frames the KRUSZING parameters stay the same for, as in the realtime crusher
+const BLOCK: usize = 32;
+/// How far ahead attacks are looked for, so that the KRUSZING has eased off by the time they
+/// arrive, in seconds
+const LOOKAHEAD: f64 = 0.005;
+/// Time taken by the KRUSZING to come back after an attack, in seconds
+const RELEASE: f64 = 0.08;
+/// Time taken by the fast envelope to fall, and by the slow one to follow the level, in seconds
+const FAST_TIME: f64 = 0.005;
+const SLOW_TIME: f64 = 0.1;
+/// How much higher the fast envelope has to be than the slow one for an attack (6 dB)
+const RISE: f64 = 2.0;
+/// Level below which nothing counts as an attack, relative to full scale (-50 dB)
+const FLOOR: f64 = 0.003;
+/// Bit depth attacks are KRUSZED to at most, unless the KRUSZED bit depth is higher already
+const CEILING_DEPTH: f64 = 16.0;
+/// Highest sample rate attacks are KRUSZED to, as high as settings go
+const CEILING_RATE: u32 = 44100;
+
+/// Runs the stages which KRUSZE the sound, raising their bit depth and sample rate around attacks
+/// so that drums keep their snap while sustains get KRUSZED. Attacks are found ahead of time, and
+/// the KRUSZING comes back gradually after them. The parameters change every 32 frames through the
+/// stages' `retune`, as with the realtime crusher. The sample rate only changes when the sound is
+/// resampled back to a fixed rate afterwards, as otherwise the output rate would change too.
+pub struct TransientStage {
+    inner: Pipeline,
+    settings: Settings,
+    /// Bit depth and sample rate at full strength
+    ceiling_depth: f64,
+    ceiling_rate: Option<u32>,
+    /// Input not yet passed on, and how many of its frames have been looked at for attacks
+    queue: Vec<Channel>,
+    analyzed: usize,
+    /// Whether each block looked at but not yet passed on starts an attack
+    hits: VecDeque<bool>,
+    lookahead: usize,
+    /// How far the KRUSZING has eased off, from 0 to 1, and how much it comes back per block
+    amount: f64,
+    release_step: f64,
+    fast: f64,
+    slow: f64,
+    fast_decay: f64,
+    slow_coefficient: f64,
+    sample_rate: u32,
+}
+
+impl TransientStage {
+    pub fn new(inner: Pipeline, settings: Settings, sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+        let blocks = |time: f64| time * rate / BLOCK as f64;
+
+        Self {
+            inner,
+            ceiling_depth: settings.bit_depth.max(CEILING_DEPTH),
+            ceiling_rate: settings
+                .restore_rate
+                .map(|_| settings.sample_rate.max(CEILING_RATE.min(sample_rate))),
+            settings,
+            queue: Vec::new(),
+            analyzed: 0,
+            hits: VecDeque::new(),
+            lookahead: blocks(LOOKAHEAD).ceil() as usize,
+            amount: 0.0,
+            release_step: 1.0 / blocks(RELEASE).max(1.0),
+            fast: 0.0,
+            slow: 0.0,
+            fast_decay: (-1.0 / blocks(FAST_TIME)).exp(),
+            slow_coefficient: 1.0 - (-1.0 / blocks(SLOW_TIME)).exp(),
+            sample_rate,
+        }
+    }
+
+    /// Looks for attacks in the blocks of the queue which are complete, or in all of it at the end
+    fn analyze(&mut self, end: bool) {
+        let frames = self
+            .queue
+            .first()
+            .map_or(0, |channel| channel.samples.len());
+
+        while self.analyzed + BLOCK <= frames || (end && self.analyzed < frames) {
+            let block = self.analyzed..(self.analyzed + BLOCK).min(frames);
+            let peak = self
+                .queue
+                .iter()
+                .flat_map(|channel| &channel.samples[block.clone()])
+                .map(|&sample| (sample as f64 / FULL_SCALE).abs())
+                .fold(0.0, f64::max);
+
+            self.fast = peak.max(self.fast * self.fast_decay);
+            self.slow += (peak - self.slow) * self.slow_coefficient;
+            self.hits
+                .push_back(self.fast > FLOOR && self.fast > self.slow * RISE);
+            self.analyzed = block.end;
+        }
+    }
+
+    /// Passes on the blocks whose lookahead has been analyzed, or all of them at the end. Blocks
+    /// KRUSZED the same way go through together.
+    fn pass_on(&mut self, end: bool) -> Sound {
+        let mut output = Sound {
+            channels: vec![Channel::default(); self.queue.len()],
+            sample_rate: self.settings.output_rate(),
+        };
+        let mut frames = 0;
+        let mut run_amount = self.amount;
+
+        while self.hits.len() > self.lookahead || (end && !self.hits.is_empty()) {
+            // Eases off ahead of attacks, linearly over the lookahead
+            let target = self
+                .hits
+                .iter()
+                .take(self.lookahead + 1)
+                .enumerate()
+                .filter(|(_, &hit)| hit)
+                .map(|(i, _)| 1.0 - i as f64 / (self.lookahead + 1) as f64)
+                .fold(0.0, f64::max);
+            let amount = (self.amount - self.release_step).max(target).max(0.0);
+
+            if amount != run_amount {
+                output.append(self.flush(frames));
+                frames = 0;
+                run_amount = amount;
+                self.retune_inner(amount);
+            }
+
+            self.amount = amount;
+            self.hits.pop_front();
+            frames = (frames + BLOCK).min(self.analyzed);
+        }
+
+        output.append(self.flush(frames));
+        output
+    }
+
+    /// Passes the first frames of the queue through the inner stages
+    fn flush(&mut self, frames: usize) -> Sound {
+        let chunk = Sound {
+            channels: self
+                .queue
+                .iter_mut()
+                .map(|channel| Channel {
+                    samples: channel.samples.drain(..frames).collect(),
+                })
+                .collect(),
+            sample_rate: self.sample_rate,
+        };
+        self.analyzed -= frames;
+
+        if frames == 0 {
+            Sound {
+                channels: vec![Channel::default(); self.queue.len()],
+                sample_rate: self.settings.output_rate(),
+            }
+        } else {
+            self.inner.process(chunk)
+        }
+    }
+
+    fn retune_inner(&mut self, amount: f64) {
+        let mut settings = self.settings.clone();
+        settings.bit_depth += (self.ceiling_depth - settings.bit_depth) * amount;
+
+        if let Some(ceiling_rate) = self.ceiling_rate {
+            settings.sample_rate = (settings.sample_rate as f64
+                + (ceiling_rate - settings.sample_rate) as f64 * amount)
+                .round() as u32;
+        }
+
+        self.inner.retune(&settings);
+    }
+}
+
+impl Stage for TransientStage {
+    fn name(&self) -> &'static str {
+        "transients"
+    }
+
+    fn process(&mut self, chunk: Sound) -> Sound {
+        if self.queue.len() < chunk.channels.len() {
+            self.queue.resize(chunk.channels.len(), Channel::default());
+        }
+
+        for (queue, channel) in self.queue.iter_mut().zip(chunk.channels) {
+            queue.samples.extend(channel.samples);
+        }
+
+        self.analyze(false);
+        self.pass_on(false)
+    }
+
+    fn finish(&mut self) -> Option<Sound> {
+        self.analyze(true);
+        let mut output = self.pass_on(true);
+
+        if let Some(tail) = self.inner.finish() {
+            output.append(tail);
+        }
+
+        Some(output)
+    }
+
+    fn retune(&mut self, settings: &Settings) {
+        self.settings = settings.clone();
+        self.ceiling_depth = settings.bit_depth.max(CEILING_DEPTH);
+        self.retune_inner(self.amount);
+    }
+
+    fn latency(&self) -> f64 {
+        self.inner.latency() + ((self.lookahead + 1) * BLOCK) as f64 / self.sample_rate as f64
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+    use crate::stream::{gather, split};
+
+    /// Quiet, then a sharp hit which decays into a long, steady tone
+    fn hit() -> Sound {
+        Sound {
+            channels: vec![Channel {
+                samples: (0..20000)
+                    .map(|i| {
+                        if i < 4000 {
+                            0
+                        } else {
+                            let t = (i - 4000) as f64 / 44100.0;
+                            let level = 0.2 + 0.7 * (-t * 60.0).exp();
+                            let tone = (t * 220.0 * std::f64::consts::TAU).sin();
+                            (tone * level * FULL_SCALE) as i32
+                        }
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        }
+    }
+
+    fn settings() -> Settings {
+        Settings {
+            bit_depth: 4.0,
+            sample_rate: 8000,
+            preserve_transients: true,
+            ..Settings::default()
+        }
+    }
+
+    #[test]
+    fn test_preserve_transients() {
+        let crushed = settings()
+            .pipeline(1, 44100, &mut ChaCha8Rng::seed_from_u64(0))
+            .unwrap()
+            .run(hit());
+        let plain = Settings {
+            preserve_transients: false,
+            ..settings()
+        }
+        .pipeline(1, 44100, &mut ChaCha8Rng::seed_from_u64(0))
+        .unwrap()
+        .run(hit());
+
+        // Changing the sample rate can round the length differently
+        assert!(crushed.frames().abs_diff(plain.frames()) <= 2);
+
+        let levels = |range: std::ops::Range<usize>, sound: &Sound| {
+            let mut samples = sound.channels[0].samples[range].to_vec();
+            samples.sort_unstable();
+            samples.dedup();
+            samples.len()
+        };
+
+        // Hardly KRUSZED on the attack, and KRUSZED as much as ever long after it
+        assert!(levels(4000..4300, &crushed) > 100);
+        assert!(levels(4000..4300, &plain) <= 16);
+        assert!(levels(15000..19000, &crushed) <= 16);
+    }
+
+    #[test]
+    fn test_chunked() {
+        let pipeline = || {
+            settings()
+                .pipeline(1, 44100, &mut ChaCha8Rng::seed_from_u64(0))
+                .unwrap()
+        };
+        let whole = pipeline().run(hit());
+
+        for block_frames in [1, 77, 4096] {
+            let chunked = gather(pipeline().pull(split(hit(), block_frames))).unwrap();
+            assert_eq!(
+                chunked.channels[0].samples, whole.channels[0].samples,
+                "block size {}",
+                block_frames
+            );
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use std::{
+    convert::TryFrom,
+    fs::File,
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::Result;
+
+use crate::{
+    output::{Encoder, OutputOptions},
+    sample::to_i16,
+    simd, Sound,
+};
+
+/// Version 1.20, the first one with the extended sound data block
+const VERSION: u16 = 0x0114;
+
+/// Block type of sound data with its own sample rate, sample size and channel count
+const SOUND_DATA: u8 = 9;
+
+/// Block type of more sound data in the format of the previous block
+const CONTINUATION: u8 = 2;
+
+/// Block sizes are 24 bits
+const MAX_BLOCK_SIZE: u64 = 0xff_ffff;
+
+/// Size of the format fields at the start of a sound data block
+const SOUND_DATA_HEADER: u64 = 12;
+
+/// Creative Voice codec of unsigned 8-bit samples
+const CODEC_8_BITS: u16 = 0;
+
+/// Creative Voice codec of signed 16-bit samples
+const CODEC_16_BITS: u16 = 4;
+
+/// Writes Creative Voice files, as played by Sound Blaster cards: interleaved little endian 8-bit
+/// unsigned or 16-bit signed samples in a single sound data block, continued in further blocks
+/// past the 16 MB a block can hold
+pub struct VocEncoder {
+    file: BufWriter<File>,
+    eight_bits: bool,
+    /// Offset of the current block's size, which is filled in once the block is full
+    block_offset: u64,
+    block_size: u64,
+}
+
+impl VocEncoder {
+    pub(crate) fn create(
+        path: &Path,
+        channels: usize,
+        sample_rate: u32,
+        options: OutputOptions,
+    ) -> Result<Self> {
+        // Anything wider than 8 bits is stored as 16 bits, the widest VOC samples
+        let eight_bits = options.bits_per_sample == 8;
+        let (bits, codec) = if eight_bits {
+            (8u8, CODEC_8_BITS)
+        } else {
+            (16, CODEC_16_BITS)
+        };
+
+        let mut file = BufWriter::new(File::create(path)?);
+        file.write_all(b"Creative Voice File\x1a")?;
+        file.write_all(&26u16.to_le_bytes())?;
+        file.write_all(&VERSION.to_le_bytes())?;
+        file.write_all(&(!VERSION).wrapping_add(0x1234).to_le_bytes())?;
+
+        file.write_all(&[SOUND_DATA, 0, 0, 0])?;
+        let block_offset = file.stream_position()? - 3;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&[bits, u8::try_from(channels)?])?;
+        file.write_all(&codec.to_le_bytes())?;
+        file.write_all(&[0; 4])?;
+
+        Ok(Self {
+            file,
+            eight_bits,
+            block_offset,
+            block_size: SOUND_DATA_HEADER,
+        })
+    }
+
+    /// Fills in the size of the current block
+    fn end_block(&mut self) -> Result<()> {
+        let end = self.file.stream_position()?;
+        self.file.seek(SeekFrom::Start(self.block_offset))?;
+        self.file.write_all(&self.block_size.to_le_bytes()[..3])?;
+        self.file.seek(SeekFrom::Start(end))?;
+        Ok(())
+    }
+}
+
+impl Encoder for VocEncoder {
+    fn write(&mut self, block: &Sound) -> Result<()> {
+        let samples = simd::interleave(&block.channels);
+        let bytes: Vec<u8> = if self.eight_bits {
+            samples
+                .into_iter()
+                .map(|sample| ((sample >> 24) as u8) ^ 0x80)
+                .collect()
+        } else {
+            samples
+                .into_iter()
+                .flat_map(|sample| to_i16(sample).to_le_bytes())
+                .collect()
+        };
+
+        // Blocks are only split between frames
+        let frame_size = bytes.len() / block.frames().max(1);
+        let mut rest = &bytes[..];
+        while !rest.is_empty() {
+            let room = (MAX_BLOCK_SIZE - self.block_size) as usize / frame_size * frame_size;
+            if room == 0 {
+                self.end_block()?;
+                self.file.write_all(&[CONTINUATION, 0, 0, 0])?;
+                self.block_offset = self.file.stream_position()? - 3;
+                self.block_size = 0;
+                continue;
+            }
+
+            let (now, later) = rest.split_at(room.min(rest.len()));
+            self.file.write_all(now)?;
+            self.block_size += now.len() as u64;
+            rest = later;
+        }
+
+        Ok(())
+    }
+
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        self.end_block()?;
+        // Terminator block
+        self.file.write_all(&[0])?;
+        self.file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        output::{save, OutputFormat},
+        Channel,
+    };
+
+    #[test]
+    fn test_save_voc() {
+        let path = std::env::temp_dir().join("krusz_test_save_voc.voc");
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![0x1234_5678, i32::MIN, 0],
+            }],
+            sample_rate: 11025,
+        };
+
+        save(&sound, &path, OutputFormat::Voc, OutputOptions::default()).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert_eq!(&bytes[..20], b"Creative Voice File\x1a");
+        assert_eq!(bytes[20..26], [26, 0, 0x14, 0x01, 0x1f, 0x11]);
+        // A sound data block of 12 bytes of format and 6 of samples
+        assert_eq!(bytes[26..30], [SOUND_DATA, 18, 0, 0]);
+        assert_eq!(bytes[30..34], 11025u32.to_le_bytes());
+        assert_eq!(bytes[34..38], [16, 1, 4, 0]);
+        assert_eq!(bytes[42..], [0x34, 0x12, 0x00, 0x80, 0x00, 0x00, 0]);
+
+        let options = OutputOptions {
+            bits_per_sample: 8,
+            ..OutputOptions::default()
+        };
+        save(&sound, &path, OutputFormat::Voc, options).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(bytes[26..30], [SOUND_DATA, 15, 0, 0]);
+        assert_eq!(bytes[34..38], [8, 1, 0, 0]);
+        assert_eq!(bytes[42..], [0x92, 0x00, 0x80, 0]);
+    }
+}
@@ -0,0 +1,235 @@
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    fs,
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, eyre, Report, Result, WrapErr};
+use rayon::prelude::*;
+
+use crate::{
+    resample::{lerp, Interpolation},
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stream::{Stage, MIN_BLOCK},
+    Sound,
+};
+
+/// Number of points of the lookup tables of named shapes and of curves given as points
+const TABLE_SIZE: usize = 1025;
+
+/// A transfer curve applied to every sample, as a lookup table of output levels for input levels
+/// evenly spaced from -1 to 1, with linear interpolation in between. Given on the command line as
+/// either the name of a built-in shape or the path to a CSV file.
+#[derive(Clone, PartialEq)]
+pub struct Waveshaper {
+    /// What the curve was made from, to show it and store it again
+    source: String,
+    table: Vec<f64>,
+}
+
+impl Waveshaper {
+    /// Builds a table from a function over -1 to 1
+    fn from_fn<F: Fn(f64) -> f64>(source: &str, f: F) -> Self {
+        Self {
+            source: source.to_owned(),
+            table: (0..TABLE_SIZE)
+                .map(|i| f(i as f64 / (TABLE_SIZE - 1) as f64 * 2.0 - 1.0))
+                .collect(),
+        }
+    }
+
+    /// Parses a CSV curve, either a single column of output levels for evenly spaced input levels,
+    /// or two columns of input and output levels, which are joined by straight lines. Lines starting
+    /// with `#` are comments.
+    fn from_csv(source: &str, csv: &str) -> Result<Self> {
+        let rows = csv
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                line.split(',')
+                    .map(|value| value.trim().parse::<f64>())
+                    .collect::<Result<Vec<_>, _>>()
+                    .wrap_err_with(|| format!("Invalid curve line {}", line))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        ensure!(rows.len() >= 2, "A curve needs at least two points");
+        ensure!(
+            rows.iter().all(|row| row.len() == rows[0].len()),
+            "All lines of a curve must have the same number of columns"
+        );
+
+        let table = match rows[0].len() {
+            1 => rows.iter().map(|row| row[0]).collect(),
+            2 => {
+                let mut points: Vec<(f64, f64)> = rows.iter().map(|row| (row[0], row[1])).collect();
+                points.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+                (0..TABLE_SIZE)
+                    .map(|i| interpolate(&points, i as f64 / (TABLE_SIZE - 1) as f64 * 2.0 - 1.0))
+                    .collect()
+            }
+            _ => return Err(eyre!("Curves must have one or two columns")),
+        };
+
+        Ok(Self {
+            source: source.to_owned(),
+            table,
+        })
+    }
+
+    pub fn shape(&self, x: f64) -> f64 {
+        let position = (x.clamp(-1.0, 1.0) + 1.0) / 2.0 * (self.table.len() - 1) as f64;
+        lerp(&self.table, position, Interpolation::Linear)
+    }
+}
+
+/// Follows the straight lines between points sorted by x, holding the end points beyond them
+fn interpolate(points: &[(f64, f64)], x: f64) -> f64 {
+    let next = points.partition_point(|&(px, _)| px < x);
+
+    match (points.get(next.wrapping_sub(1)), points.get(next)) {
+        (Some(&(x0, y0)), Some(&(x1, y1))) => y0 + (y1 - y0) * (x - x0) / (x1 - x0),
+        (None, Some(&(_, y))) | (Some(&(_, y)), None) => y,
+        (None, None) => unreachable!(),
+    }
+}
+
+/// Folds a level back and forth between -1 and 1, like a triangle wave
+fn fold(x: f64) -> f64 {
+    let t = (x + 1.0).rem_euclid(4.0);
+    if t < 2.0 {
+        t - 1.0
+    } else {
+        3.0 - t
+    }
+}
+
+impl FromStr for Waveshaper {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            // Clips at half scale, then brings the level back up
+            "hard" => Ok(Self::from_fn(s, |x| (2.0 * x).clamp(-1.0, 1.0))),
+            // Folds anything past a third of full scale back down
+            "fold" => Ok(Self::from_fn(s, |x| fold(3.0 * x))),
+            // Rounds off the peaks of both halves, like tape pushed into saturation
+            "tape" => Ok(Self::from_fn(s, |x| (1.5 * x).tanh() / 1.5f64.tanh())),
+            // Saturates the positive half only, for even harmonics like an overdriven tube
+            "asym" => Ok(Self::from_fn(s, |x| {
+                if x > 0.0 {
+                    (3.0 * x).tanh() / 3f64.tanh()
+                } else {
+                    x
+                }
+            })),
+            _ => {
+                let csv = fs::read_to_string(s)
+                    .wrap_err_with(|| format!("{} is neither a shape nor a readable curve", s))?;
+                Self::from_csv(s, &csv)
+            }
+        }
+    }
+}
+
+/// The table is left out, it's far too long to be of any help
+impl Debug for Waveshaper {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_tuple("Waveshaper").field(&self.source).finish()
+    }
+}
+
+impl Display for Waveshaper {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+// Curves are stored as their name or path, so curve files are read again when loading
+serde_via_str!(Waveshaper);
+
+pub struct WaveshapeStage {
+    shaper: Waveshaper,
+    clip_mode: ClipMode,
+}
+
+impl WaveshapeStage {
+    pub fn new(shaper: Waveshaper, clip_mode: ClipMode) -> Self {
+        Self { shaper, clip_mode }
+    }
+}
+
+impl Stage for WaveshapeStage {
+    fn name(&self) -> &'static str {
+        "waveshape"
+    }
+
+    fn process(&mut self, mut chunk: Sound) -> Sound {
+        let shaper = &self.shaper;
+        let clip_mode = self.clip_mode;
+
+        chunk
+            .channels
+            .par_iter_mut()
+            .flat_map(|channel| channel.samples.par_iter_mut().with_min_len(MIN_BLOCK))
+            .for_each(|sample| {
+                *sample = to_sample(
+                    shaper.shape(*sample as f64 / FULL_SCALE) * FULL_SCALE,
+                    clip_mode,
+                );
+            });
+
+        chunk
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::Channel;
+
+    #[test]
+    fn test_named_shapes() {
+        let hard: Waveshaper = "hard".parse().unwrap();
+        assert_eq!(hard.shape(0.25), 0.5);
+        assert_eq!(hard.shape(-0.75), -1.0);
+
+        let fold: Waveshaper = "fold".parse().unwrap();
+        assert!((fold.shape(0.5) - 0.5).abs() < 1e-12);
+        assert!((fold.shape(-0.5) + 0.5).abs() < 1e-12);
+
+        let asym: Waveshaper = "asym".parse().unwrap();
+        assert!((asym.shape(-0.5) + 0.5).abs() < 1e-12);
+        assert!(asym.shape(0.5) > 0.5);
+        assert_eq!(asym.shape(2.0), 1.0);
+
+        assert!("nonexistent-shape".parse::<Waveshaper>().is_err());
+    }
+
+    #[test]
+    fn test_csv_curves() {
+        let steps = Waveshaper::from_csv("steps.csv", "# Three steps\n-1\n0\n0.5").unwrap();
+        assert_eq!(steps.shape(-0.5), -0.5);
+        assert_eq!(steps.shape(0.5), 0.25);
+
+        let points = Waveshaper::from_csv("points.csv", "0.5, 1\n-0.5, -1\n0, 0").unwrap();
+        assert_eq!(points.shape(-1.0), -1.0);
+        assert_eq!(points.shape(0.25), 0.5);
+        assert_eq!(points.shape(0.75), 1.0);
+
+        assert!(Waveshaper::from_csv("bad.csv", "0\nloud").is_err());
+        assert!(Waveshaper::from_csv("bad.csv", "0,1,2\n1,2,3").is_err());
+        assert!(Waveshaper::from_csv("bad.csv", "0,1\n1").is_err());
+
+        let sound = Sound {
+            channels: vec![Channel {
+                samples: vec![1 << 29, -1 << 29],
+            }],
+            sample_rate: 44100,
+        };
+        let shaped = WaveshapeStage::new(points, ClipMode::Clamp).run(sound);
+        assert_eq!(shaped.channels[0].samples, vec![1 << 30, -1 << 30]);
+    }
+}
@@ -0,0 +1,315 @@
+use std::{
+    f64::consts::FRAC_PI_2,
+    ffi::OsString,
+    fmt::{self, Display, Formatter},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use color_eyre::eyre::{ensure, eyre, Report, Result};
+use num::Complex;
+
+use crate::{
+    sample::{to_sample, ClipMode, FULL_SCALE},
+    stft::fft,
+    Channel, Sound,
+};
+
+/// Lowest pitch a cycle is looked for at, in Hz
+const MIN_PITCH: f64 = 30.0;
+/// Highest pitch a cycle is looked for at, in Hz
+const MAX_PITCH: f64 = 4000.0;
+/// How much less alike than the best period a shorter one may be and still be taken as the cycle,
+/// so that the fundamental wins over its multiples
+const PITCH_TOLERANCE: f64 = 0.1;
+/// Correlation below which there's no pitch to find, such as in noise, and a frame is taken as it
+/// is instead of as a cycle
+const MIN_CORRELATION: f64 = 0.3;
+
+/// Wavetables to extract from the KRUSZED sound, as given on the command line, e.g. `2048x64`: the
+/// samples of each single cycle frame, then the number of frames
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WavetableSpec {
+    /// Samples of each frame, a power of two as synths expect
+    pub frame_size: usize,
+    pub frames: usize,
+}
+
+impl FromStr for WavetableSpec {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (frame_size, frames) = s
+            .trim()
+            .split_once(['x', 'X'])
+            .ok_or_else(|| eyre!("Wavetable must be of the form <frame size>x<frames>"))?;
+        let frame_size: usize = frame_size.trim().parse()?;
+        let frames: usize = frames.trim().parse()?;
+
+        ensure!(
+            frame_size.is_power_of_two() && (32..=8192).contains(&frame_size),
+            "Wavetable frame size must be a power of two from 32 to 8192"
+        );
+        ensure!(
+            (1..=256).contains(&frames),
+            "Wavetables must have from 1 to 256 frames"
+        );
+
+        Ok(Self { frame_size, frames })
+    }
+}
+
+impl Display for WavetableSpec {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{}x{}", self.frame_size, self.frames)
+    }
+}
+
+serde_via_str!(WavetableSpec);
+
+/// Where the wavetable of an output goes: next to it, always as WAV, e.g. `pad-wavetable.wav`
+pub fn wavetable_path(output: &Path) -> PathBuf {
+    let mut name = OsString::from(output.file_stem().unwrap_or_default());
+    name.push("-wavetable.wav");
+
+    output.with_file_name(name)
+}
+
+/// Takes a single cycle at each of the frames, spread evenly over the sound with all its channels
+/// mixed, as a mono sound of the frames one after the other. Each cycle is the pitch period found
+/// around its frame, resampled to the frame size with nothing above the Nyquist frequency of the
+/// frame, so that it plays back without aliasing at any pitch the frame size allows for, and turned
+/// to start in phase with the others. Frames without a pitch are taken as they are. The table is
+/// normalized to full scale, without DC.
+pub fn extract(sound: &Sound, spec: WavetableSpec) -> Sound {
+    let frames = sound.frames();
+    let mono: Vec<f64> = (0..frames)
+        .map(|i| {
+            sound
+                .channels
+                .iter()
+                .map(|channel| channel.samples[i] as f64)
+                .sum::<f64>()
+                / sound.channels.len().max(1) as f64
+        })
+        .collect();
+
+    let rate = sound.sample_rate as f64;
+    let shortest = ((rate / MAX_PITCH).floor() as usize).max(2);
+    let longest = ((rate / MIN_PITCH).ceil() as usize).max(shortest + 1);
+
+    let mut table: Vec<f64> = Vec::with_capacity(spec.frame_size * spec.frames);
+    for frame in 0..spec.frames {
+        let centre = ((frame as f64 + 0.5) / spec.frames as f64 * frames as f64) as usize;
+        let window = (4 * longest).min(frames);
+        let start = centre.saturating_sub(window / 2).min(frames - window);
+        let around = &mono[start..start + window];
+
+        let cycle = match period(around, shortest, longest.min(window / 2)) {
+            Some(period) => {
+                let from =
+                    (centre.saturating_sub(period / 2)).clamp(start, start + window - period);
+                &mono[from..from + period]
+            }
+            None => {
+                let length = spec.frame_size.min(frames);
+                let from = centre.saturating_sub(length / 2).min(frames - length);
+                &mono[from..from + length]
+            }
+        };
+        table.extend(band_limit(cycle, spec.frame_size));
+    }
+
+    let peak = table.iter().fold(0.0, |peak: f64, x| peak.max(x.abs()));
+    let gain = if peak > 0.0 { FULL_SCALE / peak } else { 0.0 };
+
+    Sound {
+        channels: vec![Channel {
+            // Normalized to full scale, so nothing is clipped
+            samples: table
+                .iter()
+                .map(|x| to_sample(x * gain, ClipMode::Clamp))
+                .collect(),
+        }],
+        sample_rate: sound.sample_rate,
+    }
+}
+
+/// The pitch period of a stretch of sound between `shortest` and `longest` frames, by
+/// autocorrelation, if it has one
+fn period(around: &[f64], shortest: usize, longest: usize) -> Option<usize> {
+    if longest <= shortest + 1 {
+        return None;
+    }
+
+    let len = around.len();
+    let mean = around.iter().sum::<f64>() / len as f64;
+    let mut spectrum: Vec<Complex<f64>> = around
+        .iter()
+        .map(|&x| Complex::new(x - mean, 0.0))
+        .chain(std::iter::repeat(Complex::new(0.0, 0.0)))
+        .take((2 * len).next_power_of_two())
+        .collect();
+    fft(&mut spectrum, false);
+    spectrum
+        .iter_mut()
+        .for_each(|bin| *bin = bin.norm_sqr().into());
+    fft(&mut spectrum, true);
+
+    let mut energy = vec![0.0; len + 1];
+    for (i, x) in around.iter().enumerate() {
+        energy[i + 1] = energy[i] + (x - mean).powi(2);
+    }
+    let similarity = |lag: usize| {
+        let norm = (energy[len - lag] * (energy[len] - energy[lag])).sqrt();
+        if norm > 0.0 {
+            spectrum[lag].re / norm
+        } else {
+            0.0
+        }
+    };
+
+    let peaks: Vec<(usize, f64)> = (shortest..longest)
+        .map(|lag| (lag, similarity(lag)))
+        .filter(|&(lag, s)| s >= similarity(lag - 1) && s >= similarity(lag + 1))
+        .collect();
+    let best = peaks
+        .iter()
+        .map(|&(_, s)| s)
+        .fold(f64::NEG_INFINITY, f64::max);
+    if best < MIN_CORRELATION {
+        return None;
+    }
+
+    peaks
+        .into_iter()
+        .find(|&(_, s)| s >= best - PITCH_TOLERANCE)
+        .map(|(lag, _)| lag)
+}
+
+/// Resamples a single cycle to `size` samples as one period, keeping only the harmonics below the
+/// Nyquist frequency of the new size, and without DC. The cycle is turned so that its fundamental
+/// starts at zero, rising, which keeps frames taken from anywhere in the sound in phase with each
+/// other, so that synths morph smoothly between them.
+fn band_limit(cycle: &[f64], size: usize) -> Vec<f64> {
+    if cycle.is_empty() {
+        return vec![0.0; size];
+    }
+
+    // Oversampled far enough that interpolating linearly is close to exact
+    let oversampled = (4 * cycle.len().max(size)).next_power_of_two();
+    let mut spectrum: Vec<Complex<f64>> = (0..oversampled)
+        .map(|i| {
+            let position = i as f64 * cycle.len() as f64 / oversampled as f64;
+            let index = position as usize;
+            let fraction = position - index as f64;
+            let (a, b) = (cycle[index], cycle[(index + 1) % cycle.len()]);
+            Complex::new(a + (b - a) * fraction, 0.0)
+        })
+        .collect();
+    fft(&mut spectrum, false);
+
+    // Harmonics up to just below the Nyquist frequency of the frame, with their mirror images,
+    // shifted so that the fundamental starts at zero and rising
+    let harmonics = (size / 2 - 1).min(cycle.len() / 2);
+    let scale = size as f64 / oversampled as f64;
+    let shift = -FRAC_PI_2 - spectrum[1].arg();
+    let mut frame = vec![Complex::new(0.0, 0.0); size];
+    for k in 1..=harmonics {
+        let rotation = Complex::from_polar(scale, k as f64 * shift);
+        frame[k] = spectrum[k] * rotation;
+        frame[size - k] = spectrum[oversampled - k] * rotation.conj();
+    }
+    fft(&mut frame, true);
+
+    frame.iter().map(|x| x.re).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use std::f64::consts::PI;
+
+    use super::*;
+
+    #[test]
+    fn test_wavetable_spec() {
+        let spec: WavetableSpec = "2048x64".parse().unwrap();
+        assert_eq!(
+            spec,
+            WavetableSpec {
+                frame_size: 2048,
+                frames: 64
+            }
+        );
+        assert_eq!(spec.to_string().parse::<WavetableSpec>().unwrap(), spec);
+
+        assert!("2000x64".parse::<WavetableSpec>().is_err());
+        assert!("2048x0".parse::<WavetableSpec>().is_err());
+        assert!("2048".parse::<WavetableSpec>().is_err());
+
+        assert_eq!(
+            wavetable_path(Path::new("out/pad.flac")),
+            Path::new("out/pad-wavetable.wav")
+        );
+    }
+
+    #[test]
+    fn test_extract() {
+        // A 441 Hz sawtooth, with harmonics up to the Nyquist frequency
+        let saw = Sound {
+            channels: vec![Channel {
+                samples: (0..44100)
+                    .map(|i| ((i % 100) as f64 / 50.0 - 1.0) * 0.5 * FULL_SCALE)
+                    .map(|x| to_sample(x, ClipMode::Clamp))
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        };
+
+        let table = extract(&saw, "64x4".parse().unwrap());
+        let samples = &table.channels[0].samples;
+        assert_eq!(samples.len(), 256);
+        assert!(samples.iter().map(|s| s.unsigned_abs()).max() >= Some(i32::MAX as u32));
+
+        // Every frame is the same single cycle, with only the harmonics a 64 sample frame can hold
+        let frame: Vec<Complex<f64>> = samples[..64]
+            .iter()
+            .map(|&s| Complex::new(s as f64, 0.0))
+            .collect();
+        let mut spectrum = frame.clone();
+        fft(&mut spectrum, false);
+        assert!(spectrum[1].norm() > spectrum[31].norm());
+        assert!(spectrum[32].norm() < 1e-6 * spectrum[1].norm());
+        assert!(spectrum[0].norm() < 1e-6 * spectrum[1].norm());
+        for other in samples.chunks(64).skip(1) {
+            let frame = frame.iter().map(|x| x.re as i32);
+            assert!(other
+                .iter()
+                .zip(frame)
+                .all(|(&a, b)| (a - b).abs() < 1 << 20));
+        }
+
+        // A pure tone comes out as a single harmonic
+        let tone = Sound {
+            channels: vec![Channel {
+                samples: (0..22050)
+                    .map(|i| {
+                        to_sample(
+                            (i as f64 * 220.5 / 44100.0 * 2.0 * PI).sin() * FULL_SCALE,
+                            ClipMode::Clamp,
+                        )
+                    })
+                    .collect(),
+            }],
+            sample_rate: 44100,
+        };
+        let table = extract(&tone, "32x1".parse().unwrap());
+        let mut spectrum: Vec<Complex<f64>> = table.channels[0]
+            .samples
+            .iter()
+            .map(|&s| Complex::new(s as f64, 0.0))
+            .collect();
+        fft(&mut spectrum, false);
+        assert!(spectrum[2].norm() < 1e-3 * spectrum[1].norm());
+    }
+}